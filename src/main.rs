@@ -2,13 +2,47 @@
 
 mod voice_app;
 
-use tracing::info;
+use std::{env, process};
 
+use tracing::{error, info};
+
+use crate::voice_app::app::VoiceApp;
 use crate::voice_app::app_tracing::{self, TRACING_TARGET};
-use crate::voice_app::voice_app::VoiceApp;
+use crate::voice_app::headless::{self, HEADLESS_FLAG, HEADLESS_USAGE};
+use crate::voice_app::quality::{self, QUALITY_FLAG, QUALITY_USAGE};
 
 fn main() {
     app_tracing::init();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if args.first().is_some_and(|x| x == HEADLESS_FLAG) {
+        info!(target: TRACING_TARGET, "Starting headless...");
+        if let Err(e) = headless::run(&args[1..]) {
+            error!(target: TRACING_TARGET, "Headless run failed: {e}");
+            eprintln!("{e}\n{HEADLESS_USAGE}");
+            process::exit(2);
+        }
+        return;
+    }
+
+    if args.first().is_some_and(|x| x == QUALITY_FLAG) {
+        match quality::run(&args[1..]) {
+            Ok(failures) if failures.is_empty() => return,
+            Ok(failures) => {
+                eprintln!(
+                    "Quality dropped below the baseline:\n{}",
+                    failures.join("\n")
+                );
+                process::exit(1);
+            }
+            Err(e) => {
+                error!(target: TRACING_TARGET, "Quality run failed: {e}");
+                eprintln!("{e}\n{QUALITY_USAGE}");
+                process::exit(2);
+            }
+        }
+    }
+
     info!(target: TRACING_TARGET, "Starting app...");
     VoiceApp::new((400.0, 300.0)).run();
 }
@@ -0,0 +1,203 @@
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+use iced::{
+    Color, Point, Rectangle, Renderer, Size, Theme, color,
+    mouse::Cursor,
+    widget::canvas::{self, Stroke},
+};
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+pub const ANALYZER_BACKGROUND: Color = color!(24.0, 24.0, 24.0);
+pub const ANALYZER_WAVEFORM: Color = color!(0.0, 160.0, 0.0);
+pub const ANALYZER_SPECTRUM: Color = color!(0.0, 128.0, 200.0);
+
+// Roughly a third of a second at the 48 kHz denoise rate
+const ANALYZER_HISTORY: usize = 16384;
+const SPECTRUM_SIZE: usize = 2048;
+const SPECTRUM_MIN_FREQUENCY: f32 = 50.0;
+const SPECTRUM_RANGE_DB: f32 = 90.0;
+
+// Ring of the latest samples, drawn in place so redraws don't copy or allocate it
+struct AnalyzerHistory {
+    samples: Vec<f32>,
+    position: usize,
+    spectrum: Vec<Complex<f32>>,
+    fft: Arc<dyn Fft<f32>>,
+}
+
+impl Default for AnalyzerHistory {
+    fn default() -> Self {
+        AnalyzerHistory {
+            samples: vec![0.0; ANALYZER_HISTORY],
+            position: 0,
+            spectrum: vec![Complex::new(0.0, 0.0); SPECTRUM_SIZE],
+            fft: FftPlanner::new().plan_fft_forward(SPECTRUM_SIZE),
+        }
+    }
+}
+
+impl AnalyzerHistory {
+    // Oldest sample first
+    fn get(&self, i: usize) -> f32 {
+        self.samples[(self.position + i) % ANALYZER_HISTORY]
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AnalyzerTap(Arc<Mutex<AnalyzerHistory>>);
+
+impl AnalyzerTap {
+    pub fn push(&self, samples: &[f32]) {
+        let mut history = self.0.lock().unwrap();
+        let samples: &[f32] = &samples[samples.len().saturating_sub(ANALYZER_HISTORY)..];
+        let position: usize = history.position;
+        let first: usize = samples.len().min(ANALYZER_HISTORY - position);
+        history.samples[position..position + first].copy_from_slice(&samples[..first]);
+        history.samples[..samples.len() - first].copy_from_slice(&samples[first..]);
+        history.position = (position + samples.len()) % ANALYZER_HISTORY;
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Analyzer {
+    pub raw: AnalyzerTap,
+    pub processed: AnalyzerTap,
+}
+
+pub struct AnalyzerView {
+    pub tap: AnalyzerTap,
+    pub sample_rate: usize,
+}
+
+impl AnalyzerView {
+    fn draw_waveform(history: &AnalyzerHistory, frame: &mut canvas::Frame, bounds: Rectangle) {
+        let columns: usize = bounds.width.max(1.0) as usize;
+        let samples_per_column: usize = (ANALYZER_HISTORY / columns).max(1);
+        let middle: f32 = bounds.y + bounds.height / 2.0;
+
+        let waveform = canvas::Path::new(|builder| {
+            for (column, start) in (0..ANALYZER_HISTORY)
+                .step_by(samples_per_column)
+                .enumerate()
+            {
+                let x: f32 = bounds.x + column as f32 * bounds.width / columns as f32;
+                let (min, max) = (start..(start + samples_per_column).min(ANALYZER_HISTORY))
+                    .map(|i| history.get(i))
+                    .fold((0.0_f32, 0.0_f32), |(min, max), x| (min.min(x), max.max(x)));
+                builder.move_to(Point::new(x, middle - max.min(1.0) * bounds.height / 2.0));
+                builder.line_to(Point::new(x, middle - min.max(-1.0) * bounds.height / 2.0));
+            }
+        });
+        frame.stroke(
+            &waveform,
+            Stroke::default()
+                .with_color(ANALYZER_WAVEFORM)
+                .with_width(1.0),
+        );
+    }
+
+    fn draw_spectrum(
+        &self,
+        history: &mut AnalyzerHistory,
+        frame: &mut canvas::Frame,
+        bounds: Rectangle,
+    ) {
+        for i in 0..SPECTRUM_SIZE {
+            let window: f32 = 0.5 - 0.5 * (2.0 * PI * i as f32 / SPECTRUM_SIZE as f32).cos();
+            history.spectrum[i] = Complex::new(
+                history.get(ANALYZER_HISTORY - SPECTRUM_SIZE + i) * window,
+                0.0,
+            );
+        }
+        let fft: Arc<dyn Fft<f32>> = history.fft.clone();
+        fft.process(&mut history.spectrum);
+
+        // Bins are spread on a log frequency axis, normalized so a full scale sine peaks at 0 dB
+        let nyquist: f32 = self.sample_rate as f32 / 2.0;
+        let frequency_range: f32 = (nyquist / SPECTRUM_MIN_FREQUENCY).ln();
+        let spectrum_line = canvas::Path::new(|builder| {
+            let mut started: bool = false;
+            for (bin, value) in history.spectrum[1..SPECTRUM_SIZE / 2].iter().enumerate() {
+                let frequency: f32 =
+                    (bin + 1) as f32 * self.sample_rate as f32 / SPECTRUM_SIZE as f32;
+                if frequency < SPECTRUM_MIN_FREQUENCY {
+                    continue;
+                }
+                let magnitude: f32 = value.norm() * 4.0 / SPECTRUM_SIZE as f32;
+                let db: f32 = (20.0 * (magnitude + 1e-9).log10()).clamp(-SPECTRUM_RANGE_DB, 0.0);
+                let point: Point = Point::new(
+                    bounds.x
+                        + (frequency / SPECTRUM_MIN_FREQUENCY).ln() / frequency_range
+                            * bounds.width,
+                    bounds.y - db / SPECTRUM_RANGE_DB * bounds.height,
+                );
+                if started {
+                    builder.line_to(point);
+                } else {
+                    builder.move_to(point);
+                    started = true;
+                }
+            }
+        });
+        frame.stroke(
+            &spectrum_line,
+            Stroke::default()
+                .with_color(ANALYZER_SPECTRUM)
+                .with_width(1.0),
+        );
+    }
+}
+
+impl<Message> canvas::Program<Message> for AnalyzerView {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &(),
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: Cursor,
+    ) -> Vec<canvas::Geometry> {
+        let mut frame = canvas::Frame::new(renderer, bounds.size());
+        frame.fill_rectangle(Point::ORIGIN, bounds.size(), ANALYZER_BACKGROUND);
+
+        // Waveform on the left, spectrum on the right
+        let half: Size = Size::new(bounds.width / 2.0 - 2.0, bounds.height);
+        let mut history = self.tap.0.lock().unwrap();
+        Self::draw_waveform(&history, &mut frame, Rectangle::new(Point::ORIGIN, half));
+        self.draw_spectrum(
+            &mut history,
+            &mut frame,
+            Rectangle::new(Point::new(bounds.width / 2.0 + 2.0, 0.0), half),
+        );
+        vec![frame.into_geometry()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(tap: &AnalyzerTap) -> Vec<f32> {
+        let history = tap.0.lock().unwrap();
+        (0..ANALYZER_HISTORY).map(|i| history.get(i)).collect()
+    }
+
+    #[test]
+    fn history_keeps_the_latest_samples_oldest_first() {
+        let tap: AnalyzerTap = AnalyzerTap::default();
+        let samples: Vec<f32> = (0..ANALYZER_HISTORY + 100).map(|x| x as f32).collect();
+        for chunk in samples.chunks(480) {
+            tap.push(chunk);
+        }
+        assert_eq!(history(&tap), samples[100..]);
+
+        // Pushing more than the history at once keeps its end
+        tap.push(&samples);
+        assert_eq!(history(&tap), samples[100..]);
+    }
+}
@@ -0,0 +1,1409 @@
+use std::{thread, time::Duration};
+
+use cpal::{
+    Host,
+    traits::{DeviceTrait, HostTrait},
+};
+use iced::futures::{
+    Stream,
+    channel::{mpsc::Sender, oneshot},
+    future,
+};
+use iced::{
+    Alignment, Element, Size, Subscription, Task,
+    alignment::{Horizontal, Vertical},
+    widget::{
+        Column, button, canvas, checkbox, column, combo_box, container, horizontal_rule, pick_list,
+        progress_bar, row, scrollable, slider, text, text_input,
+    },
+};
+use iced_aw::{TabLabel, Tabs};
+use tracing::{error, info};
+
+use crate::voice_app::{
+    analyzer::{Analyzer, AnalyzerView},
+    app_tracing::TRACING_TARGET,
+    app_type::{
+        VoiceAppAnalyzer, VoiceAppApplicationPickList, VoiceAppBandwidthPickList, VoiceAppButton,
+        VoiceAppCheckbox, VoiceAppCodecPickList, VoiceAppDeviceComboBox, VoiceAppEffectPickList,
+        VoiceAppFrameDurationPickList, VoiceAppLatencyProfilePickList, VoiceAppMicIcon,
+        VoiceAppProbePickList, VoiceAppProcessingRatePickList, VoiceAppProgressBar, VoiceAppSlider,
+        VoiceAppSpatialModePickList, VoiceAppTabBar, VoiceAppTextInput,
+        VoiceAppTranscriberPickList,
+    },
+    audio::{self, CallHandles, CueOutput, P2P},
+    buffer_stats::BufferStats,
+    channel_map::{InputChannelMap, OutputChannelMap},
+    codec::CodecKind,
+    cues::{Cue, CuePlayer},
+    denoise::DENOISE_SAMPLE_RATE,
+    effects::{EffectPreset, EffectSettings, EffectsHandle},
+    endpoint::{InputEndpoint, OutputEndpoint},
+    file_source::FilePlayer,
+    howling::HowlingReport,
+    input_check::InputProblem,
+    input_control::{ExtraInput, InputControl},
+    latency::ProbeSignal,
+    latency_profile::LatencyProfile,
+    message::Message,
+    mic_icon::{MIC_ICON_DISABLED, MIC_ICON_ENABLED, MicIcon},
+    opus_encoder::{
+        FrameDuration, OpusApplication, OpusBandwidth, OpusSettings, OpusSettingsHandle,
+    },
+    output_control::{ExtraOutput, OutputControl},
+    processing_rate::ProcessingRate,
+    protocol::negotiate,
+    spatial::SpatialMode,
+    state::State,
+    style::{
+        ANALYZER_HEIGHT, BUTTON_TEXT_SIZE, CHECKBOX_TEXT_SIZE, COMBO_BOX_TEXT_SIZE,
+        CONNECT_BUTTON_HEIGHT, CONNECT_BUTTON_WIDTH, MEASURE_BUTTON_WIDTH, METER_HEIGHT,
+        METER_WIDTH, MIC_ICON_HEIGHT, MIC_ICON_WIDTH, MIXER_LABEL_WIDTH, MUTE_BUTTON_WIDTH,
+        PICK_LIST_TEXT_SIZE, RESULT_TEXT_SIZE, SELF_LISTEN_BUTTON_HEIGHT, SELF_LISTEN_BUTTON_WIDTH,
+        SLIDER_LABEL_TEXT_SIZE, SLIDER_LABEL_WIDTH, TABS_HEIGHT, TABS_TEXT_SIZE, TEXT_INPUT_SIZE,
+        WARNING_TEXT_COLOR, connect_button_style, tabs_style, theme,
+    },
+    transcription::{Captions, TranscriberKind, TranscriberSettings},
+    transient::TransientSuppression,
+    wrapper::DeviceWrapper,
+};
+
+const OPUS_DEFAULT_BITRATE: i32 = 32000;
+const TICK_INTERVAL: Duration = Duration::from_millis(33);
+
+pub struct VoiceApp {
+    pub window_size: Size,
+}
+
+impl VoiceApp {
+    pub fn new(window_size: impl Into<Size>) -> Self {
+        VoiceApp {
+            window_size: window_size.into(),
+        }
+    }
+
+    fn init() -> (State, Task<Message>) {
+        let host: Host = cpal::default_host();
+        let state: State = State {
+            input_devices: combo_box::State::<DeviceWrapper>::new(
+                host.input_devices()
+                    .expect("Failed to get input devices")
+                    .map(DeviceWrapper)
+                    .collect(),
+            ),
+            output_devices: combo_box::State::<DeviceWrapper>::new(
+                host.output_devices()
+                    .expect("Failed to get output devices")
+                    .map(DeviceWrapper)
+                    .collect(),
+            ),
+            input_device: host.default_input_device().map(DeviceWrapper),
+            input_channels: InputChannelMap::default(),
+            input_channels_text: InputChannelMap::default().to_string(),
+            input_control: InputControl::default(),
+            extra_input_devices: combo_box::State::<DeviceWrapper>::new(
+                host.input_devices()
+                    .expect("Failed to get input devices")
+                    .map(DeviceWrapper)
+                    .collect(),
+            ),
+            extra_inputs: Vec::new(),
+            output_device: host.default_output_device().map(DeviceWrapper),
+            output_channels: OutputChannelMap::default(),
+            output_channels_text: OutputChannelMap::default().to_string(),
+            output_control: OutputControl::default(),
+            extra_output_devices: combo_box::State::<DeviceWrapper>::new(
+                host.output_devices()
+                    .expect("Failed to get output devices")
+                    .map(DeviceWrapper)
+                    .collect(),
+            ),
+            extra_outputs: Vec::new(),
+            self_listen: None,
+            p2p: None,
+            connecting: false,
+            disconnecting: false,
+            muted: false,
+            spatial_mode: SpatialMode::default(),
+            latency_profile: LatencyProfile::default(),
+            latency_profile_pending: false,
+            processing_rate: ProcessingRate::default(),
+            peer_address: String::new(),
+            record_calls: false,
+            music_mode: false,
+            preferred_codec: CodecKind::Opus,
+            codec_fallback: true,
+            opus_settings: OpusSettingsHandle::default(),
+            effects: EffectsHandle::default(),
+            transient_suppression: TransientSuppression::default(),
+            file_player: FilePlayer::default(),
+            playback_path: String::new(),
+            playback_looping: false,
+            playback_gain: 1.0,
+            active_tab: String::from("Action"),
+            probe_signal: ProbeSignal::Chirp,
+            latency_result: None,
+            analyzer: Analyzer::default(),
+            analyzer_raw: false,
+            cue_player: CuePlayer::default(),
+            cue_output: None,
+            captions: Captions::default(),
+            cue_volume: 1.0,
+        };
+        info!(
+            target: TRACING_TARGET,
+            "Init done.\nInput devices:\n    {}\nOutput devices:\n    {}",
+            state.input_devices.options()
+            .iter()
+            .map(|x| x.0.name().unwrap_or(String::from("Unknown")))
+            .collect::<Vec<String>>()
+            .join("\n    "),
+            state.output_devices.options()
+            .iter()
+            .map(|x| x.0.name().unwrap_or(String::from("Unknown")))
+            .collect::<Vec<String>>()
+            .join("\n    "),
+        );
+        (state, Task::<Message>::none())
+    }
+
+    fn view(state: &State) -> Element<'_, Message> {
+        let input_combo_box: VoiceAppDeviceComboBox = combo_box(
+            &state.input_devices,
+            "Select input device...",
+            state.input_device.as_ref(),
+            Message::InputDeviceChange,
+        )
+        .size(COMBO_BOX_TEXT_SIZE);
+
+        // Applies to the next call or self listen
+        let input_channels_text_input: VoiceAppTextInput = text_input(
+            "Input channels: auto, 1,2 or matrix:0.5,0.5;0,1",
+            &state.input_channels_text,
+        )
+        .on_input(Message::InputChannelsChange)
+        .size(TEXT_INPUT_SIZE);
+
+        let output_channels_text_input: VoiceAppTextInput =
+            text_input("Output channels: auto or 3,4", &state.output_channels_text)
+                .on_input(Message::OutputChannelsChange)
+                .size(TEXT_INPUT_SIZE);
+
+        // Mics added here are mixed into the outgoing stream, starting with the next call
+        let extra_input_combo_box: VoiceAppDeviceComboBox = combo_box(
+            &state.extra_input_devices,
+            "Add another input device...",
+            None,
+            Message::ExtraInputAdded,
+        )
+        .size(COMBO_BOX_TEXT_SIZE);
+
+        let problem_texts = |problems: Vec<InputProblem>| {
+            problems.into_iter().map(|problem| {
+                text(problem.to_string())
+                    .size(RESULT_TEXT_SIZE)
+                    .color(WARNING_TEXT_COLOR)
+                    .into()
+            })
+        };
+        let input_problems: Vec<InputProblem> =
+            match (state.p2p.as_ref(), state.self_listen.as_ref()) {
+                (Some(p2p), _) => p2p.input_problems(),
+                (None, Some(self_listen)) => self_listen.input_problems(),
+                (None, None) => Vec::new(),
+            };
+        let input_problem_texts = problem_texts(input_problems);
+
+        let input_gain_row = (!state.extra_inputs.is_empty()).then(|| {
+            let gain_slider: VoiceAppSlider =
+                slider(0.0..=2.0, state.input_control.gain(), |gain| {
+                    Message::InputGainChange(None, gain)
+                })
+                .step(0.01);
+            row![
+                text!("Gain")
+                    .size(SLIDER_LABEL_TEXT_SIZE)
+                    .width(MIXER_LABEL_WIDTH),
+                gain_slider,
+                text!("{:.1} ms", state.input_control.delay_ms()).size(SLIDER_LABEL_TEXT_SIZE)
+            ]
+            .spacing(10)
+            .align_y(Alignment::Center)
+        });
+
+        let extra_input_rows = state
+            .extra_inputs
+            .iter()
+            .enumerate()
+            .map(|(i, extra_input)| {
+                let problems: Vec<InputProblem> = state
+                    .p2p
+                    .as_ref()
+                    .map(|p2p| p2p.extra_input_problems(&extra_input.endpoint.to_string()))
+                    .unwrap_or_default();
+                let gain_slider: VoiceAppSlider =
+                    slider(0.0..=2.0, extra_input.control.gain(), move |gain| {
+                        Message::InputGainChange(Some(i), gain)
+                    })
+                    .step(0.01);
+                let remove_button: VoiceAppButton = button(
+                    text!("Remove")
+                        .size(BUTTON_TEXT_SIZE)
+                        .align_x(Horizontal::Center)
+                        .align_y(Vertical::Center),
+                )
+                .width(MEASURE_BUTTON_WIDTH)
+                .height(SELF_LISTEN_BUTTON_HEIGHT)
+                .on_press(Message::ExtraInputRemoved(i));
+                column![
+                    text!("{}", extra_input.endpoint).size(SLIDER_LABEL_TEXT_SIZE),
+                    row![
+                        text!("Gain")
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(MIXER_LABEL_WIDTH),
+                        gain_slider,
+                        text!("{:.1} ms", extra_input.control.delay_ms())
+                            .size(SLIDER_LABEL_TEXT_SIZE),
+                        remove_button
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                    Column::with_children(problem_texts(problems)).spacing(5)
+                ]
+                .spacing(5)
+                .into()
+            });
+
+        let output_combo_box: VoiceAppDeviceComboBox = combo_box(
+            &state.output_devices,
+            "Select output device...",
+            state.output_device.as_ref(),
+            Message::OutputDeviceChange,
+        )
+        .size(COMBO_BOX_TEXT_SIZE);
+
+        let output_volume_slider: VoiceAppSlider =
+            slider(0.0..=2.0, state.output_control.volume(), |volume| {
+                Message::OutputVolumeChange(None, volume)
+            })
+            .step(0.01);
+
+        // Devices added here play the call along with the main output, starting with the next call
+        let extra_output_combo_box: VoiceAppDeviceComboBox = combo_box(
+            &state.extra_output_devices,
+            "Add another output device...",
+            None,
+            Message::ExtraOutputAdded,
+        )
+        .size(COMBO_BOX_TEXT_SIZE);
+
+        let extra_output_rows = state
+            .extra_outputs
+            .iter()
+            .enumerate()
+            .map(|(i, extra_output)| {
+                let volume_slider: VoiceAppSlider =
+                    slider(0.0..=2.0, extra_output.control.volume(), move |volume| {
+                        Message::OutputVolumeChange(Some(i), volume)
+                    })
+                    .step(0.01);
+                let remove_button: VoiceAppButton = button(
+                    text!("Remove")
+                        .size(BUTTON_TEXT_SIZE)
+                        .align_x(Horizontal::Center)
+                        .align_y(Vertical::Center),
+                )
+                .width(MEASURE_BUTTON_WIDTH)
+                .height(SELF_LISTEN_BUTTON_HEIGHT)
+                .on_press(Message::ExtraOutputRemoved(i));
+                column![
+                    text!("{}", extra_output.endpoint).size(SLIDER_LABEL_TEXT_SIZE),
+                    row![
+                        text!("Volume")
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(MIXER_LABEL_WIDTH),
+                        volume_slider,
+                        text!("{:+.0} ppm", extra_output.control.correction_ppm())
+                            .size(SLIDER_LABEL_TEXT_SIZE),
+                        remove_button
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center)
+                ]
+                .spacing(5)
+                .into()
+            });
+
+        let test_button: VoiceAppButton = button(
+            text!("Test")
+                .size(BUTTON_TEXT_SIZE)
+                .align_x(Horizontal::Center)
+                .align_y(Vertical::Center),
+        )
+        .width(SELF_LISTEN_BUTTON_WIDTH)
+        .height(SELF_LISTEN_BUTTON_HEIGHT)
+        .on_press(Message::SelfListenPressed);
+
+        let measure_button: VoiceAppButton = button(
+            text!("Measure")
+                .size(BUTTON_TEXT_SIZE)
+                .align_x(Horizontal::Center)
+                .align_y(Vertical::Center),
+        )
+        .width(MEASURE_BUTTON_WIDTH)
+        .height(SELF_LISTEN_BUTTON_HEIGHT)
+        .on_press_maybe(
+            state
+                .self_listen
+                .is_none()
+                .then_some(Message::MeasureLatencyPressed),
+        );
+
+        let probe_pick_list: VoiceAppProbePickList = pick_list(
+            &ProbeSignal::ALL[..],
+            Some(state.probe_signal),
+            Message::ProbeSignalSelected,
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let latency_text =
+            text(state.latency_result.clone().unwrap_or_default()).size(RESULT_TEXT_SIZE);
+
+        let timing_text = text(
+            state
+                .p2p
+                .as_ref()
+                .map(|p2p| p2p.timing().to_string())
+                .unwrap_or_default(),
+        )
+        .size(RESULT_TEXT_SIZE);
+
+        let howling_text = |report: HowlingReport| {
+            report.active().then(|| {
+                text(report.to_string())
+                    .size(RESULT_TEXT_SIZE)
+                    .color(WARNING_TEXT_COLOR)
+            })
+        };
+        let call_howling_text = state
+            .p2p
+            .as_ref()
+            .and_then(|p2p| howling_text(p2p.howling()));
+        let self_listen_howling_text = state
+            .self_listen
+            .as_ref()
+            .and_then(|self_listen| howling_text(self_listen.howling()));
+
+        let buffer_stats: Vec<BufferStats> = match (state.p2p.as_ref(), state.self_listen.as_ref())
+        {
+            (Some(p2p), _) => p2p.buffer_stats(),
+            (None, Some(self_listen)) => self_listen.buffer_stats().to_vec(),
+            (None, None) => Vec::new(),
+        };
+        let diagnostics_text = text(if buffer_stats.is_empty() {
+            String::from("Start self listen or a call to see buffer diagnostics")
+        } else {
+            buffer_stats
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .size(RESULT_TEXT_SIZE);
+
+        let mic_icon: VoiceAppMicIcon = canvas(MicIcon {
+            radius: 10.0,
+            color: if state.self_listen.is_some() {
+                MIC_ICON_ENABLED
+            } else {
+                MIC_ICON_DISABLED
+            },
+        })
+        .width(MIC_ICON_WIDTH)
+        .height(MIC_ICON_HEIGHT);
+
+        let analyzer: VoiceAppAnalyzer = canvas(AnalyzerView {
+            tap: if state.analyzer_raw {
+                state.analyzer.raw.clone()
+            } else {
+                state.analyzer.processed.clone()
+            },
+            sample_rate: DENOISE_SAMPLE_RATE,
+        })
+        .width(iced::Length::Fill)
+        .height(ANALYZER_HEIGHT);
+
+        let analyzer_raw_checkbox: VoiceAppCheckbox = checkbox("Raw input", state.analyzer_raw)
+            .on_toggle(Message::AnalyzerRawToggled)
+            .text_size(CHECKBOX_TEXT_SIZE);
+
+        let effect_settings: EffectSettings = state.effects.get();
+
+        let effect_pick_list: VoiceAppEffectPickList = pick_list(
+            &EffectPreset::ALL[..],
+            Some(effect_settings.preset),
+            move |preset| {
+                Message::EffectSettingsChange(EffectSettings {
+                    preset,
+                    ..effect_settings
+                })
+            },
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let pitch_slider: VoiceAppSlider =
+            slider(-12.0..=12.0, effect_settings.pitch, move |pitch| {
+                Message::EffectSettingsChange(EffectSettings {
+                    pitch,
+                    ..effect_settings
+                })
+            })
+            .step(0.5);
+
+        let formant_slider: VoiceAppSlider =
+            slider(-12.0..=12.0, effect_settings.formant, move |formant| {
+                Message::EffectSettingsChange(EffectSettings {
+                    formant,
+                    ..effect_settings
+                })
+            })
+            .step(0.5);
+
+        let transient_checkbox: VoiceAppCheckbox = checkbox(
+            "Suppress keyboard clicks",
+            state.transient_suppression.enabled(),
+        )
+        .on_toggle(Message::TransientSuppressionToggled)
+        .text_size(CHECKBOX_TEXT_SIZE);
+
+        let connect_button: VoiceAppButton = button(
+            text(if state.connecting {
+                "Connecting..."
+            } else if state.disconnecting {
+                "Leaving..."
+            } else if state.p2p.is_some() {
+                "Disconnect"
+            } else {
+                "Connect"
+            })
+            .size(BUTTON_TEXT_SIZE)
+            .align_x(Horizontal::Center)
+            .align_y(Vertical::Center),
+        )
+        .width(CONNECT_BUTTON_WIDTH)
+        .height(CONNECT_BUTTON_HEIGHT)
+        .style(connect_button_style)
+        .on_press_maybe(
+            (!state.connecting && !state.disconnecting).then_some(Message::PeerConnect),
+        );
+
+        let mute_button: VoiceAppButton = button(
+            text(if state.muted { "Unmute" } else { "Mute" })
+                .size(BUTTON_TEXT_SIZE)
+                .align_x(Horizontal::Center)
+                .align_y(Vertical::Center),
+        )
+        .width(MUTE_BUTTON_WIDTH)
+        .height(SELF_LISTEN_BUTTON_HEIGHT)
+        .on_press(Message::MutePressed);
+
+        let mixer_strips = state
+            .p2p
+            .as_ref()
+            .map(|p2p| p2p.mixer().strips())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|strip| {
+                let id: usize = strip.id;
+                let gain_slider: VoiceAppSlider = slider(0.0..=2.0, strip.gain, move |gain| {
+                    Message::MixerGainChange(id, gain)
+                })
+                .step(0.01);
+                let mute_checkbox: VoiceAppCheckbox = checkbox("Mute", strip.muted)
+                    .on_toggle(move |muted| Message::MixerMuteToggled(id, muted))
+                    .text_size(CHECKBOX_TEXT_SIZE);
+                let meter: VoiceAppProgressBar = progress_bar(0.0..=1.0, strip.meter())
+                    .width(METER_WIDTH)
+                    .height(METER_HEIGHT);
+                let pan_slider: VoiceAppSlider = slider(-1.0..=1.0, strip.pan, move |pan| {
+                    Message::MixerPanChange(id, Some(pan))
+                })
+                .step(0.01);
+                let auto_pan_checkbox: VoiceAppCheckbox = checkbox("Auto", strip.auto_pan)
+                    .on_toggle(move |auto_pan| {
+                        Message::MixerPanChange(id, (!auto_pan).then_some(strip.pan))
+                    })
+                    .text_size(CHECKBOX_TEXT_SIZE);
+                column![
+                    row![
+                        text(strip.name)
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(MIXER_LABEL_WIDTH),
+                        gain_slider,
+                        mute_checkbox,
+                        meter
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                    row![
+                        text!("Pan")
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(MIXER_LABEL_WIDTH),
+                        pan_slider,
+                        auto_pan_checkbox
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center)
+                ]
+                .spacing(5)
+                .into()
+            });
+
+        let spatial_mode_pick_list: VoiceAppSpatialModePickList = pick_list(
+            &SpatialMode::ALL[..],
+            Some(state.spatial_mode),
+            Message::SpatialModeSelected,
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let latency_profile_pick_list: VoiceAppLatencyProfilePickList = pick_list(
+            &LatencyProfile::ALL[..],
+            Some(state.latency_profile),
+            Message::LatencyProfileSelected,
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let processing_rate_pick_list: VoiceAppProcessingRatePickList = pick_list(
+            &ProcessingRate::ALL[..],
+            Some(state.processing_rate),
+            Message::ProcessingRateSelected,
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let cue_volume_slider: VoiceAppSlider =
+            slider(0.0..=2.0, state.cue_volume, Message::CueVolumeChange).step(0.01);
+
+        let cue_checkboxes = Cue::ALL.iter().map(|cue| {
+            let cue: Cue = *cue;
+            VoiceAppCheckbox::new(cue.to_string(), state.cue_player.is_enabled(cue))
+                .on_toggle(move |enabled| Message::CueToggled(cue, enabled))
+                .text_size(CHECKBOX_TEXT_SIZE)
+                .into()
+        });
+
+        let peer_text_input: VoiceAppTextInput = text_input("Peer address...", &state.peer_address)
+            .on_input(Message::PeerAddressChange)
+            .size(TEXT_INPUT_SIZE);
+
+        // Music mode is agreed on when connecting, so it can't change during a call
+        let music_mode_checkbox: VoiceAppCheckbox = checkbox(
+            match state.p2p.as_ref() {
+                Some(p2p) if state.music_mode && !p2p.is_music() => "Music mode (declined by peer)",
+                _ => "Music mode",
+            },
+            state
+                .p2p
+                .as_ref()
+                .map_or(state.music_mode, |p2p| p2p.is_music()),
+        )
+        .on_toggle_maybe(
+            (state.p2p.is_none() && !state.connecting).then_some(Message::MusicModeToggled),
+        )
+        .text_size(CHECKBOX_TEXT_SIZE);
+
+        let record_checkbox: VoiceAppCheckbox = checkbox("Record calls", state.record_calls)
+            .on_toggle(Message::RecordCallsToggled)
+            .text_size(CHECKBOX_TEXT_SIZE);
+
+        let transcriber_settings: TranscriberSettings = state.captions.settings();
+
+        let transcriber_pick_list: VoiceAppTranscriberPickList = pick_list(
+            &TranscriberKind::ALL[..],
+            Some(transcriber_settings.kind),
+            {
+                let transcriber_settings: TranscriberSettings = transcriber_settings.clone();
+                move |kind| {
+                    Message::TranscriberSettingsChange(TranscriberSettings {
+                        kind,
+                        ..transcriber_settings.clone()
+                    })
+                }
+            },
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let whisper_binary_text_input: VoiceAppTextInput = text_input(
+            "whisper.cpp CLI binary...",
+            &transcriber_settings.whisper_binary,
+        )
+        .on_input({
+            let transcriber_settings: TranscriberSettings = transcriber_settings.clone();
+            move |whisper_binary| {
+                Message::TranscriberSettingsChange(TranscriberSettings {
+                    whisper_binary,
+                    ..transcriber_settings.clone()
+                })
+            }
+        })
+        .size(TEXT_INPUT_SIZE);
+
+        let whisper_model_text_input: VoiceAppTextInput = text_input(
+            "whisper.cpp model file...",
+            &transcriber_settings.whisper_model,
+        )
+        .on_input({
+            let transcriber_settings: TranscriberSettings = transcriber_settings.clone();
+            move |whisper_model| {
+                Message::TranscriberSettingsChange(TranscriberSettings {
+                    whisper_model,
+                    ..transcriber_settings.clone()
+                })
+            }
+        })
+        .size(TEXT_INPUT_SIZE);
+
+        let caption_lines = state
+            .captions
+            .lines()
+            .into_iter()
+            .map(|line| text(line.to_string()).size(RESULT_TEXT_SIZE).into());
+
+        let playback_text_input: VoiceAppTextInput =
+            text_input("Audio file (WAV/Ogg/Opus)...", &state.playback_path)
+                .on_input(Message::PlaybackPathChange)
+                .size(TEXT_INPUT_SIZE);
+
+        let playback_button: VoiceAppButton = button(
+            text(if state.file_player.is_playing() {
+                "Stop"
+            } else {
+                "Play"
+            })
+            .size(BUTTON_TEXT_SIZE)
+            .align_x(Horizontal::Center)
+            .align_y(Vertical::Center),
+        )
+        .width(SELF_LISTEN_BUTTON_WIDTH)
+        .height(SELF_LISTEN_BUTTON_HEIGHT)
+        .on_press(Message::PlaybackPressed);
+
+        let playback_loop_checkbox: VoiceAppCheckbox = checkbox("Loop", state.playback_looping)
+            .on_toggle(Message::PlaybackLoopToggled)
+            .text_size(CHECKBOX_TEXT_SIZE);
+
+        let playback_gain_slider: VoiceAppSlider =
+            slider(0.0..=2.0, state.playback_gain, Message::PlaybackGainChange).step(0.01);
+
+        let codec_pick_list: VoiceAppCodecPickList = pick_list(
+            &CodecKind::ALL[..],
+            Some(state.preferred_codec),
+            Message::PreferredCodecSelected,
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let codec_fallback_checkbox: VoiceAppCheckbox =
+            checkbox("Fall back to other codecs", state.codec_fallback)
+                .on_toggle(Message::CodecFallbackToggled)
+                .text_size(CHECKBOX_TEXT_SIZE);
+
+        let opus_settings: OpusSettings = state.opus_settings.get();
+
+        let application_pick_list: VoiceAppApplicationPickList = pick_list(
+            &OpusApplication::ALL[..],
+            Some(opus_settings.application),
+            move |application| {
+                Message::OpusSettingsChange(OpusSettings {
+                    application,
+                    ..opus_settings
+                })
+            },
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let auto_bitrate_checkbox: VoiceAppCheckbox =
+            checkbox("Auto bitrate", opus_settings.bitrate.is_none())
+                .on_toggle(move |auto| {
+                    Message::OpusSettingsChange(OpusSettings {
+                        bitrate: (!auto).then_some(OPUS_DEFAULT_BITRATE),
+                        ..opus_settings
+                    })
+                })
+                .text_size(CHECKBOX_TEXT_SIZE);
+
+        let bitrate: i32 = opus_settings.bitrate.unwrap_or(OPUS_DEFAULT_BITRATE);
+        let bitrate_slider: VoiceAppSlider =
+            slider(6.0..=256.0, bitrate as f32 / 1000.0, move |kbps| {
+                Message::OpusSettingsChange(OpusSettings {
+                    bitrate: Some((kbps * 1000.0) as i32),
+                    ..opus_settings
+                })
+            })
+            .step(1.0);
+
+        let vbr_checkbox: VoiceAppCheckbox = checkbox("VBR", opus_settings.vbr)
+            .on_toggle(move |vbr| {
+                Message::OpusSettingsChange(OpusSettings {
+                    vbr,
+                    ..opus_settings
+                })
+            })
+            .text_size(CHECKBOX_TEXT_SIZE);
+
+        let complexity_slider: VoiceAppSlider = slider(
+            0.0..=10.0,
+            opus_settings.complexity as f32,
+            move |complexity| {
+                Message::OpusSettingsChange(OpusSettings {
+                    complexity: complexity as u8,
+                    ..opus_settings
+                })
+            },
+        )
+        .step(1.0);
+
+        let frame_duration_pick_list: VoiceAppFrameDurationPickList = pick_list(
+            &FrameDuration::ALL[..],
+            Some(opus_settings.frame_duration),
+            move |frame_duration| {
+                Message::OpusSettingsChange(OpusSettings {
+                    frame_duration,
+                    ..opus_settings
+                })
+            },
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let bandwidth_pick_list: VoiceAppBandwidthPickList = pick_list(
+            &OpusBandwidth::ALL[..],
+            Some(opus_settings.max_bandwidth),
+            move |max_bandwidth| {
+                Message::OpusSettingsChange(OpusSettings {
+                    max_bandwidth,
+                    ..opus_settings
+                })
+            },
+        )
+        .text_size(PICK_LIST_TEXT_SIZE);
+
+        let tabs: VoiceAppTabBar = Tabs::new(Message::TabSelected)
+            .push(
+                String::from("Main"),
+                TabLabel::Text(String::from("Main")),
+                column![
+                    connect_button,
+                    mute_button,
+                    music_mode_checkbox,
+                    spatial_mode_pick_list,
+                    Column::with_children(mixer_strips).spacing(10),
+                    Column::new().push_maybe(call_howling_text),
+                    timing_text
+                ]
+                .padding(10)
+                .height(iced::Length::Fill)
+                .width(iced::Length::Fill)
+                .spacing(10)
+                .align_x(Horizontal::Center),
+            )
+            .push(
+                String::from("Settings"),
+                TabLabel::Text(String::from("Settings")),
+                scrollable(
+                    column![
+                        input_combo_box,
+                        row![
+                            text!("Channels")
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                                .width(MIXER_LABEL_WIDTH),
+                            input_channels_text_input
+                        ]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                        Column::with_children(input_problem_texts).spacing(5),
+                        Column::new().push_maybe(input_gain_row),
+                        extra_input_combo_box,
+                        Column::with_children(extra_input_rows).spacing(10),
+                        output_combo_box,
+                        row![
+                            text!("Channels")
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                                .width(MIXER_LABEL_WIDTH),
+                            output_channels_text_input
+                        ]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                        row![
+                            text!("Volume")
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                                .width(MIXER_LABEL_WIDTH),
+                            output_volume_slider
+                        ]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                        extra_output_combo_box,
+                        Column::with_children(extra_output_rows).spacing(10),
+                        row![latency_profile_pick_list, processing_rate_pick_list].spacing(10),
+                        row![test_button, measure_button, probe_pick_list, mic_icon]
+                            .spacing(10)
+                            .align_y(Alignment::Center),
+                        latency_text,
+                        Column::new().push_maybe(self_listen_howling_text),
+                        analyzer,
+                        analyzer_raw_checkbox,
+                        transient_checkbox,
+                        effect_pick_list,
+                        row![
+                            text!("Pitch")
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                                .width(SLIDER_LABEL_WIDTH),
+                            pitch_slider,
+                            text!("{:+.1} st", effect_settings.pitch).size(SLIDER_LABEL_TEXT_SIZE)
+                        ]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                        row![
+                            text!("Formant")
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                                .width(SLIDER_LABEL_WIDTH),
+                            formant_slider,
+                            text!("{:+.1} st", effect_settings.formant)
+                                .size(SLIDER_LABEL_TEXT_SIZE)
+                        ]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                        horizontal_rule(2),
+                        peer_text_input,
+                        record_checkbox,
+                    ]
+                    .padding(10)
+                    .spacing(10),
+                ),
+            )
+            .push(
+                String::from("Playback"),
+                TabLabel::Text(String::from("Playback")),
+                column![
+                    playback_text_input,
+                    row![playback_button, playback_loop_checkbox]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                    row![
+                        text!("Gain")
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(SLIDER_LABEL_WIDTH),
+                        playback_gain_slider
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                    horizontal_rule(2),
+                    row![
+                        text!("Cues")
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(SLIDER_LABEL_WIDTH),
+                        cue_volume_slider
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                    Column::with_children(cue_checkboxes).spacing(5),
+                ]
+                .padding(10)
+                .spacing(10),
+            )
+            .push(
+                String::from("Codec"),
+                TabLabel::Text(String::from("Codec")),
+                column![
+                    row![codec_pick_list, codec_fallback_checkbox]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                    row![application_pick_list, frame_duration_pick_list]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                    bandwidth_pick_list,
+                    row![auto_bitrate_checkbox, vbr_checkbox]
+                        .spacing(10)
+                        .align_y(Alignment::Center),
+                    row![
+                        text(if opus_settings.bitrate.is_some() {
+                            format!("{} kbps", bitrate / 1000)
+                        } else {
+                            String::from("Auto")
+                        })
+                        .size(SLIDER_LABEL_TEXT_SIZE)
+                        .width(SLIDER_LABEL_WIDTH),
+                        bitrate_slider
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                    row![
+                        text!("Cx {}", opus_settings.complexity)
+                            .size(SLIDER_LABEL_TEXT_SIZE)
+                            .width(SLIDER_LABEL_WIDTH),
+                        complexity_slider
+                    ]
+                    .spacing(10)
+                    .align_y(Alignment::Center),
+                ]
+                .padding(10)
+                .spacing(10),
+            )
+            .push(
+                String::from("Captions"),
+                TabLabel::Text(String::from("Captions")),
+                column![
+                    transcriber_pick_list,
+                    whisper_binary_text_input,
+                    whisper_model_text_input,
+                    text!("Takes effect on the next call, transcripts are saved next to call recordings")
+                        .size(RESULT_TEXT_SIZE),
+                    horizontal_rule(2),
+                    scrollable(Column::with_children(caption_lines).spacing(5))
+                        .anchor_bottom()
+                        .height(iced::Length::Fill),
+                ]
+                .padding(10)
+                .spacing(10),
+            )
+            .push(
+                String::from("Diagnostics"),
+                TabLabel::Text(String::from("Diagnostics")),
+                scrollable(column![diagnostics_text].padding(10)),
+            )
+            .tab_bar_style(tabs_style)
+            .tab_bar_height(TABS_HEIGHT)
+            .text_size(TABS_TEXT_SIZE)
+            .tab_label_padding(0)
+            .set_active_tab(&state.active_tab);
+
+        let app_container = container(tabs);
+
+        app_container.into()
+    }
+
+    fn update(state: &mut State, message: Message) -> Task<Message> {
+        match message {
+            Message::InputDeviceChange(device) => {
+                state.input_device = Some(device);
+                info!(target: TRACING_TARGET, "Input device changed to: {}", state.input_device.as_ref().unwrap().0.name().unwrap_or(String::from("Unknown")));
+            }
+            Message::OutputDeviceChange(device) => {
+                state.output_device = Some(device);
+                info!(target: TRACING_TARGET, "Output device changed to: {}", state.output_device.as_ref().unwrap().0.name().unwrap_or(String::from("Unknown")));
+            }
+            // Text that doesn't parse yet keeps the last valid mapping
+            Message::InputChannelsChange(text) => {
+                match InputChannelMap::parse(&text) {
+                    Ok(input_channels) => state.input_channels = input_channels,
+                    Err(e) => {
+                        info!(target: TRACING_TARGET, "Keeping input channels {}: {e}", state.input_channels)
+                    }
+                }
+                state.input_channels_text = text;
+            }
+            Message::OutputChannelsChange(text) => {
+                match OutputChannelMap::parse(&text) {
+                    Ok(output_channels) => state.output_channels = output_channels,
+                    Err(e) => {
+                        info!(target: TRACING_TARGET, "Keeping output channels {}: {e}", state.output_channels)
+                    }
+                }
+                state.output_channels_text = text;
+            }
+            Message::ExtraInputAdded(device) => {
+                let name: String = device.to_string();
+                if state
+                    .extra_inputs
+                    .iter()
+                    .all(|x| x.endpoint.to_string() != name)
+                {
+                    info!(target: TRACING_TARGET, "Extra input device added: {name}");
+                    state.extra_inputs.push(ExtraInput {
+                        endpoint: InputEndpoint::Device(device.0, InputChannelMap::default()),
+                        control: InputControl::default(),
+                    });
+                }
+            }
+            Message::ExtraInputRemoved(i) => {
+                if i < state.extra_inputs.len() {
+                    let extra_input: ExtraInput = state.extra_inputs.remove(i);
+                    info!(target: TRACING_TARGET, "Extra input device removed: {}", extra_input.endpoint);
+                }
+            }
+            // Gains apply right away, even to a running call
+            Message::InputGainChange(i, gain) => match i {
+                None => state.input_control.set_gain(gain),
+                Some(i) => {
+                    if let Some(extra_input) = state.extra_inputs.get(i) {
+                        extra_input.control.set_gain(gain);
+                    }
+                }
+            },
+            Message::ExtraOutputAdded(device) => {
+                let name: String = device.to_string();
+                if state
+                    .extra_outputs
+                    .iter()
+                    .all(|x| x.endpoint.to_string() != name)
+                {
+                    info!(target: TRACING_TARGET, "Extra output device added: {name}");
+                    state.extra_outputs.push(ExtraOutput {
+                        endpoint: OutputEndpoint::Device(device.0, OutputChannelMap::default()),
+                        control: OutputControl::default(),
+                    });
+                }
+            }
+            Message::ExtraOutputRemoved(i) => {
+                if i < state.extra_outputs.len() {
+                    let extra_output: ExtraOutput = state.extra_outputs.remove(i);
+                    info!(target: TRACING_TARGET, "Extra output device removed: {}", extra_output.endpoint);
+                }
+            }
+            // Volumes apply right away, even to a running call
+            Message::OutputVolumeChange(i, volume) => match i {
+                None => state.output_control.set_volume(volume),
+                Some(i) => {
+                    if let Some(extra_output) = state.extra_outputs.get(i) {
+                        extra_output.control.set_volume(volume);
+                    }
+                }
+            },
+            Message::PeerAddressChange(peer_address) => {
+                state.peer_address = peer_address;
+            }
+            Message::PeerConnect => {
+                if state.p2p.is_none() {
+                    // Negotiation waits for the peer, so it runs off the UI thread
+                    let (negotiation_sender, negotiation_receiver) = oneshot::channel();
+                    let peer_address: String = state.peer_address.clone();
+                    let codecs: Vec<CodecKind> =
+                        CodecKind::preferences(state.preferred_codec, state.codec_fallback);
+                    let music_mode: bool = state.music_mode;
+                    state.connecting = true;
+                    thread::spawn(move || {
+                        let _ =
+                            negotiation_sender.send(negotiate(&peer_address, &codecs, music_mode));
+                    });
+                    return Task::perform(negotiation_receiver, |negotiation| {
+                        Message::PeerNegotiated(
+                            negotiation.unwrap_or(Err(String::from("Negotiation was cancelled"))),
+                        )
+                    });
+                } else if let Some(p2p) = state.p2p.as_ref() {
+                    // The output keeps running until the disconnect cue has played
+                    p2p.hang_up();
+                    state.cue_player.play(Cue::Disconnect);
+                    state.disconnecting = true;
+                    let (delay_sender, delay_receiver) = oneshot::channel();
+                    thread::spawn(move || {
+                        thread::sleep(Cue::Disconnect.duration());
+                        let _ = delay_sender.send(());
+                    });
+                    return Task::perform(delay_receiver, |_| Message::PeerDisconnected);
+                }
+            }
+            Message::PeerDisconnected => {
+                state.disconnecting = false;
+                state.muted = false;
+                state.p2p = None;
+            }
+            Message::CueOutputFinished => {
+                state.cue_output = None;
+            }
+            Message::MutePressed => {
+                state.muted = !state.muted;
+                if let Some(p2p) = state.p2p.as_ref() {
+                    p2p.set_muted(state.muted);
+                }
+                state.cue_player.play(if state.muted {
+                    Cue::MuteOn
+                } else {
+                    Cue::MuteOff
+                });
+            }
+            Message::CueToggled(cue, enabled) => {
+                state.cue_player.set_enabled(cue, enabled);
+            }
+            Message::CueVolumeChange(cue_volume) => {
+                state.cue_volume = cue_volume;
+                state.cue_player.set_volume(cue_volume);
+            }
+            Message::MixerGainChange(id, gain) => {
+                if let Some(p2p) = state.p2p.as_ref() {
+                    p2p.mixer().set_gain(id, gain);
+                }
+            }
+            Message::MixerMuteToggled(id, muted) => {
+                if let Some(p2p) = state.p2p.as_ref() {
+                    p2p.mixer().set_muted(id, muted);
+                }
+            }
+            Message::MixerPanChange(id, pan) => {
+                if let Some(p2p) = state.p2p.as_ref() {
+                    p2p.mixer().set_pan(id, pan);
+                }
+            }
+            Message::SpatialModeSelected(spatial_mode) => {
+                state.spatial_mode = spatial_mode;
+                if let Some(p2p) = state.p2p.as_ref() {
+                    p2p.mixer().set_spatial_mode(spatial_mode);
+                }
+            }
+            // Applies to the next call or self listen, the frame length can still be changed on the Codec tab
+            Message::LatencyProfileSelected(latency_profile) => {
+                state.latency_profile = latency_profile;
+                state.latency_profile_pending = true;
+            }
+            // Applies to the next call or self listen
+            Message::ProcessingRateSelected(processing_rate) => {
+                state.processing_rate = processing_rate;
+            }
+            Message::PeerNegotiated(negotiation) => {
+                state.connecting = false;
+                match negotiation {
+                    Ok(negotiation) => {
+                        apply_latency_profile(state);
+                        state.p2p = Some(P2P::new(
+                            &InputEndpoint::Device(
+                                state.input_device.as_ref().unwrap().0.clone(),
+                                state.input_channels.clone(),
+                            ),
+                            &OutputEndpoint::Device(
+                                state.output_device.as_ref().unwrap().0.clone(),
+                                state.output_channels.clone(),
+                            ),
+                            &negotiation,
+                            state.record_calls,
+                            CallHandles {
+                                file_player: state.file_player.clone(),
+                                analyzer: state.analyzer.clone(),
+                                opus_settings: state.opus_settings.clone(),
+                                cue_player: state.cue_player.clone(),
+                                captions: state.captions.clone(),
+                                effects: state.effects.clone(),
+                                transient_suppression: state.transient_suppression.clone(),
+                                input_control: state.input_control.clone(),
+                                extra_inputs: state.extra_inputs.clone(),
+                                output_control: state.output_control.clone(),
+                                extra_outputs: state.extra_outputs.clone(),
+                            },
+                            state.latency_profile,
+                            state.processing_rate,
+                        ));
+                        if let Some(p2p) = state.p2p.as_ref() {
+                            p2p.set_muted(state.muted);
+                            p2p.mixer().set_spatial_mode(state.spatial_mode);
+                        }
+                        state.cue_player.play(Cue::Connect);
+                    }
+                    Err(e) => {
+                        error!(target: TRACING_TARGET, "Failed to connect: {e}");
+                        // No call runs to mix the cue into, it gets an output of its own for as long as it plays
+                        state.cue_output = state.output_device.as_ref().and_then(|output_device| {
+                            CueOutput::new(
+                                &OutputEndpoint::Device(
+                                    output_device.0.clone(),
+                                    state.output_channels.clone(),
+                                ),
+                                &state.cue_player,
+                                Cue::ConnectFailed,
+                            )
+                        });
+                        let (delay_sender, delay_receiver) = oneshot::channel();
+                        thread::spawn(move || {
+                            thread::sleep(Cue::ConnectFailed.duration());
+                            let _ = delay_sender.send(());
+                        });
+                        return Task::perform(delay_receiver, |_| Message::CueOutputFinished);
+                    }
+                }
+            }
+            Message::PreferredCodecSelected(preferred_codec) => {
+                state.preferred_codec = preferred_codec;
+            }
+            Message::CodecFallbackToggled(codec_fallback) => {
+                state.codec_fallback = codec_fallback;
+            }
+            Message::RecordCallsToggled(record_calls) => {
+                state.record_calls = record_calls;
+            }
+            Message::MusicModeToggled(music_mode) => {
+                state.music_mode = music_mode;
+            }
+            Message::EffectSettingsChange(effect_settings) => {
+                state.effects.set(effect_settings);
+            }
+            Message::TransientSuppressionToggled(enabled) => {
+                state.transient_suppression.set_enabled(enabled);
+            }
+            Message::OpusSettingsChange(opus_settings) => {
+                // A frame length picked by hand wins over the one of a profile still waiting for the next call
+                if opus_settings.frame_duration != state.opus_settings.get().frame_duration {
+                    state.latency_profile_pending = false;
+                }
+                state.opus_settings.set(opus_settings);
+            }
+            Message::TranscriberSettingsChange(transcriber_settings) => {
+                state.captions.set_settings(transcriber_settings);
+            }
+            Message::PlaybackPathChange(playback_path) => {
+                state.playback_path = playback_path;
+            }
+            Message::PlaybackPressed => {
+                if state.file_player.is_playing() {
+                    state.file_player.stop();
+                } else {
+                    state.file_player.set_looping(state.playback_looping);
+                    state.file_player.set_gain(state.playback_gain);
+                    // Files are loaded at the rate of whatever is running, or the one it will start at
+                    let sample_rate: usize = state
+                        .p2p
+                        .as_ref()
+                        .map(|x| x.sample_rate())
+                        .or_else(|| state.self_listen.as_ref().map(|x| x.sample_rate()))
+                        .unwrap_or(state.processing_rate.sample_rate());
+                    state
+                        .file_player
+                        .play(state.playback_path.clone(), sample_rate);
+                }
+            }
+            Message::PlaybackLoopToggled(playback_looping) => {
+                state.playback_looping = playback_looping;
+                state.file_player.set_looping(playback_looping);
+            }
+            Message::PlaybackGainChange(playback_gain) => {
+                state.playback_gain = playback_gain;
+                state.file_player.set_gain(playback_gain);
+            }
+            Message::TabSelected(tab) => {
+                state.active_tab = tab;
+            }
+            Message::SelfListenPressed => {
+                if state.self_listen.is_none() {
+                    info!(target: TRACING_TARGET, "Attempting to create streams...");
+                    apply_latency_profile(state);
+
+                    state.self_listen = Some(audio::SelfListen::new(
+                        &InputEndpoint::Device(
+                            state.input_device.as_ref().unwrap().0.clone(),
+                            state.input_channels.clone(),
+                        ),
+                        &OutputEndpoint::Device(
+                            state.output_device.as_ref().unwrap().0.clone(),
+                            state.output_channels.clone(),
+                        ),
+                        CallHandles {
+                            file_player: state.file_player.clone(),
+                            analyzer: state.analyzer.clone(),
+                            effects: state.effects.clone(),
+                            transient_suppression: state.transient_suppression.clone(),
+                            ..CallHandles::default()
+                        },
+                        None,
+                        state.latency_profile,
+                        state.processing_rate,
+                    ));
+                } else {
+                    info!(target: TRACING_TARGET, "Dropping streams");
+                    state.self_listen = None;
+                }
+            }
+            Message::MeasureLatencyPressed => {
+                if state.self_listen.is_none() {
+                    info!(target: TRACING_TARGET, "Attempting to measure latency...");
+                    apply_latency_profile(state);
+                    let (report_sender, report_receiver) = oneshot::channel();
+                    state.latency_result = Some(String::from("Measuring..."));
+                    state.self_listen = Some(audio::SelfListen::new(
+                        &InputEndpoint::Device(
+                            state.input_device.as_ref().unwrap().0.clone(),
+                            state.input_channels.clone(),
+                        ),
+                        &OutputEndpoint::Device(
+                            state.output_device.as_ref().unwrap().0.clone(),
+                            state.output_channels.clone(),
+                        ),
+                        CallHandles {
+                            file_player: state.file_player.clone(),
+                            analyzer: state.analyzer.clone(),
+                            effects: state.effects.clone(),
+                            transient_suppression: state.transient_suppression.clone(),
+                            ..CallHandles::default()
+                        },
+                        Some((state.probe_signal, report_sender)),
+                        state.latency_profile,
+                        state.processing_rate,
+                    ));
+                    return Task::perform(report_receiver, |report| {
+                        Message::LatencyMeasured(
+                            report.unwrap_or(Err(String::from("Measurement was cancelled"))),
+                        )
+                    });
+                }
+            }
+            Message::ProbeSignalSelected(probe_signal) => {
+                state.probe_signal = probe_signal;
+            }
+            Message::AnalyzerRawToggled(analyzer_raw) => {
+                state.analyzer_raw = analyzer_raw;
+            }
+            Message::Tick => {}
+            Message::LatencyMeasured(report) => {
+                state.self_listen = None;
+                state.latency_result = Some(match report {
+                    Ok(report) => report.to_string(),
+                    Err(e) => e,
+                });
+            }
+        }
+
+        Task::none()
+    }
+
+    // Redraws the analyzer while audio is flowing and the Settings tab is visible
+    fn subscription(state: &State) -> Subscription<Message> {
+        // Meters, captions, the analyzer and buffer counters redraw on ticks while their tab is visible
+        let running: bool = state.self_listen.is_some() || state.p2p.is_some();
+        if ((state.active_tab == "Settings" || state.active_tab == "Diagnostics") && running)
+            || ((state.active_tab == "Main" || state.active_tab == "Captions")
+                && state.p2p.is_some())
+        {
+            Subscription::run(ticks)
+        } else {
+            Subscription::none()
+        }
+    }
+
+    pub fn run(&self) {
+        iced::application("Voice", Self::update, Self::view)
+            .subscription(Self::subscription)
+            .theme(theme)
+            .window_size(self.window_size)
+            .antialiasing(true)
+            .run_with(Self::init)
+            .expect("Failed to run application");
+    }
+}
+
+// The encoder polls the Opus settings, so a running call would switch frame length under buffers sized for the old profile
+fn apply_latency_profile(state: &mut State) {
+    if state.latency_profile_pending && state.p2p.is_none() {
+        state.latency_profile_pending = false;
+        state.opus_settings.set(OpusSettings {
+            frame_duration: state.latency_profile.frame_duration(),
+            ..state.opus_settings.get()
+        });
+    }
+}
+
+// There is no async runtime with timers enabled, so ticks come from a plain thread
+fn ticks() -> impl Stream<Item = Message> {
+    iced::stream::channel(1, |output: Sender<Message>| async move {
+        thread::spawn(move || {
+            let mut output: Sender<Message> = output;
+            while !output.is_closed() {
+                let _ = output.try_send(Message::Tick);
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+        future::pending::<()>().await
+    })
+}
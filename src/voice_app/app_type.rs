@@ -1,13 +1,50 @@
 use iced::{
     Renderer, Theme,
-    widget::{Button, Canvas, ComboBox, TextInput},
+    widget::{Button, Canvas, Checkbox, ComboBox, PickList, ProgressBar, Slider, TextInput},
 };
 use iced_aw::Tabs;
 
-use crate::voice_app::{message::Message, mic_icon::MicIcon, wrapper::DeviceWrapper};
+use crate::voice_app::{
+    analyzer::AnalyzerView,
+    codec::CodecKind,
+    effects::EffectPreset,
+    latency::ProbeSignal,
+    latency_profile::LatencyProfile,
+    message::Message,
+    mic_icon::MicIcon,
+    opus_encoder::{FrameDuration, OpusApplication, OpusBandwidth},
+    processing_rate::ProcessingRate,
+    spatial::SpatialMode,
+    transcription::TranscriberKind,
+    wrapper::DeviceWrapper,
+};
 
 pub type VoiceAppDeviceComboBox<'a> = ComboBox<'a, DeviceWrapper, Message, Theme, Renderer>;
 pub type VoiceAppButton<'a> = Button<'a, Message, Theme, Renderer>;
 pub type VoiceAppTabBar<'a> = Tabs<'a, Message, String, Theme, Renderer>;
 pub type VoiceAppMicIcon = Canvas<MicIcon, Message>;
+pub type VoiceAppAnalyzer = Canvas<AnalyzerView, Message>;
+pub type VoiceAppCheckbox<'a> = Checkbox<'a, Message, Theme, Renderer>;
+pub type VoiceAppProbePickList<'a> =
+    PickList<'a, ProbeSignal, &'a [ProbeSignal], ProbeSignal, Message, Theme, Renderer>;
+pub type VoiceAppCodecPickList<'a> =
+    PickList<'a, CodecKind, &'a [CodecKind], CodecKind, Message, Theme, Renderer>;
+pub type VoiceAppApplicationPickList<'a> =
+    PickList<'a, OpusApplication, &'a [OpusApplication], OpusApplication, Message, Theme, Renderer>;
+pub type VoiceAppFrameDurationPickList<'a> =
+    PickList<'a, FrameDuration, &'a [FrameDuration], FrameDuration, Message, Theme, Renderer>;
+pub type VoiceAppBandwidthPickList<'a> =
+    PickList<'a, OpusBandwidth, &'a [OpusBandwidth], OpusBandwidth, Message, Theme, Renderer>;
+pub type VoiceAppSpatialModePickList<'a> =
+    PickList<'a, SpatialMode, &'a [SpatialMode], SpatialMode, Message, Theme, Renderer>;
+pub type VoiceAppEffectPickList<'a> =
+    PickList<'a, EffectPreset, &'a [EffectPreset], EffectPreset, Message, Theme, Renderer>;
+pub type VoiceAppLatencyProfilePickList<'a> =
+    PickList<'a, LatencyProfile, &'a [LatencyProfile], LatencyProfile, Message, Theme, Renderer>;
+pub type VoiceAppProcessingRatePickList<'a> =
+    PickList<'a, ProcessingRate, &'a [ProcessingRate], ProcessingRate, Message, Theme, Renderer>;
+pub type VoiceAppTranscriberPickList<'a> =
+    PickList<'a, TranscriberKind, &'a [TranscriberKind], TranscriberKind, Message, Theme, Renderer>;
+pub type VoiceAppSlider<'a> = Slider<'a, f32, Message, Theme>;
+pub type VoiceAppProgressBar<'a> = ProgressBar<'a, Theme>;
 pub type VoiceAppTextInput<'a> = TextInput<'a, Message, Theme, Renderer>;
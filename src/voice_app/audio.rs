@@ -1,45 +1,100 @@
 use std::{
-    net::UdpSocket,
+    io,
+    path::Path,
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
-    thread, usize,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use cpal::{
-    Device, Sample, Stream, StreamConfig,
-    traits::{DeviceTrait, StreamTrait},
-};
-use nnnoiseless::DenoiseState;
-use opus::{Application, Channels, Decoder, Encoder};
+use cpal::{Sample, StreamConfig};
+use iced::futures::channel::oneshot;
 use ringbuf::{
     HeapRb,
     traits::{Consumer, Observer, Producer, Split},
 };
-use rubato::{FftFixedIn, Resampler};
+use rubato::{
+    FftFixedIn, Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType,
+    WindowFunction,
+};
 use tracing::{error, info};
 
+use crate::voice_app::{
+    analyzer::Analyzer,
+    buffer_stats::{BufferStats, Xruns},
+    channel_map::ChannelMatrix,
+    codec::{AudioDecoder, AudioEncoder, CodecKind},
+    cues::{Cue, CuePlayer},
+    denoise::{DenoiseStage, denoise_offset},
+    effects::{CachedEffects, EffectsHandle, VoiceEffects},
+    endpoint::{
+        EndpointStream, InputEndpoint, OutputEndpoint, build_input_stream, build_output_stream,
+    },
+    file_source::{FilePlayer, FileReader},
+    howling::{HowlingMonitor, HowlingReport, HowlingSuppressor},
+    input_check::{ChannelLevels, InputCheck, InputChecker, InputMeter, InputProblem},
+    input_control::{ExtraInput, InputControl, MicAligner, MicDelay},
+    latency::{LatencyMeasurement, LatencyReport, ProbeCapture, ProbePlayback, ProbeSignal},
+    latency_profile::LatencyProfile,
+    mixer::{Limiter, Mixer},
+    ogg_opus::{OPUS_GRANULE_RATE, OggOpusWriter},
+    opus_encoder::{OpusEncoder, OpusSettings, OpusSettingsHandle},
+    output_control::{DriftCompensator, ExtraOutput, MAX_DRIFT_CORRECTION, OutputControl},
+    processing_rate::ProcessingRate,
+    protocol::{Negotiation, Packet},
+    timing::{POSITION_RATE, RemoteClock, StreamClock, TimingReport, unix_micros, unwrap_position},
+    transcription::{CaptionLine, Captions, Segmenter, Speaker, Transcriber, TranscriberSettings},
+    transient::TransientSuppression,
+};
+
 const TRACING_TARGET: &str = "app";
 
-type P = ringbuf::wrap::caching::Caching<
+// Large enough for 120 ms Opus packets as well as any PCM frame
+const MAX_PACKET_SIZE: usize = 4000;
+const MAX_FRAME_SIZE: usize = 5760;
+// Type byte, capture timestamp and stream position in front of the payload
+pub const AUDIO_HEADER_SIZE: usize = 13;
+
+// Silence from the peer for this long counts as a lost connection
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Mixing and effects work on 10 ms frames at the processing rate
+const MIX_FRAMES_PER_SECOND: usize = 100;
+// Remote voices are placed in a stereo field before playback
+const MIX_CHANNELS: usize = 2;
+
+// Room for 30 s of speech queued up while a slow transcriber works
+const TRANSCRIPTION_BUFFER_SECONDS: usize = 30;
+
+// Audio queued from every extra mic, enough to cover the 20 ms chunks of its resampler
+const EXTRA_MIC_TARGET_FILL_MS: usize = 20;
+const EXTRA_MIC_SLIP_MARGIN: usize = 16;
+const EXTRA_MIC_FILL_SMOOTHING: f32 = 0.005;
+
+// Windows of input levels queued for the input check, which only needs to look every so often
+const INPUT_CHECK_WINDOWS: usize = 4;
+const INPUT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+pub type P = ringbuf::wrap::caching::Caching<
     Arc<ringbuf::SharedRb<ringbuf::storage::Heap<f32>>>,
     true,
     false,
 >;
-type C = ringbuf::wrap::caching::Caching<
+pub type C = ringbuf::wrap::caching::Caching<
     Arc<ringbuf::SharedRb<ringbuf::storage::Heap<f32>>>,
     false,
     true,
 >;
 
-fn deinterleave(channels: usize, input: &Vec<f32>, output: &mut Vec<Vec<f32>>) {
+fn deinterleave(channels: usize, input: &[f32], output: &mut [Vec<f32>]) {
     for (i, val) in input.iter().enumerate() {
         output[i % channels][i / channels] = *val;
     }
 }
 
-fn interleave(input: &Vec<Vec<f32>>, output: &mut Vec<f32>) {
+fn interleave(input: &[Vec<f32>], output: &mut [f32]) {
     for i in 0..input[0].len() {
         for channel in 0..input.len() {
             output[input.len() * i + channel] = input[channel][i];
@@ -49,58 +104,123 @@ fn interleave(input: &Vec<Vec<f32>>, output: &mut Vec<f32>) {
 
 fn create_input_stream(
     channels: usize,
-    input_device: &Device,
+    input: &InputEndpoint,
     input_config: &StreamConfig,
     mut input_producer: P,
-) -> Stream {
-    input_device
-        .build_input_stream(
-            input_config,
-            move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                // `data` is slice [channel_0_sample_0, channel_1_sample_0, channel_0_sample_1, channel_1_sample_1 ...]
-                for sample in data.chunks(channels) {
-                    if input_producer.is_full() {
-                        continue;
-                    }
-                    input_producer
-                        .try_push(sample.into_iter().sum::<f32>() / channels as f32)
-                        .expect("Failed to push to input buffer");
+    mut probe_capture: Option<ProbeCapture>,
+    clock: StreamClock,
+    xruns: Xruns,
+) -> (EndpointStream, InputCheck) {
+    let device_channels: usize = input_config.channels as usize;
+    // Only the picked channels are captured, mixed down to what is sent on
+    let matrix: ChannelMatrix = input.channel_matrix(channels, device_channels);
+    let mut mapped: Vec<f32> = vec![Sample::EQUILIBRIUM; channels];
+    let input_check: InputCheck = InputCheck::default();
+    let (levels_producer, levels_consumer) =
+        HeapRb::<ChannelLevels>::new(device_channels * INPUT_CHECK_WINDOWS).split();
+    let mut meter: InputMeter =
+        InputMeter::new(levels_producer, device_channels, input_config.sample_rate.0);
+    create_input_check_thread(InputChecker::new(
+        input_check.clone(),
+        matrix.used_channels(),
+        device_channels,
+        input_config.sample_rate.0,
+        levels_consumer,
+    ));
+    let stream: EndpointStream = build_input_stream(
+        input,
+        input_config,
+        move |data: &[f32], instant: Instant| {
+            clock.advance(data.len() / device_channels, instant);
+            if let Some(probe_capture) = probe_capture.as_ref() {
+                probe_capture.start();
+            }
+            let mut dropped: usize = 0;
+            // `data` is slice [channel_0_sample_0, channel_1_sample_0, channel_0_sample_1, channel_1_sample_1 ...]
+            for frame in data.chunks(device_channels) {
+                meter.push(frame);
+                matrix.apply(frame, &mut mapped);
+                if let Some(probe_capture) = probe_capture.as_mut() {
+                    probe_capture.push(mapped.iter().sum::<f32>() / channels as f32);
                 }
-            },
-            |err| error!(target: TRACING_TARGET, "An error occurred on input stream: {err}"),
-            None,
-        )
-        .expect("Failed to build input stream")
+                if input_producer.vacant_len() < channels {
+                    dropped += channels;
+                    continue;
+                }
+                input_producer.push_slice(&mapped);
+            }
+            if dropped > 0 {
+                xruns.overrun(dropped);
+            }
+        },
+    );
+    (stream, input_check)
+}
+
+// Stops by itself once the input stream is dropped, as the meter goes with it
+fn create_input_check_thread(mut checker: InputChecker) {
+    info!(target: TRACING_TARGET, "Starting input check thread");
+
+    thread::spawn(move || {
+        while checker.is_metered() {
+            checker.poll();
+            thread::sleep(INPUT_CHECK_INTERVAL);
+        }
+
+        info!(target: TRACING_TARGET, "Stopping input check thread");
+    });
 }
 
 fn create_output_stream(
-    channels: usize,
-    output_device: &Device,
+    source_channels: usize,
+    output: &OutputEndpoint,
     output_config: &StreamConfig,
     mut resampler_consumer: C,
-) -> Stream {
-    let mut resampled: f32 = Sample::EQUILIBRIUM;
-    output_device
-        .build_output_stream(
-            output_config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                for (i, sample) in data.iter_mut().enumerate() {
-                    if i % channels == 0 {
-                        resampled = resampler_consumer.try_pop().unwrap_or(Sample::EQUILIBRIUM);
-                    }
-                    *sample = resampled;
+    mut probe_playback: Option<ProbePlayback>,
+    clock: StreamClock,
+    xruns: Xruns,
+) -> EndpointStream {
+    let channels: usize = output_config.channels as usize;
+    let mut resampled: Vec<f32> = vec![Sample::EQUILIBRIUM; source_channels];
+    // Unselected channels stay silent
+    let matrix: ChannelMatrix = output.channel_matrix(source_channels, channels);
+    build_output_stream(
+        output,
+        output_config,
+        move |data: &mut [f32], instant: Instant| {
+            clock.advance(data.len() / channels, instant);
+            if let Some(probe_playback) = probe_playback.as_ref() {
+                probe_playback.start();
+            }
+            let mut missing: usize = 0;
+            for frame in data.chunks_mut(channels) {
+                for sample in resampled.iter_mut() {
+                    *sample = resampler_consumer.try_pop().unwrap_or_else(|| {
+                        missing += 1;
+                        Sample::EQUILIBRIUM
+                    });
                 }
-            },
-            |err| error!(target: TRACING_TARGET, "An error occurred on input stream: {err}"),
-            None,
-        )
-        .expect("Failed to build output stream")
+                // While measuring, the pipeline keeps running but only the probe is audible
+                if let Some(probe_playback) = probe_playback.as_mut() {
+                    resampled.fill(probe_playback.next_sample());
+                }
+                matrix.apply(&resampled, frame);
+            }
+            if missing > 0 {
+                xruns.underrun(missing);
+            }
+        },
+    )
 }
 
 fn create_denoise_thread(
     channels: usize,
+    sample_rate: usize,
     mut input_consumer: C,
     mut denoise_producer: P,
+    analyzer: Analyzer,
+    transient_suppression: TransientSuppression,
+    xruns: Xruns,
 ) -> Arc<AtomicBool> {
     info!(target: TRACING_TARGET, "Starting denoise thread");
 
@@ -108,51 +228,139 @@ fn create_denoise_thread(
     let thread_run: Arc<AtomicBool> = denoise_thread_run.clone();
 
     thread::spawn(move || {
-        let mut denoise: Vec<Box<DenoiseState>> = vec![DenoiseState::new(); channels];
-        let mut denoise_buffer: Vec<f32> =
-            vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE * channels];
-        let mut denoise_process_buffer: Vec<f32> =
-            vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE];
-        let mut denoise_first: bool = true;
-        let mut deinterleaved_buffer: Vec<Vec<f32>> =
-            vec![vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE]; channels];
+        let mut denoise: DenoiseStage = DenoiseStage::new(channels, sample_rate);
+        let mut frame_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; denoise.frame_size()];
 
         while thread_run.load(Ordering::Relaxed) {
-            if input_consumer.occupied_len() >= DenoiseState::FRAME_SIZE * channels {
-                for sample in denoise_buffer.iter_mut() {
-                    *sample = input_consumer
-                        .try_pop()
-                        .expect("Failed to pop from input buffer")
-                        * (i16::MAX as f32)
+            if input_consumer.occupied_len() >= frame_buffer.len() {
+                input_consumer.pop_slice(&mut frame_buffer);
+                if denoise.process(
+                    &mut frame_buffer,
+                    transient_suppression.enabled(),
+                    Some(&analyzer),
+                ) {
+                    let pushed: usize = denoise_producer.push_slice(&frame_buffer);
+                    if pushed < frame_buffer.len() {
+                        xruns.overrun(frame_buffer.len() - pushed);
+                    }
                 }
+            }
+        }
 
-                deinterleave(channels, &denoise_buffer, &mut deinterleaved_buffer);
+        let transients: usize = denoise.transients();
+        info!(target: TRACING_TARGET, "Stopping denoise thread, suppressed {transients} transients");
+    });
+    denoise_thread_run
+}
 
-                for i in 0..channels {
-                    denoise[i].process_frame(&mut denoise_process_buffer, &deinterleaved_buffer[i]);
-                    deinterleaved_buffer[i] = denoise_process_buffer.clone();
-                }
+fn create_effects_thread(
+    sample_rate: usize,
+    mut input_consumer: C,
+    mut effects_producer: P,
+    effects: EffectsHandle,
+    xruns: Xruns,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting effects thread");
 
-                interleave(&deinterleaved_buffer, &mut denoise_buffer);
+    let effects_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = effects_thread_run.clone();
 
-                if denoise_first {
-                    denoise_first = false;
-                } else {
-                    for sample in denoise_buffer.iter() {
-                        if denoise_producer.is_full() {
-                            continue;
-                        }
-                        denoise_producer
-                            .try_push(*sample / (i16::MAX as f32))
-                            .expect("Failed to push to denoise buffer");
+    thread::spawn(move || {
+        let mut effects_buffer: Vec<f32> =
+            vec![Sample::EQUILIBRIUM; sample_rate / MIX_FRAMES_PER_SECOND];
+        let mut voice_effects: VoiceEffects = VoiceEffects::new(sample_rate);
+        let mut effects: CachedEffects = effects.cached();
+        let frame_budget: Duration = Duration::from_secs_f32(1.0 / MIX_FRAMES_PER_SECOND as f32);
+        let mut overruns: usize = 0;
+
+        while thread_run.load(Ordering::Relaxed) {
+            if input_consumer.occupied_len() >= effects_buffer.len() {
+                input_consumer.pop_slice(&mut effects_buffer);
+
+                let start: Instant = Instant::now();
+                voice_effects.process(effects.get(), &mut effects_buffer);
+                let elapsed: Duration = start.elapsed();
+                if elapsed > frame_budget {
+                    overruns += 1;
+                    if overruns % 100 == 1 {
+                        error!(target: TRACING_TARGET, "Effects took {elapsed:?} for a {frame_budget:?} frame, {overruns} overrun(s) so far");
                     }
                 }
+
+                let pushed: usize = effects_producer.push_slice(&effects_buffer);
+                if pushed < effects_buffer.len() {
+                    xruns.overrun(effects_buffer.len() - pushed);
+                }
             }
         }
 
-        info!(target: TRACING_TARGET, "Stopping denoise thread");
+        info!(target: TRACING_TARGET, "Stopping effects thread");
     });
-    denoise_thread_run
+
+    effects_thread_run
+}
+
+// Runs on whatever is sent, voice and music alike, as feedback rings through either
+fn create_howling_thread(
+    channels: usize,
+    sample_rate: usize,
+    mut input_consumer: C,
+    mut howling_producer: P,
+    howling: HowlingMonitor,
+    xruns: Xruns,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting howling thread");
+
+    let howling_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = howling_thread_run.clone();
+
+    thread::spawn(move || {
+        let mut howling_buffer: Vec<f32> =
+            vec![Sample::EQUILIBRIUM; sample_rate / MIX_FRAMES_PER_SECOND * channels];
+        let mut howling_suppressor: HowlingSuppressor =
+            HowlingSuppressor::new(howling, channels, sample_rate);
+
+        while thread_run.load(Ordering::Relaxed) {
+            if input_consumer.occupied_len() >= howling_buffer.len() {
+                input_consumer.pop_slice(&mut howling_buffer);
+                howling_suppressor.process(&mut howling_buffer);
+
+                let pushed: usize = howling_producer.push_slice(&howling_buffer);
+                if pushed < howling_buffer.len() {
+                    xruns.overrun(howling_buffer.len() - pushed);
+                }
+            }
+        }
+
+        info!(target: TRACING_TARGET, "Stopping howling thread");
+    });
+
+    howling_thread_run
+}
+
+pub fn create_fft_resampler(
+    channels: usize,
+    input_sample_rate: usize,
+    output_sample_rate: usize,
+) -> FftFixedIn<f32> {
+    // 20 ms chunks keep low codec sample rates from adding latency
+    FftFixedIn::<f32>::new(
+        input_sample_rate,
+        output_sample_rate,
+        input_sample_rate / 50,
+        1,
+        channels,
+    )
+    .expect("Failed to create input buffer")
+}
+
+// How far audio leaving a resampler thread lags behind the sample count that went in
+fn resampler_delay(input_sample_rate: usize, output_sample_rate: usize) -> f64 {
+    if input_sample_rate == output_sample_rate {
+        return 0.0;
+    }
+    create_fft_resampler(1, input_sample_rate, output_sample_rate).output_delay() as f64
+        / output_sample_rate as f64
 }
 
 fn create_resampler_thread(
@@ -161,6 +369,8 @@ fn create_resampler_thread(
     output_sample_rate: usize,
     mut input_consumer: C,
     mut resampler_producer: P,
+    max_fill: Option<(usize, Xruns)>,
+    xruns: Xruns,
 ) -> Arc<AtomicBool> {
     info!(target: TRACING_TARGET, "Starting resample thread");
 
@@ -168,15 +378,9 @@ fn create_resampler_thread(
     let thread_run: Arc<AtomicBool> = resampler_thread_run.clone();
 
     thread::spawn(move || {
-        let resampler_chunk_size: usize = 960;
-        let mut resampler = FftFixedIn::<f32>::new(
-            input_sample_rate,
-            output_sample_rate,
-            resampler_chunk_size,
-            1,
-            channels,
-        )
-        .expect("Failed to create input buffer");
+        let mut resampler: FftFixedIn<f32> =
+            create_fft_resampler(channels, input_sample_rate, output_sample_rate);
+        let resampler_chunk_size: usize = resampler.input_frames_max();
 
         let mut deinterleaved = resampler.input_buffer_allocate(true);
         let mut resample_process_buffer = resampler.output_buffer_allocate(true);
@@ -187,7 +391,19 @@ fn create_resampler_thread(
             vec![Sample::EQUILIBRIUM; resample_process_buffer[0].len() * channels];
 
         while thread_run.load(Ordering::Relaxed) {
-            if input_consumer.occupied_len() >= (resampler_chunk_size * channels) {
+            // With a fill limit audio backs up here while the output waits for room and the oldest of it
+            // is dropped past the limit, counted on the input. Without one a full output loses the newest audio instead
+            if let Some((max_fill, input_xruns)) = &max_fill {
+                let queued: usize = input_consumer.occupied_len();
+                if queued > *max_fill * channels {
+                    let excess: usize = queued - *max_fill * channels;
+                    let dropped: usize = input_consumer.skip(excess - excess % channels);
+                    input_xruns.overrun(dropped);
+                }
+            }
+            if input_consumer.occupied_len() >= (resampler_chunk_size * channels)
+                && (max_fill.is_none() || resampler_producer.vacant_len() >= interleaved.len())
+            {
                 for sample in resampler_buffer.iter_mut() {
                     *sample = input_consumer
                         .try_pop()
@@ -200,13 +416,9 @@ fn create_resampler_thread(
                     .expect("Failed to resample");
                 interleave(&resample_process_buffer, &mut interleaved);
 
-                for sample in interleaved.iter() {
-                    if resampler_producer.is_full() {
-                        continue;
-                    }
-                    resampler_producer
-                        .try_push(*sample)
-                        .expect("Failed to push to resampler buffer");
+                let pushed: usize = resampler_producer.push_slice(&interleaved);
+                if pushed < interleaved.len() {
+                    xruns.overrun(interleaved.len() - pushed);
                 }
             }
         }
@@ -217,46 +429,457 @@ fn create_resampler_thread(
     resampler_thread_run
 }
 
-fn create_opus_encoder_thread(
-    mut consumer: C,
-    sample_rate: u32,
+// Resamples the stereo mix for one output device, following the clock of that device.
+// Without a target fill the device is the one pacing the mix and needs no drift compensation,
+// so it keeps the fixed ratio FFT resampler. Only the others pay for an adjustable sinc one
+fn create_output_resampler_thread(
+    input_sample_rate: usize,
+    output_sample_rate: usize,
+    mut mix_consumer: C,
+    mut resampler_producer: P,
+    target_fill: Option<usize>,
+    control: OutputControl,
+    xruns: Xruns,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting output resample thread");
+
+    let resampler_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = resampler_thread_run.clone();
+
+    thread::spawn(move || {
+        let mut resampler: Box<dyn rubato::VecResampler<f32>> = match target_fill {
+            Some(_) => Box::new(
+                SincFixedIn::<f32>::new(
+                    output_sample_rate as f64 / input_sample_rate as f64,
+                    1.0 + MAX_DRIFT_CORRECTION,
+                    SincInterpolationParameters {
+                        sinc_len: 128,
+                        f_cutoff: 0.95,
+                        oversampling_factor: 128,
+                        interpolation: SincInterpolationType::Linear,
+                        window: WindowFunction::BlackmanHarris2,
+                    },
+                    input_sample_rate / MIX_FRAMES_PER_SECOND,
+                    MIX_CHANNELS,
+                )
+                .expect("Failed to create output resampler"),
+            ),
+            None => Box::new(create_fft_resampler(
+                MIX_CHANNELS,
+                input_sample_rate,
+                output_sample_rate,
+            )),
+        };
+        let frame_size: usize = resampler.input_frames_max();
+        let mut compensator: Option<DriftCompensator> =
+            target_fill.map(|x| DriftCompensator::new(x * MIX_CHANNELS));
+
+        let mut deinterleaved = resampler.input_buffer_allocate(true);
+        let mut resample_process_buffer = resampler.output_buffer_allocate(true);
+
+        let mut mix_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; frame_size * MIX_CHANNELS];
+        let mut interleaved: Vec<f32> =
+            vec![Sample::EQUILIBRIUM; resampler.output_frames_max() * MIX_CHANNELS];
+
+        while thread_run.load(Ordering::Relaxed) {
+            // Far more mix queued than the drift could explain means the device stalled, so the oldest is dropped
+            if let Some(target_fill) = target_fill {
+                let queued: usize = mix_consumer.occupied_len();
+                if queued > (target_fill * 4).max(frame_size * 2) * MIX_CHANNELS {
+                    let excess: usize = queued - target_fill * MIX_CHANNELS;
+                    let dropped: usize = mix_consumer.skip(excess - excess % MIX_CHANNELS);
+                    xruns.overrun(dropped);
+                }
+            }
+            if mix_consumer.occupied_len() >= mix_buffer.len()
+                && resampler_producer.vacant_len() >= interleaved.len()
+            {
+                if let Some(compensator) = compensator.as_mut() {
+                    let relative_ratio: f64 = compensator.update(mix_consumer.occupied_len());
+                    resampler
+                        .set_resample_ratio_relative(relative_ratio, true)
+                        .expect("Failed to adjust output resampler");
+                    control.set_correction_ppm((relative_ratio - 1.0) * 1_000_000.0);
+                }
+
+                mix_consumer.pop_slice(&mut mix_buffer);
+                deinterleave(MIX_CHANNELS, &mix_buffer, &mut deinterleaved);
+                let (_, frames) = resampler
+                    .process_into_buffer(&deinterleaved, &mut resample_process_buffer, None)
+                    .expect("Failed to resample");
+
+                let volume: f32 = control.volume();
+                for i in 0..frames {
+                    for channel in 0..MIX_CHANNELS {
+                        interleaved[i * MIX_CHANNELS + channel] =
+                            resample_process_buffer[channel][i] * volume;
+                    }
+                }
+
+                let pushed: usize =
+                    resampler_producer.push_slice(&interleaved[..frames * MIX_CHANNELS]);
+                if pushed < frames * MIX_CHANNELS {
+                    xruns.overrun(frames * MIX_CHANNELS - pushed);
+                }
+            }
+        }
+
+        info!(target: TRACING_TARGET, "Stopping output resample thread");
+    });
+
+    resampler_thread_run
+}
+
+// Mixes the main mic with the extra ones after lining them up in time, all at the processing rate
+fn create_mic_mix_thread(
     channels: usize,
-    sender: UdpSocket,
+    sample_rate: usize,
+    mut main_consumer: C,
+    main_control: InputControl,
+    mut extra_inputs: Vec<(C, InputControl, Xruns)>,
+    mut mic_mix_producer: P,
+    xruns: Xruns,
 ) -> Arc<AtomicBool> {
-    if channels > 2 {
-        panic!("Opus doesn't support more than 2 channels");
-    }
+    info!(target: TRACING_TARGET, "Starting mic mix thread");
+
+    let mic_mix_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = mic_mix_thread_run.clone();
+
+    thread::spawn(move || {
+        let frame_size: usize = sample_rate / MIX_FRAMES_PER_SECOND;
+        let frame_len: usize = frame_size * channels;
+        let target_fill: usize = sample_rate * EXTRA_MIC_TARGET_FILL_MS / 1000;
+        let mics: usize = extra_inputs.len() + 1;
+        let mut frames: Vec<Vec<f32>> = vec![vec![Sample::EQUILIBRIUM; frame_len]; mics];
+        let mut mono_frames: Vec<Vec<f32>> = vec![vec![Sample::EQUILIBRIUM; frame_size]; mics];
+        let mut fills: Vec<f32> = vec![target_fill as f32; extra_inputs.len()];
+        let mut mix_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; frame_len];
+        let mut aligner: MicAligner = MicAligner::new(mics, sample_rate);
+        let mut delays: Vec<MicDelay> = (0..mics)
+            .map(|_| MicDelay::new(channels, aligner.max_delay(), sample_rate))
+            .collect();
+
+        while thread_run.load(Ordering::Relaxed) {
+            // The main mic sets the pace, extra mics are read alongside it
+            if main_consumer.occupied_len() < frame_len || mic_mix_producer.vacant_len() < frame_len
+            {
+                continue;
+            }
+            main_consumer.pop_slice(&mut frames[0]);
+
+            for ((consumer, _, xruns), (frame, fill)) in extra_inputs
+                .iter_mut()
+                .zip(frames[1..].iter_mut().zip(fills.iter_mut()))
+            {
+                // Every mic runs on its own clock, so a sample is dropped or repeated now and then to keep its queue steady
+                *fill += (consumer.occupied_len() as f32 - *fill) * EXTRA_MIC_FILL_SMOOTHING;
+                if *fill > (target_fill + EXTRA_MIC_SLIP_MARGIN) as f32 * channels as f32
+                    && consumer.occupied_len() >= frame_len + channels
+                {
+                    consumer.skip(channels);
+                }
+                let wanted: usize =
+                    if *fill < (target_fill - EXTRA_MIC_SLIP_MARGIN) as f32 * channels as f32 {
+                        frame_len - channels
+                    } else {
+                        frame_len
+                    };
+                let popped: usize = consumer.pop_slice(&mut frame[..wanted]);
+                if popped < wanted {
+                    xruns.underrun(wanted - popped);
+                    frame[popped..wanted].fill(Sample::EQUILIBRIUM);
+                }
+                if wanted < frame_len {
+                    frame.copy_within(wanted - channels..wanted, wanted);
+                }
+            }
+
+            for (frame, mono_frame) in frames.iter().zip(mono_frames.iter_mut()) {
+                for (samples, mono) in frame.chunks_exact(channels).zip(mono_frame.iter_mut()) {
+                    *mono = samples.iter().sum::<f32>() / channels as f32;
+                }
+            }
+            if let Some(lags) = aligner.push(&mono_frames) {
+                info!(target: TRACING_TARGET, "Mic delays changed to {lags:?} samples");
+                for (i, (mic_delay, delay)) in delays.iter_mut().zip(lags.iter()).enumerate() {
+                    mic_delay.set_delay(*delay);
+                    let delay_ms: f32 = *delay as f32 * 1000.0 / sample_rate as f32;
+                    if i == 0 {
+                        main_control.set_delay_ms(delay_ms);
+                    } else {
+                        extra_inputs[i - 1].1.set_delay_ms(delay_ms);
+                    }
+                }
+            }
+
+            mix_buffer.fill(Sample::EQUILIBRIUM);
+            for (i, (frame, mic_delay)) in frames.iter().zip(delays.iter_mut()).enumerate() {
+                let gain: f32 = if i == 0 {
+                    main_control.gain()
+                } else {
+                    extra_inputs[i - 1].1.gain()
+                };
+                mic_delay.process(frame, gain, &mut mix_buffer);
+            }
+
+            let pushed: usize = mic_mix_producer.push_slice(&mix_buffer);
+            if pushed < mix_buffer.len() {
+                xruns.overrun(mix_buffer.len() - pushed);
+            }
+        }
+
+        info!(target: TRACING_TARGET, "Stopping mic mix thread");
+    });
+
+    mic_mix_thread_run
+}
+
+fn create_file_mix_thread(
+    channels: usize,
+    file_player: FilePlayer,
+    mut input_consumer: C,
+    mut mix_producer: P,
+    mut monitor: Option<(P, Xruns)>,
+    mut tap: Option<(P, Xruns, Arc<AtomicBool>)>,
+    xruns: Xruns,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting file mix thread");
+
+    let file_mix_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = file_mix_thread_run.clone();
+
+    thread::spawn(move || {
+        let mut file_reader: FileReader = file_player.reader();
+        // Room for everything the input can hold, the most that is ever taken at once
+        let capacity: usize = input_consumer.capacity().get() / channels;
+        let mut mix_samples: Vec<f32> = vec![Sample::EQUILIBRIUM; capacity * channels];
+        let mut file_samples: Vec<f32> = vec![Sample::EQUILIBRIUM; capacity];
+        let mut tap_samples: Vec<f32> = vec![Sample::EQUILIBRIUM; capacity];
+
+        while thread_run.load(Ordering::Relaxed) {
+            // Takes whatever whole frames are queued, so it needs no frame size of its own
+            let frames: usize = input_consumer.occupied_len() / channels;
+            if frames > 0 {
+                let mix_buffer: &mut [f32] = &mut mix_samples[..frames * channels];
+                let file_buffer: &mut [f32] = &mut file_samples[..frames];
+                let tap_buffer: &mut [f32] = &mut tap_samples[..frames];
+                input_consumer.pop_slice(mix_buffer);
+
+                let file_playing: bool = file_reader.read(file_buffer);
+                if file_playing {
+                    // Files play in mono, so stereo gets them in the center
+                    for (frame, file_sample) in mix_buffer
+                        .chunks_exact_mut(channels)
+                        .zip(file_buffer.iter())
+                    {
+                        for sample in frame.iter_mut() {
+                            *sample += *file_sample;
+                        }
+                    }
+                    if let Some((monitor_producer, monitor_xruns)) = monitor.as_mut() {
+                        let pushed: usize = monitor_producer.push_slice(file_buffer);
+                        if pushed < file_buffer.len() {
+                            monitor_xruns.overrun(file_buffer.len() - pushed);
+                        }
+                    }
+                }
+
+                let pushed: usize = mix_producer.push_slice(mix_buffer);
+                if pushed < mix_buffer.len() {
+                    xruns.overrun(mix_buffer.len() - pushed);
+                }
+                // A tap that falls behind loses audio rather than holding up the call.
+                // Muted speech never reaches the peer, so the tap gets silence in its place as it is pushed,
+                // not when a backlog is transcribed
+                if let Some((tap, tap_xruns, muted)) = tap.as_mut() {
+                    if muted.load(Ordering::Relaxed) {
+                        tap_buffer.fill(Sample::EQUILIBRIUM);
+                    } else {
+                        for (sample, frame) in
+                            tap_buffer.iter_mut().zip(mix_buffer.chunks_exact(channels))
+                        {
+                            *sample = frame.iter().sum::<f32>() / channels as f32;
+                        }
+                    }
+                    let pushed: usize = tap.push_slice(tap_buffer);
+                    if pushed < tap_buffer.len() {
+                        tap_xruns.overrun(tap_buffer.len() - pushed);
+                    }
+                }
+            }
+        }
+
+        info!(target: TRACING_TARGET, "Stopping file mix thread");
+    });
 
+    file_mix_thread_run
+}
+
+// The first producer feeds the main output and sets the pace, the others get copies of the same mix
+fn create_mix_thread(
+    mixer: Mixer,
+    mut mix_producers: Vec<(P, Xruns)>,
+    cue_player: CuePlayer,
+    sample_rate: usize,
+    target_fill: usize,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting mix thread");
+
+    let mix_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = mix_thread_run.clone();
+    // Attached before the thread starts so a cue played right after the call starts isn't dropped
+    cue_player.attach();
+
+    thread::spawn(move || {
+        let frame_size: usize = sample_rate / MIX_FRAMES_PER_SECOND;
+        let mut mix_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; frame_size * MIX_CHANNELS];
+        let mut cue_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; frame_size];
+        let mut limiter: Limiter = Limiter::default();
+
+        while thread_run.load(Ordering::Relaxed) {
+            // Sources are pulled on demand of the output side
+            if mix_producers[0].0.occupied_len() + mix_buffer.len() <= target_fill * MIX_CHANNELS {
+                mix_buffer.fill(Sample::EQUILIBRIUM);
+                mixer.mix_into(&mut mix_buffer);
+                cue_buffer.fill(Sample::EQUILIBRIUM);
+                cue_player.mix_into(&mut cue_buffer, sample_rate);
+                for (frame, cue_sample) in mix_buffer
+                    .chunks_exact_mut(MIX_CHANNELS)
+                    .zip(cue_buffer.iter())
+                {
+                    for sample in frame.iter_mut() {
+                        *sample += *cue_sample;
+                    }
+                }
+                limiter.process(&mut mix_buffer);
+                for (mix_producer, xruns) in mix_producers.iter_mut() {
+                    let pushed: usize = mix_producer.push_slice(&mix_buffer);
+                    if pushed < mix_buffer.len() {
+                        xruns.overrun(mix_buffer.len() - pushed);
+                    }
+                }
+            }
+        }
+
+        cue_player.detach();
+        info!(target: TRACING_TARGET, "Stopping mix thread");
+    });
+
+    mix_thread_run
+}
+
+fn create_latency_thread(
+    mut capture_consumer: C,
+    measurement: LatencyMeasurement,
+    report_sender: oneshot::Sender<Result<LatencyReport, String>>,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting latency measurement thread");
+
+    let latency_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = latency_thread_run.clone();
+
+    thread::spawn(move || {
+        let capture_len: usize = measurement.capture_len();
+        let timeout: Duration = Duration::from_secs_f32(
+            capture_len as f32 / measurement.capture_sample_rate as f32 * 2.0,
+        );
+        let started: Instant = Instant::now();
+
+        let mut capture: Vec<f32> = Vec::with_capacity(capture_len);
+
+        while thread_run.load(Ordering::Relaxed) && capture.len() < capture_len {
+            if started.elapsed() > timeout {
+                let _ = report_sender.send(Err(String::from("Timed out waiting for input")));
+                info!(target: TRACING_TARGET, "Stopping latency measurement thread");
+                return;
+            }
+
+            while capture.len() < capture_len
+                && let Some(sample) = capture_consumer.try_pop()
+            {
+                capture.push(sample);
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        if capture.len() >= capture_len {
+            let report: Result<LatencyReport, String> = measurement
+                .round_trip_ms(&capture)
+                .map(|round_trip_ms| LatencyReport { round_trip_ms });
+            match report.as_ref() {
+                Ok(report) => info!(target: TRACING_TARGET, "Latency measured. {report}"),
+                Err(e) => error!(target: TRACING_TARGET, "Latency measurement failed: {e}"),
+            }
+            let _ = report_sender.send(report);
+        }
+
+        info!(target: TRACING_TARGET, "Stopping latency measurement thread");
+    });
+
+    latency_thread_run
+}
+
+fn create_encoder_thread(
+    mut consumer: C,
+    mut encoder: Box<dyn AudioEncoder>,
+    negotiation: Negotiation,
+    mut capture: Option<OggOpusWriter>,
+    muted: Arc<AtomicBool>,
+    // The input device clock and how far the capture stages move audio, in seconds
+    (input_clock, capture_offset): (StreamClock, f64),
+    sample_rate: usize,
+) -> Arc<AtomicBool> {
     info!(target: TRACING_TARGET, "Starting encoder thread");
 
     let encoder_thread_run: Arc<AtomicBool> = Arc::new(true.into());
     let thread_run: Arc<AtomicBool> = encoder_thread_run.clone();
 
     thread::spawn(move || {
-        let mut encoder: Encoder = Encoder::new(
-            sample_rate,
-            if channels == 2 {
-                Channels::Stereo
-            } else {
-                Channels::Mono
-            },
-            Application::Voip,
-        )
-        .expect("Failed to create Opus encoder");
-
-        let mut encoder_input_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; 960];
-        let mut encoder_output_buffer: Vec<u8> = vec![Sample::EQUILIBRIUM; 960];
+        let mut encoder_input_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; encoder.frame_size()];
+        let mut encoder_output_buffer: Vec<u8> = vec![Sample::EQUILIBRIUM; MAX_PACKET_SIZE];
+        let mut packet: Vec<u8> = Vec::with_capacity(MAX_PACKET_SIZE + AUDIO_HEADER_SIZE);
+        let mut encoded_samples: u64 = 0;
+        let mut capture_failures: usize = 0;
 
         while thread_run.load(Ordering::Relaxed) {
-            if consumer.occupied_len() >= 960 {
-                for sample in encoder_input_buffer.iter_mut() {
-                    *sample = consumer.try_pop().expect("Failed to pop from consumer");
+            // Settings are applied between frames so they can be changed mid-call
+            encoder.refresh();
+            encoder_input_buffer.resize(encoder.frame_size(), Sample::EQUILIBRIUM);
+
+            if consumer.occupied_len() >= encoder_input_buffer.len() {
+                consumer.pop_slice(&mut encoder_input_buffer);
+                let encoded_frames: u64 = encoded_samples / negotiation.channels() as u64;
+                let offset: Duration = Duration::from_secs_f64(
+                    (encoded_frames as f64 / sample_rate as f64 + capture_offset).max(0.0),
+                );
+                let timestamp: u64 = input_clock
+                    .instant_at(offset)
+                    .map(unix_micros)
+                    .unwrap_or_else(|| unix_micros(Instant::now()));
+                let position: u32 =
+                    (encoded_frames * POSITION_RATE as u64 / sample_rate as u64) as u32;
+                encoded_samples += encoder_input_buffer.len() as u64;
+                // Muted frames are still encoded so the peer keeps receiving packets
+                if muted.load(Ordering::Relaxed) {
+                    encoder_input_buffer.fill(Sample::EQUILIBRIUM);
                 }
 
                 let encoded = encoder
-                    .encode_float(&encoder_input_buffer, &mut encoder_output_buffer)
+                    .encode(&encoder_input_buffer, &mut encoder_output_buffer)
                     .expect("Failed to encode");
-                if let Err(_e) = sender.send(&encoder_output_buffer[..encoded]) {
+                if let Some(writer) = capture.as_mut()
+                    && let Err(e) = writer.write_packet(&encoder_output_buffer[..encoded])
+                {
+                    capture_failed("sent", &mut capture_failures, e);
+                }
+                Packet::Audio {
+                    timestamp: (!negotiation.is_baseline()).then_some(timestamp),
+                    position: negotiation.has_positions().then_some(position),
+                    payload: &encoder_output_buffer[..encoded],
+                }
+                .write(&mut packet);
+                if let Err(_e) = negotiation.socket.send(&packet) {
                     continue;
                 }
             }
@@ -268,48 +891,117 @@ fn create_opus_encoder_thread(
     encoder_thread_run
 }
 
-fn create_opus_decoder_thread(
+fn create_decoder_thread(
     mut producer: P,
-    sample_rate: u32,
-    channels: usize,
-    receiver: UdpSocket,
+    mut decoder: Box<dyn AudioDecoder>,
+    negotiation: Negotiation,
+    mut capture: Option<OggOpusWriter>,
+    cue_player: CuePlayer,
+    remote_clock: RemoteClock,
+    xruns: Xruns,
 ) -> Arc<AtomicBool> {
-    if channels > 2 {
-        panic!("Opus doesn't support more than 2 channels");
-    }
-
     info!(target: TRACING_TARGET, "Starting decoder thread");
 
     let decoder_thread_run: Arc<AtomicBool> = Arc::new(true.into());
     let thread_run: Arc<AtomicBool> = decoder_thread_run.clone();
 
     thread::spawn(move || {
-        let mut decoder: Decoder = Decoder::new(
-            sample_rate,
-            if channels == 2 {
-                Channels::Stereo
-            } else {
-                Channels::Mono
-            },
-        )
-        .expect("Failed to create decoder");
-
-        let mut decoder_input_buffer: Vec<u8> = vec![Sample::EQUILIBRIUM; 960];
-        let mut decoder_output_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; 960];
+        let mut decoder_input_buffer: Vec<u8> =
+            vec![Sample::EQUILIBRIUM; MAX_PACKET_SIZE + AUDIO_HEADER_SIZE];
+        let mut decoder_output_buffer: Vec<f32> =
+            vec![Sample::EQUILIBRIUM; MAX_FRAME_SIZE * negotiation.channels()];
+        let mut last_packet: Option<Instant> = None;
+        let mut peer_present: bool = false;
+        let mut last_position: Option<(u32, u64)> = None;
+        let mut next_position: Option<u64> = None;
+        let mut capture_failures: usize = 0;
+        let sample_rate: f64 = remote_clock.sample_rate();
 
         while thread_run.load(Ordering::Relaxed) {
-            if let Ok(received) = receiver.recv(&mut decoder_input_buffer) {
-                let decoded = decoder
-                    .decode_float(
-                        &decoder_input_buffer[..received],
-                        &mut decoder_output_buffer,
-                        false,
-                    )
-                    .expect("Failed to decode");
-                for sample in &decoder_output_buffer[..decoded] {
-                    producer
-                        .try_push(*sample)
-                        .expect("Failed to push to producer");
+            if peer_present && last_packet.is_some_and(|x| x.elapsed() > PEER_TIMEOUT) {
+                info!(target: TRACING_TARGET, "No packets from peer for {PEER_TIMEOUT:?}");
+                peer_present = false;
+                cue_player.play(Cue::ConnectionLost);
+            }
+
+            if let Ok(received) = negotiation.socket.recv(&mut decoder_input_buffer) {
+                let (timestamp, position, payload) =
+                    match Packet::parse(&decoder_input_buffer[..received], negotiation.version) {
+                        Some(Packet::Audio {
+                            timestamp,
+                            position,
+                            payload,
+                        }) => (timestamp, position, payload),
+                        // The peer is still negotiating because our answer got lost
+                        Some(Packet::Hello { .. }) => {
+                            let _ = negotiation.socket.send(&negotiation.hello);
+                            continue;
+                        }
+                        None => continue,
+                    };
+                last_packet = Some(Instant::now());
+                if !peer_present {
+                    info!(target: TRACING_TARGET, "Receiving audio from peer");
+                    peer_present = true;
+                    cue_player.play(Cue::PeerJoined);
+                }
+                // Positions count at 48 kHz like granule positions do
+                let position: Option<u64> = position.map(|position| {
+                    let unwrapped: u64 = unwrap_position(last_position, position);
+                    last_position = Some((position, unwrapped));
+                    unwrapped
+                });
+                if let Some(writer) = capture.as_mut() {
+                    let captured: io::Result<()> = match position {
+                        Some(position) => writer.write_packet_at(payload, position),
+                        None => writer.write_packet(payload),
+                    };
+                    if let Err(e) = captured {
+                        capture_failed("received", &mut capture_failures, e);
+                    }
+                }
+                // Packets missing in front of this one are concealed by the decoder, up to a buffer of them
+                if let (Some(position), Some(expected)) = (position, next_position)
+                    && position > expected
+                {
+                    let lost: usize = (((position - expected) as f64 * sample_rate
+                        / POSITION_RATE as f64) as usize
+                        * negotiation.channels())
+                    .min(decoder_output_buffer.len());
+                    match decoder.decode(&[], &mut decoder_output_buffer[..lost]) {
+                        Ok(decoded) => {
+                            let pushed: usize =
+                                producer.push_slice(&decoder_output_buffer[..decoded]);
+                            if pushed < decoded {
+                                xruns.overrun(decoded - pushed);
+                            }
+                        }
+                        Err(e) => {
+                            error!(target: TRACING_TARGET, "Failed to conceal lost packets: {e}")
+                        }
+                    }
+                }
+                match decoder.decode(payload, &mut decoder_output_buffer) {
+                    Ok(decoded) => {
+                        if let Some(position) = position {
+                            let end: u64 = position
+                                + ((decoded / negotiation.channels()) as f64 * POSITION_RATE as f64
+                                    / sample_rate) as u64;
+                            next_position = next_position.max(Some(end));
+                        }
+                        if let Some(timestamp) = timestamp {
+                            remote_clock.observe(
+                                timestamp,
+                                position,
+                                decoded / negotiation.channels(),
+                            );
+                        }
+                        let pushed: usize = producer.push_slice(&decoder_output_buffer[..decoded]);
+                        if pushed < decoded {
+                            xruns.overrun(decoded - pushed);
+                        }
+                    }
+                    Err(e) => error!(target: TRACING_TARGET, "Failed to decode packet: {e}"),
                 }
             }
         }
@@ -320,194 +1012,1163 @@ fn create_opus_decoder_thread(
     decoder_thread_run
 }
 
+fn create_transcription_thread(
+    speaker: Speaker,
+    sample_rate: usize,
+    mut consumer: C,
+    mut transcriber: Box<dyn Transcriber>,
+    captions: Captions,
+    xruns: Xruns,
+) -> Arc<AtomicBool> {
+    info!(target: TRACING_TARGET, "Starting {speaker} transcription thread");
+
+    let transcription_thread_run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_run: Arc<AtomicBool> = transcription_thread_run.clone();
+
+    thread::spawn(move || {
+        let mut segmenter: Segmenter = Segmenter::new(sample_rate);
+        let mut buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; sample_rate / MIX_FRAMES_PER_SECOND];
+        let mut dropped: u64 = 0;
+
+        while thread_run.load(Ordering::Relaxed) {
+            // Audio pushed while the tap was full is gone. The backlog in front of it is skipped too,
+            // so captions catch up with the call and their timestamps still count every sample
+            let total_dropped: u64 = xruns.dropped();
+            if total_dropped > dropped {
+                let skipped: usize = consumer.skip(consumer.occupied_len());
+                let lost: usize = skipped + (total_dropped - dropped) as usize;
+                dropped = total_dropped;
+                segmenter.skip(lost);
+                error!(target: TRACING_TARGET, "{speaker} transcription fell behind, skipping {:.1} s", lost as f64 / sample_rate as f64);
+            }
+            if consumer.occupied_len() >= buffer.len() {
+                consumer.pop_slice(&mut buffer);
+                // Audio queues up in the tap while a segment is being transcribed
+                for (start, segment) in segmenter.push(&buffer) {
+                    match transcriber.transcribe(&segment, sample_rate) {
+                        Ok(text) if !text.is_empty() => captions.push(CaptionLine {
+                            speaker,
+                            start,
+                            text,
+                        }),
+                        Ok(_) => {}
+                        Err(e) => error!(target: TRACING_TARGET, "Failed to transcribe: {e}"),
+                    }
+                }
+            }
+        }
+
+        info!(target: TRACING_TARGET, "Stopping {speaker} transcription thread");
+    });
+
+    transcription_thread_run
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+// Lookahead of an encoder with these settings at 48 kHz, which is what players skip at the start
+fn opus_pre_skip(channels: u8, sample_rate: u32, settings: OpusSettings) -> u16 {
+    OpusEncoder::new(sample_rate, channels as usize, settings)
+        .and_then(|mut encoder| encoder.lookahead())
+        .map(|x| (x as u64 * OPUS_GRANULE_RATE as u64 / sample_rate as u64) as u16)
+        .unwrap_or_else(|e| {
+            error!(target: TRACING_TARGET, "Failed to query the Opus lookahead: {e}");
+            0
+        })
+}
+
+fn create_call_captures(
+    channels: u8,
+    sample_rate: u32,
+    opus_settings: OpusSettings,
+) -> (Option<OggOpusWriter>, Option<OggOpusWriter>) {
+    let timestamp: u64 = unix_timestamp();
+
+    let create = |direction: &str, pre_skip: u16| {
+        let path: String = format!("call-{timestamp}-{direction}.opus");
+        info!(target: TRACING_TARGET, "Capturing {direction} packets to {path}");
+        OggOpusWriter::create(&path, channels, pre_skip, sample_rate)
+            .inspect_err(|e| error!(target: TRACING_TARGET, "Failed to create capture {path}: {e}"))
+            .ok()
+    };
+
+    // The peer's encoder settings aren't known, so its lookahead is taken to be the default one
+    (
+        create("sent", opus_pre_skip(channels, sample_rate, opus_settings)),
+        create(
+            "received",
+            opus_pre_skip(channels, OPUS_GRANULE_RATE, OpusSettings::default()),
+        ),
+    )
+}
+
+// Captures fail on every packet once the disk is full, so only every 100th failure is logged
+fn capture_failed(direction: &str, failures: &mut usize, e: io::Error) {
+    *failures += 1;
+    if *failures % 100 == 1 {
+        error!(target: TRACING_TARGET, "Failed to capture {direction} packet: {e}, {failures} failure(s) so far");
+    }
+}
+
+// Plays a single cue when no call is running to mix it into, like after a failed negotiation.
+// The cue is rendered up front so the output callback doesn't lock anything
+#[allow(dead_code)]
+pub struct CueOutput {
+    output_stream: EndpointStream,
+}
+
+impl CueOutput {
+    pub fn new(output: &OutputEndpoint, cue_player: &CuePlayer, cue: Cue) -> Option<Self> {
+        let output_config: StreamConfig = output.config();
+        let channels: usize = output_config.channels as usize;
+        let samples: Vec<f32> = cue_player.render(cue, output_config.sample_rate.0 as usize)?;
+        let matrix: ChannelMatrix = output.channel_matrix(1, channels);
+        let mut position: usize = 0;
+        let output_stream: EndpointStream = build_output_stream(
+            output,
+            &output_config,
+            move |data: &mut [f32], _: Instant| {
+                for frame in data.chunks_mut(channels) {
+                    let sample: f32 = samples
+                        .get(position)
+                        .copied()
+                        .unwrap_or(Sample::EQUILIBRIUM);
+                    position += 1;
+                    matrix.apply(&[sample], frame);
+                }
+            },
+        );
+        output_stream.play();
+        Some(Self { output_stream })
+    }
+}
+
 #[allow(dead_code)]
 pub struct SelfListen {
-    input_stream: Stream,
-    output_stream: Stream,
+    input_stream: EndpointStream,
+    output_stream: EndpointStream,
     denoise_thread_run: Arc<AtomicBool>,
+    effects_thread_run: Arc<AtomicBool>,
+    howling_thread_run: Arc<AtomicBool>,
+    file_mix_thread_run: Arc<AtomicBool>,
     resampler_input_thread_run: Arc<AtomicBool>,
     resampler_output_thread_run: Arc<AtomicBool>,
+    latency_thread_run: Option<Arc<AtomicBool>>,
+    buffer_stats: Vec<BufferStats>,
+    howling: HowlingMonitor,
+    input_check: InputCheck,
+    sample_rate: usize,
 }
 
 impl SelfListen {
-    pub fn new(input_device: &Device, output_device: &Device) -> Self {
-        let input_config: StreamConfig = input_device
-            .default_input_config()
-            .expect("Failed to get default input config")
-            .into();
-        info!(target: TRACING_TARGET, "Input stream config has {} channel(s), {}Hz sample rate", input_config.channels, input_config.sample_rate.0);
-
-        let output_config: StreamConfig = output_device
-            .default_output_config()
-            .expect("Failed to get default output config")
-            .into();
-        info!(target: TRACING_TARGET, "Output stream config has {} channel(s), {}Hz sample rate", output_config.channels, output_config.sample_rate.0);
-
-        let (input_producer, input_consumer) = HeapRb::<f32>::new(8192 * 2).split();
+    pub fn new(
+        input: &InputEndpoint,
+        output: &OutputEndpoint,
+        handles: CallHandles,
+        latency_probe: Option<(ProbeSignal, oneshot::Sender<Result<LatencyReport, String>>)>,
+        profile: LatencyProfile,
+        rate: ProcessingRate,
+    ) -> Self {
+        let CallHandles {
+            file_player,
+            analyzer,
+            effects,
+            transient_suppression,
+            ..
+        } = handles;
+        let sample_rate: usize = rate.sample_rate();
+        info!(target: TRACING_TARGET, "Processing at {sample_rate}Hz");
+
+        let input_config: StreamConfig = input.config();
+        info!(target: TRACING_TARGET, "Input {input} stream config has {} channel(s), {}Hz sample rate", input_config.channels, input_config.sample_rate.0);
+
+        let output_config: StreamConfig = output.config();
+        info!(target: TRACING_TARGET, "Output {output} stream config has {} channel(s), {}Hz sample rate", output_config.channels, output_config.sample_rate.0);
+
+        let (input_producer, input_consumer) = HeapRb::<f32>::new(profile.buffer_size()).split();
         let (resampler_input_producer, resampler_input_consumer) =
-            HeapRb::<f32>::new(8192 * 2).split();
-        let (denoise_producer, denoise_consumer) = HeapRb::<f32>::new(8192 * 2).split();
-        let (resampler_output_producer, resampler_output_consumer) =
-            HeapRb::<f32>::new(8192 * 2).split();
-
-        let input_stream = create_input_stream(
-            input_config.channels as usize,
-            input_device,
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (denoise_producer, denoise_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (effects_producer, effects_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (howling_producer, howling_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (file_mix_producer, file_mix_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        // Kept short so the backlog builds up ahead of the output resampler, where the fill limit applies
+        let (resampler_output_producer, resampler_output_consumer) = HeapRb::<f32>::new(
+            (profile.mix_target_fill(output_config.sample_rate.0 as usize) + 1) * 2,
+        )
+        .split();
+
+        let input_stats: BufferStats = BufferStats::new(
+            "Input",
+            input_config.sample_rate.0 as usize,
+            input_producer.observe(),
+        );
+        let resampler_input_stats: BufferStats = BufferStats::new(
+            "Input resampler",
+            sample_rate,
+            resampler_input_producer.observe(),
+        );
+        let denoise_stats: BufferStats =
+            BufferStats::new("Denoise", sample_rate, denoise_producer.observe());
+        let effects_stats: BufferStats =
+            BufferStats::new("Effects", sample_rate, effects_producer.observe());
+        let howling_stats: BufferStats =
+            BufferStats::new("Howling", sample_rate, howling_producer.observe());
+        let file_mix_stats: BufferStats =
+            BufferStats::new("File mix", sample_rate, file_mix_producer.observe());
+        let resampler_output_stats: BufferStats = BufferStats::new(
+            "Output resampler",
+            output_config.sample_rate.0 as usize,
+            resampler_output_producer.observe(),
+        );
+        let buffer_stats: Vec<BufferStats> = vec![
+            input_stats.clone(),
+            resampler_input_stats.clone(),
+            denoise_stats.clone(),
+            effects_stats.clone(),
+            howling_stats.clone(),
+            file_mix_stats.clone(),
+            resampler_output_stats.clone(),
+        ];
+
+        let mut probe_capture: Option<ProbeCapture> = None;
+        let mut probe_playback: Option<ProbePlayback> = None;
+        let mut latency_thread_run: Option<Arc<AtomicBool>> = None;
+        if let Some((probe_signal, report_sender)) = latency_probe {
+            info!(target: TRACING_TARGET, "Measuring round trip latency with {probe_signal}");
+            let measurement: LatencyMeasurement = LatencyMeasurement::new(
+                probe_signal,
+                output_config.sample_rate.0 as usize,
+                input_config.sample_rate.0 as usize,
+            );
+            let (capture_producer, capture_consumer) =
+                HeapRb::<f32>::new(measurement.capture_len()).split();
+            probe_capture = Some(measurement.capture(capture_producer));
+            probe_playback = Some(measurement.playback());
+            latency_thread_run = Some(create_latency_thread(
+                capture_consumer,
+                measurement,
+                report_sender,
+            ));
+        }
+
+        let (input_stream, input_check) = create_input_stream(
+            1,
+            input,
             &input_config,
             input_producer,
+            probe_capture,
+            StreamClock::new(input_config.sample_rate.0),
+            input_stats.xruns(),
         );
         let resampler_input_thread_run = create_resampler_thread(
             1,
             input_config.sample_rate.0 as usize,
-            48000_usize,
+            sample_rate,
             input_consumer,
             resampler_input_producer,
+            None,
+            resampler_input_stats.xruns(),
+        );
+        let denoise_thread_run = create_denoise_thread(
+            1,
+            sample_rate,
+            resampler_input_consumer,
+            denoise_producer,
+            analyzer,
+            transient_suppression,
+            denoise_stats.xruns(),
+        );
+        let effects_thread_run = create_effects_thread(
+            sample_rate,
+            denoise_consumer,
+            effects_producer,
+            effects,
+            effects_stats.xruns(),
+        );
+        let howling: HowlingMonitor = HowlingMonitor::default();
+        let howling_thread_run = create_howling_thread(
+            1,
+            sample_rate,
+            effects_consumer,
+            howling_producer,
+            howling.clone(),
+            howling_stats.xruns(),
+        );
+        let file_mix_thread_run = create_file_mix_thread(
+            1,
+            file_player,
+            howling_consumer,
+            file_mix_producer,
+            None,
+            None,
+            file_mix_stats.xruns(),
         );
-        let denoise_thread_run =
-            create_denoise_thread(1, resampler_input_consumer, denoise_producer);
         let resampler_output_thread_run = create_resampler_thread(
             1,
-            48000_usize,
+            sample_rate,
             output_config.sample_rate.0 as usize,
-            denoise_consumer,
+            file_mix_consumer,
             resampler_output_producer,
+            Some((profile.max_fill(sample_rate), file_mix_stats.xruns())),
+            resampler_output_stats.xruns(),
         );
         let output_stream = create_output_stream(
-            output_config.channels as usize,
-            output_device,
+            1,
+            output,
             &output_config,
             resampler_output_consumer,
+            probe_playback,
+            StreamClock::new(output_config.sample_rate.0),
+            resampler_output_stats.xruns(),
         );
 
-        input_stream.play().expect("Failed to play input stream");
-        output_stream.play().expect("Failed to play output stream");
+        input_stream.play();
+        output_stream.play();
 
         SelfListen {
             input_stream,
             output_stream,
             denoise_thread_run,
+            effects_thread_run,
+            howling_thread_run,
+            file_mix_thread_run,
             resampler_input_thread_run,
             resampler_output_thread_run,
+            latency_thread_run,
+            buffer_stats,
+            howling,
+            input_check,
+            sample_rate,
         }
     }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    pub fn buffer_stats(&self) -> &[BufferStats] {
+        &self.buffer_stats
+    }
+
+    pub fn howling(&self) -> HowlingReport {
+        self.howling.report()
+    }
+
+    pub fn input_problems(&self) -> Vec<InputProblem> {
+        self.input_check.problems()
+    }
 }
 
 impl Drop for SelfListen {
     fn drop(&mut self) {
         self.denoise_thread_run.store(false, Ordering::Relaxed);
+        self.effects_thread_run.store(false, Ordering::Relaxed);
+        self.howling_thread_run.store(false, Ordering::Relaxed);
+        self.file_mix_thread_run.store(false, Ordering::Relaxed);
         self.resampler_input_thread_run
             .store(false, Ordering::Relaxed);
         self.resampler_output_thread_run
             .store(false, Ordering::Relaxed);
+        if let Some(latency_thread_run) = self.latency_thread_run.as_ref() {
+            latency_thread_run.store(false, Ordering::Relaxed);
+        }
     }
 }
 
 #[allow(dead_code)]
 pub struct P2P {
-    input_stream: Stream,
-    output_stream: Stream,
+    input_stream: EndpointStream,
+    output_stream: EndpointStream,
     resampler_input_thread_run: Arc<AtomicBool>,
-    denoise_thread_run: Arc<AtomicBool>,
+    extra_input_streams: Vec<EndpointStream>,
+    extra_input_thread_runs: Vec<Arc<AtomicBool>>,
+    denoise_thread_run: Option<Arc<AtomicBool>>,
+    effects_thread_run: Option<Arc<AtomicBool>>,
+    howling_thread_run: Arc<AtomicBool>,
+    file_mix_thread_run: Arc<AtomicBool>,
+    resampler_codec_input_thread_run: Option<Arc<AtomicBool>>,
     encoder_thread_run: Arc<AtomicBool>,
     decoder_thread_run: Arc<AtomicBool>,
+    resampler_codec_output_thread_run: Option<Arc<AtomicBool>>,
+    mix_thread_run: Arc<AtomicBool>,
     resampler_output_thread_run: Arc<AtomicBool>,
+    extra_output_streams: Vec<EndpointStream>,
+    extra_resampler_thread_runs: Vec<Arc<AtomicBool>>,
+    muted: Arc<AtomicBool>,
+    music: bool,
+    mixer: Mixer,
+    sample_rate: usize,
+    codec_sample_rate: usize,
+    input_clock: StreamClock,
+    output_clock: StreamClock,
+    remote_clock: RemoteClock,
+    send_buffer_stats: Vec<BufferStats>,
+    receive_buffer_stats: Vec<BufferStats>,
+    extra_output_buffer_stats: Vec<BufferStats>,
+    transcription_thread_runs: Vec<Arc<AtomicBool>>,
+    captions: Captions,
+    howling: HowlingMonitor,
+    input_check: InputCheck,
+    extra_input_checks: Vec<(String, InputCheck)>,
+}
+
+// Handles shared between the UI and a running call or self listen
+#[derive(Clone, Default)]
+pub struct CallHandles {
+    pub file_player: FilePlayer,
+    pub analyzer: Analyzer,
+    pub opus_settings: OpusSettingsHandle,
+    pub cue_player: CuePlayer,
+    pub effects: EffectsHandle,
+    pub transient_suppression: TransientSuppression,
+    pub captions: Captions,
+    pub input_control: InputControl,
+    pub extra_inputs: Vec<ExtraInput>,
+    pub output_control: OutputControl,
+    pub extra_outputs: Vec<ExtraOutput>,
 }
 
 impl P2P {
-    pub fn new(input_device: &Device, output_device: &Device, peer: &String) -> Self {
-        let input_config: StreamConfig = input_device
-            .default_input_config()
-            .expect("Failed to get default input config")
-            .into();
-        info!(target: TRACING_TARGET, "Input stream config has {} channel(s), {}Hz sample rate", input_config.channels, input_config.sample_rate.0);
-
-        let output_config: StreamConfig = output_device
-            .default_output_config()
-            .expect("Failed to get default output config")
-            .into();
-        info!(target: TRACING_TARGET, "Output stream config has {} channel(s), {}Hz sample rate", output_config.channels, output_config.sample_rate.0);
-
-        let mut port: usize = 4000;
-        info!(target: TRACING_TARGET, "Binding UDP socket on port {port}");
-        let mut socket = UdpSocket::bind(format!("0.0.0.0:{port}"));
-        let mut bind_errors: usize = 0;
-        while socket.is_err() && bind_errors < 3 {
-            bind_errors += 1;
-            port += 1;
-            info!(target: TRACING_TARGET, "Binding failed, using {port} instead");
-            socket = UdpSocket::bind(format!("0.0.0.0:{port}"));
-        }
-        let socket = socket.unwrap_or_else(|_e| panic!("Binding failed, exiting..."));
-        socket
-            .set_nonblocking(true)
-            .expect("Failed to move socket into nonblocking mode");
-        socket.connect(peer).expect("Failed to connect");
-
-        let socket_sender = socket.try_clone().expect("Failed to clone socket");
-
-        let (input_producer, input_consumer) = HeapRb::<f32>::new(8192 * 2).split();
+    pub fn new(
+        input: &InputEndpoint,
+        output: &OutputEndpoint,
+        negotiation: &Negotiation,
+        record: bool,
+        handles: CallHandles,
+        profile: LatencyProfile,
+        rate: ProcessingRate,
+    ) -> Self {
+        let CallHandles {
+            file_player,
+            analyzer,
+            opus_settings,
+            cue_player,
+            effects,
+            transient_suppression,
+            captions,
+            input_control,
+            extra_inputs,
+            output_control,
+            extra_outputs,
+        } = handles;
+
+        let input_config: StreamConfig = input.config();
+        info!(target: TRACING_TARGET, "Input {input} stream config has {} channel(s), {}Hz sample rate", input_config.channels, input_config.sample_rate.0);
+
+        let output_config: StreamConfig = output.config();
+        info!(target: TRACING_TARGET, "Output {output} stream config has {} channel(s), {}Hz sample rate", output_config.channels, output_config.sample_rate.0);
+
+        // Music mode sends stereo as it is captured, at full bandwidth, and buffers more to ride out
+        // jitter without dropouts
+        let channels: usize = negotiation.channels();
+        let (profile, rate, opus_settings) = if negotiation.music {
+            info!(target: TRACING_TARGET, "Using music mode");
+            (
+                LatencyProfile::Robust,
+                ProcessingRate::Fullband,
+                OpusSettingsHandle::new(OpusSettings::music()),
+            )
+        } else if negotiation.is_baseline() {
+            // The baseline app decodes into 20 ms buffers and stops on any packet it can't decode
+            info!(target: TRACING_TARGET, "Using default Opus settings for a baseline peer");
+            (
+                profile,
+                rate,
+                OpusSettingsHandle::new(OpusSettings::default()),
+            )
+        } else {
+            (profile, rate, opus_settings)
+        };
+        let sample_rate: usize = rate.sample_rate();
+        info!(target: TRACING_TARGET, "Processing at {sample_rate}Hz");
+
+        let codec: CodecKind = negotiation.codec;
+        let codec_sample_rate: usize = codec.sample_rate(sample_rate) as usize;
+        info!(target: TRACING_TARGET, "Using {codec} at {codec_sample_rate}Hz");
+
+        let (input_producer, input_consumer) = HeapRb::<f32>::new(profile.buffer_size()).split();
         let (resampler_input_producer, resampler_input_consumer) =
-            HeapRb::<f32>::new(8192 * 2).split();
-        let (denoise_producer, denoise_consumer) = HeapRb::<f32>::new(8192 * 2).split();
-        let (decoder_producer, decoder_consumer) = HeapRb::<f32>::new(8192 * 2).split();
-        let (resampler_output_producer, resampler_output_consumer) =
-            HeapRb::<f32>::new(8192 * 2).split();
-
-        let input_stream = create_input_stream(
-            input_config.channels as usize,
-            input_device,
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (denoise_producer, denoise_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (effects_producer, effects_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (howling_producer, howling_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (file_mix_producer, file_mix_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (monitor_producer, monitor_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (decoder_producer, decoder_consumer) =
+            HeapRb::<f32>::new(profile.buffer_size()).split();
+        let (mix_producer, mix_consumer) = HeapRb::<f32>::new(profile.buffer_size()).split();
+        // Output is pulled through the mixer, so this buffer bounds the playback latency
+        let (resampler_output_producer, resampler_output_consumer) = HeapRb::<f32>::new(
+            (profile.mix_target_fill(output_config.sample_rate.0 as usize) + 1) * 2 * MIX_CHANNELS,
+        )
+        .split();
+
+        // Everything queued between the decoder and the output device adds to the end to end latency
+        let decoder_stats: BufferStats = BufferStats::new(
+            "Decoder",
+            sample_rate * channels,
+            decoder_producer.observe(),
+        );
+        let mut receive_buffer_stats: Vec<BufferStats> = vec![
+            decoder_stats.clone(),
+            BufferStats::new("Mix", sample_rate * MIX_CHANNELS, mix_producer.observe()),
+            BufferStats::new(
+                "Output resampler",
+                output_config.sample_rate.0 as usize * MIX_CHANNELS,
+                resampler_output_producer.observe(),
+            ),
+        ];
+        let mix_stats: BufferStats = receive_buffer_stats[1].clone();
+        let output_stats: BufferStats = receive_buffer_stats[2].clone();
+        let monitor_stats: BufferStats =
+            BufferStats::new("File monitor", sample_rate, monitor_producer.observe());
+        let mut send_buffer_stats: Vec<BufferStats> = vec![
+            BufferStats::new(
+                "Input",
+                input_config.sample_rate.0 as usize * channels,
+                input_producer.observe(),
+            ),
+            BufferStats::new(
+                "Input resampler",
+                sample_rate * channels,
+                resampler_input_producer.observe(),
+            ),
+            BufferStats::new("Denoise", sample_rate, denoise_producer.observe()),
+            BufferStats::new("Effects", sample_rate, effects_producer.observe()),
+            BufferStats::new(
+                "Howling",
+                sample_rate * channels,
+                howling_producer.observe(),
+            ),
+            BufferStats::new(
+                "File mix",
+                sample_rate * channels,
+                file_mix_producer.observe(),
+            ),
+            monitor_stats.clone(),
+        ];
+        let input_clock: StreamClock = StreamClock::new(input_config.sample_rate.0);
+        let output_clock: StreamClock = StreamClock::new(output_config.sample_rate.0);
+        let remote_clock: RemoteClock = RemoteClock::new(codec_sample_rate as u32);
+
+        let muted: Arc<AtomicBool> = Arc::new(false.into());
+        // Captions follow the audio as it is sent and as it is played, both mono at the processing rate
+        let transcriber_settings: TranscriberSettings = captions.settings();
+        let mut transcription_thread_runs: Vec<Arc<AtomicBool>> = Vec::new();
+        let (local_tap, peer_tap) = match (
+            transcriber_settings.create("local"),
+            transcriber_settings.create("peer"),
+        ) {
+            (Some(local_transcriber), Some(peer_transcriber)) => {
+                let path: String = format!("call-{}-transcript.txt", unix_timestamp());
+                info!(target: TRACING_TARGET, "Saving transcript to {path}");
+                if let Err(e) = captions.start_transcript(Path::new(&path)) {
+                    error!(target: TRACING_TARGET, "Failed to create transcript {path}: {e}");
+                }
+                let (local_tap, local_consumer) =
+                    HeapRb::<f32>::new(sample_rate * TRANSCRIPTION_BUFFER_SECONDS).split();
+                let (peer_tap, peer_consumer) =
+                    HeapRb::<f32>::new(sample_rate * TRANSCRIPTION_BUFFER_SECONDS).split();
+                let local_tap_stats: BufferStats =
+                    BufferStats::new("Local captions", sample_rate, local_tap.observe());
+                let peer_tap_stats: BufferStats =
+                    BufferStats::new("Peer captions", sample_rate, peer_tap.observe());
+                transcription_thread_runs.push(create_transcription_thread(
+                    Speaker::Local,
+                    sample_rate,
+                    local_consumer,
+                    local_transcriber,
+                    captions.clone(),
+                    local_tap_stats.xruns(),
+                ));
+                transcription_thread_runs.push(create_transcription_thread(
+                    Speaker::Peer,
+                    sample_rate,
+                    peer_consumer,
+                    peer_transcriber,
+                    captions.clone(),
+                    peer_tap_stats.xruns(),
+                ));
+                (
+                    Some((local_tap, local_tap_stats)),
+                    Some((peer_tap, peer_tap_stats)),
+                )
+            }
+            _ => (None, None),
+        };
+
+        let (input_stream, input_check) = create_input_stream(
+            channels,
+            input,
             &input_config,
             input_producer,
+            None,
+            input_clock.clone(),
+            send_buffer_stats[0].xruns(),
         );
         let resampler_input_thread_run = create_resampler_thread(
-            1,
+            channels,
             input_config.sample_rate.0 as usize,
-            48000_usize,
+            sample_rate,
             input_consumer,
             resampler_input_producer,
+            None,
+            send_buffer_stats[1].xruns(),
         );
-        let denoise_thread_run =
-            create_denoise_thread(1, resampler_input_consumer, denoise_producer);
-        let encoder_thread_run =
-            create_opus_encoder_thread(denoise_consumer, 48000_u32, 1, socket_sender);
-        let decoder_thread_run = create_opus_decoder_thread(decoder_producer, 48000_u32, 1, socket);
-        let resampler_output_thread_run = create_resampler_thread(
+        // Extra mics get their own capture stream and resampler and are mixed in before any processing
+        let mut extra_input_streams: Vec<EndpointStream> = Vec::new();
+        let mut extra_input_checks: Vec<(String, InputCheck)> = Vec::new();
+        let mut extra_input_thread_runs: Vec<Arc<AtomicBool>> = Vec::new();
+        let capture_consumer: C = if extra_inputs.is_empty() {
+            resampler_input_consumer
+        } else {
+            let mut extra_consumers: Vec<(C, InputControl, Xruns)> = Vec::new();
+            for (i, extra_input) in extra_inputs.iter().enumerate() {
+                let extra_config: StreamConfig = extra_input.endpoint.config();
+                info!(target: TRACING_TARGET, "Extra input {} stream config has {} channel(s), {}Hz sample rate", extra_input.endpoint, extra_config.channels, extra_config.sample_rate.0);
+                let (extra_input_producer, extra_input_consumer) =
+                    HeapRb::<f32>::new(profile.buffer_size()).split();
+                let (extra_resampler_producer, extra_resampler_consumer) =
+                    HeapRb::<f32>::new(profile.buffer_size()).split();
+                let extra_input_stats: BufferStats = BufferStats::new(
+                    format!("Mic {} input", i + 2),
+                    extra_config.sample_rate.0 as usize * channels,
+                    extra_input_producer.observe(),
+                );
+                let extra_resampler_stats: BufferStats = BufferStats::new(
+                    format!("Mic {} resampler", i + 2),
+                    sample_rate * channels,
+                    extra_resampler_producer.observe(),
+                );
+                let (extra_input_stream, extra_input_check) = create_input_stream(
+                    channels,
+                    &extra_input.endpoint,
+                    &extra_config,
+                    extra_input_producer,
+                    None,
+                    StreamClock::new(extra_config.sample_rate.0),
+                    extra_input_stats.xruns(),
+                );
+                extra_input_streams.push(extra_input_stream);
+                extra_input_checks.push((extra_input.endpoint.to_string(), extra_input_check));
+                extra_input_thread_runs.push(create_resampler_thread(
+                    channels,
+                    extra_config.sample_rate.0 as usize,
+                    sample_rate,
+                    extra_input_consumer,
+                    extra_resampler_producer,
+                    Some((
+                        profile.max_fill(extra_config.sample_rate.0 as usize),
+                        extra_input_stats.xruns(),
+                    )),
+                    extra_resampler_stats.xruns(),
+                ));
+                extra_consumers.push((
+                    extra_resampler_consumer,
+                    extra_input.control.clone(),
+                    extra_resampler_stats.xruns(),
+                ));
+                send_buffer_stats.push(extra_input_stats);
+                send_buffer_stats.push(extra_resampler_stats);
+            }
+            let (mic_mix_producer, mic_mix_consumer) =
+                HeapRb::<f32>::new(profile.buffer_size()).split();
+            let mic_mix_stats: BufferStats = BufferStats::new(
+                "Mic mix",
+                sample_rate * channels,
+                mic_mix_producer.observe(),
+            );
+            extra_input_thread_runs.push(create_mic_mix_thread(
+                channels,
+                sample_rate,
+                resampler_input_consumer,
+                input_control,
+                extra_consumers,
+                mic_mix_producer,
+                mic_mix_stats.xruns(),
+            ));
+            send_buffer_stats.push(mic_mix_stats);
+            mic_mix_consumer
+        };
+        // Noise suppression and voice effects would eat into music, so it skips them
+        let (processed_consumer, denoise_thread_run, effects_thread_run) = if negotiation.music {
+            (capture_consumer, None, None)
+        } else {
+            let denoise_thread_run = create_denoise_thread(
+                1,
+                sample_rate,
+                capture_consumer,
+                denoise_producer,
+                analyzer,
+                transient_suppression,
+                send_buffer_stats[2].xruns(),
+            );
+            let effects_thread_run = create_effects_thread(
+                sample_rate,
+                denoise_consumer,
+                effects_producer,
+                effects,
+                send_buffer_stats[3].xruns(),
+            );
+            (
+                effects_consumer,
+                Some(denoise_thread_run),
+                Some(effects_thread_run),
+            )
+        };
+        let howling: HowlingMonitor = HowlingMonitor::default();
+        let howling_thread_run = create_howling_thread(
+            channels,
+            sample_rate,
+            processed_consumer,
+            howling_producer,
+            howling.clone(),
+            send_buffer_stats[4].xruns(),
+        );
+        let file_mix_thread_run = create_file_mix_thread(
+            channels,
+            file_player,
+            howling_consumer,
+            file_mix_producer,
+            Some((monitor_producer, monitor_stats.xruns())),
+            local_tap.map(|(tap, stats)| {
+                let xruns: Xruns = stats.xruns();
+                send_buffer_stats.push(stats);
+                (tap, xruns, muted.clone())
+            }),
+            send_buffer_stats[5].xruns(),
+        );
+        let (sent_capture, received_capture) = if record && codec == CodecKind::Opus {
+            create_call_captures(channels as u8, sample_rate as u32, opus_settings.get())
+        } else {
+            if record {
+                error!(target: TRACING_TARGET, "Call recording is only supported with Opus");
+            }
+            (None, None)
+        };
+
+        // Codecs running at another rate than the processing get their own resamplers around them
+        let (
+            encoder_consumer,
+            decoder_producer,
+            decoder_xruns,
+            resampler_codec_input_thread_run,
+            resampler_codec_output_thread_run,
+        ) = if codec_sample_rate == sample_rate {
+            (
+                file_mix_consumer,
+                decoder_producer,
+                decoder_stats.xruns(),
+                None,
+                None,
+            )
+        } else {
+            let (encoder_producer, encoder_consumer) =
+                HeapRb::<f32>::new(profile.buffer_size()).split();
+            let (codec_producer, codec_consumer) =
+                HeapRb::<f32>::new(profile.buffer_size()).split();
+            let encoder_stats: BufferStats =
+                BufferStats::new("Encoder", codec_sample_rate, encoder_producer.observe());
+            let codec_stats: BufferStats = BufferStats::new(
+                "Codec resampler",
+                codec_sample_rate,
+                codec_producer.observe(),
+            );
+            send_buffer_stats.push(encoder_stats.clone());
+            receive_buffer_stats.push(codec_stats.clone());
+            (
+                encoder_consumer,
+                codec_producer,
+                codec_stats.xruns(),
+                Some(create_resampler_thread(
+                    1,
+                    sample_rate,
+                    codec_sample_rate,
+                    file_mix_consumer,
+                    encoder_producer,
+                    None,
+                    encoder_stats.xruns(),
+                )),
+                Some(create_resampler_thread(
+                    1,
+                    codec_sample_rate,
+                    sample_rate,
+                    codec_consumer,
+                    decoder_producer,
+                    None,
+                    decoder_stats.xruns(),
+                )),
+            )
+        };
+
+        // Every stage keeps the sample count, the resamplers delay the audio and denoising drops its first frame
+        let mut capture_offset: f64 =
+            -resampler_delay(input_config.sample_rate.0 as usize, sample_rate)
+                - resampler_delay(sample_rate, codec_sample_rate);
+        if !negotiation.music {
+            capture_offset += denoise_offset(sample_rate);
+        }
+        let encoder_thread_run = create_encoder_thread(
+            encoder_consumer,
+            codec
+                .create_encoder(channels, sample_rate, opus_settings)
+                .expect("Failed to create encoder"),
+            negotiation.clone(),
+            sent_capture,
+            muted.clone(),
+            (input_clock.clone(), capture_offset),
+            codec_sample_rate,
+        );
+        let decoder_thread_run = create_decoder_thread(
+            decoder_producer,
+            codec
+                .create_decoder(channels, sample_rate)
+                .expect("Failed to create decoder"),
+            negotiation.clone(),
+            received_capture,
+            cue_player.clone(),
+            remote_clock.clone(),
+            decoder_xruns,
+        );
+        let mixer: Mixer = Mixer::new(profile, sample_rate);
+        // Stereo music keeps its own image, so it is not spread around like voices
+        let peer_id: usize = mixer.add_source(
+            "Peer",
+            decoder_consumer,
+            channels,
+            negotiation.music.then_some(0.0),
+            decoder_stats.xruns(),
+        );
+        if let Some((peer_tap, peer_tap_stats)) = peer_tap {
+            mixer.set_tap(peer_id, peer_tap, peer_tap_stats.xruns());
+            receive_buffer_stats.push(peer_tap_stats);
+        }
+        mixer.add_source(
+            "File playback",
+            monitor_consumer,
             1,
-            48000_usize,
+            Some(0.0),
+            monitor_stats.xruns(),
+        );
+        // Extra devices get their own copy of the mix, resampler and drift compensation
+        let mut mix_producers: Vec<(P, Xruns)> = vec![(mix_producer, mix_stats.xruns())];
+        let mut extra_output_streams: Vec<EndpointStream> = Vec::new();
+        let mut extra_resampler_thread_runs: Vec<Arc<AtomicBool>> = Vec::new();
+        let mut extra_output_buffer_stats: Vec<BufferStats> = Vec::new();
+        for (i, extra_output) in extra_outputs.iter().enumerate() {
+            let extra_config: StreamConfig = extra_output.endpoint.config();
+            info!(target: TRACING_TARGET, "Extra output {} stream config has {} channel(s), {}Hz sample rate", extra_output.endpoint, extra_config.channels, extra_config.sample_rate.0);
+            let (extra_mix_producer, extra_mix_consumer) =
+                HeapRb::<f32>::new(profile.buffer_size()).split();
+            let (extra_resampler_producer, extra_resampler_consumer) = HeapRb::<f32>::new(
+                (profile.mix_target_fill(extra_config.sample_rate.0 as usize) + 1)
+                    * 2
+                    * MIX_CHANNELS,
+            )
+            .split();
+            let extra_mix_stats: BufferStats = BufferStats::new(
+                format!("Output {} mix", i + 2),
+                sample_rate * MIX_CHANNELS,
+                extra_mix_producer.observe(),
+            );
+            let extra_resampler_stats: BufferStats = BufferStats::new(
+                format!("Output {} resampler", i + 2),
+                extra_config.sample_rate.0 as usize * MIX_CHANNELS,
+                extra_resampler_producer.observe(),
+            );
+            mix_producers.push((extra_mix_producer, extra_mix_stats.xruns()));
+            extra_resampler_thread_runs.push(create_output_resampler_thread(
+                sample_rate,
+                extra_config.sample_rate.0 as usize,
+                extra_mix_consumer,
+                extra_resampler_producer,
+                Some(profile.mix_target_fill(sample_rate)),
+                extra_output.control.clone(),
+                extra_resampler_stats.xruns(),
+            ));
+            extra_output_streams.push(create_output_stream(
+                MIX_CHANNELS,
+                &extra_output.endpoint,
+                &extra_config,
+                extra_resampler_consumer,
+                None,
+                StreamClock::new(extra_config.sample_rate.0),
+                extra_resampler_stats.xruns(),
+            ));
+            extra_output_buffer_stats.push(extra_mix_stats);
+            extra_output_buffer_stats.push(extra_resampler_stats);
+        }
+
+        let mix_thread_run = create_mix_thread(
+            mixer.clone(),
+            mix_producers,
+            cue_player,
+            sample_rate,
+            profile.mix_target_fill(sample_rate),
+        );
+        let resampler_output_thread_run = create_output_resampler_thread(
+            sample_rate,
             output_config.sample_rate.0 as usize,
-            decoder_consumer,
+            mix_consumer,
             resampler_output_producer,
+            None,
+            output_control,
+            output_stats.xruns(),
         );
         let output_stream = create_output_stream(
-            output_config.channels as usize,
-            output_device,
+            MIX_CHANNELS,
+            output,
             &output_config,
             resampler_output_consumer,
+            None,
+            output_clock.clone(),
+            output_stats.xruns(),
         );
 
-        input_stream.play().expect("Failed to play input stream");
-        output_stream.play().expect("Failed to play output stream");
+        input_stream.play();
+        for extra_input_stream in extra_input_streams.iter() {
+            extra_input_stream.play();
+        }
+        output_stream.play();
+        for extra_output_stream in extra_output_streams.iter() {
+            extra_output_stream.play();
+        }
 
         Self {
             input_stream,
             output_stream,
             resampler_input_thread_run,
+            extra_input_streams,
+            extra_input_thread_runs,
             denoise_thread_run,
+            effects_thread_run,
+            howling_thread_run,
+            file_mix_thread_run,
+            resampler_codec_input_thread_run,
             encoder_thread_run,
             decoder_thread_run,
+            resampler_codec_output_thread_run,
+            mix_thread_run,
             resampler_output_thread_run,
+            extra_output_streams,
+            extra_resampler_thread_runs,
+            muted,
+            music: negotiation.music,
+            mixer,
+            sample_rate,
+            codec_sample_rate,
+            input_clock,
+            output_clock,
+            remote_clock,
+            send_buffer_stats,
+            receive_buffer_stats,
+            extra_output_buffer_stats,
+            transcription_thread_runs,
+            captions,
+            howling,
+            input_check,
+            extra_input_checks,
+        }
+    }
+
+    pub fn buffer_stats(&self) -> Vec<BufferStats> {
+        self.send_buffer_stats
+            .iter()
+            .chain(self.receive_buffer_stats.iter())
+            .chain(self.extra_output_buffer_stats.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    pub fn is_music(&self) -> bool {
+        self.music
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    pub fn howling(&self) -> HowlingReport {
+        self.howling.report()
+    }
+
+    pub fn input_problems(&self) -> Vec<InputProblem> {
+        self.input_check.problems()
+    }
+
+    // Extra inputs are told apart by their names, as they can be removed during the call
+    pub fn extra_input_problems(&self, name: &str) -> Vec<InputProblem> {
+        self.extra_input_checks
+            .iter()
+            .find(|x| x.0 == name)
+            .map(|x| x.1.problems())
+            .unwrap_or_default()
+    }
+
+    pub fn timing(&self) -> TimingReport {
+        TimingReport {
+            input_device_latency: self.input_clock.device_latency(),
+            output_device_latency: self.output_clock.device_latency(),
+            transport_latency: self.remote_clock.transport_latency(),
+            receive_buffering: Duration::from_secs_f32(
+                self.receive_buffer_stats
+                    .iter()
+                    .map(|x| x.latency_ms())
+                    .sum::<f32>()
+                    / 1000.0,
+            ),
+            input_drift_ppm: self.input_clock.drift_ppm(),
+            output_drift_ppm: self.output_clock.drift_ppm(),
+            remote_drift_ppm: self.remote_clock.drift_ppm(),
         }
     }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    // Stops sending and receiving while the output keeps playing, so a goodbye cue can finish
+    pub fn hang_up(&self) {
+        self.resampler_input_thread_run
+            .store(false, Ordering::Relaxed);
+        for thread_run in self.extra_input_thread_runs.iter() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        if let Some(thread_run) = self.denoise_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        if let Some(thread_run) = self.effects_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        self.howling_thread_run.store(false, Ordering::Relaxed);
+        self.file_mix_thread_run.store(false, Ordering::Relaxed);
+        if let Some(thread_run) = self.resampler_codec_input_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        self.encoder_thread_run.store(false, Ordering::Relaxed);
+        self.decoder_thread_run.store(false, Ordering::Relaxed);
+    }
 }
 
 impl Drop for P2P {
     fn drop(&mut self) {
-        self.denoise_thread_run.store(false, Ordering::Relaxed);
+        if let Some(thread_run) = self.denoise_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        if let Some(thread_run) = self.effects_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        self.howling_thread_run.store(false, Ordering::Relaxed);
         self.resampler_input_thread_run
             .store(false, Ordering::Relaxed);
+        for thread_run in self.extra_input_thread_runs.iter() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        self.file_mix_thread_run.store(false, Ordering::Relaxed);
+        if let Some(thread_run) = self.resampler_codec_input_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        if let Some(thread_run) = self.resampler_codec_output_thread_run.as_ref() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
         self.encoder_thread_run.store(false, Ordering::Relaxed);
         self.decoder_thread_run.store(false, Ordering::Relaxed);
+        self.mix_thread_run.store(false, Ordering::Relaxed);
         self.resampler_output_thread_run
             .store(false, Ordering::Relaxed);
+        for thread_run in self.extra_resampler_thread_runs.iter() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        for thread_run in self.transcription_thread_runs.iter() {
+            thread_run.store(false, Ordering::Relaxed);
+        }
+        self.captions.stop_transcript();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INPUT_RATE: usize = 48000;
+    const OUTPUT_RATE: usize = 24000;
+
+    fn ring(name: &str, capacity: usize) -> (P, C, BufferStats) {
+        let (producer, consumer) = HeapRb::<f32>::new(capacity).split();
+        let stats: BufferStats = BufferStats::new(name, INPUT_RATE, producer.observe());
+        (producer, consumer, stats)
+    }
+
+    fn wait_for_overrun(stats: &BufferStats) {
+        let start: Instant = Instant::now();
+        while stats.xruns().overruns() == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn resampler_drops_past_the_fill_limit_count_on_its_input() {
+        let (mut input_producer, input_consumer, input_stats) = ring("Input", INPUT_RATE);
+        let (mut output_producer, _output_consumer, output_stats) = ring("Output", 100);
+        // A full output makes audio back up in the input
+        output_producer.push_slice(&[0.0; 100]);
+        let thread_run: Arc<AtomicBool> = create_resampler_thread(
+            1,
+            INPUT_RATE,
+            OUTPUT_RATE,
+            input_consumer,
+            output_producer,
+            Some((INPUT_RATE / 10, input_stats.xruns())),
+            output_stats.xruns(),
+        );
+        input_producer.push_slice(&vec![0.0; INPUT_RATE / 5]);
+        wait_for_overrun(&input_stats);
+        thread_run.store(false, Ordering::Relaxed);
+
+        assert_eq!(input_stats.xruns().overruns(), 1);
+        assert_eq!(input_stats.xruns().dropped(), INPUT_RATE as u64 / 10);
+        assert_eq!(output_stats.xruns().overruns(), 0);
+    }
+
+    #[test]
+    fn resampler_pushes_into_a_full_output_count_on_the_output() {
+        let (mut input_producer, input_consumer, input_stats) = ring("Input", INPUT_RATE);
+        let (mut output_producer, _output_consumer, output_stats) = ring("Output", 100);
+        output_producer.push_slice(&[0.0; 100]);
+        let thread_run: Arc<AtomicBool> = create_resampler_thread(
+            1,
+            INPUT_RATE,
+            OUTPUT_RATE,
+            input_consumer,
+            output_producer,
+            None,
+            output_stats.xruns(),
+        );
+        input_producer.push_slice(&vec![0.0; INPUT_RATE / 50]);
+        wait_for_overrun(&output_stats);
+        thread_run.store(false, Ordering::Relaxed);
+
+        assert_eq!(output_stats.xruns().overruns(), 1);
+        assert_eq!(output_stats.xruns().dropped(), OUTPUT_RATE as u64 / 50);
+        assert_eq!(input_stats.xruns().overruns(), 0);
     }
 }
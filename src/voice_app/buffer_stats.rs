@@ -0,0 +1,181 @@
+use std::{
+    fmt::Display,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use ringbuf::traits::Observer;
+use tracing::error;
+
+use crate::voice_app::app_tracing::TRACING_TARGET;
+
+pub type O = ringbuf::wrap::Obs<Arc<ringbuf::SharedRb<ringbuf::storage::Heap<f32>>>>;
+
+const XRUN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+struct XrunState {
+    overruns: AtomicU64,
+    dropped: AtomicU64,
+    underruns: AtomicU64,
+    missing: AtomicU64,
+    epoch: Instant,
+    // Milliseconds since `epoch` plus one of the last log line, zero before the first one.
+    // Atomics keep the audio callbacks from ever waiting on a lock
+    last_log: AtomicU64,
+}
+
+// Counts of samples lost because a ring buffer was full or empty when a stage needed it
+#[derive(Clone)]
+pub struct Xruns {
+    name: Arc<str>,
+    state: Arc<XrunState>,
+}
+
+impl Xruns {
+    fn new(name: Arc<str>) -> Self {
+        Xruns {
+            name,
+            state: Arc::new(XrunState {
+                overruns: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+                underruns: AtomicU64::new(0),
+                missing: AtomicU64::new(0),
+                epoch: Instant::now(),
+                last_log: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn overrun(&self, dropped: usize) {
+        self.state.overruns.fetch_add(1, Ordering::Relaxed);
+        self.state
+            .dropped
+            .fetch_add(dropped as u64, Ordering::Relaxed);
+        self.log();
+    }
+
+    pub fn underrun(&self, missing: usize) {
+        self.state.underruns.fetch_add(1, Ordering::Relaxed);
+        self.state
+            .missing
+            .fetch_add(missing as u64, Ordering::Relaxed);
+        self.log();
+    }
+
+    pub fn overruns(&self) -> u64 {
+        self.state.overruns.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.state.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.state.underruns.load(Ordering::Relaxed)
+    }
+
+    pub fn missing(&self) -> u64 {
+        self.state.missing.load(Ordering::Relaxed)
+    }
+
+    fn log(&self) {
+        let now: u64 = self.state.epoch.elapsed().as_millis() as u64 + 1;
+        let last: u64 = self.state.last_log.load(Ordering::Relaxed);
+        if last != 0 && now < last + XRUN_LOG_INTERVAL.as_millis() as u64 {
+            return;
+        }
+        if self
+            .state
+            .last_log
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            error!(target: TRACING_TARGET, "{} buffer: {} overrun(s) dropping {} sample(s), {} underrun(s) missing {} sample(s) so far", self.name, self.overruns(), self.dropped(), self.underruns(), self.missing());
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BufferStats {
+    pub name: Arc<str>,
+    pub samples_per_second: usize,
+    observer: O,
+    xruns: Xruns,
+}
+
+impl BufferStats {
+    // Names of buffers that come per device are built at runtime
+    pub fn new(name: impl Into<Arc<str>>, samples_per_second: usize, observer: O) -> Self {
+        let name: Arc<str> = name.into();
+        BufferStats {
+            name: name.clone(),
+            samples_per_second,
+            observer,
+            xruns: Xruns::new(name),
+        }
+    }
+
+    pub fn occupied_len(&self) -> usize {
+        self.observer.occupied_len()
+    }
+
+    pub fn latency_ms(&self) -> f32 {
+        self.occupied_len() as f32 * 1000.0 / self.samples_per_second as f32
+    }
+
+    pub fn xruns(&self) -> Xruns {
+        self.xruns.clone()
+    }
+}
+
+impl Display for BufferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {:.1} ms queued, {} overrun(s) / {} dropped, {} underrun(s) / {} missing",
+            self.name,
+            self.latency_ms(),
+            self.xruns.overruns(),
+            self.xruns.dropped(),
+            self.xruns.underruns(),
+            self.xruns.missing()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{
+        HeapRb,
+        traits::{Producer, Split},
+    };
+
+    use super::*;
+    use crate::voice_app::audio::{C, P};
+
+    #[test]
+    fn stats_follow_the_buffer_and_its_xruns() {
+        let (mut producer, _consumer): (P, C) = HeapRb::<f32>::new(4800).split();
+        let stats: BufferStats = BufferStats::new("Test", 48000, producer.observe());
+        producer.push_slice(&[0.0; 480]);
+        assert_eq!(stats.occupied_len(), 480);
+        assert_eq!(stats.latency_ms(), 10.0);
+
+        // Copies of the handle count into the same totals
+        let xruns: Xruns = stats.xruns();
+        xruns.overrun(10);
+        stats.xruns().overrun(5);
+        xruns.underrun(3);
+        assert_eq!(stats.xruns().overruns(), 2);
+        assert_eq!(stats.xruns().dropped(), 15);
+        assert_eq!(stats.xruns().underruns(), 1);
+        assert_eq!(stats.xruns().missing(), 3);
+        assert_eq!(
+            stats.to_string(),
+            "Test: 10.0 ms queued, 2 overrun(s) / 15 dropped, 1 underrun(s) / 3 missing"
+        );
+    }
+}
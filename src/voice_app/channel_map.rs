@@ -0,0 +1,334 @@
+use std::fmt::Display;
+
+use tracing::error;
+
+use crate::voice_app::app_tracing::TRACING_TARGET;
+
+// Channels are numbered from 1 in text and from 0 everywhere else
+fn parse_channels(value: &str) -> Result<Vec<usize>, String> {
+    value
+        .split(',')
+        .map(|x| match x.trim().parse::<usize>() {
+            Ok(0) | Err(_) => Err(format!("Invalid channel {x}, channels start at 1")),
+            Ok(channel) => Ok(channel - 1),
+        })
+        .collect()
+}
+
+fn write_channels(f: &mut std::fmt::Formatter<'_>, channels: &[usize]) -> std::fmt::Result {
+    let channels: Vec<String> = channels.iter().map(|x| (x + 1).to_string()).collect();
+    f.write_str(&channels.join(","))
+}
+
+// Weights turning one frame of channels into another, one row per output channel
+pub struct ChannelMatrix {
+    rows: Vec<Vec<f32>>,
+}
+
+impl ChannelMatrix {
+    pub fn apply(&self, frame: &[f32], output: &mut [f32]) {
+        for (sample, row) in output.iter_mut().zip(self.rows.iter()) {
+            *sample = row.iter().zip(frame.iter()).map(|(w, x)| w * x).sum();
+        }
+    }
+
+    // Input channels that make it into any output channel
+    pub fn used_channels(&self) -> Vec<usize> {
+        (0..self.rows.iter().map(|x| x.len()).max().unwrap_or(0))
+            .filter(|x| {
+                self.rows
+                    .iter()
+                    .any(|row| row.get(*x).is_some_and(|w| *w != 0.0))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum InputChannelMap {
+    // Mono averages every channel, stereo takes the first two
+    #[default]
+    Auto,
+    Channels(Vec<usize>),
+    // One row of weights per channel sent on, one column per device channel
+    Matrix(Vec<Vec<f32>>),
+}
+
+impl InputChannelMap {
+    // `auto`, channel numbers like `1,2` or a matrix like `matrix:0.5,0.5,0;0,0,1`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value: &str = value.trim();
+        if value.is_empty() || value == "auto" {
+            Ok(InputChannelMap::Auto)
+        } else if let Some(matrix) = value.strip_prefix("matrix:") {
+            matrix
+                .split(';')
+                .map(|row| {
+                    row.split(',')
+                        .map(|x| {
+                            x.trim()
+                                .parse::<f32>()
+                                .map_err(|e| format!("Invalid weight {x}: {e}"))
+                        })
+                        .collect()
+                })
+                .collect::<Result<Vec<Vec<f32>>, String>>()
+                .map(InputChannelMap::Matrix)
+        } else {
+            parse_channels(value).map(InputChannelMap::Channels)
+        }
+    }
+
+    pub fn matrix(&self, channels: usize, device_channels: usize) -> ChannelMatrix {
+        let rows: Vec<Vec<f32>> = match self {
+            InputChannelMap::Auto => (0..channels)
+                .map(|channel| {
+                    if channels == 1 {
+                        vec![1.0 / device_channels as f32; device_channels]
+                    } else {
+                        select(channel.min(device_channels - 1), device_channels)
+                    }
+                })
+                .collect(),
+            InputChannelMap::Channels(selected) => {
+                let selected: Vec<usize> = selected
+                    .iter()
+                    .copied()
+                    .filter(|x| *x < device_channels)
+                    .collect();
+                if selected.is_empty() {
+                    error!(target: TRACING_TARGET, "None of the input channels {self} exist on a {device_channels} channel device, using all of them");
+                    return InputChannelMap::Auto.matrix(channels, device_channels);
+                }
+                if channels == 1 {
+                    let mut row: Vec<f32> = vec![0.0; device_channels];
+                    for channel in selected.iter() {
+                        row[*channel] += 1.0 / selected.len() as f32;
+                    }
+                    vec![row]
+                } else {
+                    (0..channels)
+                        .map(|x| select(selected[x.min(selected.len() - 1)], device_channels))
+                        .collect()
+                }
+            }
+            InputChannelMap::Matrix(matrix) => {
+                if matrix.is_empty() {
+                    return InputChannelMap::Auto.matrix(channels, device_channels);
+                }
+                let matrix: Vec<Vec<f32>> = matrix
+                    .iter()
+                    .map(|row| {
+                        let mut row: Vec<f32> = row.clone();
+                        row.resize(device_channels, 0.0);
+                        row
+                    })
+                    .collect();
+                // Mono averages the rows, stereo repeats a single row
+                if channels == 1 {
+                    vec![
+                        (0..device_channels)
+                            .map(|x| {
+                                matrix.iter().map(|row| row[x]).sum::<f32>() / matrix.len() as f32
+                            })
+                            .collect(),
+                    ]
+                } else {
+                    (0..channels)
+                        .map(|x| matrix[x.min(matrix.len() - 1)].clone())
+                        .collect()
+                }
+            }
+        };
+        ChannelMatrix { rows }
+    }
+}
+
+impl Display for InputChannelMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputChannelMap::Auto => f.write_str("auto"),
+            InputChannelMap::Channels(channels) => write_channels(f, channels),
+            InputChannelMap::Matrix(matrix) => {
+                let rows: Vec<String> = matrix
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|x| x.to_string())
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    })
+                    .collect();
+                write!(f, "matrix:{}", rows.join(";"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputChannelMap {
+    // Mono plays on every channel, stereo on the first two
+    #[default]
+    Auto,
+    Channels(Vec<usize>),
+}
+
+impl OutputChannelMap {
+    // `auto` or channel numbers like `3,4`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value: &str = value.trim();
+        if value.is_empty() || value == "auto" {
+            Ok(OutputChannelMap::Auto)
+        } else {
+            parse_channels(value).map(OutputChannelMap::Channels)
+        }
+    }
+
+    pub fn matrix(&self, source_channels: usize, device_channels: usize) -> ChannelMatrix {
+        let selected: Vec<usize> = match self {
+            OutputChannelMap::Auto if source_channels == 1 => (0..device_channels).collect(),
+            OutputChannelMap::Auto => (0..source_channels.min(device_channels)).collect(),
+            OutputChannelMap::Channels(selected) => {
+                let selected: Vec<usize> = selected
+                    .iter()
+                    .copied()
+                    .filter(|x| *x < device_channels)
+                    .collect();
+                if selected.is_empty() {
+                    error!(target: TRACING_TARGET, "None of the output channels {self} exist on a {device_channels} channel device, using the default ones");
+                    return OutputChannelMap::Auto.matrix(source_channels, device_channels);
+                }
+                selected
+            }
+        };
+        let mut rows: Vec<Vec<f32>> = vec![vec![0.0; source_channels]; device_channels];
+        // Mono goes to every selected channel, stereo is downmixed when only one is selected
+        for (i, channel) in selected.iter().enumerate() {
+            rows[*channel] = if source_channels == 1 {
+                vec![1.0]
+            } else if selected.len() == 1 {
+                vec![1.0 / source_channels as f32; source_channels]
+            } else {
+                select(i.min(source_channels - 1), source_channels)
+            };
+        }
+        ChannelMatrix { rows }
+    }
+}
+
+impl Display for OutputChannelMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputChannelMap::Auto => f.write_str("auto"),
+            OutputChannelMap::Channels(channels) => write_channels(f, channels),
+        }
+    }
+}
+
+fn select(channel: usize, channels: usize) -> Vec<f32> {
+    let mut row: Vec<f32> = vec![0.0; channels];
+    row[channel] = 1.0;
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(matrix: &ChannelMatrix, frame: &[f32]) -> Vec<f32> {
+        let mut output: Vec<f32> = vec![0.0; matrix.rows.len()];
+        matrix.apply(frame, &mut output);
+        output
+    }
+
+    #[test]
+    fn maps_round_trip_through_text() {
+        for value in ["auto", "3", "1,2", "matrix:0.5,0.5,0;0,0,1"] {
+            assert_eq!(InputChannelMap::parse(value).unwrap().to_string(), value);
+        }
+        assert_eq!(
+            InputChannelMap::parse("3").unwrap(),
+            InputChannelMap::Channels(vec![2])
+        );
+        assert_eq!(
+            OutputChannelMap::parse("3,4").unwrap(),
+            OutputChannelMap::Channels(vec![2, 3])
+        );
+        assert_eq!(InputChannelMap::parse("").unwrap(), InputChannelMap::Auto);
+        assert!(InputChannelMap::parse("0").is_err());
+        assert!(OutputChannelMap::parse("1,x").is_err());
+        assert!(InputChannelMap::parse("matrix:1,x").is_err());
+    }
+
+    #[test]
+    fn auto_input_averages_mono_and_takes_the_first_two_for_stereo() {
+        let frame: [f32; 4] = [0.4, 0.8, 0.0, 0.0];
+        assert_eq!(apply(&InputChannelMap::Auto.matrix(1, 4), &frame), [0.3]);
+        assert_eq!(
+            apply(&InputChannelMap::Auto.matrix(2, 4), &frame),
+            [0.4, 0.8]
+        );
+        // A mono device feeds both sides
+        assert_eq!(
+            apply(&InputChannelMap::Auto.matrix(2, 1), &[0.5]),
+            [0.5, 0.5]
+        );
+    }
+
+    #[test]
+    fn selected_input_channel_keeps_its_level() {
+        let mut frame: [f32; 8] = [0.0; 8];
+        frame[5] = 0.5;
+        let map: InputChannelMap = InputChannelMap::parse("6").unwrap();
+        assert_eq!(apply(&map.matrix(1, 8), &frame), [0.5]);
+        assert_eq!(apply(&map.matrix(2, 8), &frame), [0.5, 0.5]);
+        assert_eq!(map.matrix(1, 8).used_channels(), [5]);
+
+        let map: InputChannelMap = InputChannelMap::parse("1,6").unwrap();
+        assert_eq!(apply(&map.matrix(1, 8), &frame), [0.25]);
+        assert_eq!(apply(&map.matrix(2, 8), &frame), [0.0, 0.5]);
+    }
+
+    #[test]
+    fn missing_input_channels_fall_back_to_auto() {
+        let map: InputChannelMap = InputChannelMap::parse("5").unwrap();
+        assert_eq!(apply(&map.matrix(1, 2), &[0.2, 0.4]), [0.3]);
+        // Only the channels that exist are used
+        let map: InputChannelMap = InputChannelMap::parse("2,5").unwrap();
+        assert_eq!(apply(&map.matrix(1, 2), &[0.2, 0.4]), [0.4]);
+    }
+
+    #[test]
+    fn matrix_rows_are_padded_and_averaged_for_mono() {
+        let map: InputChannelMap = InputChannelMap::parse("matrix:1;0,0,1").unwrap();
+        let frame: [f32; 3] = [0.2, 0.9, 0.6];
+        assert_eq!(apply(&map.matrix(2, 3), &frame), [0.2, 0.6]);
+        assert_eq!(apply(&map.matrix(1, 3), &frame), [0.4]);
+        assert_eq!(map.matrix(1, 3).used_channels(), [0, 2]);
+    }
+
+    #[test]
+    fn output_plays_only_on_the_selected_channels() {
+        let map: OutputChannelMap = OutputChannelMap::parse("3,4").unwrap();
+        assert_eq!(apply(&map.matrix(2, 4), &[0.2, 0.4]), [0.0, 0.0, 0.2, 0.4]);
+        assert_eq!(apply(&map.matrix(1, 4), &[0.5]), [0.0, 0.0, 0.5, 0.5]);
+        // Stereo on a single channel is downmixed
+        let map: OutputChannelMap = OutputChannelMap::parse("2").unwrap();
+        assert_eq!(apply(&map.matrix(2, 2), &[0.2, 0.4]), [0.0, 0.3]);
+    }
+
+    #[test]
+    fn auto_output_fills_every_channel_with_mono_and_the_first_two_with_stereo() {
+        assert_eq!(
+            apply(&OutputChannelMap::Auto.matrix(1, 3), &[0.5]),
+            [0.5, 0.5, 0.5]
+        );
+        assert_eq!(
+            apply(&OutputChannelMap::Auto.matrix(2, 3), &[0.2, 0.4]),
+            [0.2, 0.4, 0.0]
+        );
+        // Missing channels fall back to the default ones
+        let map: OutputChannelMap = OutputChannelMap::parse("7").unwrap();
+        assert_eq!(apply(&map.matrix(2, 2), &[0.2, 0.4]), [0.2, 0.4]);
+    }
+}
@@ -0,0 +1,426 @@
+use std::fmt::Display;
+
+use cpal::Sample;
+use opus::{Channels, Decoder};
+use tracing::{error, info};
+
+use crate::voice_app::{
+    app_tracing::TRACING_TARGET,
+    opus_encoder::{OpusEncoder, OpusSettings, OpusSettingsHandle},
+};
+
+// G.711 is sent in 20 ms frames like RTP does by default, L16 in 10 ms so a 48kHz frame of 960
+// bytes stays below the MTU
+const L16_FRAME_DURATION_MS: usize = 10;
+const G711_FRAME_DURATION_MS: usize = 20;
+
+pub trait AudioEncoder: Send {
+    fn frame_size(&self) -> usize;
+    // Called between frames so codecs can pick up settings changed mid-call
+    fn refresh(&mut self) {}
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, String>;
+}
+
+// An empty input is a lost packet, concealed over the whole output
+pub trait AudioDecoder: Send {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecKind {
+    Opus,
+    L16,
+    Pcmu,
+    Pcma,
+}
+
+impl CodecKind {
+    pub const ALL: [CodecKind; 4] = [
+        CodecKind::Opus,
+        CodecKind::L16,
+        CodecKind::Pcmu,
+        CodecKind::Pcma,
+    ];
+
+    pub fn id(&self) -> u8 {
+        match self {
+            CodecKind::Opus => 0,
+            CodecKind::L16 => 1,
+            CodecKind::Pcmu => 2,
+            CodecKind::Pcma => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        CodecKind::ALL.into_iter().find(|x| x.id() == id)
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "opus" => Ok(CodecKind::Opus),
+            "l16" | "pcm" => Ok(CodecKind::L16),
+            "pcmu" | "ulaw" => Ok(CodecKind::Pcmu),
+            "pcma" | "alaw" => Ok(CodecKind::Pcma),
+            _ => Err(format!("Unknown codec {value}")),
+        }
+    }
+
+    // The preferred codec first, followed by every other codec when falling back is allowed
+    pub fn preferences(preferred: CodecKind, fallback: bool) -> Vec<CodecKind> {
+        std::iter::once(preferred)
+            .chain(
+                CodecKind::ALL
+                    .into_iter()
+                    .filter(|x| fallback && *x != preferred),
+            )
+            .collect()
+    }
+
+    // Opus runs at the internal processing rate, as the peer decodes it at its own rate anyway
+    pub fn sample_rate(&self, processing_rate: usize) -> u32 {
+        match self {
+            CodecKind::Opus => processing_rate as u32,
+            CodecKind::L16 => 48000,
+            CodecKind::Pcmu | CodecKind::Pcma => 8000,
+        }
+    }
+
+    pub fn create_encoder(
+        &self,
+        channels: usize,
+        processing_rate: usize,
+        opus_settings: OpusSettingsHandle,
+    ) -> Result<Box<dyn AudioEncoder>, String> {
+        let sample_rate: u32 = self.sample_rate(processing_rate);
+        let frame_size: usize = match self {
+            CodecKind::L16 => sample_rate as usize / 1000 * L16_FRAME_DURATION_MS,
+            _ => sample_rate as usize / 1000 * G711_FRAME_DURATION_MS,
+        };
+        Ok(match self {
+            CodecKind::Opus => Box::new(OpusCodecEncoder {
+                version: opus_settings.version(),
+                encoder: OpusEncoder::new(sample_rate, channels, opus_settings.get())?,
+                settings: opus_settings,
+                channels,
+            }),
+            CodecKind::L16 => Box::new(PcmEncoder {
+                frame_size: frame_size * channels,
+                sample_size: 2,
+                encode: |x, output| output.copy_from_slice(&f32_to_i16(x).to_be_bytes()),
+            }),
+            CodecKind::Pcmu => Box::new(PcmEncoder {
+                frame_size: frame_size * channels,
+                sample_size: 1,
+                encode: |x, output| output[0] = linear_to_ulaw(f32_to_i16(x)),
+            }),
+            CodecKind::Pcma => Box::new(PcmEncoder {
+                frame_size: frame_size * channels,
+                sample_size: 1,
+                encode: |x, output| output[0] = linear_to_alaw(f32_to_i16(x)),
+            }),
+        })
+    }
+
+    pub fn create_decoder(
+        &self,
+        channels: usize,
+        processing_rate: usize,
+    ) -> Result<Box<dyn AudioDecoder>, String> {
+        Ok(match self {
+            CodecKind::Opus => Box::new(OpusCodecDecoder {
+                decoder: Decoder::new(
+                    self.sample_rate(processing_rate),
+                    if channels == 2 {
+                        Channels::Stereo
+                    } else {
+                        Channels::Mono
+                    },
+                )
+                .map_err(|e| e.to_string())?,
+                channels,
+            }),
+            CodecKind::L16 => Box::new(L16Decoder),
+            CodecKind::Pcmu => Box::new(G711Decoder(ulaw_to_linear)),
+            CodecKind::Pcma => Box::new(G711Decoder(alaw_to_linear)),
+        })
+    }
+}
+
+impl Display for CodecKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecKind::Opus => f.write_str("Opus"),
+            CodecKind::L16 => f.write_str("PCM (L16)"),
+            CodecKind::Pcmu => f.write_str("G.711 μ-law"),
+            CodecKind::Pcma => f.write_str("G.711 A-law"),
+        }
+    }
+}
+
+struct OpusCodecEncoder {
+    encoder: OpusEncoder,
+    settings: OpusSettingsHandle,
+    // Version of the settings last applied
+    version: u64,
+    channels: usize,
+}
+
+impl AudioEncoder for OpusCodecEncoder {
+    fn frame_size(&self) -> usize {
+        self.encoder.frame_size() * self.channels
+    }
+
+    fn refresh(&mut self) {
+        let version: u64 = self.settings.version();
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+        let settings: OpusSettings = self.settings.get();
+        if settings == self.encoder.settings() {
+            return;
+        }
+        match self.encoder.update(settings) {
+            Ok(()) => {
+                info!(target: TRACING_TARGET, "Applied Opus encoder settings {settings:?}")
+            }
+            Err(e) => {
+                error!(target: TRACING_TARGET, "Failed to apply Opus encoder settings: {e}");
+                self.settings.set(self.encoder.settings());
+            }
+        }
+    }
+
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, String> {
+        self.encoder.encode_float(input, output)
+    }
+}
+
+struct OpusCodecDecoder {
+    decoder: Decoder,
+    channels: usize,
+}
+
+impl AudioDecoder for OpusCodecDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, String> {
+        self.decoder
+            .decode_float(input, output, false)
+            .map(|decoded| decoded * self.channels)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Writes every sample into its own `sample_size` bytes of the output
+struct PcmEncoder {
+    frame_size: usize,
+    sample_size: usize,
+    encode: fn(f32, &mut [u8]),
+}
+
+impl AudioEncoder for PcmEncoder {
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, String> {
+        let encoded: usize = input.len() * self.sample_size;
+        if encoded > output.len() {
+            return Err(String::from("Encoded frame doesn't fit the output buffer"));
+        }
+        for (sample, bytes) in input.iter().zip(output.chunks_exact_mut(self.sample_size)) {
+            (self.encode)(*sample, bytes);
+        }
+        Ok(encoded)
+    }
+}
+
+struct L16Decoder;
+
+impl AudioDecoder for L16Decoder {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, String> {
+        if input.is_empty() {
+            output.fill(Sample::EQUILIBRIUM);
+            return Ok(output.len());
+        }
+        let decoded: usize = input.len() / 2;
+        if decoded > output.len() {
+            return Err(String::from("Decoded frame doesn't fit the output buffer"));
+        }
+        for (sample, bytes) in output.iter_mut().zip(input.chunks_exact(2)) {
+            *sample = i16::from_be_bytes([bytes[0], bytes[1]]).to_sample::<f32>();
+        }
+        Ok(decoded)
+    }
+}
+
+struct G711Decoder(fn(u8) -> i16);
+
+impl AudioDecoder for G711Decoder {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, String> {
+        if input.is_empty() {
+            output.fill(Sample::EQUILIBRIUM);
+            return Ok(output.len());
+        }
+        if input.len() > output.len() {
+            return Err(String::from("Decoded frame doesn't fit the output buffer"));
+        }
+        for (sample, byte) in output.iter_mut().zip(input) {
+            *sample = (self.0)(*byte).to_sample::<f32>();
+        }
+        Ok(input.len())
+    }
+}
+
+fn f32_to_i16(sample: f32) -> i16 {
+    sample.clamp(-1.0, 1.0).to_sample::<i16>()
+}
+
+// G.711 conversions follow the reference implementation from Sun Microsystems
+const ULAW_BIAS: i32 = 0x84;
+const ULAW_CLIP: i32 = 32635;
+const ALAW_SEGMENT_ENDS: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    let mut pcm: i32 = sample as i32;
+    let sign: i32 = if pcm < 0 {
+        pcm = -pcm;
+        0x80
+    } else {
+        0
+    };
+    pcm = pcm.min(ULAW_CLIP) + ULAW_BIAS;
+    let exponent: i32 = (31 - (pcm as u32).leading_zeros() as i32 - 7).clamp(0, 7);
+    let mantissa: i32 = (pcm >> (exponent + 3)) & 0x0F;
+    !((sign | (exponent << 4) | mantissa) as u8)
+}
+
+fn ulaw_to_linear(value: u8) -> i16 {
+    let value: i32 = !value as i32;
+    let exponent: i32 = (value >> 4) & 0x07;
+    let mantissa: i32 = value & 0x0F;
+    let magnitude: i32 = (((mantissa << 3) + ULAW_BIAS) << exponent) - ULAW_BIAS;
+    if value & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let mut pcm: i32 = sample as i32 >> 3;
+    let mask: i32 = if pcm >= 0 {
+        0xD5
+    } else {
+        pcm = -pcm - 1;
+        0x55
+    };
+    match ALAW_SEGMENT_ENDS.iter().position(|end| pcm <= *end) {
+        Some(segment) => {
+            let shift: i32 = if segment < 2 { 1 } else { segment as i32 };
+            ((((segment as i32) << 4) | ((pcm >> shift) & 0x0F)) ^ mask) as u8
+        }
+        None => (0x7F ^ mask) as u8,
+    }
+}
+
+fn alaw_to_linear(value: u8) -> i16 {
+    let value: i32 = (value ^ 0x55) as i32;
+    let segment: i32 = (value & 0x70) >> 4;
+    let mut magnitude: i32 = (value & 0x0F) << 4;
+    magnitude = match segment {
+        0 => magnitude + 8,
+        1 => magnitude + 0x108,
+        _ => (magnitude + 0x108) << (segment - 1),
+    };
+    if value & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::audio::AUDIO_HEADER_SIZE;
+
+    // Largest UDP payload that fits a 1500 byte Ethernet MTU behind IPv6 and UDP headers
+    const MTU_PAYLOAD: usize = 1452;
+
+    fn ramp(length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|x| (x as f32 / length as f32) * 1.8 - 0.9)
+            .collect()
+    }
+
+    fn round_trip(codec: CodecKind) -> (usize, f32) {
+        let mut encoder: Box<dyn AudioEncoder> = codec
+            .create_encoder(1, 48000, OpusSettingsHandle::default())
+            .unwrap();
+        let mut decoder: Box<dyn AudioDecoder> = codec.create_decoder(1, 48000).unwrap();
+        let input: Vec<f32> = ramp(encoder.frame_size());
+        let mut packet: Vec<u8> = vec![0; 4000];
+        let encoded: usize = encoder.encode(&input, &mut packet).unwrap();
+        let mut output: Vec<f32> = vec![0.0; 5760];
+        let decoded: usize = decoder.decode(&packet[..encoded], &mut output).unwrap();
+        assert_eq!(decoded, input.len());
+        let error: f32 = input
+            .iter()
+            .zip(&output)
+            .map(|(x, y)| (x - y).abs())
+            .fold(0.0, f32::max);
+        (encoded, error)
+    }
+
+    #[test]
+    fn l16_fits_the_mtu() {
+        let (encoded, error) = round_trip(CodecKind::L16);
+        assert_eq!(encoded, 960);
+        assert!(encoded + AUDIO_HEADER_SIZE < MTU_PAYLOAD);
+        assert!(error < 1e-4);
+    }
+
+    #[test]
+    fn g711_round_trip() {
+        for codec in [CodecKind::Pcmu, CodecKind::Pcma] {
+            let (encoded, error) = round_trip(codec);
+            assert_eq!(encoded, 160);
+            // Companding keeps a few percent of the amplitude at the loudest segment
+            assert!(error < 0.03, "{codec} error {error}");
+        }
+    }
+
+    #[test]
+    fn g711_keeps_sign_and_silence() {
+        for x in [-32000i16, -1000, -8, 0, 8, 1000, 32000] {
+            let ulaw: i16 = ulaw_to_linear(linear_to_ulaw(x));
+            let alaw: i16 = alaw_to_linear(linear_to_alaw(x));
+            assert!((ulaw as i32 - x as i32).abs() <= (x as i32).abs() / 16 + 8);
+            assert!((alaw as i32 - x as i32).abs() <= (x as i32).abs() / 16 + 16);
+        }
+    }
+
+    #[test]
+    fn lost_packets_are_concealed_over_the_whole_output() {
+        for codec in CodecKind::ALL {
+            let mut decoder: Box<dyn AudioDecoder> = codec.create_decoder(1, 48000).unwrap();
+            let mut output: Vec<f32> = vec![1.0; 960];
+            assert_eq!(decoder.decode(&[], &mut output).unwrap(), 960, "{codec}");
+            // Nothing was decoded yet, so even Opus conceals with silence
+            assert!(output.iter().all(|x| x.abs() < 1e-3), "{codec}");
+        }
+    }
+
+    #[test]
+    fn strict_preferences_offer_one_codec() {
+        assert_eq!(
+            CodecKind::preferences(CodecKind::Pcma, false),
+            vec![CodecKind::Pcma]
+        );
+        assert_eq!(CodecKind::preferences(CodecKind::Pcma, true).len(), 4);
+        assert_eq!(
+            CodecKind::preferences(CodecKind::Pcma, true)[0],
+            CodecKind::Pcma
+        );
+    }
+}
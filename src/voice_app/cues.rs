@@ -0,0 +1,229 @@
+use std::{
+    f32::consts::TAU,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const CUE_AMPLITUDE: f32 = 0.3;
+const CUE_FADE_SECONDS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cue {
+    Connect,
+    Disconnect,
+    PeerJoined,
+    MuteOn,
+    MuteOff,
+    ConnectionLost,
+    ConnectFailed,
+}
+
+impl Cue {
+    pub const ALL: [Cue; 7] = [
+        Cue::Connect,
+        Cue::Disconnect,
+        Cue::PeerJoined,
+        Cue::MuteOn,
+        Cue::MuteOff,
+        Cue::ConnectionLost,
+        Cue::ConnectFailed,
+    ];
+
+    // Notes as (frequency, milliseconds), a zero frequency is a rest
+    fn notes(&self) -> &'static [(f32, u32)] {
+        match self {
+            Cue::Connect => &[(523.25, 100), (783.99, 140)],
+            Cue::Disconnect => &[(783.99, 100), (523.25, 140)],
+            Cue::PeerJoined => &[(659.25, 80), (783.99, 80), (1046.5, 120)],
+            Cue::MuteOn => &[(659.25, 60), (440.0, 90)],
+            Cue::MuteOff => &[(440.0, 60), (659.25, 90)],
+            Cue::ConnectionLost => &[
+                (329.63, 100),
+                (0.0, 60),
+                (329.63, 100),
+                (0.0, 60),
+                (329.63, 100),
+            ],
+            Cue::ConnectFailed => &[(392.0, 120), (261.63, 120), (196.0, 220)],
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.notes().iter().map(|x| x.1 as u64).sum())
+    }
+
+    pub fn synthesize(&self, sample_rate: usize) -> Vec<f32> {
+        let fade: f32 = CUE_FADE_SECONDS * sample_rate as f32;
+        let mut samples: Vec<f32> = Vec::new();
+        for (frequency, milliseconds) in self.notes() {
+            let length: usize = sample_rate * *milliseconds as usize / 1000;
+            samples.extend((0..length).map(|i| {
+                let envelope: f32 = (i as f32 / fade).min((length - i) as f32 / fade).min(1.0);
+                (TAU * frequency * i as f32 / sample_rate as f32).sin() * envelope * CUE_AMPLITUDE
+            }));
+        }
+        samples
+    }
+
+    fn index(&self) -> usize {
+        Cue::ALL.iter().position(|x| x == self).unwrap_or(0)
+    }
+}
+
+impl Display for Cue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cue::Connect => f.write_str("Connect"),
+            Cue::Disconnect => f.write_str("Disconnect"),
+            Cue::PeerJoined => f.write_str("Peer joined"),
+            Cue::MuteOn => f.write_str("Mute on"),
+            Cue::MuteOff => f.write_str("Mute off"),
+            Cue::ConnectionLost => f.write_str("Connection lost"),
+            Cue::ConnectFailed => f.write_str("Connection failed"),
+        }
+    }
+}
+
+struct Cues {
+    // Synthesized once the rate of the mix they go into is known
+    pending: Vec<Cue>,
+    playing: Vec<(Vec<f32>, usize)>,
+    // Mixes currently taking cues, nothing queues up while there are none
+    outputs: usize,
+    enabled: [bool; Cue::ALL.len()],
+    volume: f32,
+}
+
+impl Default for Cues {
+    fn default() -> Self {
+        Cues {
+            pending: Vec::new(),
+            playing: Vec::new(),
+            outputs: 0,
+            enabled: [true; Cue::ALL.len()],
+            volume: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CuePlayer(Arc<Mutex<Cues>>);
+
+impl CuePlayer {
+    pub fn play(&self, cue: Cue) {
+        let mut cues = self.0.lock().unwrap();
+        if cues.outputs > 0 && cues.enabled[cue.index()] {
+            cues.pending.push(cue);
+        }
+    }
+
+    pub fn attach(&self) {
+        self.0.lock().unwrap().outputs += 1;
+    }
+
+    // Cues still queued or playing are dropped with the last output
+    pub fn detach(&self) {
+        let mut cues = self.0.lock().unwrap();
+        cues.outputs = cues.outputs.saturating_sub(1);
+        if cues.outputs == 0 {
+            cues.pending.clear();
+            cues.playing.clear();
+        }
+    }
+
+    // For playing a cue on its own, at the cue volume. Nothing when the cue is turned off
+    pub fn render(&self, cue: Cue, sample_rate: usize) -> Option<Vec<f32>> {
+        let cues = self.0.lock().unwrap();
+        cues.enabled[cue.index()].then(|| {
+            cue.synthesize(sample_rate)
+                .iter()
+                .map(|x| x * cues.volume)
+                .collect()
+        })
+    }
+
+    pub fn is_enabled(&self, cue: Cue) -> bool {
+        self.0.lock().unwrap().enabled[cue.index()]
+    }
+
+    pub fn set_enabled(&self, cue: Cue, enabled: bool) {
+        self.0.lock().unwrap().enabled[cue.index()] = enabled;
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.0.lock().unwrap().volume = volume;
+    }
+
+    // `output` is mono at `sample_rate`
+    pub fn mix_into(&self, output: &mut [f32], sample_rate: usize) {
+        let mut cues = self.0.lock().unwrap();
+        let pending: Vec<Cue> = std::mem::take(&mut cues.pending);
+        cues.playing
+            .extend(pending.iter().map(|cue| (cue.synthesize(sample_rate), 0)));
+        let volume: f32 = cues.volume;
+        for (samples, position) in cues.playing.iter_mut() {
+            for (sample, cue_sample) in output.iter_mut().zip(samples[*position..].iter()) {
+                *sample += cue_sample * volume;
+            }
+            *position = (*position + output.len()).min(samples.len());
+        }
+        cues.playing
+            .retain(|(samples, position)| *position < samples.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    fn mix(cue_player: &CuePlayer) -> Vec<f32> {
+        let mut output: Vec<f32> = vec![0.0; SAMPLE_RATE];
+        cue_player.mix_into(&mut output, SAMPLE_RATE);
+        output
+    }
+
+    #[test]
+    fn cues_without_an_output_are_dropped() {
+        let cue_player: CuePlayer = CuePlayer::default();
+        cue_player.play(Cue::MuteOn);
+        cue_player.attach();
+        assert!(mix(&cue_player).iter().all(|x| *x == 0.0));
+
+        cue_player.play(Cue::MuteOn);
+        cue_player.detach();
+        cue_player.attach();
+        assert!(mix(&cue_player).iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn cues_play_once_at_their_volume() {
+        let cue_player: CuePlayer = CuePlayer::default();
+        cue_player.attach();
+        cue_player.set_volume(0.5);
+        cue_player.play(Cue::Connect);
+        let expected: Vec<f32> = Cue::Connect.synthesize(SAMPLE_RATE);
+        let output: Vec<f32> = mix(&cue_player);
+        assert!(
+            output
+                .iter()
+                .zip(expected.iter())
+                .all(|(x, y)| (x - y * 0.5).abs() < 1e-6)
+        );
+        assert!(output[expected.len()..].iter().all(|x| *x == 0.0));
+        assert!(mix(&cue_player).iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn disabled_cues_are_silent() {
+        let cue_player: CuePlayer = CuePlayer::default();
+        cue_player.attach();
+        cue_player.set_enabled(Cue::ConnectFailed, false);
+        cue_player.play(Cue::ConnectFailed);
+        assert!(mix(&cue_player).iter().all(|x| *x == 0.0));
+        assert!(cue_player.render(Cue::ConnectFailed, SAMPLE_RATE).is_none());
+        assert!(cue_player.render(Cue::Connect, SAMPLE_RATE).is_some());
+    }
+}
@@ -0,0 +1,218 @@
+use cpal::Sample;
+use nnnoiseless::DenoiseState;
+use rubato::{FftFixedIn, Resampler};
+
+use crate::voice_app::{
+    analyzer::Analyzer,
+    transient::{LOOKAHEAD, TransientSuppressor},
+};
+
+// RNNoise only takes 10 ms frames at 48 kHz, lower processing rates are resampled around it
+pub const DENOISE_SAMPLE_RATE: usize = 48000;
+const DENOISE_FRAMES_PER_SECOND: usize = DENOISE_SAMPLE_RATE / DenoiseState::FRAME_SIZE;
+
+fn create_denoise_resamplers(
+    channels: usize,
+    sample_rate: usize,
+) -> Option<(FftFixedIn<f32>, FftFixedIn<f32>)> {
+    (sample_rate != DENOISE_SAMPLE_RATE).then(|| {
+        (
+            FftFixedIn::<f32>::new(
+                sample_rate,
+                DENOISE_SAMPLE_RATE,
+                sample_rate / DENOISE_FRAMES_PER_SECOND,
+                1,
+                channels,
+            )
+            .expect("Failed to create denoise upsampler"),
+            FftFixedIn::<f32>::new(
+                DENOISE_SAMPLE_RATE,
+                sample_rate,
+                DenoiseState::FRAME_SIZE,
+                1,
+                channels,
+            )
+            .expect("Failed to create denoise downsampler"),
+        )
+    })
+}
+
+// Denoising drops its first frame, which moves the audio earlier, and resampling around RNNoise and the
+// transient suppressor's lookahead delay it. In seconds
+pub fn denoise_offset(sample_rate: usize) -> f64 {
+    let resampler_delay: f64 = create_denoise_resamplers(1, sample_rate)
+        .map(|(upsampler, downsampler)| {
+            upsampler.output_delay() as f64 / DENOISE_SAMPLE_RATE as f64
+                + downsampler.output_delay() as f64 / sample_rate as f64
+        })
+        .unwrap_or(0.0);
+    (DENOISE_SAMPLE_RATE / DENOISE_FRAMES_PER_SECOND - LOOKAHEAD) as f64
+        / DENOISE_SAMPLE_RATE as f64
+        - resampler_delay
+}
+
+// Noise suppression and the transient suppressor behind it, one 10 ms frame at a time.
+// The analyzer and the transient suppressor see the 48 kHz audio
+pub struct DenoiseStage {
+    channels: usize,
+    resamplers: Option<(FftFixedIn<f32>, FftFixedIn<f32>)>,
+    resample_buffer: Vec<Vec<f32>>,
+    denoise: Vec<Box<DenoiseState<'static>>>,
+    denoise_buffer: Vec<f32>,
+    denoise_process_buffer: Vec<f32>,
+    deinterleaved_buffer: Vec<Vec<f32>>,
+    suppressors: Vec<TransientSuppressor>,
+    first: bool,
+}
+
+impl DenoiseStage {
+    pub fn new(channels: usize, sample_rate: usize) -> Self {
+        DenoiseStage {
+            channels,
+            resamplers: create_denoise_resamplers(channels, sample_rate),
+            resample_buffer: vec![
+                vec![Sample::EQUILIBRIUM; sample_rate / DENOISE_FRAMES_PER_SECOND];
+                channels
+            ],
+            denoise: vec![DenoiseState::new(); channels],
+            denoise_buffer: vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE * channels],
+            denoise_process_buffer: vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE],
+            deinterleaved_buffer: vec![
+                vec![Sample::EQUILIBRIUM; DenoiseState::FRAME_SIZE];
+                channels
+            ],
+            suppressors: (0..channels)
+                .map(|_| TransientSuppressor::default())
+                .collect(),
+            first: true,
+        }
+    }
+
+    // Interleaved samples per frame at the processing rate
+    pub fn frame_size(&self) -> usize {
+        self.resample_buffer[0].len() * self.channels
+    }
+
+    pub fn transients(&self) -> usize {
+        self.suppressors.iter().map(|x| x.detected()).sum()
+    }
+
+    // Denoises `frame` in place. RNNoise primes itself with the first frame, which is to be dropped
+    // and returns false
+    pub fn process(
+        &mut self,
+        frame: &mut [f32],
+        transient_suppression: bool,
+        analyzer: Option<&Analyzer>,
+    ) -> bool {
+        let channels: usize = self.channels;
+        // RNNoise works on 16 bit sample values
+        if let Some((upsampler, _)) = self.resamplers.as_mut() {
+            for (i, sample) in frame.iter().enumerate() {
+                self.resample_buffer[i % channels][i / channels] = sample * i16::MAX as f32;
+            }
+            upsampler
+                .process_into_buffer(&self.resample_buffer, &mut self.deinterleaved_buffer, None)
+                .expect("Failed to resample");
+        } else {
+            for (i, sample) in frame.iter().enumerate() {
+                self.deinterleaved_buffer[i % channels][i / channels] = sample * i16::MAX as f32;
+            }
+        }
+        if let Some(analyzer) = analyzer {
+            self.interleave_denoised(1.0 / i16::MAX as f32);
+            analyzer.raw.push(&self.denoise_buffer);
+        }
+
+        for i in 0..channels {
+            let vad: f32 = self.denoise[i].process_frame(
+                &mut self.denoise_process_buffer,
+                &self.deinterleaved_buffer[i],
+            );
+            let denoised: &mut Vec<f32> = &mut self.deinterleaved_buffer[i];
+            denoised.copy_from_slice(&self.denoise_process_buffer);
+            for sample in denoised.iter_mut() {
+                *sample /= i16::MAX as f32;
+            }
+            // Clicks survive RNNoise as they are too short to count as noise
+            self.suppressors[i].process(denoised, Some(vad), transient_suppression);
+        }
+
+        self.interleave_denoised(1.0);
+        if let Some((_, downsampler)) = self.resamplers.as_mut() {
+            downsampler
+                .process_into_buffer(&self.deinterleaved_buffer, &mut self.resample_buffer, None)
+                .expect("Failed to resample");
+            for (i, sample) in frame.iter_mut().enumerate() {
+                *sample = self.resample_buffer[i % channels][i / channels];
+            }
+        } else {
+            frame.copy_from_slice(&self.denoise_buffer);
+        }
+
+        if self.first {
+            self.first = false;
+            return false;
+        }
+        if let Some(analyzer) = analyzer {
+            analyzer.processed.push(&self.denoise_buffer);
+        }
+        true
+    }
+
+    fn interleave_denoised(&mut self, scale: f32) {
+        for (i, sample) in self.denoise_buffer.iter_mut().enumerate() {
+            *sample = self.deinterleaved_buffer[i % self.channels][i / self.channels] * scale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::voice_app::file_source::load_file;
+
+    fn fixture(name: &str) -> Vec<f32> {
+        load_file(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join(name),
+            DENOISE_SAMPLE_RATE,
+        )
+        .expect("Failed to load fixture")
+    }
+
+    // The denoised samples without the suppressor's lookahead, and the transients found
+    fn denoise(samples: &[f32], transient_suppression: bool) -> (Vec<f32>, usize) {
+        let mut stage: DenoiseStage = DenoiseStage::new(1, DENOISE_SAMPLE_RATE);
+        let mut output: Vec<f32> = samples.to_vec();
+        for frame in output.chunks_exact_mut(stage.frame_size()) {
+            stage.process(frame, transient_suppression, None);
+        }
+        output.drain(..LOOKAHEAD);
+        (output, stage.transients())
+    }
+
+    #[test]
+    fn keyboard_clicks_are_attenuated() {
+        let keyboard: Vec<f32> = fixture("keyboard.wav");
+        let (denoised, _) = denoise(&keyboard, false);
+        let (suppressed, transients) = denoise(&keyboard, true);
+        let energy = |samples: &[f32]| samples.iter().map(|x| x * x).sum::<f32>();
+        assert!(transients >= 20, "{transients}");
+        // At least 10 dB below what RNNoise lets through
+        assert!(energy(&suppressed) < energy(&denoised) * 0.1);
+    }
+
+    #[test]
+    fn speech_passes_the_transient_suppressor_unchanged() {
+        let speech: Vec<f32> = fixture("speech.wav");
+        let (denoised, _) = denoise(&speech, false);
+        let (suppressed, transients) = denoise(&speech, true);
+        assert_eq!(transients, 0);
+        assert_eq!(suppressed, denoised);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    f32::consts::{PI, TAU},
+    fmt::Display,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use cpal::Sample;
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+
+// Sizes at 48 kHz, scaled to the processing rate. Pitch shifting grains of 32 ms
+const GRAIN_SIZE: usize = 1536;
+// Four times overlap of 1024 sample frames, the hop is also the 187.5 Hz pitch of the robot voice
+const STFT_HOP: usize = 256;
+// Sum of the squared Hann windows at four times overlap
+const STFT_OVERLAP_GAIN: f32 = 1.5;
+// Half width of the smoothing that separates the spectral envelope from the harmonics
+const ENVELOPE_HALF_WIDTH: usize = 6;
+const ENVELOPE_MAX_GAIN: f32 = 8.0;
+const RADIO_LOW_CUT: f32 = 300.0;
+const RADIO_HIGH_CUT: f32 = 3400.0;
+const RADIO_DRIVE: f32 = 2.0;
+const RADIO_LEVEL: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EffectPreset {
+    #[default]
+    Off,
+    PitchFormant,
+    Robot,
+    Radio,
+}
+
+impl EffectPreset {
+    pub const ALL: [EffectPreset; 4] = [
+        EffectPreset::Off,
+        EffectPreset::PitchFormant,
+        EffectPreset::Robot,
+        EffectPreset::Radio,
+    ];
+}
+
+impl Display for EffectPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EffectPreset::Off => f.write_str("No effect"),
+            EffectPreset::PitchFormant => f.write_str("Pitch / formant"),
+            EffectPreset::Robot => f.write_str("Robot"),
+            EffectPreset::Radio => f.write_str("Radio"),
+        }
+    }
+}
+
+// Pitch and formant shifts are in semitones
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EffectSettings {
+    pub preset: EffectPreset,
+    pub pitch: f32,
+    pub formant: f32,
+}
+
+#[derive(Default)]
+struct SharedSettings {
+    settings: Mutex<EffectSettings>,
+    version: AtomicU64,
+}
+
+// The processing threads poll the version every frame and only lock when the settings changed
+#[derive(Clone, Default)]
+pub struct EffectsHandle(Arc<SharedSettings>);
+
+impl EffectsHandle {
+    pub fn get(&self) -> EffectSettings {
+        *self.0.settings.lock().unwrap()
+    }
+
+    pub fn set(&self, settings: EffectSettings) {
+        *self.0.settings.lock().unwrap() = settings;
+        self.0.version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn version(&self) -> u64 {
+        self.0.version.load(Ordering::Acquire)
+    }
+
+    pub fn cached(&self) -> CachedEffects {
+        CachedEffects {
+            version: self.version(),
+            settings: self.get(),
+            handle: self.clone(),
+        }
+    }
+}
+
+// Copy of the settings owned by one processing thread
+pub struct CachedEffects {
+    handle: EffectsHandle,
+    // Version of the settings last copied
+    version: u64,
+    settings: EffectSettings,
+}
+
+impl CachedEffects {
+    pub fn get(&mut self) -> EffectSettings {
+        let version: u64 = self.handle.version();
+        if version != self.version {
+            self.version = version;
+            self.settings = self.handle.get();
+        }
+        self.settings
+    }
+}
+
+fn semitones_to_ratio(semitones: f32) -> f32 {
+    2.0_f32.powf(semitones / 12.0)
+}
+
+// Two crossfaded read taps sweeping through a delay line, shifts formants along with the pitch
+struct PitchShifter {
+    grain_size: usize,
+    delay_line: Vec<f32>,
+    write: usize,
+    phase: f32,
+}
+
+impl PitchShifter {
+    fn new(grain_size: usize) -> Self {
+        PitchShifter {
+            grain_size,
+            delay_line: vec![Sample::EQUILIBRIUM; grain_size * 2],
+            write: 0,
+            phase: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay_line.fill(Sample::EQUILIBRIUM);
+        self.write = 0;
+        self.phase = 0.0;
+    }
+
+    fn read(&self, delay: f32) -> f32 {
+        let position: f32 = self.write as f32 - delay + self.delay_line.len() as f32;
+        let index: usize = position.floor() as usize;
+        let fraction: f32 = position - position.floor();
+        let a: f32 = self.delay_line[index % self.delay_line.len()];
+        let b: f32 = self.delay_line[(index + 1) % self.delay_line.len()];
+        a + (b - a) * fraction
+    }
+
+    fn process(&mut self, ratio: f32, buffer: &mut [f32]) {
+        let step: f32 = (1.0 - ratio) / self.grain_size as f32;
+        for sample in buffer.iter_mut() {
+            self.delay_line[self.write] = *sample;
+            // Unshifted audio skips the taps, which would otherwise comb filter it
+            if ratio != 1.0 {
+                let second: f32 = (self.phase + 0.5).fract();
+                *sample = self.read(self.phase * self.grain_size as f32)
+                    * (PI * self.phase).sin().powi(2)
+                    + self.read(second * self.grain_size as f32) * (PI * second).sin().powi(2);
+                self.phase = (self.phase + step).rem_euclid(1.0);
+            }
+            self.write = (self.write + 1) % self.delay_line.len();
+        }
+    }
+}
+
+enum Spectral {
+    // Spectral envelope stretched by the ratio, harmonics stay in place
+    Formant(f32),
+    // Magnitudes only, every frame restarts the waveform at the hop rate
+    Robot,
+}
+
+struct SpectralProcessor {
+    size: usize,
+    hop: usize,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    overlap: Vec<f32>,
+    ready: Vec<f32>,
+    position: usize,
+    spectrum: Vec<Complex<f32>>,
+    envelope: Vec<f32>,
+}
+
+impl SpectralProcessor {
+    fn new(hop: usize) -> Self {
+        // Kept at four times overlap whatever the rate
+        let size: usize = hop * 4;
+        let mut planner: FftPlanner<f32> = FftPlanner::new();
+        SpectralProcessor {
+            size,
+            hop,
+            forward: planner.plan_fft_forward(size),
+            inverse: planner.plan_fft_inverse(size),
+            window: (0..size)
+                .map(|i| 0.5 - 0.5 * (TAU * i as f32 / size as f32).cos())
+                .collect(),
+            input: vec![Sample::EQUILIBRIUM; size],
+            overlap: vec![Sample::EQUILIBRIUM; size],
+            ready: vec![Sample::EQUILIBRIUM; hop],
+            position: 0,
+            spectrum: vec![Complex::new(0.0, 0.0); size],
+            envelope: vec![0.0; size / 2 + 1],
+        }
+    }
+
+    // The spectrum and envelope are rewritten every frame and need no clearing
+    fn reset(&mut self) {
+        self.input.fill(Sample::EQUILIBRIUM);
+        self.overlap.fill(Sample::EQUILIBRIUM);
+        self.ready.fill(Sample::EQUILIBRIUM);
+        self.position = 0;
+    }
+
+    fn process(&mut self, spectral: Spectral, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            self.input[self.size - self.hop + self.position] = *sample;
+            *sample = self.ready[self.position];
+            self.position += 1;
+            if self.position == self.hop {
+                self.process_frame(&spectral);
+                self.position = 0;
+            }
+        }
+    }
+
+    fn process_frame(&mut self, spectral: &Spectral) {
+        for ((bin, sample), window) in self
+            .spectrum
+            .iter_mut()
+            .zip(self.input.iter())
+            .zip(self.window.iter())
+        {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.forward.process(&mut self.spectrum);
+
+        let half: usize = self.size / 2;
+        match spectral {
+            Spectral::Formant(ratio) => {
+                for (k, envelope) in self.envelope.iter_mut().enumerate() {
+                    let bins = &self.spectrum[k.saturating_sub(ENVELOPE_HALF_WIDTH)
+                        ..(k + ENVELOPE_HALF_WIDTH + 1).min(half + 1)];
+                    *envelope =
+                        bins.iter().map(|x| x.norm()).sum::<f32>() / bins.len() as f32 + 1e-9;
+                }
+                for k in 0..=half {
+                    let source: f32 = k as f32 / ratio;
+                    let index: usize = source.floor() as usize;
+                    let shifted: f32 = if index < half {
+                        let fraction: f32 = source - index as f32;
+                        self.envelope[index]
+                            + (self.envelope[index + 1] - self.envelope[index]) * fraction
+                    } else {
+                        0.0
+                    };
+                    self.spectrum[k] *= (shifted / self.envelope[k]).min(ENVELOPE_MAX_GAIN);
+                }
+            }
+            Spectral::Robot => {
+                // Alternating signs center the pulse in the frame where the synthesis window is open
+                for k in 0..=half {
+                    let sign: f32 = if k % 2 == 0 { 1.0 } else { -1.0 };
+                    self.spectrum[k] = Complex::new(self.spectrum[k].norm() * sign, 0.0);
+                }
+            }
+        }
+        for k in 1..half {
+            self.spectrum[self.size - k] = self.spectrum[k].conj();
+        }
+        self.inverse.process(&mut self.spectrum);
+
+        let scale: f32 = 1.0 / (self.size as f32 * STFT_OVERLAP_GAIN);
+        for ((sample, bin), window) in self
+            .overlap
+            .iter_mut()
+            .zip(self.spectrum.iter())
+            .zip(self.window.iter())
+        {
+            *sample += bin.re * window * scale;
+        }
+        self.ready.copy_from_slice(&self.overlap[..self.hop]);
+        self.overlap.copy_within(self.hop.., 0);
+        self.overlap[self.size - self.hop..].fill(Sample::EQUILIBRIUM);
+        self.input.copy_within(self.hop.., 0);
+    }
+}
+
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    // Butterworth sections from the Audio EQ Cookbook
+    fn new(frequency: f32, high_pass: bool, sample_rate: usize) -> Self {
+        let w0: f32 = TAU * frequency / sample_rate as f32;
+        let alpha: f32 = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos: f32 = w0.cos();
+        let a0: f32 = 1.0 + alpha;
+        let (b0, b1) = if high_pass {
+            ((1.0 + cos) / 2.0, -(1.0 + cos))
+        } else {
+            ((1.0 - cos) / 2.0, 1.0 - cos)
+        };
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.x1 = 0.0;
+        self.x2 = 0.0;
+        self.y1 = 0.0;
+        self.y2 = 0.0;
+    }
+
+    fn process(&mut self, buffer: &mut [f32]) {
+        for sample in buffer.iter_mut() {
+            let y: f32 = self.b0 * *sample + self.b1 * self.x1 + self.b2 * self.x2
+                - self.a1 * self.y1
+                - self.a2 * self.y2;
+            self.x2 = self.x1;
+            self.x1 = *sample;
+            self.y2 = self.y1;
+            self.y1 = y;
+            *sample = y;
+        }
+    }
+}
+
+// Every preset's processors are built up front, so switching on the audio thread never allocates
+pub struct VoiceEffects {
+    preset: EffectPreset,
+    pitch_shifter: PitchShifter,
+    spectral: SpectralProcessor,
+    low_cut: Biquad,
+    high_cut: Biquad,
+}
+
+impl VoiceEffects {
+    pub fn new(sample_rate: usize) -> Self {
+        VoiceEffects {
+            preset: EffectPreset::Off,
+            pitch_shifter: PitchShifter::new(GRAIN_SIZE * sample_rate / 48000),
+            spectral: SpectralProcessor::new(STFT_HOP * sample_rate / 48000),
+            low_cut: Biquad::new(RADIO_LOW_CUT, true, sample_rate),
+            high_cut: Biquad::new(RADIO_HIGH_CUT, false, sample_rate),
+        }
+    }
+
+    pub fn process(&mut self, settings: EffectSettings, buffer: &mut [f32]) {
+        // Switching presets starts from clean state so no stale audio leaks through
+        if settings.preset != self.preset {
+            self.preset = settings.preset;
+            self.pitch_shifter.reset();
+            self.spectral.reset();
+            self.low_cut.reset();
+            self.high_cut.reset();
+        }
+        match settings.preset {
+            EffectPreset::Off => {}
+            EffectPreset::PitchFormant => {
+                let pitch: f32 = semitones_to_ratio(settings.pitch);
+                self.pitch_shifter.process(pitch, buffer);
+                // The pitch shifter moved the formants too, so the envelope is moved back by the same ratio
+                let formant: f32 = semitones_to_ratio(settings.formant) / pitch;
+                self.spectral.process(Spectral::Formant(formant), buffer);
+            }
+            EffectPreset::Robot => self.spectral.process(Spectral::Robot, buffer),
+            EffectPreset::Radio => {
+                self.low_cut.process(buffer);
+                self.high_cut.process(buffer);
+                for sample in buffer.iter_mut() {
+                    *sample = (*sample * RADIO_DRIVE).tanh() * RADIO_LEVEL;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::test_signals::noise;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    fn tone(frequency: f32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| 0.5 * (TAU * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    // In Hz, from the upward zero crossings
+    fn frequency(samples: &[f32]) -> f32 {
+        let crossings: usize = samples
+            .windows(2)
+            .filter(|x| x[0] < 0.0 && x[1] >= 0.0)
+            .count();
+        crossings as f32 * SAMPLE_RATE as f32 / samples.len() as f32
+    }
+
+    // In samples, the strongest autocorrelation between 1 kHz and 100 Hz
+    fn period(samples: &[f32]) -> usize {
+        let correlation = |lag: usize| {
+            samples
+                .iter()
+                .zip(samples[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        };
+        (SAMPLE_RATE / 1000..SAMPLE_RATE / 100)
+            .max_by(|a, b| correlation(*a).total_cmp(&correlation(*b)))
+            .unwrap()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    // Processes in 10 ms frames like the effects thread
+    fn process(settings: EffectSettings, input: &[f32]) -> Vec<f32> {
+        let mut effects: VoiceEffects = VoiceEffects::new(SAMPLE_RATE);
+        let mut output: Vec<f32> = input.to_vec();
+        for frame in output.chunks_mut(SAMPLE_RATE / 100) {
+            effects.process(settings, frame);
+        }
+        output
+    }
+
+    fn settings(preset: EffectPreset, pitch: f32, formant: f32) -> EffectSettings {
+        EffectSettings {
+            preset,
+            pitch,
+            formant,
+        }
+    }
+
+    #[test]
+    fn no_effect_passes_audio_through() {
+        let input: Vec<f32> = noise(SAMPLE_RATE, 0.5, 1);
+        assert_eq!(process(EffectSettings::default(), &input), input);
+    }
+
+    #[test]
+    fn unshifted_pitch_and_formant_only_delay_the_audio() {
+        let input: Vec<f32> = tone(440.0, SAMPLE_RATE);
+        let output: Vec<f32> = process(settings(EffectPreset::PitchFormant, 0.0, 0.0), &input);
+        // One STFT frame of latency
+        let delay: usize = STFT_HOP * 4;
+        for (a, b) in output[delay * 2..].iter().zip(input[delay..].iter()) {
+            assert!((a - b).abs() < 1e-3, "{a} {b}");
+        }
+    }
+
+    #[test]
+    fn pitch_shifts_by_semitones() {
+        let input: Vec<f32> = tone(440.0, SAMPLE_RATE * 2);
+        for (semitones, expected) in [(12.0, 880.0), (-12.0, 220.0), (7.0, 659.3)] {
+            let mut shifter: PitchShifter = PitchShifter::new(GRAIN_SIZE);
+            let mut output: Vec<f32> = input.clone();
+            shifter.process(semitones_to_ratio(semitones), &mut output);
+            let measured: f32 = frequency(&output[SAMPLE_RATE..]);
+            assert!((measured / expected - 1.0).abs() < 0.02, "{measured}");
+        }
+    }
+
+    #[test]
+    fn formant_shifts_keep_the_pitch() {
+        // A voice-like 200 Hz tone, harmonics falling off at 6 dB per octave
+        let input: Vec<f32> = (0..SAMPLE_RATE * 2)
+            .map(|i| {
+                (1..=20)
+                    .map(|k| {
+                        (TAU * 200.0 * k as f32 * i as f32 / SAMPLE_RATE as f32).sin() / k as f32
+                    })
+                    .sum::<f32>()
+                    * 0.2
+            })
+            .collect();
+        for (pitch, formant, expected) in [(0.0, 5.0, 240), (12.0, 0.0, 120), (12.0, -5.0, 120)] {
+            let output: Vec<f32> =
+                process(settings(EffectPreset::PitchFormant, pitch, formant), &input);
+            let measured: usize = period(&output[SAMPLE_RATE..]);
+            // The grains of the pitch shifter wobble by a sample
+            assert!(
+                measured.abs_diff(expected) <= 1,
+                "{pitch} {formant} {measured}"
+            );
+        }
+    }
+
+    #[test]
+    fn robot_repeats_at_the_hop_rate() {
+        let input: Vec<f32> = noise(SAMPLE_RATE, 0.5, 1);
+        let output: Vec<f32> = process(settings(EffectPreset::Robot, 0.0, 0.0), &input);
+        let tail: &[f32] = &output[SAMPLE_RATE / 2..];
+        let correlation = |lag: usize| {
+            tail.iter()
+                .zip(tail[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / tail.iter().map(|x| x * x).sum::<f32>()
+        };
+        assert!(correlation(STFT_HOP) > 0.5, "{}", correlation(STFT_HOP));
+        assert!(
+            correlation(STFT_HOP / 2).abs() < 0.2,
+            "{}",
+            correlation(STFT_HOP / 2)
+        );
+    }
+
+    #[test]
+    fn switching_presets_starts_from_clean_state() {
+        let input: Vec<f32> = noise(SAMPLE_RATE, 0.5, 1);
+        let pitch_formant: EffectSettings = settings(EffectPreset::PitchFormant, 5.0, -3.0);
+        let mut effects: VoiceEffects = VoiceEffects::new(SAMPLE_RATE);
+        let mut output: Vec<f32> = input.clone();
+        let (first, second) = output.split_at_mut(SAMPLE_RATE / 2);
+        for frame in first.chunks_mut(SAMPLE_RATE / 100) {
+            effects.process(pitch_formant, frame);
+        }
+        for frame in second.chunks_mut(SAMPLE_RATE / 100) {
+            effects.process(settings(EffectPreset::Robot, 0.0, 0.0), frame);
+        }
+        let mut again: Vec<f32> = input.clone();
+        for frame in again.chunks_mut(SAMPLE_RATE / 100) {
+            effects.process(pitch_formant, frame);
+        }
+        assert_eq!(again, process(pitch_formant, &input));
+    }
+
+    #[test]
+    fn cached_settings_follow_the_handle() {
+        let handle: EffectsHandle = EffectsHandle::default();
+        let mut cached: CachedEffects = handle.cached();
+        assert_eq!(cached.get(), EffectSettings::default());
+        let robot: EffectSettings = settings(EffectPreset::Robot, 0.0, 0.0);
+        handle.set(robot);
+        assert_eq!(cached.get(), robot);
+    }
+
+    #[test]
+    fn radio_keeps_the_telephone_band() {
+        let radio = |frequency: f32| {
+            let output: Vec<f32> = process(
+                settings(EffectPreset::Radio, 0.0, 0.0),
+                &tone(frequency, SAMPLE_RATE),
+            );
+            rms(&output[SAMPLE_RATE / 2..])
+        };
+        let band: f32 = radio(1000.0);
+        assert!(radio(60.0) < band * 0.1);
+        assert!(radio(12000.0) < band * 0.1);
+        assert!(band * 2.0_f32.sqrt() <= RADIO_LEVEL);
+    }
+}
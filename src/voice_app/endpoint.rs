@@ -0,0 +1,477 @@
+use std::{
+    f32::consts::TAU,
+    fmt::Display,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use cpal::{
+    BufferSize, Device, SampleRate, Stream, StreamConfig,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use tracing::{error, info};
+
+use crate::voice_app::{
+    app_tracing::TRACING_TARGET,
+    channel_map::{ChannelMatrix, InputChannelMap, OutputChannelMap},
+    file_source::load_wav,
+};
+
+pub const VIRTUAL_SAMPLE_RATE: u32 = 48000;
+const VIRTUAL_PERIOD: Duration = Duration::from_millis(10);
+
+// Devices keep the channels picked for them, files and tones always use the default mapping
+#[derive(Clone)]
+pub enum InputEndpoint {
+    Device(Device, InputChannelMap),
+    WavFile(Arc<WavInput>),
+    Tone(f32),
+}
+
+// Input WAV files are read when they are picked, so a bad path is reported before anything starts
+pub struct WavInput {
+    path: PathBuf,
+    channels: u16,
+    sample_rate: u32,
+    samples: Arc<Vec<f32>>,
+}
+
+// Output WAV files are created when they are picked, and the stream playing into one takes its writer
+pub struct WavOutput {
+    path: PathBuf,
+    writer: Mutex<Option<WavWriter<BufWriter<File>>>>,
+}
+
+// `Null` paces like a device and discards everything played on it
+#[derive(Clone)]
+pub enum OutputEndpoint {
+    Device(Device, OutputChannelMap),
+    WavFile(Arc<WavOutput>),
+    Null,
+}
+
+impl InputEndpoint {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "default" {
+            cpal::default_host()
+                .default_input_device()
+                .map(|x| InputEndpoint::Device(x, InputChannelMap::default()))
+                .ok_or(String::from("No default input device"))
+        } else if let Some(frequency) = value.strip_prefix("tone:") {
+            frequency
+                .parse::<f32>()
+                .map(InputEndpoint::Tone)
+                .map_err(|e| format!("Invalid tone frequency {frequency}: {e}"))
+        } else {
+            let path: PathBuf = PathBuf::from(value);
+            let (channels, sample_rate, samples) = load_wav(&path)
+                .map_err(|e| format!("Failed to read input WAV file {value}: {e}"))?;
+            Ok(InputEndpoint::WavFile(Arc::new(WavInput {
+                path,
+                channels: channels as u16,
+                sample_rate: sample_rate as u32,
+                samples: Arc::new(samples),
+            })))
+        }
+    }
+
+    pub fn config(&self) -> StreamConfig {
+        match self {
+            InputEndpoint::Device(device, _) => device
+                .default_input_config()
+                .expect("Failed to get default input config")
+                .into(),
+            InputEndpoint::WavFile(wav) => virtual_config(wav.channels, wav.sample_rate),
+            InputEndpoint::Tone(_) => virtual_config(1, VIRTUAL_SAMPLE_RATE),
+        }
+    }
+
+    pub fn with_channels(self, channels: InputChannelMap) -> Result<Self, String> {
+        match self {
+            InputEndpoint::Device(device, _) => Ok(InputEndpoint::Device(device, channels)),
+            _ => Err(String::from(
+                "Input channels can only be picked for devices",
+            )),
+        }
+    }
+
+    // Turns a frame of device channels into the `channels` sent on
+    pub fn channel_matrix(&self, channels: usize, device_channels: usize) -> ChannelMatrix {
+        match self {
+            InputEndpoint::Device(_, map) => map.matrix(channels, device_channels),
+            _ => InputChannelMap::Auto.matrix(channels, device_channels),
+        }
+    }
+}
+
+impl OutputEndpoint {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "default" {
+            cpal::default_host()
+                .default_output_device()
+                .map(|x| OutputEndpoint::Device(x, OutputChannelMap::default()))
+                .ok_or(String::from("No default output device"))
+        } else if value == "null" {
+            Ok(OutputEndpoint::Null)
+        } else {
+            // Creating the file up front reports a bad path before anything starts
+            let config: StreamConfig = virtual_config(1, VIRTUAL_SAMPLE_RATE);
+            let spec: WavSpec = WavSpec {
+                channels: config.channels,
+                sample_rate: config.sample_rate.0,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            };
+            let writer: WavWriter<BufWriter<File>> = WavWriter::create(value, spec)
+                .map_err(|e| format!("Failed to create output WAV file {value}: {e}"))?;
+            Ok(OutputEndpoint::WavFile(Arc::new(WavOutput {
+                path: PathBuf::from(value),
+                writer: Mutex::new(Some(writer)),
+            })))
+        }
+    }
+
+    pub fn config(&self) -> StreamConfig {
+        match self {
+            OutputEndpoint::Device(device, _) => device
+                .default_output_config()
+                .expect("Failed to get default output config")
+                .into(),
+            OutputEndpoint::WavFile(_) | OutputEndpoint::Null => {
+                virtual_config(1, VIRTUAL_SAMPLE_RATE)
+            }
+        }
+    }
+
+    pub fn with_channels(self, channels: OutputChannelMap) -> Result<Self, String> {
+        match self {
+            OutputEndpoint::Device(device, _) => Ok(OutputEndpoint::Device(device, channels)),
+            _ => Err(String::from(
+                "Output channels can only be picked for devices",
+            )),
+        }
+    }
+
+    // Turns a frame of `source_channels` into the channels of the device
+    pub fn channel_matrix(&self, source_channels: usize, device_channels: usize) -> ChannelMatrix {
+        match self {
+            OutputEndpoint::Device(_, map) => map.matrix(source_channels, device_channels),
+            _ => OutputChannelMap::Auto.matrix(source_channels, device_channels),
+        }
+    }
+}
+
+impl Display for InputEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputEndpoint::Device(device, _) => {
+                f.write_str(device.name().unwrap_or(String::from("Unknown")).as_str())
+            }
+            InputEndpoint::WavFile(wav) => write!(f, "WAV file {}", wav.path.display()),
+            InputEndpoint::Tone(frequency) => write!(f, "{frequency}Hz tone"),
+        }
+    }
+}
+
+impl Display for OutputEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputEndpoint::Device(device, _) => {
+                f.write_str(device.name().unwrap_or(String::from("Unknown")).as_str())
+            }
+            OutputEndpoint::WavFile(wav) => write!(f, "WAV file {}", wav.path.display()),
+            OutputEndpoint::Null => f.write_str("Null output"),
+        }
+    }
+}
+
+fn virtual_config(channels: u16, sample_rate: u32) -> StreamConfig {
+    StreamConfig {
+        channels,
+        sample_rate: SampleRate(sample_rate),
+        buffer_size: BufferSize::Default,
+    }
+}
+
+pub enum EndpointStream {
+    Device(Stream),
+    Virtual(VirtualStream),
+}
+
+impl EndpointStream {
+    pub fn play(&self) {
+        match self {
+            EndpointStream::Device(stream) => stream.play().expect("Failed to play stream"),
+            EndpointStream::Virtual(stream) => stream.play.store(true, Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct VirtualStream {
+    play: Arc<AtomicBool>,
+    run: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.run.store(false, Ordering::Relaxed);
+        // Joining makes sure file backed endpoints are finalized before the stream is gone
+        if let Some(handle) = self.handle.take()
+            && handle.join().is_err()
+        {
+            error!(target: TRACING_TARGET, "Virtual stream thread panicked");
+        }
+    }
+}
+
+// Calls `callback` with as many frames as a real device would have delivered since the stream started playing,
+// timestamped as if the device ran exactly at its nominal rate
+fn spawn_virtual_stream(
+    channels: usize,
+    sample_rate: u32,
+    mut callback: impl FnMut(&mut [f32], Instant) + Send + 'static,
+) -> VirtualStream {
+    let play: Arc<AtomicBool> = Arc::new(false.into());
+    let run: Arc<AtomicBool> = Arc::new(true.into());
+    let thread_play: Arc<AtomicBool> = play.clone();
+    let thread_run: Arc<AtomicBool> = run.clone();
+
+    let handle: JoinHandle<()> = thread::spawn(move || {
+        while thread_run.load(Ordering::Relaxed) && !thread_play.load(Ordering::Relaxed) {
+            thread::sleep(VIRTUAL_PERIOD);
+        }
+
+        let start: Instant = Instant::now();
+        let mut frames_done: u64 = 0;
+        let mut buffer: Vec<f32> = Vec::new();
+
+        while thread_run.load(Ordering::Relaxed) {
+            let frames_due: u64 = (start.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+            if frames_due > frames_done {
+                buffer.resize((frames_due - frames_done) as usize * channels, 0.0);
+                callback(
+                    &mut buffer,
+                    start + Duration::from_secs_f64(frames_done as f64 / sample_rate as f64),
+                );
+                frames_done = frames_due;
+            }
+            thread::sleep(VIRTUAL_PERIOD);
+        }
+    });
+
+    VirtualStream {
+        play,
+        run,
+        handle: Some(handle),
+    }
+}
+
+pub fn build_input_stream(
+    input: &InputEndpoint,
+    config: &StreamConfig,
+    mut data_callback: impl FnMut(&[f32], Instant) + Send + 'static,
+) -> EndpointStream {
+    match input {
+        InputEndpoint::Device(device, _) => EndpointStream::Device(
+            device
+                .build_input_stream(
+                    config,
+                    move |data: &[f32], info: &cpal::InputCallbackInfo| {
+                        // Device timestamps are on their own clock, only the distance to the callback is meaningful
+                        let timestamp = info.timestamp();
+                        let delay: Duration = timestamp
+                            .callback
+                            .duration_since(&timestamp.capture)
+                            .unwrap_or_default();
+                        data_callback(data, Instant::now() - delay)
+                    },
+                    |err| error!(target: TRACING_TARGET, "An error occurred on input stream: {err}"),
+                    None,
+                )
+                .expect("Failed to build input stream"),
+        ),
+        InputEndpoint::WavFile(wav) => {
+            info!(target: TRACING_TARGET, "Using WAV file {} as input", wav.path.display());
+            let samples: Arc<Vec<f32>> = wav.samples.clone();
+
+            let mut position: usize = 0;
+            EndpointStream::Virtual(spawn_virtual_stream(
+                config.channels as usize,
+                config.sample_rate.0,
+                move |data: &mut [f32], instant: Instant| {
+                    // Past the end of the file the virtual microphone delivers silence
+                    for sample in data.iter_mut() {
+                        *sample = samples.get(position).copied().unwrap_or(0.0);
+                        position += 1;
+                    }
+                    data_callback(data, instant);
+                },
+            ))
+        }
+        InputEndpoint::Tone(frequency) => {
+            info!(target: TRACING_TARGET, "Using {frequency}Hz tone as input");
+            let phase_step: f32 = TAU * *frequency / config.sample_rate.0 as f32;
+            let mut phase: f32 = 0.0;
+            EndpointStream::Virtual(spawn_virtual_stream(
+                config.channels as usize,
+                config.sample_rate.0,
+                move |data: &mut [f32], instant: Instant| {
+                    for sample in data.iter_mut() {
+                        *sample = phase.sin() * 0.5;
+                        phase = (phase + phase_step) % TAU;
+                    }
+                    data_callback(data, instant);
+                },
+            ))
+        }
+    }
+}
+
+pub fn build_output_stream(
+    output: &OutputEndpoint,
+    config: &StreamConfig,
+    mut data_callback: impl FnMut(&mut [f32], Instant) + Send + 'static,
+) -> EndpointStream {
+    match output {
+        OutputEndpoint::Device(device, _) => EndpointStream::Device(
+            device
+                .build_output_stream(
+                    config,
+                    move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                        let timestamp = info.timestamp();
+                        let delay: Duration = timestamp
+                            .playback
+                            .duration_since(&timestamp.callback)
+                            .unwrap_or_default();
+                        data_callback(data, Instant::now() + delay)
+                    },
+                    |err| error!(target: TRACING_TARGET, "An error occurred on output stream: {err}"),
+                    None,
+                )
+                .expect("Failed to build output stream"),
+        ),
+        OutputEndpoint::WavFile(wav) => {
+            info!(target: TRACING_TARGET, "Using WAV file {} as output", wav.path.display());
+            // The writer is finalized when the virtual stream thread drops the callback.
+            // A file is only played into once, a second stream on it discards its audio like the null output
+            let mut writer: Option<WavWriter<BufWriter<File>>> = wav.writer.lock().unwrap().take();
+            if writer.is_none() {
+                error!(target: TRACING_TARGET, "Output WAV file {} is already in use", wav.path.display());
+            }
+
+            EndpointStream::Virtual(spawn_virtual_stream(
+                config.channels as usize,
+                config.sample_rate.0,
+                move |data: &mut [f32], instant: Instant| {
+                    data_callback(data, instant);
+                    if let Some(writer) = writer.as_mut() {
+                        for sample in data.iter() {
+                            if let Err(e) = writer.write_sample(*sample) {
+                                error!(target: TRACING_TARGET, "Failed to write output WAV file: {e}");
+                                break;
+                            }
+                        }
+                    }
+                },
+            ))
+        }
+        OutputEndpoint::Null => {
+            info!(target: TRACING_TARGET, "Using null output");
+            EndpointStream::Virtual(spawn_virtual_stream(
+                config.channels as usize,
+                config.sample_rate.0,
+                data_callback,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tones_and_wav_files_are_inputs() {
+        let tone: InputEndpoint = InputEndpoint::parse("tone:1000").unwrap();
+        assert!(matches!(tone, InputEndpoint::Tone(1000.0)));
+        assert_eq!(tone.config().channels, 1);
+        assert_eq!(tone.config().sample_rate.0, VIRTUAL_SAMPLE_RATE);
+        assert!(InputEndpoint::parse("tone:loud").is_err());
+
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "p2p-voice-endpoint-input-test-{}.wav",
+            std::process::id()
+        ));
+        let spec: WavSpec = WavSpec {
+            channels: 2,
+            sample_rate: 24000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        WavWriter::create(&path, spec)
+            .expect("Failed to create test file")
+            .finalize()
+            .unwrap();
+        let wav: InputEndpoint = InputEndpoint::parse(path.to_str().unwrap()).unwrap();
+        assert_eq!(wav.to_string(), format!("WAV file {}", path.display()));
+        assert_eq!(wav.config().channels, 2);
+        assert_eq!(wav.config().sample_rate.0, 24000);
+        std::fs::remove_file(&path).unwrap();
+        assert!(InputEndpoint::parse("missing.wav").is_err());
+    }
+
+    #[test]
+    fn output_files_are_created_when_picked() {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "p2p-voice-endpoint-test-{}.wav",
+            std::process::id()
+        ));
+        let output: OutputEndpoint = OutputEndpoint::parse(path.to_str().unwrap()).unwrap();
+        assert!(path.exists());
+        assert_eq!(output.config().sample_rate.0, VIRTUAL_SAMPLE_RATE);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            OutputEndpoint::parse("null").unwrap(),
+            OutputEndpoint::Null
+        ));
+        assert!(OutputEndpoint::parse("/missing/directory/out.wav").is_err());
+    }
+
+    #[test]
+    fn output_files_are_written_through_the_writer_made_when_picked() {
+        let directory: PathBuf =
+            std::env::temp_dir().join(format!("p2p-voice-endpoint-test-{}", std::process::id()));
+        std::fs::create_dir(&directory).unwrap();
+        let path: PathBuf = directory.join("out.wav");
+        let output: OutputEndpoint = OutputEndpoint::parse(path.to_str().unwrap()).unwrap();
+        // The path going away before the stream starts no longer matters
+        std::fs::remove_dir_all(&directory).unwrap();
+        let stream: EndpointStream = build_output_stream(&output, &output.config(), |_, _| {});
+        stream.play();
+        drop(stream);
+    }
+
+    #[test]
+    fn channels_are_only_picked_for_devices() {
+        assert!(
+            InputEndpoint::Tone(440.0)
+                .with_channels(InputChannelMap::default())
+                .is_err()
+        );
+        assert!(
+            OutputEndpoint::Null
+                .with_channels(OutputChannelMap::default())
+                .is_err()
+        );
+    }
+}
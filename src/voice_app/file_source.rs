@@ -0,0 +1,343 @@
+use std::{
+    io,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    },
+    thread,
+};
+
+use cpal::Sample;
+use hound::{SampleFormat, WavReader};
+use opus::{Channels, Decoder};
+use rubato::{FftFixedIn, Resampler};
+use tracing::{error, info};
+
+use crate::voice_app::{
+    app_tracing::TRACING_TARGET,
+    ogg_opus::{OPUS_GRANULE_RATE, OggOpusStream, read_ogg_opus},
+};
+
+pub fn resample(samples: &[f32], input_sample_rate: usize, output_sample_rate: usize) -> Vec<f32> {
+    if input_sample_rate == output_sample_rate {
+        return samples.to_vec();
+    }
+
+    let chunk_size: usize = 1024;
+    let mut resampler =
+        FftFixedIn::<f32>::new(input_sample_rate, output_sample_rate, chunk_size, 1, 1)
+            .expect("Failed to create resampler");
+    let mut resample_process_buffer = resampler.output_buffer_allocate(true);
+    let mut resampled: Vec<f32> = Vec::with_capacity(
+        samples.len() * output_sample_rate / input_sample_rate + resampler.output_frames_max(),
+    );
+
+    for chunk in samples.chunks(chunk_size) {
+        let (_, written) = resampler
+            .process_partial_into_buffer(Some(&[chunk]), &mut resample_process_buffer, None)
+            .expect("Failed to resample");
+        resampled.extend_from_slice(&resample_process_buffer[0][..written]);
+    }
+    let (_, written) = resampler
+        .process_partial_into_buffer(None::<&[&[f32]]>, &mut resample_process_buffer, None)
+        .expect("Failed to resample");
+    resampled.extend_from_slice(&resample_process_buffer[0][..written]);
+
+    resampled.drain(..resampler.output_delay().min(resampled.len()));
+    resampled.truncate(samples.len() * output_sample_rate / input_sample_rate);
+    resampled
+}
+
+fn downmix(channels: usize, samples: &[f32]) -> Vec<f32> {
+    samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+pub fn load_wav(path: &Path) -> io::Result<(usize, usize, Vec<f32>)> {
+    let reader = WavReader::open(path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader
+            .into_samples::<f32>()
+            .collect::<Result<Vec<f32>, hound::Error>>()
+            .map_err(io::Error::other)?,
+        SampleFormat::Int => {
+            let scale: f32 = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|x| x.map(|x| x as f32 / scale))
+                .collect::<Result<Vec<f32>, hound::Error>>()
+                .map_err(io::Error::other)?
+        }
+    };
+    Ok((spec.channels as usize, spec.sample_rate as usize, samples))
+}
+
+// Opus decodes straight to any of its own rates, which saves a resampling pass at the processing rate
+const OPUS_DECODE_RATES: [usize; 5] = [8000, 12000, 16000, 24000, 48000];
+
+fn load_ogg_opus(path: &Path, sample_rate: usize) -> io::Result<(usize, usize, Vec<f32>)> {
+    let stream: OggOpusStream = read_ogg_opus(path)?;
+    let channels: usize = stream.channels.clamp(1, 2) as usize;
+    let decode_rate: usize = if OPUS_DECODE_RATES.contains(&sample_rate) {
+        sample_rate
+    } else {
+        OPUS_GRANULE_RATE as usize
+    };
+    let mut decoder: Decoder = Decoder::new(
+        decode_rate as u32,
+        if channels == 2 {
+            Channels::Stereo
+        } else {
+            Channels::Mono
+        },
+    )
+    .map_err(io::Error::other)?;
+
+    // Opus packets are at most 120 ms long
+    let mut decoder_output_buffer: Vec<f32> =
+        vec![Sample::EQUILIBRIUM; decode_rate / 1000 * 120 * channels];
+    let mut samples: Vec<f32> = Vec::new();
+    for packet in stream.packets.iter() {
+        match decoder.decode_float(packet, &mut decoder_output_buffer, false) {
+            Ok(decoded) => samples.extend_from_slice(&decoder_output_buffer[..decoded * channels]),
+            Err(e) => error!(target: TRACING_TARGET, "Skipping undecodable Opus packet: {e}"),
+        }
+    }
+    // Pre-skip is counted at 48 kHz like the granule positions
+    let pre_skip: usize = stream.pre_skip as usize * decode_rate / OPUS_GRANULE_RATE as usize;
+    samples.drain(..(pre_skip * channels).min(samples.len()));
+
+    Ok((channels, decode_rate, samples))
+}
+
+pub fn load_file(path: &Path, sample_rate: usize) -> io::Result<Vec<f32>> {
+    let extension: String = path
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let (channels, file_sample_rate, samples) = match extension.as_str() {
+        "wav" | "wave" => load_wav(path)?,
+        "opus" | "ogg" | "oga" => load_ogg_opus(path, sample_rate)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported audio file type: {extension}"),
+            ));
+        }
+    };
+
+    Ok(resample(
+        &downmix(channels, &samples),
+        file_sample_rate,
+        sample_rate,
+    ))
+}
+
+// The loaded samples only change when a file starts playing, so readers pick them up by version
+// instead of locking for every frame
+#[derive(Default)]
+struct Playback {
+    samples: Mutex<Arc<Vec<f32>>>,
+    version: AtomicU64,
+    playing: AtomicBool,
+    looping: AtomicBool,
+    gain: AtomicU32,
+}
+
+#[derive(Clone)]
+pub struct FilePlayer(Arc<Playback>);
+
+impl Default for FilePlayer {
+    fn default() -> Self {
+        let playback: Playback = Playback::default();
+        playback.gain.store(1.0_f32.to_bits(), Ordering::Relaxed);
+        FilePlayer(Arc::new(playback))
+    }
+}
+
+impl FilePlayer {
+    pub fn play(&self, path: String, sample_rate: usize) {
+        let playback: Arc<Playback> = self.0.clone();
+        thread::spawn(move || {
+            info!(target: TRACING_TARGET, "Loading audio file {path}");
+            match load_file(Path::new(&path), sample_rate) {
+                Ok(samples) => {
+                    *playback.samples.lock().unwrap() = Arc::new(samples);
+                    playback.version.fetch_add(1, Ordering::Release);
+                    playback.playing.store(true, Ordering::Relaxed);
+                    info!(target: TRACING_TARGET, "Playing audio file {path}");
+                }
+                Err(e) => error!(target: TRACING_TARGET, "Failed to load audio file {path}: {e}"),
+            }
+        });
+    }
+
+    pub fn stop(&self) {
+        self.0.playing.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.0.playing.load(Ordering::Relaxed)
+    }
+
+    pub fn set_looping(&self, looping: bool) {
+        self.0.looping.store(looping, Ordering::Relaxed);
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.0.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn reader(&self) -> FileReader {
+        FileReader {
+            playback: self.0.clone(),
+            version: 0,
+            samples: Arc::default(),
+            position: 0,
+        }
+    }
+}
+
+// Reads the playing file from a single audio thread
+pub struct FileReader {
+    playback: Arc<Playback>,
+    version: u64,
+    samples: Arc<Vec<f32>>,
+    position: usize,
+}
+
+impl FileReader {
+    pub fn read(&mut self, output: &mut [f32]) -> bool {
+        output.fill(Sample::EQUILIBRIUM);
+        let version: u64 = self.playback.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.version = version;
+            self.samples = self.playback.samples.lock().unwrap().clone();
+            self.position = 0;
+        }
+        if !self.playback.playing.load(Ordering::Relaxed) || self.samples.is_empty() {
+            return false;
+        }
+
+        let looping: bool = self.playback.looping.load(Ordering::Relaxed);
+        let gain: f32 = f32::from_bits(self.playback.gain.load(Ordering::Relaxed));
+        for sample in output.iter_mut() {
+            if self.position >= self.samples.len() {
+                if !looping {
+                    self.playback.playing.store(false, Ordering::Relaxed);
+                    break;
+                }
+                self.position = 0;
+            }
+            *sample = self.samples[self.position] * gain;
+            self.position += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, f32::consts::TAU, fs, path::PathBuf};
+
+    use hound::{WavSpec, WavWriter};
+
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: usize, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| (TAU * frequency * i as f32 / sample_rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    // A player with `samples` loaded as `play` would leave it
+    fn loaded(samples: Vec<f32>) -> FilePlayer {
+        let player: FilePlayer = FilePlayer::default();
+        *player.0.samples.lock().unwrap() = Arc::new(samples);
+        player.0.version.fetch_add(1, Ordering::Release);
+        player.0.playing.store(true, Ordering::Relaxed);
+        player
+    }
+
+    #[test]
+    fn resampling_keeps_length_and_level() {
+        let samples: Vec<f32> = sine(440.0, 16000, 16000);
+        let resampled: Vec<f32> = resample(&samples, 16000, 48000);
+        assert_eq!(resampled.len(), 48000);
+        // Lined up with the input, away from the edges
+        let expected: Vec<f32> = sine(440.0, 48000, 48000);
+        let error: Vec<f32> = resampled[4800..43200]
+            .iter()
+            .zip(expected[4800..43200].iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        assert!(rms(&error) < 0.01, "{}", rms(&error));
+        assert_eq!(resample(&samples, 16000, 16000), samples);
+    }
+
+    #[test]
+    fn wav_files_are_downmixed_and_resampled() {
+        let path: PathBuf = env::temp_dir().join("p2p-voice-file-source-test.wav");
+        let spec: WavSpec = WavSpec {
+            channels: 2,
+            sample_rate: 24000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).expect("Failed to create test file");
+        for sample in sine(440.0, 24000, 24000) {
+            // Silent on the right, so the mix keeps half of the left at a quarter of full scale
+            writer.write_sample((sample * 16384.0) as i16).unwrap();
+            writer.write_sample(0_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let samples: Vec<f32> = load_file(&path, 48000).expect("Failed to load test file");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 48000);
+        let level: f32 = rms(&samples[4800..43200]);
+        assert!((level - 0.125 / 2.0_f32.sqrt()).abs() < 0.005, "{level}");
+
+        assert_eq!(
+            load_file(Path::new("music.mp3"), 48000).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn files_play_once_or_loop() {
+        let player: FilePlayer = loaded(vec![1.0, 2.0, 3.0]);
+        player.set_gain(0.5);
+        let mut reader: FileReader = player.reader();
+        let mut output: Vec<f32> = vec![0.0; 5];
+        assert!(reader.read(&mut output));
+        assert_eq!(output, [0.5, 1.0, 1.5, 0.0, 0.0]);
+        assert!(!player.is_playing());
+        assert!(!reader.read(&mut output));
+        assert_eq!(output, [0.0; 5]);
+
+        let player: FilePlayer = loaded(vec![1.0, 2.0, 3.0]);
+        player.set_looping(true);
+        let mut reader: FileReader = player.reader();
+        assert!(reader.read(&mut output));
+        assert_eq!(output, [1.0, 2.0, 3.0, 1.0, 2.0]);
+        assert!(player.is_playing());
+
+        // Another file starts from its beginning
+        *player.0.samples.lock().unwrap() = Arc::new(vec![4.0]);
+        player.0.version.fetch_add(1, Ordering::Release);
+        assert!(reader.read(&mut output[..2]));
+        assert_eq!(output[..2], [4.0, 4.0]);
+
+        player.stop();
+        assert!(!reader.read(&mut output));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{thread, time::Duration};
+
+use tracing::info;
+
+use crate::voice_app::{
+    app_tracing::TRACING_TARGET,
+    audio::{CallHandles, P2P, SelfListen},
+    channel_map::{InputChannelMap, OutputChannelMap},
+    codec::CodecKind,
+    endpoint::{InputEndpoint, OutputEndpoint},
+    howling::HowlingReport,
+    input_control::{ExtraInput, InputControl},
+    latency_profile::LatencyProfile,
+    opus_encoder::{OpusSettings, OpusSettingsHandle},
+    output_control::{ExtraOutput, OutputControl},
+    processing_rate::ProcessingRate,
+    protocol::{Negotiation, negotiate},
+    transcription::{Captions, TranscriberKind, TranscriberSettings},
+    transient::TransientSuppression,
+};
+
+pub const HEADLESS_FLAG: &str = "--headless";
+
+pub const HEADLESS_USAGE: &str = "Usage: p2p-voice --headless <self-listen|p2p> [options]
+    --input <default|tone:<Hz>|file.wav>    Capture end, a 440Hz tone by default, `default` is the default input device
+    --input-channels <auto|1,2|matrix:...>  Input device channels to use, or a downmix matrix with a row per sent channel
+    --also-input <default|tone:<Hz>|file.wav> Another capture end mixed in for p2p, can be repeated
+    --output <default|null|file.wav>        Playback end, null by default, `default` is the default output device
+    --output-channels <auto|3,4>            Output device channels to play on
+    --also-output <default|null|file.wav>   Another playback end for p2p, can be repeated
+    --peer <address>                        Peer address, required for p2p
+    --duration <seconds>                    How long to run, 10 seconds by default
+    --codec <opus|l16|pcmu|pcma>            Preferred codec, the peer may pick another one
+    --codec-only                            Fail instead of falling back to another codec
+    --profile <ultra-low|balanced|robust>   Latency profile, balanced by default
+    --rate <16000|24000|48000>              Internal processing sample rate, 48000 by default
+    --captions <stub|whisper-cli>           Transcribe both sides of a p2p call to a transcript file
+    --whisper-model <path>                  Model for whisper-cli captions
+    --suppress-clicks                       Suppress keyboard clicks and other transients in the capture
+    --music                                 Ask the peer for stereo music mode
+    --record                                Capture sent and received packets to Ogg/Opus files";
+
+enum HeadlessMode {
+    SelfListen,
+    P2P,
+}
+
+struct HeadlessOptions {
+    mode: HeadlessMode,
+    input: InputEndpoint,
+    extra_inputs: Vec<InputEndpoint>,
+    output: OutputEndpoint,
+    extra_outputs: Vec<OutputEndpoint>,
+    peer: Option<String>,
+    duration: Duration,
+    codec: CodecKind,
+    codec_fallback: bool,
+    profile: LatencyProfile,
+    rate: ProcessingRate,
+    transcriber: TranscriberSettings,
+    suppress_clicks: bool,
+    music: bool,
+    record: bool,
+}
+
+fn parse(args: &[String]) -> Result<HeadlessOptions, String> {
+    let mut args = args.iter();
+    let mode: HeadlessMode = match args.next().map(|x| x.as_str()) {
+        Some("self-listen") => HeadlessMode::SelfListen,
+        Some("p2p") => HeadlessMode::P2P,
+        Some(mode) => return Err(format!("Unknown mode {mode}")),
+        None => return Err(String::from("Missing mode")),
+    };
+
+    let mut input: Option<InputEndpoint> = None;
+    let mut input_channels: Option<InputChannelMap> = None;
+    let mut extra_inputs: Vec<InputEndpoint> = Vec::new();
+    let mut output: Option<OutputEndpoint> = None;
+    let mut output_channels: Option<OutputChannelMap> = None;
+    let mut extra_outputs: Vec<OutputEndpoint> = Vec::new();
+    let mut peer: Option<String> = None;
+    let mut duration: Duration = Duration::from_secs(10);
+    let mut codec: CodecKind = CodecKind::Opus;
+    let mut codec_fallback: bool = true;
+    let mut profile: LatencyProfile = LatencyProfile::default();
+    let mut rate: ProcessingRate = ProcessingRate::default();
+    let mut transcriber: TranscriberSettings = TranscriberSettings::default();
+    let mut suppress_clicks: bool = false;
+    let mut music: bool = false;
+    let mut record: bool = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--input" => input = Some(InputEndpoint::parse(value()?)?),
+            "--input-channels" => input_channels = Some(InputChannelMap::parse(value()?)?),
+            "--also-input" => extra_inputs.push(InputEndpoint::parse(value()?)?),
+            "--output" => output = Some(OutputEndpoint::parse(value()?)?),
+            "--output-channels" => output_channels = Some(OutputChannelMap::parse(value()?)?),
+            "--also-output" => extra_outputs.push(OutputEndpoint::parse(value()?)?),
+            "--peer" => peer = Some(value()?.clone()),
+            "--duration" => {
+                duration = Duration::try_from_secs_f64(
+                    value()?
+                        .parse::<f64>()
+                        .map_err(|e| format!("Invalid duration: {e}"))?,
+                )
+                .map_err(|e| format!("Invalid duration: {e}"))?
+            }
+            "--codec" => codec = CodecKind::parse(value()?)?,
+            "--codec-only" => codec_fallback = false,
+            "--profile" => profile = LatencyProfile::parse(value()?)?,
+            "--rate" => rate = ProcessingRate::parse(value()?)?,
+            "--captions" => transcriber.kind = TranscriberKind::parse(value()?)?,
+            "--whisper-model" => transcriber.whisper_model = value()?.clone(),
+            "--suppress-clicks" => suppress_clicks = true,
+            "--music" => music = true,
+            "--record" => record = true,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    // Without a sound card in CI or a container, nothing runs on real devices unless asked for
+    let mut input: InputEndpoint = input.unwrap_or(InputEndpoint::Tone(440.0));
+    if let Some(input_channels) = input_channels {
+        input = input.with_channels(input_channels)?;
+    }
+    let mut output: OutputEndpoint = output.unwrap_or(OutputEndpoint::Null);
+    if let Some(output_channels) = output_channels {
+        output = output.with_channels(output_channels)?;
+    }
+
+    Ok(HeadlessOptions {
+        mode,
+        input,
+        extra_inputs,
+        output,
+        extra_outputs,
+        peer,
+        duration,
+        codec,
+        codec_fallback,
+        profile,
+        rate,
+        transcriber,
+        suppress_clicks,
+        music,
+        record,
+    })
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    let options: HeadlessOptions = parse(args)?;
+    info!(target: TRACING_TARGET, "Running headless for {:?}", options.duration);
+
+    let transient_suppression: TransientSuppression = TransientSuppression::default();
+    transient_suppression.set_enabled(options.suppress_clicks);
+
+    match options.mode {
+        HeadlessMode::SelfListen => {
+            let self_listen: SelfListen = SelfListen::new(
+                &options.input,
+                &options.output,
+                CallHandles {
+                    transient_suppression,
+                    ..CallHandles::default()
+                },
+                None,
+                options.profile,
+                options.rate,
+            );
+            thread::sleep(options.duration);
+            drop(self_listen);
+        }
+        HeadlessMode::P2P => {
+            let peer: String = options
+                .peer
+                .ok_or(String::from("--peer is required for p2p"))?;
+            let negotiation: Negotiation = negotiate(
+                &peer,
+                &CodecKind::preferences(options.codec, options.codec_fallback),
+                options.music,
+            )?;
+            let opus_settings: OpusSettingsHandle = OpusSettingsHandle::new(OpusSettings {
+                frame_duration: options.profile.frame_duration(),
+                ..OpusSettings::default()
+            });
+            let captions: Captions = Captions::default();
+            captions.set_settings(options.transcriber);
+            let p2p: P2P = P2P::new(
+                &options.input,
+                &options.output,
+                &negotiation,
+                options.record,
+                CallHandles {
+                    opus_settings,
+                    captions,
+                    transient_suppression,
+                    extra_inputs: options
+                        .extra_inputs
+                        .into_iter()
+                        .map(|endpoint| ExtraInput {
+                            endpoint,
+                            control: InputControl::default(),
+                        })
+                        .collect(),
+                    extra_outputs: options
+                        .extra_outputs
+                        .into_iter()
+                        .map(|endpoint| ExtraOutput {
+                            endpoint,
+                            control: OutputControl::default(),
+                        })
+                        .collect(),
+                    ..CallHandles::default()
+                },
+                options.profile,
+                options.rate,
+            );
+            thread::sleep(options.duration);
+            info!(target: TRACING_TARGET, "{}", p2p.timing());
+            let howling: HowlingReport = p2p.howling();
+            if howling.active() {
+                info!(target: TRACING_TARGET, "{howling}");
+            }
+            for stats in p2p.buffer_stats() {
+                info!(target: TRACING_TARGET, "{stats}");
+            }
+            drop(p2p);
+        }
+    }
+
+    info!(target: TRACING_TARGET, "Headless run finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn defaults_run_without_devices() {
+        let options: HeadlessOptions = parse(&args("self-listen")).unwrap();
+        assert!(matches!(options.mode, HeadlessMode::SelfListen));
+        assert!(matches!(options.input, InputEndpoint::Tone(440.0)));
+        assert!(matches!(options.output, OutputEndpoint::Null));
+        assert_eq!(options.duration, Duration::from_secs(10));
+        assert_eq!(options.codec, CodecKind::Opus);
+        assert!(options.codec_fallback);
+        assert_eq!(options.transcriber.kind, TranscriberKind::Off);
+        assert!(options.peer.is_none());
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let options: HeadlessOptions = parse(&args(
+            "p2p --peer 127.0.0.1:4000 --input tone:880 --also-input tone:220 --also-output null \
+             --duration 2.5 --codec pcmu --codec-only --profile robust --rate 16000 \
+             --captions stub --suppress-clicks --music --record",
+        ))
+        .unwrap();
+        assert!(matches!(options.mode, HeadlessMode::P2P));
+        assert_eq!(options.peer.as_deref(), Some("127.0.0.1:4000"));
+        assert!(matches!(options.input, InputEndpoint::Tone(880.0)));
+        assert_eq!(options.extra_inputs.len(), 1);
+        assert_eq!(options.extra_outputs.len(), 1);
+        assert_eq!(options.duration, Duration::from_millis(2500));
+        assert_eq!(options.codec, CodecKind::Pcmu);
+        assert!(!options.codec_fallback);
+        assert_eq!(options.profile, LatencyProfile::Robust);
+        assert_eq!(options.rate, ProcessingRate::Wideband);
+        assert_eq!(options.transcriber.kind, TranscriberKind::Stub);
+        assert!(options.suppress_clicks && options.music && options.record);
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        for line in [
+            "",
+            "conference",
+            "p2p --peer",
+            "p2p --volume 11",
+            "p2p --duration soon",
+            "p2p --duration -1",
+            "p2p --duration nan",
+            "p2p --duration inf",
+            "p2p --codec speex",
+            "p2p --profile instant",
+            "self-listen --input tone:440 --input-channels 1,2",
+            "p2p --rate 44100",
+        ] {
+            assert!(parse(&args(line)).is_err(), "{line}");
+        }
+    }
+}
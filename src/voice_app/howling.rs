@@ -0,0 +1,479 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::TAU,
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use rustfft::{Fft, FftPlanner, num_complex::Complex};
+use tracing::info;
+
+use crate::voice_app::app_tracing::TRACING_TARGET;
+
+// 11.7 Hz bins at 48 kHz and finer at lower processing rates, fine enough to place a narrow notch
+// on a feedback tone
+const FFT_SIZE: usize = 4096;
+const HOP: usize = 1024;
+const MIN_FREQUENCY: f32 = 150.0;
+const MAX_FREQUENCY: f32 = 12000.0;
+const PEAKS_PER_FRAME: usize = 6;
+// Quieter peaks can't be ringing through a room
+const MIN_LEVEL_DB: f32 = -45.0;
+// Power of the peak over the background of the spectrum, over the bins just outside its main lobe,
+// and over its octave neighbours, which voiced speech always has
+const PAPR_DB: f32 = 15.0;
+const PNPR_DB: f32 = 12.0;
+const PHPR_DB: f32 = 20.0;
+const NEIGHBOUR_BINS: std::ops::RangeInclusive<usize> = 4..=8;
+// Speech harmonics glide within a syllable, feedback stays on one frequency and keeps ringing
+const PERSISTENCE_SECONDS: f32 = 0.4;
+// Modes of a long loop sit a few hertz apart and take turns being the loudest, and a peak can
+// drop out of an analysis or two while they beat against each other
+const TRACK_TOLERANCE: f32 = 25.0;
+const MAX_MISSES: usize = 2;
+const MAX_NOTCHES: usize = 6;
+const NOTCH_Q: f32 = 20.0;
+const NOTCH_TOLERANCE: f32 = 25.0;
+// Notches stay a while after the ringing stops, as the loop is still there
+const NOTCH_HOLD_SECONDS: f32 = 60.0;
+// Used once every notch is taken
+const GAIN_STEP_DB: f32 = 6.0;
+const MAX_GAIN_REDUCTION_DB: f32 = 18.0;
+const GAIN_RECOVERY_DB_PER_SECOND: f32 = 1.0;
+const GAIN_SMOOTHING: f32 = 0.001;
+
+// What the suppressor is currently doing against feedback
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HowlingReport {
+    pub frequencies: Vec<f32>,
+    pub gain_reduction_db: f32,
+}
+
+impl HowlingReport {
+    pub fn active(&self) -> bool {
+        !self.frequencies.is_empty() || self.gain_reduction_db > 0.0
+    }
+}
+
+impl Display for HowlingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let frequencies: Vec<String> = self
+            .frequencies
+            .iter()
+            .map(|x| format!("{x:.0} Hz"))
+            .collect();
+        write!(f, "Feedback suppressed at {}", frequencies.join(", "))?;
+        if self.gain_reduction_db > 0.0 {
+            write!(f, ", {:.0} dB quieter", self.gain_reduction_db)?;
+        }
+        f.write_str(". Move the speakers away from the microphone or use headphones")
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HowlingMonitor(Arc<Mutex<HowlingReport>>);
+
+impl HowlingMonitor {
+    pub fn report(&self) -> HowlingReport {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, report: HowlingReport) {
+        *self.0.lock().unwrap() = report;
+    }
+}
+
+struct Notch {
+    frequency: f32,
+    hold: usize,
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    // x1, x2, y1 and y2 of each channel
+    state: Vec<[f32; 4]>,
+}
+
+impl Notch {
+    // Notch from the Audio EQ Cookbook
+    fn new(frequency: f32, channels: usize, sample_rate: f32) -> Self {
+        let w0: f32 = TAU * frequency / sample_rate;
+        let alpha: f32 = w0.sin() / (2.0 * NOTCH_Q);
+        let cos: f32 = w0.cos();
+        let a0: f32 = 1.0 + alpha;
+        Notch {
+            frequency,
+            hold: (NOTCH_HOLD_SECONDS * sample_rate) as usize,
+            b0: 1.0 / a0,
+            b1: -2.0 * cos / a0,
+            b2: 1.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            state: vec![[0.0; 4]; channels],
+        }
+    }
+
+    fn process(&mut self, channel: usize, sample: f32) -> f32 {
+        let [x1, x2, y1, y2]: [f32; 4] = self.state[channel];
+        let y: f32 = self.b0 * sample + self.b1 * x1 + self.b2 * x2 - self.a1 * y1 - self.a2 * y2;
+        self.state[channel] = [sample, x1, y, y1];
+        y
+    }
+}
+
+struct Candidate {
+    frequency: f32,
+    duration: usize,
+    seen: bool,
+    misses: usize,
+}
+
+// Finds peaks that are narrow, have no harmonics and stay put, which is how a closed loop between
+// a speaker and a microphone rings, and notches them out of the outgoing audio.
+// Stereo is analyzed as its mix, as both channels go through the same room
+pub struct HowlingSuppressor {
+    monitor: HowlingMonitor,
+    channels: usize,
+    sample_rate: f32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    history: VecDeque<f32>,
+    since_analysis: usize,
+    spectrum: Vec<Complex<f32>>,
+    power: Vec<f32>,
+    sorted: Vec<f32>,
+    peaks: Vec<usize>,
+    candidates: Vec<Candidate>,
+    notches: Vec<Notch>,
+    gain_reduction_db: f32,
+    gain: f32,
+}
+
+impl HowlingSuppressor {
+    pub fn new(monitor: HowlingMonitor, channels: usize, sample_rate: usize) -> Self {
+        HowlingSuppressor {
+            monitor,
+            channels,
+            sample_rate: sample_rate as f32,
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            window: (0..FFT_SIZE)
+                .map(|x| 0.5 - 0.5 * (TAU * x as f32 / FFT_SIZE as f32).cos())
+                .collect(),
+            history: VecDeque::with_capacity(FFT_SIZE),
+            since_analysis: 0,
+            spectrum: vec![Complex::default(); FFT_SIZE],
+            power: vec![0.0; FFT_SIZE / 2 + 1],
+            sorted: Vec::with_capacity(FFT_SIZE / 2 + 1),
+            peaks: Vec::with_capacity(FFT_SIZE / 2 + 1),
+            candidates: Vec::new(),
+            notches: Vec::new(),
+            gain_reduction_db: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    // Takes interleaved frames
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let channels: usize = self.channels;
+        // The analysis looks at the input, so a tone already notched is still seen and its notch kept
+        self.history.extend(
+            buffer
+                .chunks_exact(channels)
+                .map(|x| x.iter().sum::<f32>() / channels as f32),
+        );
+        let excess: usize = self.history.len().saturating_sub(FFT_SIZE);
+        self.history.drain(..excess);
+        self.since_analysis += buffer.len() / channels;
+        if self.since_analysis >= HOP && self.history.len() == FFT_SIZE {
+            self.analyze();
+        }
+
+        let target: f32 = 10.0_f32.powf(-self.gain_reduction_db / 20.0);
+        for frame in buffer.chunks_exact_mut(channels) {
+            self.gain += (target - self.gain) * GAIN_SMOOTHING;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut y: f32 = *sample;
+                for notch in self.notches.iter_mut() {
+                    y = notch.process(channel, y);
+                }
+                *sample = y * self.gain;
+            }
+        }
+    }
+
+    fn analyze(&mut self) {
+        let elapsed: usize = self.since_analysis;
+        self.since_analysis = 0;
+
+        for ((bin, sample), window) in self
+            .spectrum
+            .iter_mut()
+            .zip(self.history.iter())
+            .zip(self.window.iter())
+        {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.fft.process(&mut self.spectrum);
+        // Relative to a full scale sine, which peaks at a quarter of the window length
+        let full_scale: f32 = (FFT_SIZE * FFT_SIZE) as f32 / 16.0;
+        for (power, bin) in self.power.iter_mut().zip(self.spectrum.iter()) {
+            *power = bin.norm_sqr() / full_scale;
+        }
+
+        let bin_width: f32 = self.sample_rate / FFT_SIZE as f32;
+        let min_bin: usize = (MIN_FREQUENCY / bin_width) as usize;
+        // Short of Nyquist at low processing rates, leaving a neighbour above the last peak
+        let max_bin: usize = ((MAX_FREQUENCY / bin_width) as usize).min(FFT_SIZE / 2 - 1);
+        // The median stays on the background even when several tones ring at once.
+        // Unstable sorts, as the stable ones allocate
+        self.sorted.clear();
+        self.sorted
+            .extend_from_slice(&self.power[min_bin..=max_bin]);
+        self.sorted.sort_unstable_by(f32::total_cmp);
+        let floor: f32 = self.sorted[self.sorted.len() / 2];
+
+        let power: &[f32] = &self.power;
+        self.peaks.clear();
+        self.peaks.extend(
+            (min_bin..=max_bin).filter(|x| power[*x] > power[x - 1] && power[*x] >= power[x + 1]),
+        );
+        self.peaks
+            .sort_unstable_by(|a, b| power[*b].total_cmp(&power[*a]));
+        self.peaks.truncate(PEAKS_PER_FRAME);
+        // Taken out while candidates are updated, and put back so its allocation is kept
+        let mut peaks: Vec<usize> = std::mem::take(&mut self.peaks);
+        peaks.retain(|x| self.is_howling(*x, floor));
+
+        for candidate in self.candidates.iter_mut() {
+            candidate.seen = false;
+        }
+        for peak in peaks.iter() {
+            let frequency: f32 = self.peak_frequency(*peak);
+            match self
+                .candidates
+                .iter_mut()
+                .find(|x| !x.seen && (x.frequency - frequency).abs() <= TRACK_TOLERANCE)
+            {
+                Some(candidate) => {
+                    candidate.seen = true;
+                    candidate.misses = 0;
+                    candidate.frequency = frequency;
+                    candidate.duration += elapsed;
+                }
+                None => self.candidates.push(Candidate {
+                    frequency,
+                    duration: 0,
+                    seen: true,
+                    misses: 0,
+                }),
+            }
+        }
+        let found: bool = !peaks.is_empty();
+        self.peaks = peaks;
+        for candidate in self.candidates.iter_mut().filter(|x| !x.seen) {
+            candidate.misses += 1;
+        }
+        self.candidates.retain(|x| x.misses <= MAX_MISSES);
+
+        let persistence: usize = (PERSISTENCE_SECONDS * self.sample_rate) as usize;
+        let mut howling: Vec<f32> = Vec::new();
+        for candidate in self.candidates.iter_mut().filter(|x| x.seen) {
+            if candidate.duration >= persistence {
+                candidate.duration = 0;
+                howling.push(candidate.frequency);
+            }
+        }
+        for frequency in howling.iter() {
+            self.suppress(*frequency);
+        }
+
+        for notch in self.notches.iter_mut() {
+            notch.hold = notch.hold.saturating_sub(elapsed);
+        }
+        self.notches.retain(|x| {
+            if x.hold == 0 {
+                info!(target: TRACING_TARGET, "Removing feedback notch at {:.0}Hz", x.frequency);
+            }
+            x.hold > 0
+        });
+        if !found {
+            self.gain_reduction_db = (self.gain_reduction_db
+                - GAIN_RECOVERY_DB_PER_SECOND * elapsed as f32 / self.sample_rate)
+                .max(0.0);
+        }
+
+        let report: HowlingReport = HowlingReport {
+            frequencies: self.notches.iter().map(|x| x.frequency).collect(),
+            // Whole decibels so the report doesn't change on every analysis while recovering
+            gain_reduction_db: self.gain_reduction_db.ceil(),
+        };
+        if report != self.monitor.report() {
+            self.monitor.set(report);
+        }
+    }
+
+    fn is_howling(&self, bin: usize, floor: f32) -> bool {
+        let ratio_db = |other: f32| 10.0 * (self.power[bin] / other.max(f32::MIN_POSITIVE)).log10();
+        let around = |center: usize, range: std::ops::RangeInclusive<usize>| -> f32 {
+            range
+                .flat_map(|x| [center.saturating_sub(x), center + x])
+                .filter(|x| *x < self.power.len())
+                .map(|x| self.power[x])
+                .fold(0.0, f32::max)
+        };
+
+        let level_db: f32 = 10.0 * self.power[bin].max(f32::MIN_POSITIVE).log10();
+        let harmonic: f32 = around(bin * 2, 0..=1).max(around(bin / 2, 0..=1));
+
+        level_db >= MIN_LEVEL_DB
+            && ratio_db(floor) >= PAPR_DB
+            && ratio_db(around(bin, NEIGHBOUR_BINS)) >= PNPR_DB
+            && ratio_db(harmonic) >= PHPR_DB
+    }
+
+    // Parabolic interpolation between the bins around the peak
+    fn peak_frequency(&self, bin: usize) -> f32 {
+        let [left, center, right]: [f32; 3] =
+            [bin - 1, bin, bin + 1].map(|x| self.power[x].max(f32::MIN_POSITIVE).ln());
+        let denominator: f32 = left - 2.0 * center + right;
+        let offset: f32 = if denominator.abs() > f32::EPSILON {
+            (0.5 * (left - right) / denominator).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
+        (bin as f32 + offset) * self.sample_rate / FFT_SIZE as f32
+    }
+
+    fn suppress(&mut self, frequency: f32) {
+        if let Some(notch) = self
+            .notches
+            .iter_mut()
+            .find(|x| (x.frequency - frequency).abs() <= NOTCH_TOLERANCE)
+        {
+            notch.hold = (NOTCH_HOLD_SECONDS * self.sample_rate) as usize;
+        } else if self.notches.len() < MAX_NOTCHES {
+            info!(target: TRACING_TARGET, "Feedback detected at {frequency:.0}Hz, adding a notch");
+            self.notches
+                .push(Notch::new(frequency, self.channels, self.sample_rate));
+        } else {
+            self.gain_reduction_db =
+                (self.gain_reduction_db + GAIN_STEP_DB).min(MAX_GAIN_REDUCTION_DB);
+            info!(target: TRACING_TARGET, "Feedback detected at {frequency:.0}Hz with every notch taken, reducing gain by {:.0}dB", self.gain_reduction_db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::voice_app::{file_source::load_file, test_signals::noise};
+
+    const SAMPLE_RATE: usize = 48000;
+
+    // A tone over faint noise, like a loop ringing in a quiet room, on every channel
+    fn ringing(frequency: f32, seconds: f32, channels: usize) -> Vec<f32> {
+        noise((seconds * SAMPLE_RATE as f32) as usize, 0.001, 1)
+            .iter()
+            .enumerate()
+            .flat_map(|(i, noise)| {
+                let tone: f32 = (TAU * frequency * i as f32 / SAMPLE_RATE as f32).sin() * 0.25;
+                vec![tone + noise; channels]
+            })
+            .collect()
+    }
+
+    fn suppress(suppressor: &mut HowlingSuppressor, samples: &mut [f32]) {
+        for chunk in samples.chunks_mut(SAMPLE_RATE / 100 * suppressor.channels) {
+            suppressor.process(chunk);
+        }
+    }
+
+    // A lone peak at bin 100 that passes every detector, for the tests to spoil one at a time
+    fn lone_peak() -> HowlingSuppressor {
+        let mut suppressor: HowlingSuppressor =
+            HowlingSuppressor::new(HowlingMonitor::default(), 1, SAMPLE_RATE);
+        suppressor.power.fill(1e-7);
+        suppressor.power[100] = 1e-2;
+        suppressor
+    }
+
+    #[test]
+    fn detectors_need_a_lone_narrow_peak_without_harmonics() {
+        let floor: f32 = 1e-7;
+        assert!(lone_peak().is_howling(100, floor));
+
+        // Too quiet to be ringing through a room
+        let mut quiet: HowlingSuppressor = lone_peak();
+        quiet.power[100] = 1e-5;
+        assert!(!quiet.is_howling(100, floor));
+
+        // PAPR, barely above the background
+        assert!(!lone_peak().is_howling(100, 1e-2 / 10.0));
+
+        // PNPR, a wide peak like a formant
+        let mut wide: HowlingSuppressor = lone_peak();
+        wide.power[105] = 1e-2 / 4.0;
+        assert!(!wide.is_howling(100, floor));
+
+        // PHPR, an octave above like voiced speech
+        let mut harmonic: HowlingSuppressor = lone_peak();
+        harmonic.power[200] = 1e-2 / 10.0;
+        assert!(!harmonic.is_howling(100, floor));
+        let mut subharmonic: HowlingSuppressor = lone_peak();
+        subharmonic.power[50] = 1e-2 / 10.0;
+        assert!(!subharmonic.is_howling(100, floor));
+    }
+
+    #[test]
+    fn sustained_tone_is_notched_at_its_frequency() {
+        let monitor: HowlingMonitor = HowlingMonitor::default();
+        let mut suppressor: HowlingSuppressor =
+            HowlingSuppressor::new(monitor.clone(), 2, SAMPLE_RATE);
+        // Between two bins, so the peak has to be interpolated
+        let input: Vec<f32> = ringing(1005.0, 2.0, 2);
+        let mut output: Vec<f32> = input.clone();
+        suppress(&mut suppressor, &mut output);
+
+        let report: HowlingReport = monitor.report();
+        assert_eq!(report.frequencies.len(), 1);
+        assert!((report.frequencies[0] - 1005.0).abs() < 2.0, "{report:?}");
+        assert_eq!(report.gain_reduction_db, 0.0);
+
+        // Both channels lose the tone over the last half second
+        let tail: usize = SAMPLE_RATE;
+        let energy = |samples: &[f32]| samples.iter().map(|x| x * x).sum::<f32>();
+        for channel in 0..2 {
+            let channel_energy = |samples: &[f32]| {
+                energy(
+                    &samples[samples.len() - tail..]
+                        .iter()
+                        .skip(channel)
+                        .step_by(2)
+                        .copied()
+                        .collect::<Vec<f32>>(),
+                )
+            };
+            assert!(channel_energy(&output) < channel_energy(&input) * 0.01);
+        }
+    }
+
+    #[test]
+    fn speech_is_left_alone() {
+        let monitor: HowlingMonitor = HowlingMonitor::default();
+        let mut suppressor: HowlingSuppressor =
+            HowlingSuppressor::new(monitor.clone(), 1, SAMPLE_RATE);
+        let input: Vec<f32> = load_file(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/speech.wav"),
+            SAMPLE_RATE,
+        )
+        .expect("Failed to load fixture");
+        let mut output: Vec<f32> = input.clone();
+        suppress(&mut suppressor, &mut output);
+
+        assert!(!monitor.report().active());
+        assert_eq!(output, input);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use ringbuf::{
+    HeapCons, HeapProd,
+    traits::{Consumer, Observer, Producer},
+};
+use tracing::info;
+
+use crate::voice_app::app_tracing::TRACING_TARGET;
+
+// A few samples in a row at full scale are a flattened waveform rather than a loud peak
+const CLIP_LEVEL: f32 = 0.99;
+const CLIP_RUN: usize = 3;
+// Levels are judged a second at a time, and a clip stays reported a while so it can be read
+const CLIP_HOLD_WINDOWS: usize = 5;
+// About -40 dBFS, cheap interfaces sit well below that
+const DC_OFFSET_LEVEL: f32 = 0.01;
+// About -90 dBFS, under the self noise of any working microphone
+const DEAD_LEVEL: f32 = 3e-5;
+// Speech peaking under -30 dBFS leaves the noise suppression and the codec little to work with
+const LOW_PEAK_LEVEL: f32 = 0.03;
+// A second counts as speech when it is 10 dB over the quietest one so far, the floor creeping up
+// slowly so that a noisier room is followed
+const SPEECH_OVER_FLOOR: f32 = 3.16;
+const FLOOR_RISE: f32 = 1.05;
+const LOW_LEVEL_WINDOWS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputProblem {
+    Silent,
+    DeadChannel(usize),
+    Clipping,
+    DcOffset,
+    TooQuiet,
+}
+
+impl Display for InputProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputProblem::Silent => f.write_str(
+                "Input is silent, check that the mic is plugged in and not muted in the OS or on the mic",
+            ),
+            InputProblem::DeadChannel(channel) => write!(
+                f,
+                "Input channel {} is silent, pick the channels the mic is on under Channels",
+                channel + 1
+            ),
+            InputProblem::Clipping => f.write_str("Input is clipping, reduce the OS mic gain"),
+            InputProblem::DcOffset => f.write_str(
+                "Input has a DC offset, the mic or its interface may be faulty, try another port or interface",
+            ),
+            InputProblem::TooQuiet => f.write_str(
+                "Input is very quiet, raise the OS mic gain or move closer to the mic",
+            ),
+        }
+    }
+}
+
+// Problems currently found on a capture device
+#[derive(Clone, Default)]
+pub struct InputCheck(Arc<Mutex<Vec<InputProblem>>>);
+
+impl InputCheck {
+    pub fn problems(&self) -> Vec<InputProblem> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, problems: Vec<InputProblem>) {
+        *self.0.lock().unwrap() = problems;
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ChannelLevels {
+    sum: f32,
+    squares: f32,
+    peak: f32,
+    run: usize,
+    clipped: bool,
+}
+
+// Sums up the levels of the raw device channels, before any mixing hides which one is at fault.
+// It runs in the capture callback, so a second of levels at a time is handed over to the checker
+// and a window is dropped if the checker falls behind
+pub struct InputMeter {
+    producer: HeapProd<ChannelLevels>,
+    window: usize,
+    frames: usize,
+    levels: Vec<ChannelLevels>,
+}
+
+impl InputMeter {
+    pub fn new(
+        producer: HeapProd<ChannelLevels>,
+        device_channels: usize,
+        sample_rate: u32,
+    ) -> Self {
+        InputMeter {
+            producer,
+            window: sample_rate as usize,
+            frames: 0,
+            levels: vec![ChannelLevels::default(); device_channels],
+        }
+    }
+
+    pub fn push(&mut self, frame: &[f32]) {
+        for (levels, sample) in self.levels.iter_mut().zip(frame.iter()) {
+            levels.sum += sample;
+            levels.squares += sample * sample;
+            levels.peak = levels.peak.max(sample.abs());
+            if sample.abs() >= CLIP_LEVEL {
+                levels.run += 1;
+                levels.clipped |= levels.run >= CLIP_RUN;
+            } else {
+                levels.run = 0;
+            }
+        }
+        self.frames += 1;
+        if self.frames == self.window {
+            self.frames = 0;
+            if self.producer.vacant_len() >= self.levels.len() {
+                self.producer.push_slice(&self.levels);
+            }
+            self.levels.fill(ChannelLevels::default());
+        }
+    }
+}
+
+// Judges the windows of levels from an `InputMeter`, away from the capture callback
+pub struct InputChecker {
+    check: InputCheck,
+    channels: Vec<usize>,
+    window: usize,
+    consumer: HeapCons<ChannelLevels>,
+    levels: Vec<ChannelLevels>,
+    clip_hold: usize,
+    floor: Option<f32>,
+    quiet_windows: usize,
+}
+
+impl InputChecker {
+    // `channels` are the device channels actually captured
+    pub fn new(
+        check: InputCheck,
+        channels: Vec<usize>,
+        device_channels: usize,
+        sample_rate: u32,
+        consumer: HeapCons<ChannelLevels>,
+    ) -> Self {
+        InputChecker {
+            check,
+            channels,
+            window: sample_rate as usize,
+            consumer,
+            levels: vec![ChannelLevels::default(); device_channels],
+            clip_hold: 0,
+            floor: None,
+            quiet_windows: 0,
+        }
+    }
+
+    // False once the meter is dropped along with its input stream
+    pub fn is_metered(&self) -> bool {
+        self.consumer.write_is_held()
+    }
+
+    pub fn poll(&mut self) {
+        while self.consumer.occupied_len() >= self.levels.len() {
+            self.consumer.pop_slice(&mut self.levels);
+            self.evaluate();
+        }
+    }
+
+    fn evaluate(&mut self) {
+        let frames: f32 = self.window as f32;
+        let levels: &[ChannelLevels] = &self.levels;
+
+        let mut problems: Vec<InputProblem> = Vec::new();
+        let dead: Vec<usize> = self
+            .channels
+            .iter()
+            .copied()
+            .filter(|x| (levels[*x].squares / frames).sqrt() < DEAD_LEVEL)
+            .collect();
+        if dead.len() == self.channels.len() {
+            problems.push(InputProblem::Silent);
+        } else {
+            problems.extend(dead.iter().map(|x| InputProblem::DeadChannel(*x)));
+        }
+
+        if self.channels.iter().any(|x| levels[*x].clipped) {
+            self.clip_hold = CLIP_HOLD_WINDOWS;
+        } else {
+            self.clip_hold = self.clip_hold.saturating_sub(1);
+        }
+        if self.clip_hold > 0 {
+            problems.push(InputProblem::Clipping);
+        }
+
+        if self
+            .channels
+            .iter()
+            .any(|x| (levels[*x].sum / frames).abs() > DC_OFFSET_LEVEL)
+        {
+            problems.push(InputProblem::DcOffset);
+        }
+
+        // Too quiet only means something while the user talks, which is told apart from the room
+        // by rising well over the quietest stretch so far
+        let live: Vec<usize> = self
+            .channels
+            .iter()
+            .copied()
+            .filter(|x| !dead.contains(x))
+            .collect();
+        if let Some(loudest) = live
+            .iter()
+            .map(|x| &levels[*x])
+            .max_by(|a, b| a.squares.total_cmp(&b.squares))
+        {
+            let rms: f32 = (loudest.squares / frames).sqrt();
+            let floor: f32 = self.floor.map_or(rms, |x| (x * FLOOR_RISE).min(rms));
+            self.floor = Some(floor);
+            if rms > floor * SPEECH_OVER_FLOOR {
+                self.quiet_windows = if loudest.peak >= LOW_PEAK_LEVEL {
+                    0
+                } else {
+                    (self.quiet_windows + 1).min(LOW_LEVEL_WINDOWS)
+                };
+            }
+        }
+        if self.quiet_windows == LOW_LEVEL_WINDOWS {
+            problems.push(InputProblem::TooQuiet);
+        }
+
+        let previous: Vec<InputProblem> = self.check.problems();
+        if problems != previous {
+            for problem in problems.iter().filter(|x| !previous.contains(x)) {
+                info!(target: TRACING_TARGET, "{problem}");
+            }
+            self.check.set(problems);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{HeapRb, traits::Split};
+
+    use super::*;
+
+    // Windows of 100 frames
+    const SAMPLE_RATE: u32 = 100;
+
+    fn meter_and_checker(
+        channels: Vec<usize>,
+        device_channels: usize,
+    ) -> (InputMeter, InputChecker) {
+        let (producer, consumer) = HeapRb::<ChannelLevels>::new(device_channels * 2).split();
+        (
+            InputMeter::new(producer, device_channels, SAMPLE_RATE),
+            InputChecker::new(
+                InputCheck::default(),
+                channels,
+                device_channels,
+                SAMPLE_RATE,
+                consumer,
+            ),
+        )
+    }
+
+    // A window with a tone of this amplitude on each device channel
+    fn window(meter: &mut InputMeter, amplitudes: &[f32]) {
+        for i in 0..SAMPLE_RATE {
+            let frame: Vec<f32> = amplitudes
+                .iter()
+                .map(|x| x * (i as f32 * 0.7).sin())
+                .collect();
+            meter.push(&frame);
+        }
+    }
+
+    #[test]
+    fn silent_channels_are_reported() {
+        let (mut meter, mut checker) = meter_and_checker(vec![0, 1], 2);
+        window(&mut meter, &[0.1, 0.0]);
+        checker.poll();
+        assert_eq!(checker.check.problems(), vec![InputProblem::DeadChannel(1)]);
+
+        window(&mut meter, &[0.0, 0.0]);
+        checker.poll();
+        assert_eq!(checker.check.problems(), vec![InputProblem::Silent]);
+
+        // Channels that aren't captured don't matter
+        let (mut meter, mut checker) = meter_and_checker(vec![0], 2);
+        window(&mut meter, &[0.1, 0.0]);
+        checker.poll();
+        assert!(checker.check.problems().is_empty());
+    }
+
+    #[test]
+    fn clipping_stays_reported_a_while() {
+        let (mut meter, mut checker) = meter_and_checker(vec![0], 1);
+        window(&mut meter, &[2.0]);
+        checker.poll();
+        assert_eq!(checker.check.problems(), vec![InputProblem::Clipping]);
+        for _ in 1..CLIP_HOLD_WINDOWS {
+            window(&mut meter, &[0.1]);
+            checker.poll();
+            assert_eq!(checker.check.problems(), vec![InputProblem::Clipping]);
+        }
+        window(&mut meter, &[0.1]);
+        checker.poll();
+        assert!(checker.check.problems().is_empty());
+    }
+
+    #[test]
+    fn checker_stops_with_the_meter() {
+        let (mut meter, mut checker) = meter_and_checker(vec![0], 1);
+        // Windows the checker has no room for are dropped rather than blocking the callback
+        for _ in 0..3 {
+            window(&mut meter, &[0.1]);
+        }
+        assert_eq!(checker.consumer.occupied_len(), 2);
+        assert!(checker.is_metered());
+        drop(meter);
+        assert!(!checker.is_metered());
+        checker.poll();
+        assert_eq!(checker.consumer.occupied_len(), 0);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use rustfft::{FftPlanner, num_complex::Complex};
+
+use crate::voice_app::endpoint::InputEndpoint;
+
+// Delays are estimated on the last 170 ms of every microphone, about once a second.
+// Sizes at 48 kHz, scaled to the processing rate
+const ALIGN_WINDOW: usize = 8192;
+const ALIGN_INTERVAL: usize = 48000;
+// Covers the buffering of a second capture chain plus a few meters of distance between the mics
+const MAX_ALIGN_LAG: usize = 2400;
+// About -50 dBFS, quieter windows carry too little to correlate
+const ALIGN_MIN_RMS: f32 = 0.003;
+// Below this the mics most likely picked up different sounds
+const ALIGN_MIN_CORRELATION: f32 = 0.3;
+// Smaller changes are jitter and would only add clicks
+const ALIGN_TOLERANCE: isize = 2;
+// A new delay fades in over this long instead of jumping
+const DELAY_CROSSFADE_MS: usize = 5;
+
+struct InputControlState {
+    gain: f32,
+    delay_ms: f32,
+}
+
+impl Default for InputControlState {
+    fn default() -> Self {
+        InputControlState {
+            gain: 1.0,
+            delay_ms: 0.0,
+        }
+    }
+}
+
+// Gain of a single microphone and the delay currently lining it up with the others
+#[derive(Clone, Default)]
+pub struct InputControl(Arc<Mutex<InputControlState>>);
+
+impl InputControl {
+    pub fn gain(&self) -> f32 {
+        self.0.lock().unwrap().gain
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.0.lock().unwrap().gain = gain;
+    }
+
+    pub fn delay_ms(&self) -> f32 {
+        self.0.lock().unwrap().delay_ms
+    }
+
+    pub fn set_delay_ms(&self, delay_ms: f32) {
+        self.0.lock().unwrap().delay_ms = delay_ms;
+    }
+}
+
+// A microphone mixed into the outgoing stream next to the main one
+#[derive(Clone)]
+pub struct ExtraInput {
+    pub endpoint: InputEndpoint,
+    pub control: InputControl,
+}
+
+// Two mics hearing the same voice at slightly different times comb filter each other when summed.
+// The lag of every mic behind the main one is found by cross correlation and the earlier ones are delayed to match
+pub struct MicAligner {
+    window: usize,
+    interval: usize,
+    max_lag: usize,
+    histories: Vec<VecDeque<f32>>,
+    lags: Vec<isize>,
+    candidates: Vec<Option<isize>>,
+    since_update: usize,
+    planner: FftPlanner<f32>,
+}
+
+impl MicAligner {
+    pub fn new(mics: usize, sample_rate: usize) -> Self {
+        let window: usize = ALIGN_WINDOW * sample_rate / 48000;
+        MicAligner {
+            window,
+            interval: ALIGN_INTERVAL * sample_rate / 48000,
+            max_lag: MAX_ALIGN_LAG * sample_rate / 48000,
+            histories: vec![VecDeque::with_capacity(window); mics],
+            lags: vec![0; mics],
+            candidates: vec![None; mics],
+            since_update: 0,
+            planner: FftPlanner::new(),
+        }
+    }
+
+    // `frames` holds the same stretch of mono audio from every mic, the main one first.
+    // Returns the delay in samples for every mic whenever the alignment changes
+    pub fn push(&mut self, frames: &[Vec<f32>]) -> Option<Vec<usize>> {
+        for (history, frame) in self.histories.iter_mut().zip(frames.iter()) {
+            history.extend(frame.iter());
+            let excess: usize = history.len().saturating_sub(self.window);
+            history.drain(..excess);
+        }
+        self.since_update += frames[0].len();
+        if self.since_update < self.interval || self.histories[0].len() < self.window {
+            return None;
+        }
+        self.since_update = 0;
+
+        let main: Vec<f32> = self.histories[0].iter().copied().collect();
+        let main_energy: f32 = main.iter().map(|x| x * x).sum();
+        if (main_energy / self.window as f32).sqrt() < ALIGN_MIN_RMS {
+            return None;
+        }
+
+        let mut changed: bool = false;
+        for i in 1..self.histories.len() {
+            let extra: Vec<f32> = self.histories[i].iter().copied().collect();
+            let Some(lag) = self.find_lag(&main, main_energy, &extra) else {
+                self.candidates[i] = None;
+                continue;
+            };
+            // A new lag has to show up twice in a row before the delays move
+            let confirmed: bool = self.candidates[i]
+                .is_some_and(|candidate| (candidate - lag).abs() <= ALIGN_TOLERANCE);
+            self.candidates[i] = Some(lag);
+            if confirmed && (self.lags[i] - lag).abs() > ALIGN_TOLERANCE {
+                self.lags[i] = lag;
+                changed = true;
+            }
+        }
+
+        changed.then(|| {
+            let latest: isize = self.lags.iter().copied().max().unwrap_or(0).max(0);
+            self.lags
+                .iter()
+                .map(|lag| (latest - lag) as usize)
+                .collect()
+        })
+    }
+
+    // Mics can lag up to the maximum either way, so one may need twice that to match another
+    pub fn max_delay(&self) -> usize {
+        self.max_lag * 2
+    }
+
+    // Positive lags mean `extra` hears things after `main`
+    fn find_lag(&mut self, main: &[f32], main_energy: f32, extra: &[f32]) -> Option<isize> {
+        let extra_energy: f32 = extra.iter().map(|x| x * x).sum();
+        if extra_energy <= 0.0 {
+            return None;
+        }
+
+        let size: usize = (self.window * 2).next_power_of_two();
+        let mut main_spectrum: Vec<Complex<f32>> = vec![Complex::default(); size];
+        let mut extra_spectrum: Vec<Complex<f32>> = vec![Complex::default(); size];
+        for (bin, sample) in main_spectrum.iter_mut().zip(main.iter()) {
+            bin.re = *sample;
+        }
+        for (bin, sample) in extra_spectrum.iter_mut().zip(extra.iter()) {
+            bin.re = *sample;
+        }
+
+        self.planner
+            .plan_fft_forward(size)
+            .process(&mut main_spectrum);
+        self.planner
+            .plan_fft_forward(size)
+            .process(&mut extra_spectrum);
+        for (extra_bin, main_bin) in extra_spectrum.iter_mut().zip(main_spectrum.iter()) {
+            *extra_bin *= main_bin.conj();
+        }
+        self.planner
+            .plan_fft_inverse(size)
+            .process(&mut extra_spectrum);
+
+        // Negative lags wrap around to the end of the correlation
+        let (lag, peak) = (-(self.max_lag as isize)..=self.max_lag as isize)
+            .map(|lag| {
+                (
+                    lag,
+                    extra_spectrum[lag.rem_euclid(size as isize) as usize].re,
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let correlation: f32 = peak / size as f32 / (main_energy * extra_energy).sqrt();
+
+        (correlation >= ALIGN_MIN_CORRELATION).then_some(lag)
+    }
+}
+
+// Delays a mic by whole samples. When the delay changes, audio at the old and the new delay is crossfaded
+// so the jump doesn't click
+pub struct MicDelay {
+    channels: usize,
+    // Interleaved, always `history` frames before the newest frame
+    buffer: VecDeque<f32>,
+    history: usize,
+    delay: usize,
+    previous: usize,
+    fade: usize,
+    fade_length: usize,
+}
+
+impl MicDelay {
+    pub fn new(channels: usize, max_delay: usize, sample_rate: usize) -> Self {
+        MicDelay {
+            channels,
+            buffer: VecDeque::from(vec![0.0; max_delay * channels]),
+            history: max_delay,
+            delay: 0,
+            previous: 0,
+            fade: 0,
+            fade_length: (sample_rate * DELAY_CROSSFADE_MS / 1000).max(1),
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: usize) {
+        let delay: usize = delay.min(self.history);
+        if delay != self.delay {
+            self.previous = self.delay;
+            self.delay = delay;
+            self.fade = self.fade_length;
+        }
+    }
+
+    // Adds `frame` delayed and scaled by `gain` into `output`
+    pub fn process(&mut self, frame: &[f32], gain: f32, output: &mut [f32]) {
+        let channels: usize = self.channels;
+        self.buffer.extend(frame.iter());
+        for (i, mixed) in output.iter_mut().enumerate().take(frame.len()) {
+            let newest: usize = self.history * channels + i;
+            let sample: f32 = self.buffer[newest - self.delay * channels];
+            let sample: f32 = if self.fade > 0 {
+                let weight: f32 = self.fade as f32 / self.fade_length as f32;
+                if i % channels == channels - 1 {
+                    self.fade -= 1;
+                }
+                self.buffer[newest - self.previous * channels] * weight + sample * (1.0 - weight)
+            } else {
+                sample
+            };
+            *mixed += sample * gain;
+        }
+        self.buffer.drain(..frame.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::test_signals::noise;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    #[test]
+    fn aligner_delays_the_mic_hearing_things_first() {
+        let lag: usize = 37;
+        // Noise only correlates at its own lag
+        let signal: Vec<f32> = noise(SAMPLE_RATE * 3, 0.1, 1);
+        let late: Vec<f32> = [vec![0.0; lag], signal.clone()].concat();
+        let mut aligner: MicAligner = MicAligner::new(2, SAMPLE_RATE);
+        let mut delays: Option<Vec<usize>> = None;
+        for (main, extra) in signal.chunks(480).zip(late.chunks(480)) {
+            delays = aligner.push(&[main.to_vec(), extra.to_vec()]).or(delays);
+        }
+        assert_eq!(delays, Some(vec![lag, 0]));
+    }
+
+    #[test]
+    fn delay_shifts_whole_frames_of_every_channel() {
+        let mut delay: MicDelay = MicDelay::new(2, 100, SAMPLE_RATE);
+        delay.set_delay(3);
+        // Let the crossfade from no delay finish first
+        let mut output: Vec<f32> = vec![0.0; 1000];
+        delay.process(&[0.0; 1000], 1.0, &mut output);
+
+        let input: Vec<f32> = (1..=20).map(|x| x as f32).collect();
+        let mut output: Vec<f32> = vec![0.0; 20];
+        delay.process(&input, 0.5, &mut output);
+        assert_eq!(output[..6], [0.0; 6]);
+        assert_eq!(
+            output[6..],
+            input[..14].iter().map(|x| x * 0.5).collect::<Vec<f32>>()
+        );
+    }
+
+    #[test]
+    fn delay_changes_are_crossfaded() {
+        let frequency: f32 = 200.0;
+        let signal: Vec<f32> = (0..SAMPLE_RATE)
+            .map(|i| (std::f32::consts::TAU * frequency * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect();
+        let mut delay: MicDelay = MicDelay::new(1, 2400, SAMPLE_RATE);
+        let mut output: Vec<f32> = vec![0.0; signal.len()];
+        for (i, (frame, output)) in signal.chunks(480).zip(output.chunks_mut(480)).enumerate() {
+            if i == 20 {
+                // Half a period, the worst case for a hard switch
+                delay.set_delay(120);
+            }
+            delay.process(frame, 1.0, output);
+        }
+        // A 200 Hz sine moves at most 2 pi 200 / 48000 per sample, a jump would move up to 2
+        let largest_step: f32 = output
+            .windows(2)
+            .map(|x| (x[1] - x[0]).abs())
+            .fold(0.0, f32::max);
+        assert!(largest_step < 0.05, "{largest_step}");
+        let end: usize = signal.len() - 1;
+        assert_eq!(output[end], signal[end - 120]);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    f32::consts::{PI, TAU},
+    fmt::Display,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use ringbuf::traits::Producer;
+use rustfft::{FftPlanner, num_complex::Complex};
+
+use crate::voice_app::{audio::P, file_source::resample};
+
+pub const PROBE_LEAD_SECONDS: f32 = 0.5;
+pub const PROBE_TAIL_SECONDS: f32 = 1.5;
+const PROBE_AMPLITUDE: f32 = 0.5;
+const CHIRP_SECONDS: f32 = 0.5;
+const CHIRP_START_FREQUENCY: f32 = 100.0;
+const CHIRP_END_FREQUENCY: f32 = 8000.0;
+const MLS_ORDER: u32 = 15;
+const MIN_PEAK_RATIO: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeSignal {
+    Chirp,
+    Mls,
+}
+
+impl ProbeSignal {
+    pub const ALL: [ProbeSignal; 2] = [ProbeSignal::Chirp, ProbeSignal::Mls];
+
+    pub fn generate(&self, sample_rate: usize) -> Vec<f32> {
+        match self {
+            ProbeSignal::Chirp => {
+                let length: usize = (CHIRP_SECONDS * sample_rate as f32) as usize;
+                let end_frequency: f32 = CHIRP_END_FREQUENCY.min(sample_rate as f32 * 0.45);
+                let sweep_rate: f32 = (end_frequency / CHIRP_START_FREQUENCY).ln();
+                let fade: usize = sample_rate / 200;
+                (0..length)
+                    .map(|i| {
+                        let t: f32 = i as f32 / sample_rate as f32;
+                        let phase: f32 = TAU * CHIRP_START_FREQUENCY * CHIRP_SECONDS / sweep_rate
+                            * ((t / CHIRP_SECONDS * sweep_rate).exp() - 1.0);
+                        let edge: usize = i.min(length - 1 - i);
+                        let envelope: f32 = if edge < fade {
+                            0.5 - 0.5 * (PI * edge as f32 / fade as f32).cos()
+                        } else {
+                            1.0
+                        };
+                        phase.sin() * envelope * PROBE_AMPLITUDE
+                    })
+                    .collect()
+            }
+            ProbeSignal::Mls => {
+                let mut register: u32 = 1;
+                (0..(1 << MLS_ORDER) - 1)
+                    .map(|_| {
+                        let bit: u32 = (register ^ (register >> 1)) & 1;
+                        register = (register >> 1) | (bit << (MLS_ORDER - 1));
+                        if bit == 1 {
+                            PROBE_AMPLITUDE
+                        } else {
+                            -PROBE_AMPLITUDE
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Display for ProbeSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeSignal::Chirp => f.write_str("Chirp"),
+            ProbeSignal::Mls => f.write_str("MLS"),
+        }
+    }
+}
+
+pub struct ProbePlayback {
+    signal: Vec<f32>,
+    lead: usize,
+    position: usize,
+    started: Arc<OnceLock<Instant>>,
+}
+
+impl ProbePlayback {
+    pub fn new(signal: Vec<f32>, lead: usize, started: Arc<OnceLock<Instant>>) -> Self {
+        ProbePlayback {
+            signal,
+            lead,
+            position: 0,
+            started,
+        }
+    }
+
+    pub fn start(&self) {
+        self.started.get_or_init(Instant::now);
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let sample: f32 = self
+            .position
+            .checked_sub(self.lead)
+            .and_then(|x| self.signal.get(x).copied())
+            .unwrap_or(0.0);
+        self.position += 1;
+        sample
+    }
+}
+
+pub struct ProbeCapture {
+    producer: P,
+    started: Arc<OnceLock<Instant>>,
+}
+
+impl ProbeCapture {
+    pub fn new(producer: P, started: Arc<OnceLock<Instant>>) -> Self {
+        ProbeCapture { producer, started }
+    }
+
+    pub fn start(&self) {
+        self.started.get_or_init(Instant::now);
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        let _ = self.producer.try_push(sample);
+    }
+}
+
+// The probe is played straight into the output callback and picked up in the input callback, so the round trip
+// covers both devices and the air between them but none of the ring buffers in between
+#[derive(Debug, Clone)]
+pub struct LatencyReport {
+    pub round_trip_ms: f32,
+}
+
+impl Display for LatencyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Round trip: {:.1} ms (output device, speaker to mic and input device)",
+            self.round_trip_ms
+        )
+    }
+}
+
+// Returns the offset in `capture` where `probe` matches best, or None if no clear peak is found
+pub fn find_probe(capture: &[f32], probe: &[f32]) -> Option<usize> {
+    if capture.len() < probe.len() {
+        return None;
+    }
+
+    let size: usize = (capture.len() + probe.len()).next_power_of_two();
+    let mut planner: FftPlanner<f32> = FftPlanner::new();
+
+    let mut capture_spectrum: Vec<Complex<f32>> = vec![Complex::default(); size];
+    let mut probe_spectrum: Vec<Complex<f32>> = vec![Complex::default(); size];
+    for (bin, sample) in capture_spectrum.iter_mut().zip(capture.iter()) {
+        bin.re = *sample;
+    }
+    for (bin, sample) in probe_spectrum.iter_mut().zip(probe.iter()) {
+        bin.re = *sample;
+    }
+
+    planner
+        .plan_fft_forward(size)
+        .process(&mut capture_spectrum);
+    planner.plan_fft_forward(size).process(&mut probe_spectrum);
+    for (capture_bin, probe_bin) in capture_spectrum.iter_mut().zip(probe_spectrum.iter()) {
+        *capture_bin *= probe_bin.conj();
+    }
+    planner
+        .plan_fft_inverse(size)
+        .process(&mut capture_spectrum);
+
+    let correlation: Vec<f32> = capture_spectrum[..capture.len() - probe.len() + 1]
+        .iter()
+        .map(|x| x.re.abs())
+        .collect();
+    let (peak_offset, peak) = correlation
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    let mean: f32 = correlation.iter().sum::<f32>() / correlation.len() as f32;
+
+    if *peak > mean * MIN_PEAK_RATIO {
+        Some(peak_offset)
+    } else {
+        None
+    }
+}
+
+pub struct LatencyMeasurement {
+    pub probe: Vec<f32>,
+    pub probe_sample_rate: usize,
+    pub probe_lead: usize,
+    pub probe_started: Arc<OnceLock<Instant>>,
+    pub capture_sample_rate: usize,
+    pub capture_started: Arc<OnceLock<Instant>>,
+}
+
+impl LatencyMeasurement {
+    pub fn new(signal: ProbeSignal, probe_sample_rate: usize, capture_sample_rate: usize) -> Self {
+        LatencyMeasurement {
+            probe: signal.generate(probe_sample_rate),
+            probe_sample_rate,
+            probe_lead: (PROBE_LEAD_SECONDS * probe_sample_rate as f32) as usize,
+            probe_started: Arc::new(OnceLock::new()),
+            capture_sample_rate,
+            capture_started: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn playback(&self) -> ProbePlayback {
+        ProbePlayback::new(
+            self.probe.clone(),
+            self.probe_lead,
+            self.probe_started.clone(),
+        )
+    }
+
+    pub fn capture(&self, producer: P) -> ProbeCapture {
+        ProbeCapture::new(producer, self.capture_started.clone())
+    }
+
+    pub fn capture_len(&self) -> usize {
+        let probe_seconds: f32 = self.probe.len() as f32 / self.probe_sample_rate as f32;
+        ((PROBE_LEAD_SECONDS + probe_seconds + PROBE_TAIL_SECONDS)
+            * self.capture_sample_rate as f32) as usize
+    }
+
+    pub fn round_trip_ms(&self, capture: &[f32]) -> Result<f32, String> {
+        let (Some(capture_started), Some(probe_started)) =
+            (self.capture_started.get(), self.probe_started.get())
+        else {
+            return Err(String::from("Streams did not start"));
+        };
+
+        let capture: Vec<f32> = resample(capture, self.capture_sample_rate, self.probe_sample_rate);
+        let offset: usize = find_probe(&capture, &self.probe)
+            .ok_or(String::from("Probe signal was not detected in the input"))?;
+
+        // Capture sample `k` is delivered at `capture_started + k / rate`, probe sample 0 is written at `probe_started + lead / rate`
+        let streams_offset_ms: f32 = if capture_started >= probe_started {
+            capture_started.duration_since(*probe_started).as_secs_f32() * 1000.0
+        } else {
+            -probe_started.duration_since(*capture_started).as_secs_f32() * 1000.0
+        };
+        Ok(streams_offset_ms
+            + (offset as f32 - self.probe_lead as f32) * 1000.0 / self.probe_sample_rate as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::voice_app::test_signals::noise;
+
+    #[test]
+    fn probes_are_found_in_noise() {
+        for signal in ProbeSignal::ALL {
+            let probe: Vec<f32> = signal.generate(48000);
+            let mut capture: Vec<f32> = noise(probe.len() + 48000, 0.1, 1);
+            for (sample, x) in capture[12345..].iter_mut().zip(probe.iter()) {
+                *sample += x * 0.2;
+            }
+            assert_eq!(find_probe(&capture, &probe), Some(12345), "{signal}");
+            assert_eq!(
+                find_probe(&noise(probe.len() + 48000, 0.1, 1), &probe),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn playback_leads_with_silence() {
+        let measurement: LatencyMeasurement =
+            LatencyMeasurement::new(ProbeSignal::Chirp, 48000, 48000);
+        let mut playback: ProbePlayback = measurement.playback();
+        let played: Vec<f32> = (0..measurement.probe_lead + measurement.probe.len() + 10)
+            .map(|_| playback.next_sample())
+            .collect();
+        assert!(played[..measurement.probe_lead].iter().all(|x| *x == 0.0));
+        assert_eq!(
+            played[measurement.probe_lead..measurement.probe_lead + measurement.probe.len()],
+            measurement.probe
+        );
+        assert!(
+            played[measurement.probe_lead + measurement.probe.len()..]
+                .iter()
+                .all(|x| *x == 0.0)
+        );
+    }
+
+    #[test]
+    fn round_trip_covers_the_delay_between_streams_and_in_the_capture() {
+        // The capture device runs at another rate and starts 20 ms after the output
+        let measurement: LatencyMeasurement =
+            LatencyMeasurement::new(ProbeSignal::Mls, 48000, 44100);
+        let started: Instant = Instant::now();
+        measurement.probe_started.set(started).unwrap();
+        measurement
+            .capture_started
+            .set(started + Duration::from_millis(20))
+            .unwrap();
+
+        // The probe is heard 50 ms after it is played, so 30 ms into the capture past the lead
+        let probe: Vec<f32> = resample(&measurement.probe, 48000, 44100);
+        let mut capture: Vec<f32> = noise(measurement.capture_len(), 0.05, 1);
+        let offset: usize = (PROBE_LEAD_SECONDS * 44100.0) as usize + 44100 * 30 / 1000;
+        for (sample, x) in capture[offset..].iter_mut().zip(probe.iter()) {
+            *sample += x;
+        }
+
+        let round_trip_ms: f32 = measurement.round_trip_ms(&capture).unwrap();
+        assert!((round_trip_ms - 50.0).abs() < 1.0, "{round_trip_ms}");
+    }
+
+    #[test]
+    fn round_trip_needs_both_streams_started() {
+        let measurement: LatencyMeasurement =
+            LatencyMeasurement::new(ProbeSignal::Chirp, 48000, 48000);
+        measurement.probe_started.set(Instant::now()).unwrap();
+        assert!(
+            measurement
+                .round_trip_ms(&vec![0.0; measurement.capture_len()])
+                .is_err()
+        );
+    }
+}
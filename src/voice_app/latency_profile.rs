@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+use crate::voice_app::opus_encoder::FrameDuration;
+
+// Buffer sizes and fills are in samples per channel, fills are given at 48 kHz and scaled to the
+// internal processing rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatencyProfile {
+    UltraLow,
+    #[default]
+    Balanced,
+    Robust,
+}
+
+impl LatencyProfile {
+    pub const ALL: [LatencyProfile; 3] = [
+        LatencyProfile::UltraLow,
+        LatencyProfile::Balanced,
+        LatencyProfile::Robust,
+    ];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "ultra-low" | "ultralow" => Ok(LatencyProfile::UltraLow),
+            "balanced" => Ok(LatencyProfile::Balanced),
+            "robust" => Ok(LatencyProfile::Robust),
+            _ => Err(format!("Unknown latency profile {value}")),
+        }
+    }
+
+    // Capacity of the ring buffers between stages, always room for a 120 ms Opus packet
+    pub fn buffer_size(&self) -> usize {
+        match self {
+            LatencyProfile::UltraLow => 6144,
+            LatencyProfile::Balanced => 8192,
+            LatencyProfile::Robust => 16384,
+        }
+    }
+
+    pub fn frame_duration(&self) -> FrameDuration {
+        match self {
+            LatencyProfile::UltraLow => FrameDuration::Ms10,
+            LatencyProfile::Balanced => FrameDuration::Ms20,
+            LatencyProfile::Robust => FrameDuration::Ms40,
+        }
+    }
+
+    // Audio a remote source has to queue before it starts playing, after joining or running dry
+    pub fn prebuffer(&self, sample_rate: usize) -> usize {
+        let prebuffer: usize = match self {
+            LatencyProfile::UltraLow => 960,
+            LatencyProfile::Balanced => 1920,
+            LatencyProfile::Robust => 4800,
+        };
+        prebuffer * sample_rate / 48000
+    }
+
+    // Anything queued beyond this is old audio and gets dropped instead of delaying everything after it
+    pub fn max_fill(&self, sample_rate: usize) -> usize {
+        let max_fill: usize = match self {
+            LatencyProfile::UltraLow => 2400,
+            LatencyProfile::Balanced => 4800,
+            LatencyProfile::Robust => 12000,
+        };
+        max_fill * sample_rate / 48000
+    }
+
+    // Mixed audio kept ready ahead of the output device
+    pub fn mix_target_fill(&self, sample_rate: usize) -> usize {
+        let mix_target_fill: usize = match self {
+            LatencyProfile::UltraLow => 960,
+            LatencyProfile::Balanced => 1920,
+            LatencyProfile::Robust => 3840,
+        };
+        mix_target_fill * sample_rate / 48000
+    }
+}
+
+impl Display for LatencyProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatencyProfile::UltraLow => f.write_str("Ultra-low latency"),
+            LatencyProfile::Balanced => f.write_str("Balanced latency"),
+            LatencyProfile::Robust => f.write_str("Robust latency"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::processing_rate::ProcessingRate;
+
+    #[test]
+    fn profiles_parse_by_name() {
+        for (name, profile) in ["ultra-low", "Balanced", "ROBUST"]
+            .into_iter()
+            .zip(LatencyProfile::ALL)
+        {
+            assert_eq!(LatencyProfile::parse(name), Ok(profile));
+        }
+        assert_eq!(
+            LatencyProfile::parse("ultralow"),
+            Ok(LatencyProfile::UltraLow)
+        );
+        assert!(LatencyProfile::parse("fast").is_err());
+    }
+
+    #[test]
+    fn fills_scale_with_the_processing_rate() {
+        for profile in LatencyProfile::ALL {
+            for rate in ProcessingRate::ALL {
+                let sample_rate: usize = rate.sample_rate();
+                let ratio: usize = 48000 / sample_rate;
+                assert_eq!(
+                    profile.prebuffer(sample_rate) * ratio,
+                    profile.prebuffer(48000)
+                );
+                assert_eq!(
+                    profile.max_fill(sample_rate) * ratio,
+                    profile.max_fill(48000)
+                );
+                assert_eq!(
+                    profile.mix_target_fill(sample_rate) * ratio,
+                    profile.mix_target_fill(48000)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn buffers_hold_what_is_queued_in_them() {
+        // 120 ms Opus packets, the largest a peer can send
+        let largest_packet: usize = 48000 * 120 / 1000;
+        for profile in LatencyProfile::ALL {
+            assert!(profile.buffer_size() >= largest_packet);
+            assert!(profile.prebuffer(48000) < profile.max_fill(48000));
+            assert!(profile.max_fill(48000) <= profile.buffer_size());
+            // The mix is stereo
+            assert!(profile.mix_target_fill(48000) * 2 <= profile.buffer_size());
+        }
+    }
+
+    #[test]
+    fn more_robust_profiles_send_longer_frames() {
+        let frames: Vec<usize> = LatencyProfile::ALL
+            .iter()
+            .map(|x| x.frame_duration().samples(48000))
+            .collect();
+        assert!(frames.windows(2).all(|x| x[0] < x[1]), "{frames:?}");
+    }
+}
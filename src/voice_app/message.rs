@@ -1,11 +1,62 @@
-use crate::voice_app::wrapper::DeviceWrapper;
+use crate::voice_app::{
+    codec::CodecKind,
+    cues::Cue,
+    effects::EffectSettings,
+    latency::{LatencyReport, ProbeSignal},
+    latency_profile::LatencyProfile,
+    opus_encoder::OpusSettings,
+    processing_rate::ProcessingRate,
+    protocol::Negotiation,
+    spatial::SpatialMode,
+    transcription::TranscriberSettings,
+    wrapper::DeviceWrapper,
+};
 
 #[derive(Debug, Clone)]
 pub enum Message {
     InputDeviceChange(DeviceWrapper),
     OutputDeviceChange(DeviceWrapper),
+    InputChannelsChange(String),
+    OutputChannelsChange(String),
+    ExtraInputAdded(DeviceWrapper),
+    ExtraInputRemoved(usize),
+    // `None` is the main input device, otherwise the index of an extra one
+    InputGainChange(Option<usize>, f32),
+    ExtraOutputAdded(DeviceWrapper),
+    ExtraOutputRemoved(usize),
+    // `None` is the main output device, otherwise the index of an extra one
+    OutputVolumeChange(Option<usize>, f32),
     PeerAddressChange(String),
     TabSelected(String),
     PeerConnect,
+    PeerNegotiated(Result<Negotiation, String>),
+    PeerDisconnected,
+    CueOutputFinished,
+    MutePressed,
+    CueToggled(Cue, bool),
+    CueVolumeChange(f32),
+    MixerGainChange(usize, f32),
+    MixerMuteToggled(usize, bool),
+    MixerPanChange(usize, Option<f32>),
+    SpatialModeSelected(SpatialMode),
+    LatencyProfileSelected(LatencyProfile),
+    ProcessingRateSelected(ProcessingRate),
+    RecordCallsToggled(bool),
+    MusicModeToggled(bool),
+    PreferredCodecSelected(CodecKind),
+    CodecFallbackToggled(bool),
+    OpusSettingsChange(OpusSettings),
+    TranscriberSettingsChange(TranscriberSettings),
+    EffectSettingsChange(EffectSettings),
+    TransientSuppressionToggled(bool),
     SelfListenPressed,
+    MeasureLatencyPressed,
+    ProbeSignalSelected(ProbeSignal),
+    LatencyMeasured(Result<LatencyReport, String>),
+    AnalyzerRawToggled(bool),
+    Tick,
+    PlaybackPathChange(String),
+    PlaybackPressed,
+    PlaybackLoopToggled(bool),
+    PlaybackGainChange(f32),
 }
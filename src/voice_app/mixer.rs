@@ -0,0 +1,513 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering},
+};
+
+use cpal::Sample;
+use ringbuf::traits::{Consumer, Observer, Producer};
+
+use crate::voice_app::{
+    audio::{C, P},
+    buffer_stats::Xruns,
+    latency_profile::LatencyProfile,
+    spatial::{SpatialMode, Spatializer},
+    time_stretch::TimeStretcher,
+};
+
+// Peak ceiling of the mix, sources summing above it are pulled down by the limiter
+const MIX_CEILING: f32 = 0.98;
+// Fraction of the way back to unity gain the limiter recovers per frame
+const LIMITER_RELEASE: f32 = 0.02;
+const METER_DECAY: f32 = 0.9;
+const METER_RANGE_DB: f32 = 60.0;
+// Automatically placed sources are spread evenly between these pan positions
+const AUTO_SPREAD: f32 = 0.7;
+
+// Set by the UI and read by the mix every frame, so neither ever waits on the other
+struct SourceControls {
+    name: String,
+    gain: AtomicU32,
+    muted: AtomicBool,
+    // Manual placement, NaN while automatically spread
+    pan: AtomicU32,
+    // Peak meter written by the mix
+    level: AtomicU32,
+}
+
+impl SourceControls {
+    fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    fn pan(&self) -> Option<f32> {
+        Some(f32::from_bits(self.pan.load(Ordering::Relaxed))).filter(|x| !x.is_nan())
+    }
+
+    fn level(&self) -> f32 {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+}
+
+struct MixerSource {
+    controls: Arc<SourceControls>,
+    consumer: C,
+    // Mono sources are placed by the spatializer, stereo ones only balanced
+    channels: usize,
+    spatializer: Spatializer,
+    xruns: Xruns,
+    // Waiting for the prebuffer to fill before playing
+    buffering: bool,
+    // Plays slightly faster or slower so the buffer neither creeps up nor runs dry
+    stretcher: TimeStretcher,
+    // Receives the source's audio before gain and placement, e.g. for captions
+    tap: Option<(P, Xruns)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceStrip {
+    pub id: usize,
+    pub name: String,
+    pub gain: f32,
+    pub muted: bool,
+    pub level: f32,
+    pub pan: f32,
+    pub auto_pan: bool,
+}
+
+impl SourceStrip {
+    // Peak level mapped onto 0..1 over the meter range
+    pub fn meter(&self) -> f32 {
+        ((20.0 * (self.level + 1e-9).log10() + METER_RANGE_DB) / METER_RANGE_DB).clamp(0.0, 1.0)
+    }
+}
+
+// Position of the `auto_index`th of `auto_count` automatically placed sources
+fn auto_pan(auto_index: usize, auto_count: usize) -> f32 {
+    if auto_count > 1 {
+        -AUTO_SPREAD + 2.0 * AUTO_SPREAD * auto_index as f32 / (auto_count - 1) as f32
+    } else {
+        0.0
+    }
+}
+
+// Only the mix and setting up a call lock the sources
+#[derive(Default)]
+struct Sources {
+    sources: Vec<MixerSource>,
+    buffer: Vec<f32>,
+    downmix: Vec<f32>,
+    profile: LatencyProfile,
+    sample_rate: usize,
+}
+
+#[derive(Default)]
+struct SharedMixer {
+    sources: Mutex<Sources>,
+    // The UI reaches the controls through their own list, never through the sources
+    controls: Mutex<Vec<Arc<SourceControls>>>,
+    // Index into `SpatialMode::ALL`
+    spatial_mode: AtomicU8,
+}
+
+#[derive(Clone, Default)]
+pub struct Mixer(Arc<SharedMixer>);
+
+impl Mixer {
+    pub fn new(profile: LatencyProfile, sample_rate: usize) -> Self {
+        Mixer(Arc::new(SharedMixer {
+            sources: Mutex::new(Sources {
+                profile,
+                sample_rate,
+                ..Sources::default()
+            }),
+            ..SharedMixer::default()
+        }))
+    }
+
+    pub fn add_source(
+        &self,
+        name: &str,
+        consumer: C,
+        channels: usize,
+        pan: Option<f32>,
+        xruns: Xruns,
+    ) -> usize {
+        let controls: Arc<SourceControls> = Arc::new(SourceControls {
+            name: String::from(name),
+            gain: AtomicU32::new(1.0_f32.to_bits()),
+            muted: AtomicBool::new(false),
+            pan: AtomicU32::new(pan.unwrap_or(f32::NAN).to_bits()),
+            level: AtomicU32::new(0.0_f32.to_bits()),
+        });
+        let mut mixer = self.0.sources.lock().unwrap();
+        let sample_rate: usize = mixer.sample_rate;
+        mixer.sources.push(MixerSource {
+            controls: controls.clone(),
+            consumer,
+            channels,
+            spatializer: Spatializer::new(sample_rate),
+            xruns,
+            buffering: true,
+            stretcher: TimeStretcher::new(channels, sample_rate),
+            tap: None,
+        });
+        let mut list = self.0.controls.lock().unwrap();
+        list.push(controls);
+        list.len() - 1
+    }
+
+    pub fn strips(&self) -> Vec<SourceStrip> {
+        let list = self.0.controls.lock().unwrap();
+        let auto_count: usize = list.iter().filter(|x| x.pan().is_none()).count();
+        let mut auto_index: usize = 0;
+        list.iter()
+            .enumerate()
+            .map(|(id, controls)| {
+                let pan: Option<f32> = controls.pan();
+                SourceStrip {
+                    id,
+                    name: controls.name.clone(),
+                    gain: controls.gain(),
+                    muted: controls.muted(),
+                    level: controls.level(),
+                    pan: pan.unwrap_or_else(|| {
+                        auto_index += 1;
+                        auto_pan(auto_index - 1, auto_count)
+                    }),
+                    auto_pan: pan.is_none(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_tap(&self, id: usize, tap: P, xruns: Xruns) {
+        if let Some(source) = self.0.sources.lock().unwrap().sources.get_mut(id) {
+            source.tap = Some((tap, xruns));
+        }
+    }
+
+    pub fn set_spatial_mode(&self, spatial_mode: SpatialMode) {
+        let index: usize = SpatialMode::ALL
+            .iter()
+            .position(|x| *x == spatial_mode)
+            .unwrap_or_default();
+        self.0.spatial_mode.store(index as u8, Ordering::Relaxed);
+    }
+
+    fn controls(&self, id: usize) -> Option<Arc<SourceControls>> {
+        self.0.controls.lock().unwrap().get(id).cloned()
+    }
+
+    // `None` returns the source to automatic placement
+    pub fn set_pan(&self, id: usize, pan: Option<f32>) {
+        if let Some(controls) = self.controls(id) {
+            let pan: f32 = pan.unwrap_or(f32::NAN);
+            controls.pan.store(pan.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_gain(&self, id: usize, gain: f32) {
+        if let Some(controls) = self.controls(id) {
+            controls.gain.store(gain.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_muted(&self, id: usize, muted: bool) {
+        if let Some(controls) = self.controls(id) {
+            controls.muted.store(muted, Ordering::Relaxed);
+        }
+    }
+
+    // Mixes into interleaved stereo, sources that fell behind are mixed as silence for the missing samples
+    pub fn mix_into(&self, output: &mut [f32]) {
+        let spatial_mode: SpatialMode =
+            SpatialMode::ALL[self.0.spatial_mode.load(Ordering::Relaxed) as usize];
+        let mut mixer = self.0.sources.lock().unwrap();
+        let Sources {
+            sources,
+            buffer,
+            downmix,
+            profile,
+            sample_rate,
+        } = &mut *mixer;
+        let auto_count: usize = sources
+            .iter()
+            .filter(|x| x.controls.pan().is_none())
+            .count();
+        let mut auto_index: usize = 0;
+        for source in sources.iter_mut() {
+            let pan: f32 = source.controls.pan().unwrap_or_else(|| {
+                auto_index += 1;
+                auto_pan(auto_index - 1, auto_count)
+            });
+            let channels: usize = source.channels;
+            buffer.resize(output.len() / 2 * channels, Sample::EQUILIBRIUM);
+            let queued: usize = source.consumer.occupied_len();
+            let max_fill: usize = profile.max_fill(*sample_rate) * channels;
+            // Dropping the oldest audio keeps a burst of packets from delaying the rest of the call,
+            // when the time stretching can't catch up fast enough
+            if queued > max_fill {
+                let excess: usize = queued - max_fill;
+                let dropped: usize = source.consumer.skip(excess - excess % channels);
+                source.xruns.overrun(dropped);
+            }
+            if source.buffering
+                && source.consumer.occupied_len() + source.stretcher.queued()
+                    >= profile.prebuffer(*sample_rate) * channels
+            {
+                source.buffering = false;
+            }
+            let popped: usize = if source.buffering {
+                0
+            } else {
+                source.stretcher.pull(
+                    &mut source.consumer,
+                    buffer,
+                    profile.prebuffer(*sample_rate),
+                )
+            };
+            if popped < buffer.len() && !source.buffering {
+                // An empty source is just not talking, running dry in the middle of a frame is an underrun
+                if popped > 0 {
+                    source.xruns.underrun(buffer.len() - popped);
+                }
+                source.buffering = true;
+            }
+            // A tap that falls behind loses audio rather than holding up playback.
+            // Missing audio is tapped as silence so the tap keeps time with the call
+            if let Some((tap, tap_xruns)) = source.tap.as_mut() {
+                downmix.clear();
+                downmix.extend(
+                    buffer[..popped]
+                        .chunks_exact(channels)
+                        .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+                );
+                downmix.resize(buffer.len() / channels, Sample::EQUILIBRIUM);
+                let pushed: usize = tap.push_slice(downmix);
+                if pushed < downmix.len() {
+                    tap_xruns.overrun(downmix.len() - pushed);
+                }
+            }
+            // Meters keep moving while muted so a muted peer can still be seen talking
+            let controls: &SourceControls = &source.controls;
+            let gain: f32 = controls.gain();
+            let peak: f32 = buffer[..popped]
+                .iter()
+                .fold(0.0_f32, |peak, x| peak.max(x.abs()))
+                * gain;
+            let level: f32 = peak.max(controls.level() * METER_DECAY);
+            controls.level.store(level.to_bits(), Ordering::Relaxed);
+            let gain: f32 = if controls.muted() { 0.0 } else { gain };
+            if channels == 2 {
+                // Panning a stereo source turns down the opposite side
+                let (left, right) = (gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0));
+                for (frame, sample) in output
+                    .chunks_exact_mut(2)
+                    .zip(buffer[..popped].chunks_exact(2))
+                {
+                    frame[0] += sample[0] * left;
+                    frame[1] += sample[1] * right;
+                }
+            } else {
+                // Muted sources still run through the spatializer to keep its delay line continuous
+                source
+                    .spatializer
+                    .process(spatial_mode, pan, gain, &buffer[..popped], output);
+            }
+        }
+    }
+}
+
+pub struct Limiter {
+    gain: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Limiter { gain: 1.0 }
+    }
+}
+
+impl Limiter {
+    pub fn process(&mut self, buffer: &mut [f32]) {
+        let peak: f32 = buffer.iter().fold(0.0_f32, |peak, x| peak.max(x.abs()));
+        let target: f32 = if peak > MIX_CEILING {
+            MIX_CEILING / peak
+        } else {
+            1.0
+        };
+        // Gain drops to the target within the frame and recovers slowly to avoid pumping
+        let next: f32 = if target < self.gain {
+            target
+        } else {
+            self.gain + (target - self.gain) * LIMITER_RELEASE
+        };
+        let step: f32 = (next - self.gain) / buffer.len().max(1) as f32;
+        for (i, sample) in buffer.iter_mut().enumerate() {
+            let gain: f32 = self.gain + step * (i + 1) as f32;
+            *sample = (*sample * gain).clamp(-MIX_CEILING, MIX_CEILING);
+        }
+        self.gain = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{
+        HeapRb,
+        traits::{Producer, Split},
+    };
+
+    use super::*;
+    use crate::voice_app::{audio::P, buffer_stats::BufferStats};
+
+    const SAMPLE_RATE: usize = 48000;
+    const FRAME: usize = SAMPLE_RATE / 100;
+
+    // Returns the producer feeding the new source and its stats
+    fn add_source(mixer: &Mixer, channels: usize, pan: Option<f32>) -> (P, BufferStats) {
+        let (producer, consumer): (P, C) = HeapRb::<f32>::new(SAMPLE_RATE * channels).split();
+        let stats: BufferStats = BufferStats::new("Source", SAMPLE_RATE, producer.observe());
+        mixer.add_source("Peer", consumer, channels, pan, stats.xruns());
+        (producer, stats)
+    }
+
+    // Interleaved stereo
+    fn mix(mixer: &Mixer) -> Vec<f32> {
+        let mut output: Vec<f32> = vec![Sample::EQUILIBRIUM; FRAME * 2];
+        mixer.mix_into(&mut output);
+        output
+    }
+
+    #[test]
+    fn sources_are_spread_unless_placed() {
+        let mixer: Mixer = Mixer::new(LatencyProfile::Balanced, SAMPLE_RATE);
+        for pan in [None, Some(0.3), None] {
+            add_source(&mixer, 1, pan);
+        }
+        let strips: Vec<SourceStrip> = mixer.strips();
+        assert_eq!(
+            strips.iter().map(|x| x.pan).collect::<Vec<f32>>(),
+            [-AUTO_SPREAD, 0.3, AUTO_SPREAD]
+        );
+        assert_eq!(
+            strips.iter().map(|x| x.auto_pan).collect::<Vec<bool>>(),
+            [true, false, true]
+        );
+
+        mixer.set_pan(1, None);
+        assert_eq!(mixer.strips()[1].pan, 0.0);
+    }
+
+    #[test]
+    fn controls_never_wait_for_the_mix() {
+        let mixer: Mixer = Mixer::new(LatencyProfile::Balanced, SAMPLE_RATE);
+        add_source(&mixer, 1, None);
+        // Stands in for a mix in progress
+        let _sources = mixer.0.sources.lock().unwrap();
+        mixer.set_gain(0, 0.5);
+        mixer.set_muted(0, true);
+        mixer.set_pan(0, Some(0.2));
+        mixer.set_spatial_mode(SpatialMode::HeadShadow);
+        let strip: SourceStrip = mixer.strips().remove(0);
+        assert_eq!((strip.gain, strip.muted, strip.pan), (0.5, true, 0.2));
+        assert_eq!(mixer.0.spatial_mode.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn sources_play_after_prebuffering_at_their_gain() {
+        let mixer: Mixer = Mixer::new(LatencyProfile::Balanced, SAMPLE_RATE);
+        // Hard left, so the right channel stays silent
+        let (mut producer, stats) = add_source(&mixer, 1, Some(-1.0));
+        mixer.set_gain(0, 0.5);
+        let prebuffer: usize = LatencyProfile::Balanced.prebuffer(SAMPLE_RATE);
+
+        producer.push_slice(&vec![0.4; prebuffer - FRAME]);
+        assert!(mix(&mixer).iter().all(|x| *x == 0.0));
+        producer.push_slice(&vec![0.4; FRAME]);
+        let output: Vec<f32> = mix(&mixer);
+        let (left, right): (Vec<f32>, Vec<f32>) =
+            output.chunks_exact(2).map(|x| (x[0], x[1])).unzip();
+        assert!(left.iter().all(|x| (x - 0.2).abs() < 1e-6));
+        assert!(right.iter().all(|x| x.abs() < 1e-6));
+
+        // Muted sources are silent but still metered
+        mixer.set_muted(0, true);
+        assert!(mix(&mixer).iter().all(|x| *x == 0.0));
+        let strip: SourceStrip = mixer.strips().remove(0);
+        assert!(strip.muted);
+        assert!((strip.level - 0.2).abs() < 1e-6);
+
+        // Running dry in the middle of a frame is an underrun, then the source buffers again
+        mixer.set_muted(0, false);
+        let queued: usize = stats.occupied_len();
+        producer.push_slice(&vec![0.4; FRAME - queued % FRAME + FRAME / 2]);
+        while stats.occupied_len() > 0 {
+            mix(&mixer);
+        }
+        assert_eq!(stats.xruns().underruns(), 1);
+        assert_eq!(stats.xruns().missing(), FRAME as u64 / 2);
+        producer.push_slice(&vec![0.4; FRAME]);
+        assert!(mix(&mixer).iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn stereo_sources_are_balanced() {
+        let mixer: Mixer = Mixer::new(LatencyProfile::Balanced, SAMPLE_RATE);
+        let (mut producer, _stats) = add_source(&mixer, 2, Some(0.5));
+        producer.push_slice(&[0.4, 0.2].repeat(LatencyProfile::Balanced.prebuffer(SAMPLE_RATE)));
+        for frame in mix(&mixer).chunks_exact(2) {
+            assert!((frame[0] - 0.2).abs() < 1e-6);
+            assert!((frame[1] - 0.2).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn overfull_sources_drop_their_oldest_audio() {
+        let mixer: Mixer = Mixer::new(LatencyProfile::Balanced, SAMPLE_RATE);
+        let (mut producer, stats) = add_source(&mixer, 1, None);
+        let max_fill: usize = LatencyProfile::Balanced.max_fill(SAMPLE_RATE);
+        producer.push_slice(&vec![0.1; max_fill + 1000]);
+        mix(&mixer);
+        assert_eq!(stats.xruns().overruns(), 1);
+        assert_eq!(stats.xruns().dropped(), 1000);
+        assert!(stats.occupied_len() <= max_fill);
+    }
+
+    #[test]
+    fn meter_spans_sixty_decibels() {
+        let strip = |level: f32| SourceStrip {
+            id: 0,
+            name: String::new(),
+            gain: 1.0,
+            muted: false,
+            level,
+            pan: 0.0,
+            auto_pan: true,
+        };
+        assert!((strip(1.0).meter() - 1.0).abs() < 1e-3);
+        assert!((strip(0.001).meter()).abs() < 1e-3);
+        assert_eq!(strip(0.0).meter(), 0.0);
+    }
+
+    #[test]
+    fn limiter_holds_the_ceiling_and_recovers() {
+        let mut limiter: Limiter = Limiter::default();
+        let mut loud: Vec<f32> = vec![1.5; FRAME];
+        limiter.process(&mut loud);
+        assert!(loud.iter().all(|x| *x <= MIX_CEILING));
+
+        let mut quiet: Vec<f32> = vec![0.5; FRAME];
+        limiter.process(&mut quiet);
+        assert!(quiet[FRAME - 1] < 0.5);
+        for _ in 0..500 {
+            quiet.fill(0.5);
+            limiter.process(&mut quiet);
+        }
+        assert!((quiet[FRAME - 1] - 0.5).abs() < 1e-3);
+    }
+}
@@ -1,9 +1,38 @@
+pub mod analyzer;
+pub mod app;
 pub mod app_tracing;
 pub mod app_type;
 pub mod audio;
+pub mod buffer_stats;
+pub mod channel_map;
+pub mod codec;
+pub mod cues;
+pub mod denoise;
+pub mod effects;
+pub mod endpoint;
+pub mod file_source;
+pub mod headless;
+pub mod howling;
+pub mod input_check;
+pub mod input_control;
+pub mod latency;
+pub mod latency_profile;
 pub mod message;
 pub mod mic_icon;
+pub mod mixer;
+pub mod ogg_opus;
+pub mod opus_encoder;
+pub mod output_control;
+pub mod processing_rate;
+pub mod protocol;
+pub mod quality;
+pub mod spatial;
 pub mod state;
 pub mod style;
-pub mod voice_app;
+#[cfg(test)]
+pub mod test_signals;
+pub mod time_stretch;
+pub mod timing;
+pub mod transcription;
+pub mod transient;
 pub mod wrapper;
@@ -0,0 +1,457 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::error;
+
+use crate::voice_app::app_tracing::TRACING_TARGET;
+
+const OGG_CRC_POLYNOMIAL: u32 = 0x04c1_1db7;
+const OGG_MAX_SEGMENTS: usize = 255;
+const OGG_HEADER_TYPE_CONTINUED: u8 = 0x01;
+const OGG_HEADER_TYPE_BOS: u8 = 0x02;
+const OGG_HEADER_TYPE_EOS: u8 = 0x04;
+
+// Ogg granule positions for Opus are always counted at 48 kHz, see RFC 7845
+pub const OPUS_GRANULE_RATE: u32 = 48000;
+const OPUS_VENDOR: &str = concat!("p2p-voice ", env!("CARGO_PKG_VERSION"));
+
+// Flush a page roughly every second so an interrupted capture stays playable
+const PAGE_FLUSH_DURATION: u64 = OPUS_GRANULE_RATE as u64;
+
+// Lost packets are filled with packets of empty CELT frames, which decode as silence. Configurations 31 down
+// to 28 are 20, 10, 5 and 2.5 ms long, and a packet holds at most 120 ms
+const GAP_FRAME_CONFIGS: [(u8, u64); 4] = [(31, 960), (30, 480), (29, 240), (28, 120)];
+const GAP_MAX_DURATION: u64 = 5760;
+
+fn ogg_crc_table() -> [u32; 256] {
+    let mut table: [u32; 256] = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc: u32 = (i as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ OGG_CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+fn ogg_crc(table: &[u32; 256], data: &[u8]) -> u32 {
+    data.iter().fold(0_u32, |crc, byte| {
+        (crc << 8) ^ table[(((crc >> 24) as u8) ^ byte) as usize]
+    })
+}
+
+pub struct OggOpusWriter {
+    writer: BufWriter<File>,
+    crc_table: [u32; 256],
+    serial: u32,
+    sequence: u32,
+    channels: u8,
+    granule: u64,
+    // Stream position of the first packet written by position
+    origin: Option<u64>,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    unflushed_duration: u64,
+    continued: bool,
+    finished: bool,
+}
+
+impl OggOpusWriter {
+    pub fn create(
+        path: impl AsRef<Path>,
+        channels: u8,
+        pre_skip: u16,
+        input_sample_rate: u32,
+    ) -> io::Result<Self> {
+        let serial: u32 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.subsec_nanos() ^ x.as_secs() as u32)
+            .unwrap_or(0);
+
+        let mut writer: OggOpusWriter = OggOpusWriter {
+            writer: BufWriter::new(File::create(path)?),
+            crc_table: ogg_crc_table(),
+            serial,
+            sequence: 0,
+            channels,
+            granule: 0,
+            origin: None,
+            segments: Vec::new(),
+            data: Vec::new(),
+            unflushed_duration: 0,
+            continued: false,
+            finished: false,
+        };
+
+        let mut opus_head: Vec<u8> = Vec::with_capacity(19);
+        opus_head.extend_from_slice(b"OpusHead");
+        opus_head.push(1);
+        opus_head.push(channels);
+        opus_head.extend_from_slice(&pre_skip.to_le_bytes());
+        opus_head.extend_from_slice(&input_sample_rate.to_le_bytes());
+        opus_head.extend_from_slice(&0_i16.to_le_bytes());
+        opus_head.push(0);
+        writer.push_packet(&opus_head);
+        writer.write_page(OGG_HEADER_TYPE_BOS)?;
+
+        let mut opus_tags: Vec<u8> = Vec::new();
+        opus_tags.extend_from_slice(b"OpusTags");
+        opus_tags.extend_from_slice(&(OPUS_VENDOR.len() as u32).to_le_bytes());
+        opus_tags.extend_from_slice(OPUS_VENDOR.as_bytes());
+        opus_tags.extend_from_slice(&0_u32.to_le_bytes());
+        writer.push_packet(&opus_tags);
+        writer.write_page(0)?;
+        writer.writer.flush()?;
+
+        Ok(writer)
+    }
+
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let duration: usize = opus::packet::get_nb_samples(packet, OPUS_GRANULE_RATE)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.write_packet_with_duration(packet, duration as u64)
+    }
+
+    // `position` is where the packet starts in the sender's stream at 48 kHz. Gaps left by lost packets are
+    // filled so granule positions keep up, late packets whose place was already filled are left out
+    pub fn write_packet_at(&mut self, packet: &[u8], position: u64) -> io::Result<()> {
+        let expected: u64 = *self.origin.get_or_insert(position) + self.granule;
+        if position < expected {
+            return Ok(());
+        }
+        self.write_gap(position - expected)?;
+        self.write_packet(packet)
+    }
+
+    fn write_gap(&mut self, mut duration: u64) -> io::Result<()> {
+        let stereo: u8 = if self.channels == 2 { 0x04 } else { 0 };
+        while duration > 0 {
+            let Some((config, frame_duration)) = GAP_FRAME_CONFIGS
+                .into_iter()
+                .find(|(_, frame_duration)| *frame_duration <= duration)
+            else {
+                // Less than the shortest frame is left, which can't happen at any Opus rate
+                break;
+            };
+            let frames: u64 = duration.min(GAP_MAX_DURATION) / frame_duration;
+            // Code 3 with a frame count and no frame data, every frame is empty
+            let packet: [u8; 2] = [config << 3 | stereo | 0x03, frames as u8];
+            self.write_packet_with_duration(&packet, frames * frame_duration)?;
+            duration -= frames * frame_duration;
+        }
+        Ok(())
+    }
+
+    fn write_packet_with_duration(&mut self, packet: &[u8], duration: u64) -> io::Result<()> {
+        // Packets that don't fit on their own are split by `write_page` instead
+        if !self.segments.is_empty()
+            && self.segments.len() + packet.len() / 255 + 1 > OGG_MAX_SEGMENTS
+        {
+            self.write_page(0)?;
+        }
+        self.granule += duration;
+        self.unflushed_duration += duration;
+        self.push_packet(packet);
+        if self.unflushed_duration >= PAGE_FLUSH_DURATION {
+            self.write_page(0)?;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_page(OGG_HEADER_TYPE_EOS)?;
+        self.writer.flush()
+    }
+
+    fn push_packet(&mut self, packet: &[u8]) {
+        let mut remaining: usize = packet.len();
+        loop {
+            let lacing: usize = remaining.min(255);
+            self.segments.push(lacing as u8);
+            remaining -= lacing;
+            if lacing < 255 {
+                break;
+            }
+        }
+        self.data.extend_from_slice(packet);
+    }
+
+    fn write_page(&mut self, header_type: u8) -> io::Result<()> {
+        // Packets larger than a single page are split, the remainder is carried over as continued
+        while self.segments.len() > OGG_MAX_SEGMENTS {
+            let data_len: usize = OGG_MAX_SEGMENTS * 255;
+            let segments: Vec<u8> = self.segments.drain(..OGG_MAX_SEGMENTS).collect();
+            let data: Vec<u8> = self.data.drain(..data_len).collect();
+            self.write_raw_page(
+                header_type & !OGG_HEADER_TYPE_EOS,
+                u64::MAX,
+                &segments,
+                &data,
+            )?;
+            self.continued = true;
+        }
+
+        let segments: Vec<u8> = std::mem::take(&mut self.segments);
+        let data: Vec<u8> = std::mem::take(&mut self.data);
+        self.write_raw_page(header_type, self.granule, &segments, &data)?;
+        self.continued = false;
+        self.unflushed_duration = 0;
+        Ok(())
+    }
+
+    fn write_raw_page(
+        &mut self,
+        header_type: u8,
+        granule: u64,
+        segments: &[u8],
+        data: &[u8],
+    ) -> io::Result<()> {
+        let mut page: Vec<u8> = Vec::with_capacity(27 + segments.len() + data.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0);
+        page.push(
+            header_type
+                | if self.continued {
+                    OGG_HEADER_TYPE_CONTINUED
+                } else {
+                    0
+                },
+        );
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&0_u32.to_le_bytes());
+        page.push(segments.len() as u8);
+        page.extend_from_slice(segments);
+        page.extend_from_slice(data);
+
+        let crc: u32 = ogg_crc(&self.crc_table, &page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence += 1;
+        self.writer.write_all(&page)
+    }
+}
+
+impl Drop for OggOpusWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            error!(target: TRACING_TARGET, "Failed to finalize Ogg/Opus capture: {e}");
+        }
+    }
+}
+
+pub struct OggOpusStream {
+    pub channels: u8,
+    pub pre_skip: u16,
+    pub packets: Vec<Vec<u8>>,
+}
+
+pub fn read_ogg_opus(path: impl AsRef<Path>) -> io::Result<OggOpusStream> {
+    let data: Vec<u8> = fs::read(path)?;
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+
+    let mut serial: Option<u32> = None;
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    let mut packet: Vec<u8> = Vec::new();
+    let mut offset: usize = 0;
+
+    while offset + 27 <= data.len() {
+        if &data[offset..offset + 4] != b"OggS" {
+            return Err(invalid("Missing Ogg capture pattern"));
+        }
+        let page_serial: u32 =
+            u32::from_le_bytes(data[offset + 14..offset + 18].try_into().unwrap());
+        let segment_count: usize = data[offset + 26] as usize;
+        let segments_start: usize = offset + 27;
+        let mut body_offset: usize = segments_start + segment_count;
+        if body_offset > data.len() {
+            return Err(invalid("Truncated Ogg page"));
+        }
+
+        // Only the first logical stream is read, other multiplexed streams are skipped
+        let selected: bool = *serial.get_or_insert(page_serial) == page_serial;
+        for lacing in &data[segments_start..segments_start + segment_count] {
+            let end: usize = body_offset + *lacing as usize;
+            if end > data.len() {
+                return Err(invalid("Truncated Ogg page"));
+            }
+            if selected {
+                packet.extend_from_slice(&data[body_offset..end]);
+                if *lacing < 255 {
+                    packets.push(std::mem::take(&mut packet));
+                }
+            }
+            body_offset = end;
+        }
+        offset = body_offset;
+    }
+
+    if packets.len() < 2 || !packets[0].starts_with(b"OpusHead") || packets[0].len() < 19 {
+        return Err(invalid("Not an Ogg/Opus stream"));
+    }
+    if !packets[1].starts_with(b"OpusTags") {
+        return Err(invalid("Missing OpusTags header"));
+    }
+
+    let channels: u8 = packets[0][9];
+    let pre_skip: u16 = u16::from_le_bytes([packets[0][10], packets[0][11]]);
+    packets.drain(..2);
+
+    Ok(OggOpusStream {
+        channels,
+        pre_skip,
+        packets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+
+    struct Page {
+        header_type: u8,
+        granule: u64,
+        sequence: u32,
+        segments: Vec<u8>,
+        body: Vec<u8>,
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("p2p-voice-{}-{name}.opus", std::process::id()))
+    }
+
+    fn pages(data: &[u8]) -> Vec<Page> {
+        let table: [u32; 256] = ogg_crc_table();
+        let mut pages: Vec<Page> = Vec::new();
+        let mut offset: usize = 0;
+        while offset < data.len() {
+            assert_eq!(&data[offset..offset + 4], b"OggS");
+            let count: usize = data[offset + 26] as usize;
+            let segments: Vec<u8> = data[offset + 27..offset + 27 + count].to_vec();
+            let length: usize = 27 + count + segments.iter().map(|x| *x as usize).sum::<usize>();
+            let mut page: Vec<u8> = data[offset..offset + length].to_vec();
+            let crc: u32 = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(ogg_crc(&table, &page), crc);
+            pages.push(Page {
+                header_type: page[5],
+                granule: u64::from_le_bytes(page[6..14].try_into().unwrap()),
+                sequence: u32::from_le_bytes(page[18..22].try_into().unwrap()),
+                body: page[27 + count..].to_vec(),
+                segments,
+            });
+            offset += length;
+        }
+        pages
+    }
+
+    #[test]
+    fn crc_matches_the_ogg_reference() {
+        // CRC-32 with polynomial 0x04c11db7, no reflection and a zero initial value
+        assert_eq!(ogg_crc(&ogg_crc_table(), b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn headers_follow_rfc_7845() {
+        let path: PathBuf = temp_path("headers");
+        drop(OggOpusWriter::create(&path, 2, 312, 24000).unwrap());
+        let pages: Vec<Page> = pages(&fs::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].header_type, OGG_HEADER_TYPE_BOS);
+        assert_eq!(pages[0].granule, 0);
+        assert_eq!(pages[0].segments, vec![19]);
+        let head: &[u8] = &pages[0].body;
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[8], 1);
+        assert_eq!(head[9], 2);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), 312);
+        assert_eq!(u32::from_le_bytes(head[12..16].try_into().unwrap()), 24000);
+        assert_eq!(&head[16..], &[0, 0, 0]);
+
+        assert_eq!(pages[1].header_type, 0);
+        assert_eq!(pages[1].sequence, 1);
+        let tags: &[u8] = &pages[1].body;
+        assert_eq!(&tags[..8], b"OpusTags");
+        let vendor: usize = u32::from_le_bytes(tags[8..12].try_into().unwrap()) as usize;
+        assert_eq!(&tags[12..12 + vendor], OPUS_VENDOR.as_bytes());
+        assert_eq!(&tags[12 + vendor..], &[0, 0, 0, 0]);
+
+        assert_eq!(pages[2].header_type, OGG_HEADER_TYPE_EOS);
+        assert!(pages[2].segments.is_empty());
+    }
+
+    #[test]
+    fn large_packets_are_laced_and_split_across_pages() {
+        let path: PathBuf = temp_path("lacing");
+        let packet: Vec<u8> = (0..70000).map(|x| x as u8).collect();
+        {
+            let mut writer: OggOpusWriter = OggOpusWriter::create(&path, 1, 0, 48000).unwrap();
+            writer.write_packet_with_duration(&packet, 960).unwrap();
+            writer.write_packet_with_duration(&[1; 255], 960).unwrap();
+        }
+        let pages: Vec<Page> = pages(&fs::read(&path).unwrap());
+        let stream: OggOpusStream = read_ogg_opus(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // 70000 bytes need 274 lacing values of 255 and a final 130, more than one page holds
+        assert_eq!(pages[2].segments.len(), OGG_MAX_SEGMENTS);
+        assert_eq!(pages[2].granule, u64::MAX);
+        // The rest of it no longer leaves room for the next packet
+        assert_eq!(pages[3].header_type, OGG_HEADER_TYPE_CONTINUED);
+        assert_eq!(pages[3].segments.len(), 20);
+        assert_eq!(pages[3].granule, 960);
+        assert_eq!(pages[4].header_type, OGG_HEADER_TYPE_EOS);
+        assert_eq!(pages[4].granule, 1920);
+        // A packet of exactly 255 bytes ends with a zero lacing value
+        assert_eq!(pages[4].segments, vec![255, 0]);
+        assert_eq!(stream.packets, vec![packet, vec![1; 255]]);
+    }
+
+    #[test]
+    fn lost_packets_keep_granule_positions_in_sync() {
+        let path: PathBuf = temp_path("gaps");
+        // A 20 ms CELT packet with a single empty frame
+        let packet: [u8; 1] = [0xF8];
+        {
+            let mut writer: OggOpusWriter = OggOpusWriter::create(&path, 1, 0, 48000).unwrap();
+            writer.write_packet_at(&packet, 96000).unwrap();
+            // 200 ms and then 2.5 ms go missing
+            writer.write_packet_at(&packet, 96960 + 9600).unwrap();
+            writer
+                .write_packet_at(&packet, 96960 + 9600 + 960 + 120)
+                .unwrap();
+            // Arrives after its place was filled
+            writer.write_packet_at(&packet, 96960).unwrap();
+        }
+        let pages: Vec<Page> = pages(&fs::read(&path).unwrap());
+        let stream: OggOpusStream = read_ogg_opus(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(pages.last().unwrap().granule, 960 + 9600 + 960 + 120 + 960);
+        let durations: Vec<usize> = stream
+            .packets
+            .iter()
+            .map(|x| opus::packet::get_nb_samples(x, OPUS_GRANULE_RATE).unwrap())
+            .collect();
+        assert_eq!(durations, vec![960, 5760, 3840, 960, 120, 960]);
+    }
+}
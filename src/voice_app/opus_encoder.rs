@@ -0,0 +1,352 @@
+use std::{
+    ffi::CStr,
+    fmt::Display,
+    os::raw::c_int,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use audiopus_sys as ffi;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpusApplication {
+    Voip,
+    Audio,
+    LowDelay,
+}
+
+impl OpusApplication {
+    pub const ALL: [OpusApplication; 3] = [
+        OpusApplication::Voip,
+        OpusApplication::Audio,
+        OpusApplication::LowDelay,
+    ];
+
+    fn value(&self) -> c_int {
+        match self {
+            OpusApplication::Voip => ffi::OPUS_APPLICATION_VOIP,
+            OpusApplication::Audio => ffi::OPUS_APPLICATION_AUDIO,
+            OpusApplication::LowDelay => ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
+
+impl Display for OpusApplication {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpusApplication::Voip => f.write_str("VoIP"),
+            OpusApplication::Audio => f.write_str("Audio"),
+            OpusApplication::LowDelay => f.write_str("Low delay"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpusBandwidth {
+    Narrowband,
+    Mediumband,
+    Wideband,
+    Superwideband,
+    Fullband,
+}
+
+impl OpusBandwidth {
+    pub const ALL: [OpusBandwidth; 5] = [
+        OpusBandwidth::Narrowband,
+        OpusBandwidth::Mediumband,
+        OpusBandwidth::Wideband,
+        OpusBandwidth::Superwideband,
+        OpusBandwidth::Fullband,
+    ];
+
+    fn value(&self) -> c_int {
+        match self {
+            OpusBandwidth::Narrowband => ffi::OPUS_BANDWIDTH_NARROWBAND,
+            OpusBandwidth::Mediumband => ffi::OPUS_BANDWIDTH_MEDIUMBAND,
+            OpusBandwidth::Wideband => ffi::OPUS_BANDWIDTH_WIDEBAND,
+            OpusBandwidth::Superwideband => ffi::OPUS_BANDWIDTH_SUPERWIDEBAND,
+            OpusBandwidth::Fullband => ffi::OPUS_BANDWIDTH_FULLBAND,
+        }
+    }
+}
+
+impl Display for OpusBandwidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpusBandwidth::Narrowband => f.write_str("Narrowband (4 kHz)"),
+            OpusBandwidth::Mediumband => f.write_str("Mediumband (6 kHz)"),
+            OpusBandwidth::Wideband => f.write_str("Wideband (8 kHz)"),
+            OpusBandwidth::Superwideband => f.write_str("Superwideband (12 kHz)"),
+            OpusBandwidth::Fullband => f.write_str("Fullband (20 kHz)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl FrameDuration {
+    pub const ALL: [FrameDuration; 6] = [
+        FrameDuration::Ms2_5,
+        FrameDuration::Ms5,
+        FrameDuration::Ms10,
+        FrameDuration::Ms20,
+        FrameDuration::Ms40,
+        FrameDuration::Ms60,
+    ];
+
+    pub fn samples(&self, sample_rate: u32) -> usize {
+        let tenths_of_ms: usize = match self {
+            FrameDuration::Ms2_5 => 25,
+            FrameDuration::Ms5 => 50,
+            FrameDuration::Ms10 => 100,
+            FrameDuration::Ms20 => 200,
+            FrameDuration::Ms40 => 400,
+            FrameDuration::Ms60 => 600,
+        };
+        sample_rate as usize * tenths_of_ms / 10000
+    }
+}
+
+impl Display for FrameDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameDuration::Ms2_5 => f.write_str("2.5 ms"),
+            FrameDuration::Ms5 => f.write_str("5 ms"),
+            FrameDuration::Ms10 => f.write_str("10 ms"),
+            FrameDuration::Ms20 => f.write_str("20 ms"),
+            FrameDuration::Ms40 => f.write_str("40 ms"),
+            FrameDuration::Ms60 => f.write_str("60 ms"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpusSettings {
+    pub application: OpusApplication,
+    // `None` lets the encoder pick the bitrate
+    pub bitrate: Option<i32>,
+    pub vbr: bool,
+    pub complexity: u8,
+    pub frame_duration: FrameDuration,
+    pub max_bandwidth: OpusBandwidth,
+}
+
+impl Default for OpusSettings {
+    fn default() -> Self {
+        OpusSettings {
+            application: OpusApplication::Voip,
+            bitrate: None,
+            vbr: true,
+            complexity: 10,
+            frame_duration: FrameDuration::Ms20,
+            max_bandwidth: OpusBandwidth::Fullband,
+        }
+    }
+}
+
+impl OpusSettings {
+    // Stereo music at a bitrate where Opus is close to transparent
+    pub fn music() -> Self {
+        OpusSettings {
+            application: OpusApplication::Audio,
+            bitrate: Some(256000),
+            ..OpusSettings::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct SharedSettings {
+    settings: Mutex<OpusSettings>,
+    version: AtomicU64,
+}
+
+// The encoder polls the version between frames and only locks when the settings changed
+#[derive(Clone, Default)]
+pub struct OpusSettingsHandle(Arc<SharedSettings>);
+
+impl OpusSettingsHandle {
+    pub fn new(settings: OpusSettings) -> Self {
+        OpusSettingsHandle(Arc::new(SharedSettings {
+            settings: Mutex::new(settings),
+            version: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn get(&self) -> OpusSettings {
+        *self.0.settings.lock().unwrap()
+    }
+
+    pub fn set(&self, settings: OpusSettings) {
+        *self.0.settings.lock().unwrap() = settings;
+        self.0.version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn version(&self) -> u64 {
+        self.0.version.load(Ordering::Acquire)
+    }
+}
+
+fn opus_error(function: &str, code: c_int) -> String {
+    let description: String = unsafe { CStr::from_ptr(ffi::opus_strerror(code)) }
+        .to_string_lossy()
+        .into_owned();
+    format!("{function} failed: {description}")
+}
+
+pub struct OpusEncoder {
+    ptr: *mut ffi::OpusEncoder,
+    channels: usize,
+    sample_rate: u32,
+    settings: OpusSettings,
+}
+
+// Opus states can be moved between threads as long as they are used by one thread at a time
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(sample_rate: u32, channels: usize, settings: OpusSettings) -> Result<Self, String> {
+        let mut error: c_int = ffi::OPUS_OK;
+        let ptr: *mut ffi::OpusEncoder = unsafe {
+            ffi::opus_encoder_create(
+                sample_rate as c_int,
+                channels as c_int,
+                settings.application.value(),
+                &mut error,
+            )
+        };
+        if error != ffi::OPUS_OK || ptr.is_null() {
+            return Err(opus_error("opus_encoder_create", error));
+        }
+
+        let mut encoder: OpusEncoder = OpusEncoder {
+            ptr,
+            channels,
+            sample_rate,
+            settings,
+        };
+        encoder.apply_ctls(&settings)?;
+        Ok(encoder)
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.settings.frame_duration.samples(self.sample_rate)
+    }
+
+    pub fn settings(&self) -> OpusSettings {
+        self.settings
+    }
+
+    // The application can only be chosen before the first frame, so changing it recreates the encoder
+    pub fn update(&mut self, settings: OpusSettings) -> Result<(), String> {
+        if settings == self.settings {
+            return Ok(());
+        }
+        if settings.application != self.settings.application {
+            *self = OpusEncoder::new(self.sample_rate, self.channels, settings)?;
+            return Ok(());
+        }
+        self.apply_ctls(&settings)?;
+        self.settings = settings;
+        Ok(())
+    }
+
+    // Samples the encoder delays its output by at the encoder rate, which players skip at the start
+    pub fn lookahead(&mut self) -> Result<usize, String> {
+        let mut lookahead: i32 = 0;
+        let result: c_int = unsafe {
+            ffi::opus_encoder_ctl(
+                self.ptr,
+                ffi::OPUS_GET_LOOKAHEAD_REQUEST,
+                &mut lookahead as *mut i32,
+            )
+        };
+        if result < 0 {
+            return Err(opus_error("opus_encoder_ctl", result));
+        }
+        Ok(lookahead as usize)
+    }
+
+    pub fn encode_float(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, String> {
+        let encoded: i32 = unsafe {
+            ffi::opus_encode_float(
+                self.ptr,
+                input.as_ptr(),
+                (input.len() / self.channels) as c_int,
+                output.as_mut_ptr(),
+                output.len() as i32,
+            )
+        };
+        if encoded < 0 {
+            return Err(opus_error("opus_encode_float", encoded));
+        }
+        Ok(encoded as usize)
+    }
+
+    fn apply_ctls(&mut self, settings: &OpusSettings) -> Result<(), String> {
+        self.ctl(
+            ffi::OPUS_SET_BITRATE_REQUEST,
+            settings.bitrate.unwrap_or(ffi::OPUS_AUTO),
+        )?;
+        self.ctl(ffi::OPUS_SET_VBR_REQUEST, settings.vbr as i32)?;
+        self.ctl(
+            ffi::OPUS_SET_COMPLEXITY_REQUEST,
+            settings.complexity.min(10) as i32,
+        )?;
+        self.ctl(
+            ffi::OPUS_SET_MAX_BANDWIDTH_REQUEST,
+            settings.max_bandwidth.value(),
+        )?;
+        Ok(())
+    }
+
+    fn ctl(&mut self, request: c_int, value: i32) -> Result<(), String> {
+        let result: c_int = unsafe { ffi::opus_encoder_ctl(self.ptr, request, value) };
+        if result < 0 {
+            return Err(opus_error("opus_encoder_ctl", result));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { ffi::opus_encoder_destroy(self.ptr) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookahead_depends_on_application_and_rate() {
+        let lookahead = |sample_rate: u32, application: OpusApplication| {
+            OpusEncoder::new(
+                sample_rate,
+                1,
+                OpusSettings {
+                    application,
+                    ..OpusSettings::default()
+                },
+            )
+            .unwrap()
+            .lookahead()
+            .unwrap()
+        };
+        // 6.5 ms, or 2.5 ms without the SILK delay
+        assert_eq!(lookahead(48000, OpusApplication::Voip), 312);
+        assert_eq!(lookahead(16000, OpusApplication::Voip), 104);
+        assert_eq!(lookahead(48000, OpusApplication::LowDelay), 120);
+    }
+}
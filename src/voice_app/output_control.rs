@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+
+use crate::voice_app::endpoint::OutputEndpoint;
+
+// The largest correction applied to the resampling ratio, far beyond the drift of real sound cards
+pub const MAX_DRIFT_CORRECTION: f64 = 0.002;
+// Correction per relative fill error, settling within about ten seconds
+const DRIFT_GAIN: f64 = 0.004;
+// Fill levels jump by whole mix frames, so they are averaged over roughly a second of chunks
+const FILL_SMOOTHING: f64 = 0.01;
+
+struct OutputControlState {
+    volume: f32,
+    correction_ppm: f64,
+}
+
+impl Default for OutputControlState {
+    fn default() -> Self {
+        OutputControlState {
+            volume: 1.0,
+            correction_ppm: 0.0,
+        }
+    }
+}
+
+// Volume of a single output device and the drift correction its resampler currently applies
+#[derive(Clone, Default)]
+pub struct OutputControl(Arc<Mutex<OutputControlState>>);
+
+impl OutputControl {
+    pub fn volume(&self) -> f32 {
+        self.0.lock().unwrap().volume
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.0.lock().unwrap().volume = volume;
+    }
+
+    pub fn correction_ppm(&self) -> f64 {
+        self.0.lock().unwrap().correction_ppm
+    }
+
+    pub fn set_correction_ppm(&self, correction_ppm: f64) {
+        self.0.lock().unwrap().correction_ppm = correction_ppm;
+    }
+}
+
+// A device playing the call next to the main output
+#[derive(Clone)]
+pub struct ExtraOutput {
+    pub endpoint: OutputEndpoint,
+    pub control: OutputControl,
+}
+
+// Every output device runs on its own clock while the mix runs on the clock of the main one.
+// The resampler ratio is nudged so the mix queued for a device stays at its target fill
+pub struct DriftCompensator {
+    target: f64,
+    fill: f64,
+}
+
+impl DriftCompensator {
+    pub fn new(target: usize) -> Self {
+        DriftCompensator {
+            target: target as f64,
+            fill: target as f64,
+        }
+    }
+
+    // Returns the resampling ratio relative to the nominal one
+    pub fn update(&mut self, fill: usize) -> f64 {
+        self.fill += (fill as f64 - self.fill) * FILL_SMOOTHING;
+        let error: f64 = (self.fill - self.target) / self.target;
+        // A growing queue means the device plays slower than the mix arrives, so fewer samples are made from it
+        (1.0 - error * DRIFT_GAIN).clamp(1.0 - MAX_DRIFT_CORRECTION, 1.0 + MAX_DRIFT_CORRECTION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = 4800;
+
+    #[test]
+    fn target_fill_keeps_the_nominal_ratio() {
+        let mut compensator: DriftCompensator = DriftCompensator::new(TARGET);
+        for _ in 0..1000 {
+            assert_eq!(compensator.update(TARGET), 1.0);
+        }
+    }
+
+    #[test]
+    fn single_jumps_are_smoothed() {
+        let mut compensator: DriftCompensator = DriftCompensator::new(TARGET);
+        let ratio: f64 = compensator.update(TARGET * 2);
+        assert!(ratio < 1.0);
+        assert!(1.0 - ratio < MAX_DRIFT_CORRECTION * 0.1);
+    }
+
+    #[test]
+    fn sustained_errors_are_corrected_up_to_the_limit() {
+        let mut growing: DriftCompensator = DriftCompensator::new(TARGET);
+        let mut draining: DriftCompensator = DriftCompensator::new(TARGET);
+        let mut slightly_full: DriftCompensator = DriftCompensator::new(TARGET);
+        for _ in 0..1000 {
+            growing.update(TARGET * 2);
+            draining.update(0);
+            slightly_full.update(TARGET * 11 / 10);
+        }
+        assert_eq!(growing.update(TARGET * 2), 1.0 - MAX_DRIFT_CORRECTION);
+        assert_eq!(draining.update(0), 1.0 + MAX_DRIFT_CORRECTION);
+        let ratio: f64 = slightly_full.update(TARGET * 11 / 10);
+        assert!((ratio - (1.0 - 0.1 * DRIFT_GAIN)).abs() < 1e-6, "{ratio}");
+    }
+
+    #[test]
+    fn controls_are_shared_between_clones() {
+        let control: OutputControl = OutputControl::default();
+        assert_eq!(control.volume(), 1.0);
+        let clone: OutputControl = control.clone();
+        clone.set_volume(0.5);
+        clone.set_correction_ppm(-120.0);
+        assert_eq!(control.volume(), 0.5);
+        assert_eq!(control.correction_ppm(), -120.0);
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Display;
+
+// Sample rate everything between the capture and output resamplers runs at. Lower rates spend
+// less CPU on resampling, effects and encoding at the cost of the highest frequencies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessingRate {
+    Wideband,
+    SuperWideband,
+    #[default]
+    Fullband,
+}
+
+impl ProcessingRate {
+    pub const ALL: [ProcessingRate; 3] = [
+        ProcessingRate::Wideband,
+        ProcessingRate::SuperWideband,
+        ProcessingRate::Fullband,
+    ];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "16000" | "16k" | "wideband" => Ok(ProcessingRate::Wideband),
+            "24000" | "24k" | "super-wideband" | "superwideband" => {
+                Ok(ProcessingRate::SuperWideband)
+            }
+            "48000" | "48k" | "fullband" => Ok(ProcessingRate::Fullband),
+            _ => Err(format!("Unknown processing rate {value}")),
+        }
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        match self {
+            ProcessingRate::Wideband => 16000,
+            ProcessingRate::SuperWideband => 24000,
+            ProcessingRate::Fullband => 48000,
+        }
+    }
+}
+
+impl Display for ProcessingRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessingRate::Wideband => f.write_str("16 kHz processing (wideband)"),
+            ProcessingRate::SuperWideband => f.write_str("24 kHz processing (super-wideband)"),
+            ProcessingRate::Fullband => f.write_str("48 kHz processing (fullband)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::denoise::DENOISE_SAMPLE_RATE;
+
+    #[test]
+    fn rates_parse_by_number_and_name() {
+        for (values, rate) in [
+            ["16000", "16k", "wideband"],
+            ["24000", "24K", "super-wideband"],
+            ["48000", "48k", "Fullband"],
+        ]
+        .into_iter()
+        .zip(ProcessingRate::ALL)
+        {
+            for value in values {
+                assert_eq!(ProcessingRate::parse(value), Ok(rate));
+            }
+        }
+        assert!(ProcessingRate::parse("44100").is_err());
+        assert_eq!(ProcessingRate::default().sample_rate(), 48000);
+    }
+
+    #[test]
+    fn rates_split_into_whole_frames() {
+        for rate in ProcessingRate::ALL {
+            let sample_rate: usize = rate.sample_rate();
+            // 10 ms mix frames and 2.5 ms Opus frames
+            assert_eq!(sample_rate % 400, 0);
+            // Resampled around RNNoise a 10 ms frame at a time
+            assert_eq!(DENOISE_SAMPLE_RATE % sample_rate, 0);
+        }
+    }
+}
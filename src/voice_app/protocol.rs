@@ -0,0 +1,425 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::UdpSocket,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info};
+
+use crate::voice_app::{app_tracing::TRACING_TARGET, codec::CodecKind};
+
+const PACKET_AUDIO: u8 = 0x02;
+
+// Newest wire format spoken here, peers settle on the older of their two versions.
+// Version 0 is the baseline app, which sends bare mono Opus packets and knows no hello.
+// Version 1 added capture timestamps to audio, version 2 stream positions after them
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const BASELINE_VERSION: u8 = 0;
+const POSITION_VERSION: u8 = 2;
+
+// Hellos are valid Opus packets to a baseline peer: a single empty 20 ms frame padded with our fields,
+// which it decodes as silence instead of failing
+const HELLO_TOC: u8 = 0xFB;
+const HELLO_FRAME_COUNT: u8 = 0x41;
+const HELLO_MAGIC: &[u8; 4] = b"P2PV";
+
+const HELLO_INTERVAL: Duration = Duration::from_millis(200);
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(15);
+// A peer sending audio without answering our hellos for this long runs the baseline app
+const BASELINE_DETECTION: Duration = Duration::from_secs(1);
+
+pub enum Packet<'a> {
+    Hello {
+        version: u8,
+        nonce: u64,
+        codecs: Vec<CodecKind>,
+        music: bool,
+    },
+    // Capture time of the first sample in microseconds since the Unix epoch and where it sits in the
+    // sender's stream, wrapping like an RTP timestamp. The baseline app sends bare payloads
+    Audio {
+        timestamp: Option<u64>,
+        position: Option<u32>,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> Packet<'a> {
+    // Audio is read in the layout of the negotiated `version`
+    pub fn parse(data: &'a [u8], version: u8) -> Option<Self> {
+        if let Some(hello) = Packet::parse_hello(data) {
+            return Some(hello);
+        }
+        if version == BASELINE_VERSION {
+            return Some(Packet::Audio {
+                timestamp: None,
+                position: None,
+                payload: data,
+            });
+        }
+        if *data.first()? != PACKET_AUDIO {
+            return None;
+        }
+        let timestamp: u64 = u64::from_be_bytes(data.get(1..9)?.try_into().ok()?);
+        if version < POSITION_VERSION {
+            return Some(Packet::Audio {
+                timestamp: Some(timestamp),
+                position: None,
+                payload: &data[9..],
+            });
+        }
+        Some(Packet::Audio {
+            timestamp: Some(timestamp),
+            position: Some(u32::from_be_bytes(data.get(9..13)?.try_into().ok()?)),
+            payload: &data[13..],
+        })
+    }
+
+    fn parse_hello(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 3 || data[..2] != [HELLO_TOC, HELLO_FRAME_COUNT] {
+            return None;
+        }
+        let body: &[u8] = &data[3..];
+        if body.len() != data[2] as usize || !body.starts_with(HELLO_MAGIC) {
+            return None;
+        }
+        // Fields added by later versions follow the music flag and are skipped here
+        let fields: &[u8] = &body[HELLO_MAGIC.len()..];
+        let version: u8 = *fields.first()?;
+        let nonce: u64 = u64::from_be_bytes(fields.get(1..9)?.try_into().ok()?);
+        let count: usize = *fields.get(9)? as usize;
+        let codecs: Vec<CodecKind> = fields
+            .get(10..10 + count)?
+            .iter()
+            .filter_map(|x| CodecKind::from_id(*x))
+            .collect();
+        let music: bool = fields.get(10 + count).is_some_and(|x| *x == 1);
+        Some(Packet::Hello {
+            version,
+            nonce,
+            codecs,
+            music,
+        })
+    }
+
+    pub fn write(&self, output: &mut Vec<u8>) {
+        output.clear();
+        match self {
+            Packet::Hello {
+                version,
+                nonce,
+                codecs,
+                music,
+            } => {
+                output.extend_from_slice(&[HELLO_TOC, HELLO_FRAME_COUNT, 0]);
+                output.extend_from_slice(HELLO_MAGIC);
+                output.push(*version);
+                output.extend_from_slice(&nonce.to_be_bytes());
+                output.push(codecs.len() as u8);
+                output.extend(codecs.iter().map(|x| x.id()));
+                output.push(*music as u8);
+                output[2] = (output.len() - 3) as u8;
+            }
+            Packet::Audio {
+                timestamp,
+                position,
+                payload,
+            } => {
+                if let Some(timestamp) = timestamp {
+                    output.push(PACKET_AUDIO);
+                    output.extend_from_slice(&timestamp.to_be_bytes());
+                }
+                if let Some(position) = position {
+                    output.extend_from_slice(&position.to_be_bytes());
+                }
+                output.extend_from_slice(payload);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Negotiation {
+    pub socket: Arc<UdpSocket>,
+    pub codec: CodecKind,
+    // Stereo Opus tuned for music without any voice processing, only when both peers asked for it
+    pub music: bool,
+    // Our own hello, repeated to peers that connect late or missed it
+    pub hello: Vec<u8>,
+    pub version: u8,
+}
+
+impl Negotiation {
+    pub fn channels(&self) -> usize {
+        if self.music { 2 } else { 1 }
+    }
+
+    pub fn is_baseline(&self) -> bool {
+        self.version == BASELINE_VERSION
+    }
+
+    pub fn has_positions(&self) -> bool {
+        self.version >= POSITION_VERSION
+    }
+}
+
+fn random_nonce() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// Both peers pick the first codec in the list of the peer with the larger nonce that the other side supports
+fn choose_codec(
+    nonce: u64,
+    codecs: &[CodecKind],
+    peer_nonce: u64,
+    peer_codecs: &[CodecKind],
+) -> Option<CodecKind> {
+    let (preferred, other) = if nonce > peer_nonce {
+        (codecs, peer_codecs)
+    } else {
+        (peer_codecs, codecs)
+    };
+    preferred.iter().copied().find(|x| other.contains(x))
+}
+
+fn bind() -> io::Result<UdpSocket> {
+    let mut port: usize = 4000;
+    info!(target: TRACING_TARGET, "Binding UDP socket on port {port}");
+    let mut socket = UdpSocket::bind(format!("0.0.0.0:{port}"));
+    let mut bind_errors: usize = 0;
+    while socket.is_err() && bind_errors < 3 {
+        bind_errors += 1;
+        port += 1;
+        info!(target: TRACING_TARGET, "Binding failed, using {port} instead");
+        socket = UdpSocket::bind(format!("0.0.0.0:{port}"));
+    }
+    socket
+}
+
+pub fn negotiate(peer: &str, codecs: &[CodecKind], music: bool) -> Result<Negotiation, String> {
+    let socket: UdpSocket = bind().map_err(|e| format!("Binding failed: {e}"))?;
+    socket
+        .set_nonblocking(true)
+        .map_err(|e| format!("Failed to move socket into nonblocking mode: {e}"))?;
+    socket
+        .connect(peer)
+        .map_err(|e| format!("Failed to connect: {e}"))?;
+
+    let nonce: u64 = random_nonce();
+    let mut hello: Vec<u8> = Vec::new();
+    Packet::Hello {
+        version: PROTOCOL_VERSION,
+        nonce,
+        codecs: codecs.to_vec(),
+        music,
+    }
+    .write(&mut hello);
+
+    info!(target: TRACING_TARGET, "Negotiating codec with {peer}, offering {codecs:?}");
+    let start: Instant = Instant::now();
+    let mut last_hello: Option<Instant> = None;
+    let mut first_audio: Option<Instant> = None;
+    let mut buffer: Vec<u8> = vec![0; 1500];
+
+    while start.elapsed() < NEGOTIATION_TIMEOUT {
+        if last_hello.is_none_or(|x| x.elapsed() >= HELLO_INTERVAL) {
+            // The peer may not be listening yet, so failed sends are retried with the next hello
+            let _ = socket.send(&hello);
+            last_hello = Some(Instant::now());
+        }
+
+        match socket.recv(&mut buffer) {
+            Ok(received) => match Packet::parse(&buffer[..received], BASELINE_VERSION) {
+                Some(Packet::Hello {
+                    version: peer_version,
+                    nonce: peer_nonce,
+                    codecs: peer_codecs,
+                    music: peer_music,
+                }) => {
+                    // Answer once more so the peer finishes even if our earlier hellos were lost
+                    let _ = socket.send(&hello);
+                    let codec: CodecKind = choose_codec(nonce, codecs, peer_nonce, &peer_codecs)
+                        .ok_or(String::from("No codec supported by both peers"))?;
+                    info!(target: TRACING_TARGET, "Negotiated {codec} with {peer}");
+                    // Stereo music needs Opus, so both sides fall back to voice otherwise
+                    let agreed: bool = music && peer_music && codec == CodecKind::Opus;
+                    if agreed {
+                        info!(target: TRACING_TARGET, "Music mode agreed with {peer}");
+                    } else if music || peer_music {
+                        info!(target: TRACING_TARGET, "Music mode not agreed with {peer}, using voice mode");
+                    }
+                    let version: u8 = PROTOCOL_VERSION.min(peer_version);
+                    info!(target: TRACING_TARGET, "Using protocol version {version} with {peer}");
+                    return Ok(Negotiation {
+                        socket: Arc::new(socket),
+                        codec,
+                        music: agreed,
+                        hello,
+                        version,
+                    });
+                }
+                Some(Packet::Audio { .. }) => {
+                    // Only the baseline app sends audio before answering, it takes mono Opus and nothing else
+                    let first_audio: Instant = *first_audio.get_or_insert_with(Instant::now);
+                    if first_audio.elapsed() >= BASELINE_DETECTION {
+                        if !codecs.contains(&CodecKind::Opus) {
+                            return Err(String::from(
+                                "Peer runs the baseline app, which only supports Opus",
+                            ));
+                        }
+                        info!(target: TRACING_TARGET, "Peer {peer} runs the baseline app, using mono Opus without negotiation");
+                        return Ok(Negotiation {
+                            socket: Arc::new(socket),
+                            codec: CodecKind::Opus,
+                            music: false,
+                            hello,
+                            version: BASELINE_VERSION,
+                        });
+                    }
+                }
+                None => {}
+            },
+            // Until the peer binds its socket the connected socket reports refused connections
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                thread::sleep(Duration::from_millis(5))
+            }
+            Err(e) => {
+                error!(target: TRACING_TARGET, "Failed to receive hello: {e}");
+                thread::sleep(HELLO_INTERVAL);
+            }
+        }
+    }
+
+    Err(format!(
+        "Peer {peer} didn't answer within {NEGOTIATION_TIMEOUT:?}"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use opus::{Channels, Decoder};
+
+    use super::*;
+
+    fn hello(codecs: Vec<CodecKind>) -> Vec<u8> {
+        let mut output: Vec<u8> = Vec::new();
+        Packet::Hello {
+            version: PROTOCOL_VERSION,
+            nonce: 0x0102030405060708,
+            codecs,
+            music: true,
+        }
+        .write(&mut output);
+        output
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let data: Vec<u8> = hello(vec![CodecKind::L16, CodecKind::Opus]);
+        match Packet::parse(&data, BASELINE_VERSION) {
+            Some(Packet::Hello {
+                version,
+                nonce,
+                codecs,
+                music,
+            }) => {
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(nonce, 0x0102030405060708);
+                assert_eq!(codecs, vec![CodecKind::L16, CodecKind::Opus]);
+                assert!(music);
+            }
+            _ => panic!("Hello not recognized"),
+        }
+    }
+
+    #[test]
+    fn hello_skips_fields_of_later_versions() {
+        let mut data: Vec<u8> = hello(vec![CodecKind::Opus]);
+        data.extend_from_slice(&[7, 7, 7]);
+        data[2] += 3;
+        assert!(matches!(
+            Packet::parse(&data, PROTOCOL_VERSION),
+            Some(Packet::Hello { music: true, .. })
+        ));
+    }
+
+    #[test]
+    fn hello_decodes_as_opus_silence() {
+        let data: Vec<u8> = hello(CodecKind::ALL.to_vec());
+        let mut decoder: Decoder = Decoder::new(48000, Channels::Mono).unwrap();
+        let mut output: Vec<f32> = vec![1.0; 960];
+        assert_eq!(
+            decoder.decode_float(&data, &mut output, false).unwrap(),
+            960
+        );
+        assert!(output.iter().all(|x| x.abs() < 1e-3));
+    }
+
+    #[test]
+    fn baseline_audio_is_the_whole_datagram() {
+        let data: [u8; 4] = [0x78, 1, 2, 3];
+        assert!(matches!(
+            Packet::parse(&data, BASELINE_VERSION),
+            Some(Packet::Audio { timestamp: None, position: None, payload }) if payload == data
+        ));
+    }
+
+    #[test]
+    fn audio_round_trip() {
+        let mut data: Vec<u8> = Vec::new();
+        Packet::Audio {
+            timestamp: Some(42),
+            position: Some(u32::MAX),
+            payload: &[9, 8, 7],
+        }
+        .write(&mut data);
+        assert!(matches!(
+            Packet::parse(&data, PROTOCOL_VERSION),
+            Some(Packet::Audio {
+                timestamp: Some(42),
+                position: Some(u32::MAX),
+                payload: [9, 8, 7]
+            })
+        ));
+        assert!(Packet::parse(&data[..11], PROTOCOL_VERSION).is_none());
+    }
+
+    #[test]
+    fn version_1_audio_has_no_position() {
+        let mut data: Vec<u8> = Vec::new();
+        Packet::Audio {
+            timestamp: Some(42),
+            position: None,
+            payload: &[9, 8, 7],
+        }
+        .write(&mut data);
+        assert!(matches!(
+            Packet::parse(&data, 1),
+            Some(Packet::Audio {
+                timestamp: Some(42),
+                position: None,
+                payload: [9, 8, 7]
+            })
+        ));
+    }
+
+    #[test]
+    fn codec_choice_follows_the_larger_nonce() {
+        let ours: Vec<CodecKind> = CodecKind::preferences(CodecKind::L16, true);
+        let theirs: Vec<CodecKind> = CodecKind::preferences(CodecKind::Pcmu, true);
+        assert_eq!(choose_codec(2, &ours, 1, &theirs), Some(CodecKind::L16));
+        assert_eq!(choose_codec(1, &ours, 2, &theirs), Some(CodecKind::Pcmu));
+        let strict: Vec<CodecKind> = CodecKind::preferences(CodecKind::L16, false);
+        let other: Vec<CodecKind> = CodecKind::preferences(CodecKind::Pcma, false);
+        assert_eq!(choose_codec(1, &strict, 2, &other), None);
+    }
+}
@@ -0,0 +1,676 @@
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use cpal::Sample;
+use rubato::{FftFixedIn, Resampler};
+use rustfft::{FftPlanner, num_complex::Complex};
+use tracing::{error, info};
+
+use crate::voice_app::{
+    app_tracing::TRACING_TARGET,
+    audio::create_fft_resampler,
+    codec::{AudioDecoder, AudioEncoder, CodecKind},
+    denoise::DenoiseStage,
+    effects::{EffectSettings, VoiceEffects},
+    file_source::{load_file, load_wav},
+    howling::{HowlingMonitor, HowlingSuppressor},
+    latency::find_probe,
+    opus_encoder::OpusSettingsHandle,
+    processing_rate::ProcessingRate,
+};
+
+pub const QUALITY_FLAG: &str = "--quality";
+
+pub const QUALITY_USAGE: &str = "Usage: p2p-voice --quality <reference.wav>... [options]
+    --rate <16000|24000|48000>      Internal processing sample rate, 48000 by default
+    --codec <opus|l16|pcmu|pcma>    Codec to run the references through, opus by default
+    --loss <percent>                Simulated packet loss, 0 by default
+    --noise <noise.wav>             Noise looped into the references, like recorded typing
+    --suppress-transients           Suppress clicks after noise suppression
+    --baseline <file>               Baseline scores, quality-baseline.txt by default, references without one fail
+    --update-baseline               Store the measured scores as the new baseline";
+
+const DEFAULT_BASELINE: &str = "quality-baseline.txt";
+const MAX_ALIGNMENT_SECONDS: f32 = 0.5;
+
+// Segmental SNR is computed over 20 ms segments clamped to a perceptually meaningful range
+const SEGMENT_SECONDS: f32 = 0.02;
+const SEGMENT_SNR_MIN: f32 = -10.0;
+const SEGMENT_SNR_MAX: f32 = 35.0;
+const SILENT_SEGMENT_DB: f32 = -50.0;
+
+const SPECTRUM_FRAME_SIZE: usize = 512;
+const SPECTRUM_BANDS: usize = 32;
+const SPECTRUM_MIN_FREQUENCY: f32 = 50.0;
+const SPECTRUM_MAX_FREQUENCY: f32 = 8000.0;
+const SPECTRUM_FLOOR_DB: f32 = 60.0;
+const SIMILARITY_PATCH_FRAMES: usize = 30;
+
+// Allowed drop before a score counts as a regression
+const SNR_TOLERANCE_DB: f32 = 0.5;
+const SIMILARITY_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
+pub struct QualityScores {
+    pub snr: f32,
+    pub segmental_snr: f32,
+    pub similarity: f32,
+}
+
+impl QualityScores {
+    fn regressions(&self, baseline: &QualityScores) -> Vec<String> {
+        let mut regressions: Vec<String> = Vec::new();
+        if self.snr < baseline.snr - SNR_TOLERANCE_DB {
+            regressions.push(format!("SNR {:.2} < {:.2} dB", self.snr, baseline.snr));
+        }
+        if self.segmental_snr < baseline.segmental_snr - SNR_TOLERANCE_DB {
+            regressions.push(format!(
+                "segmental SNR {:.2} < {:.2} dB",
+                self.segmental_snr, baseline.segmental_snr
+            ));
+        }
+        if self.similarity < baseline.similarity - SIMILARITY_TOLERANCE {
+            regressions.push(format!(
+                "spectral similarity {:.3} < {:.3}",
+                self.similarity, baseline.similarity
+            ));
+        }
+        regressions
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut scores: QualityScores = QualityScores {
+            snr: f32::NAN,
+            segmental_snr: f32::NAN,
+            similarity: f32::NAN,
+        };
+        for field in value.split_whitespace() {
+            let (key, value) = field.split_once('=')?;
+            let value: f32 = value.parse().ok()?;
+            match key {
+                "snr" => scores.snr = value,
+                "segsnr" => scores.segmental_snr = value,
+                "similarity" => scores.similarity = value,
+                _ => return None,
+            }
+        }
+        Some(scores)
+    }
+}
+
+impl Display for QualityScores {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "snr={:.2} segsnr={:.2} similarity={:.3}",
+            self.snr, self.segmental_snr, self.similarity
+        )
+    }
+}
+
+// Runs a signal through a resampler the way the resampler threads do, chunk by chunk
+fn resample_stream(
+    samples: &[f32],
+    input_sample_rate: usize,
+    output_sample_rate: usize,
+) -> Vec<f32> {
+    if input_sample_rate == output_sample_rate {
+        return samples.to_vec();
+    }
+    let mut resampler: FftFixedIn<f32> =
+        create_fft_resampler(1, input_sample_rate, output_sample_rate);
+    let chunk_size: usize = resampler.input_frames_max();
+    let mut input: Vec<Vec<f32>> = resampler.input_buffer_allocate(true);
+    let mut output: Vec<Vec<f32>> = resampler.output_buffer_allocate(true);
+    let mut resampled: Vec<f32> = Vec::with_capacity(
+        samples.len() * output_sample_rate / input_sample_rate + output[0].len() * 2,
+    );
+    // Silence behind the signal pushes its end out of the resampler
+    let mut padded: Vec<f32> = samples.to_vec();
+    padded.resize(samples.len() + chunk_size * 2, Sample::EQUILIBRIUM);
+    for chunk in padded.chunks_exact(chunk_size) {
+        input[0].copy_from_slice(chunk);
+        resampler
+            .process_into_buffer(&input, &mut output, None)
+            .expect("Failed to resample");
+        resampled.extend_from_slice(&output[0]);
+    }
+    resampled
+}
+
+// The denoise, effects and howling threads, with voice effects at their defaults
+fn capture(samples: &[f32], sample_rate: usize, suppress_transients: bool) -> Vec<f32> {
+    let mut denoise: DenoiseStage = DenoiseStage::new(1, sample_rate);
+    let mut howling_suppressor: HowlingSuppressor =
+        HowlingSuppressor::new(HowlingMonitor::default(), 1, sample_rate);
+    let mut voice_effects: VoiceEffects = VoiceEffects::new(sample_rate);
+    let mut frame: Vec<f32> = vec![Sample::EQUILIBRIUM; denoise.frame_size()];
+    let mut processed: Vec<f32> = Vec::with_capacity(samples.len());
+
+    for chunk in samples.chunks(frame.len()) {
+        frame.fill(Sample::EQUILIBRIUM);
+        frame[..chunk.len()].copy_from_slice(chunk);
+        if !denoise.process(&mut frame, suppress_transients, None) {
+            continue;
+        }
+        voice_effects.process(EffectSettings::default(), &mut frame);
+        howling_suppressor.process(&mut frame);
+        processed.extend_from_slice(&frame);
+    }
+    if suppress_transients {
+        info!(target: TRACING_TARGET, "Suppressed {} transients", denoise.transients());
+    }
+    processed
+}
+
+// Deterministic packet loss so runs are comparable
+struct PacketLoss {
+    state: u32,
+    threshold: u32,
+}
+
+impl PacketLoss {
+    fn new(percent: f32) -> Self {
+        PacketLoss {
+            state: 0x2545_f491,
+            threshold: (percent.clamp(0.0, 100.0) / 100.0 * u32::MAX as f32) as u32,
+        }
+    }
+
+    fn lost(&mut self) -> bool {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state < self.threshold
+    }
+}
+
+// The codec resamplers, encoder and decoder threads, with the network dropping packets in between
+fn transmit(
+    samples: &[f32],
+    sample_rate: usize,
+    codec: CodecKind,
+    loss: f32,
+) -> Result<Vec<f32>, String> {
+    let codec_sample_rate: usize = codec.sample_rate(sample_rate) as usize;
+    let samples: Vec<f32> = resample_stream(samples, sample_rate, codec_sample_rate);
+
+    let mut encoder: Box<dyn AudioEncoder> =
+        codec.create_encoder(1, sample_rate, OpusSettingsHandle::default())?;
+    let mut decoder: Box<dyn AudioDecoder> = codec.create_decoder(1, sample_rate)?;
+    let mut packet_loss: PacketLoss = PacketLoss::new(loss);
+
+    let mut encoder_input_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; encoder.frame_size()];
+    let mut encoder_output_buffer: Vec<u8> = vec![0; 4000];
+    let mut decoder_output_buffer: Vec<f32> = vec![Sample::EQUILIBRIUM; 5760];
+    let mut received: Vec<f32> = Vec::with_capacity(samples.len());
+
+    for chunk in samples.chunks(encoder_input_buffer.len()) {
+        encoder_input_buffer.fill(Sample::EQUILIBRIUM);
+        encoder_input_buffer[..chunk.len()].copy_from_slice(chunk);
+        let encoded: usize = encoder.encode(&encoder_input_buffer, &mut encoder_output_buffer)?;
+        // Lost packets are concealed by the decoder like the decoder thread does when positions skip ahead
+        let decoded: usize = if packet_loss.lost() {
+            decoder.decode(
+                &[],
+                &mut decoder_output_buffer[..encoder_input_buffer.len()],
+            )?
+        } else {
+            decoder.decode(
+                &encoder_output_buffer[..encoded],
+                &mut decoder_output_buffer,
+            )?
+        };
+        received.extend_from_slice(&decoder_output_buffer[..decoded]);
+    }
+
+    Ok(resample_stream(&received, codec_sample_rate, sample_rate))
+}
+
+// Sends the reference through the same stages as a call, from the capture resampler to the output one
+pub fn process(
+    reference: &[f32],
+    sample_rate: usize,
+    rate: ProcessingRate,
+    codec: CodecKind,
+    loss: f32,
+    suppress_transients: bool,
+) -> Result<Vec<f32>, String> {
+    let processing_rate: usize = rate.sample_rate();
+    let internal: Vec<f32> = resample_stream(reference, sample_rate, processing_rate);
+    let processed: Vec<f32> = capture(&internal, processing_rate, suppress_transients);
+    let received: Vec<f32> = transmit(&processed, processing_rate, codec, loss)?;
+    Ok(resample_stream(&received, processing_rate, sample_rate))
+}
+
+// The noise is looped when it is shorter than the reference
+fn add_noise(reference: &[f32], noise: &[f32]) -> Vec<f32> {
+    if noise.is_empty() {
+        return reference.to_vec();
+    }
+    reference
+        .iter()
+        .zip(noise.iter().cycle())
+        .map(|(x, n)| x + n)
+        .collect()
+}
+
+// Removes the pipeline delay so samples of both signals line up
+fn align(reference: &[f32], degraded: &[f32], sample_rate: usize) -> Vec<f32> {
+    let mut padded: Vec<f32> = degraded.to_vec();
+    padded.resize(
+        reference.len() + (MAX_ALIGNMENT_SECONDS * sample_rate as f32) as usize,
+        Sample::EQUILIBRIUM,
+    );
+    let delay: usize = find_probe(&padded, reference).unwrap_or_else(|| {
+        error!(target: TRACING_TARGET, "Failed to find pipeline delay, comparing unaligned");
+        0
+    });
+    info!(target: TRACING_TARGET, "Pipeline delay is {delay} samples");
+    padded[delay..delay + reference.len()].to_vec()
+}
+
+fn energy_db(samples: &[f32]) -> f32 {
+    10.0 * (samples.iter().map(|x| x * x).sum::<f32>() / samples.len().max(1) as f32 + 1e-12)
+        .log10()
+}
+
+pub fn snr(reference: &[f32], degraded: &[f32]) -> f32 {
+    let signal: f32 = reference.iter().map(|x| x * x).sum();
+    let noise: f32 = reference
+        .iter()
+        .zip(degraded)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum();
+    10.0 * ((signal + 1e-12) / (noise + 1e-12)).log10()
+}
+
+pub fn segmental_snr(reference: &[f32], degraded: &[f32], sample_rate: usize) -> f32 {
+    let segment: usize = (SEGMENT_SECONDS * sample_rate as f32) as usize;
+    let scores: Vec<f32> = reference
+        .chunks(segment)
+        .zip(degraded.chunks(segment))
+        .filter(|(x, _)| energy_db(x) > SILENT_SEGMENT_DB)
+        .map(|(x, y)| snr(x, y).clamp(SEGMENT_SNR_MIN, SEGMENT_SNR_MAX))
+        .collect();
+    scores.iter().sum::<f32>() / scores.len().max(1) as f32
+}
+
+// Log band energies per frame, limited to a fixed range below the loudest bin like ViSQOL does
+fn spectrogram(samples: &[f32], sample_rate: usize) -> Vec<[f32; SPECTRUM_BANDS]> {
+    let mut planner: FftPlanner<f32> = FftPlanner::new();
+    let fft = planner.plan_fft_forward(SPECTRUM_FRAME_SIZE);
+    let window: Vec<f32> = (0..SPECTRUM_FRAME_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / SPECTRUM_FRAME_SIZE as f32).cos())
+        .collect();
+
+    let max_frequency: f32 = SPECTRUM_MAX_FREQUENCY.min(sample_rate as f32 / 2.0);
+    let band_edges: Vec<usize> = (0..=SPECTRUM_BANDS)
+        .map(|i| {
+            let frequency: f32 = SPECTRUM_MIN_FREQUENCY
+                * (max_frequency / SPECTRUM_MIN_FREQUENCY).powf(i as f32 / SPECTRUM_BANDS as f32);
+            ((frequency * SPECTRUM_FRAME_SIZE as f32 / sample_rate as f32) as usize)
+                .min(SPECTRUM_FRAME_SIZE / 2)
+        })
+        .collect();
+
+    let mut frames: Vec<[f32; SPECTRUM_BANDS]> = samples
+        .chunks_exact(SPECTRUM_FRAME_SIZE / 2)
+        .collect::<Vec<&[f32]>>()
+        .windows(2)
+        .map(|halves| {
+            let mut spectrum: Vec<Complex<f32>> = halves
+                .concat()
+                .iter()
+                .zip(window.iter())
+                .map(|(x, w)| Complex::new(x * w, 0.0))
+                .collect();
+            fft.process(&mut spectrum);
+
+            let mut bands: [f32; SPECTRUM_BANDS] = [0.0; SPECTRUM_BANDS];
+            for (band, edges) in bands.iter_mut().zip(band_edges.windows(2)) {
+                let bins: &[Complex<f32>] = &spectrum[edges[0]..edges[1].max(edges[0] + 1)];
+                let power: f32 = bins.iter().map(|x| x.norm_sqr()).sum::<f32>() / bins.len() as f32;
+                *band = 10.0 * (power + 1e-12).log10();
+            }
+            bands
+        })
+        .collect();
+
+    let max: f32 = frames
+        .iter()
+        .flat_map(|x| x.iter())
+        .fold(f32::MIN, |a, b| a.max(*b));
+    for band in frames.iter_mut().flat_map(|x| x.iter_mut()) {
+        *band = band.max(max - SPECTRUM_FLOOR_DB);
+    }
+    frames
+}
+
+// Neurogram similarity over patches of frames from 0 to 1, a relative score rather than a MOS
+pub fn spectral_similarity(reference: &[f32], degraded: &[f32], sample_rate: usize) -> f32 {
+    let reference: Vec<[f32; SPECTRUM_BANDS]> = spectrogram(reference, sample_rate);
+    let degraded: Vec<[f32; SPECTRUM_BANDS]> = spectrogram(degraded, sample_rate);
+    let c1: f32 = (0.01 * SPECTRUM_FLOOR_DB).powi(2);
+    let c2: f32 = (0.03 * SPECTRUM_FLOOR_DB).powi(2);
+
+    let mut similarities: Vec<f32> = Vec::new();
+    for (x, y) in reference
+        .chunks(SIMILARITY_PATCH_FRAMES)
+        .zip(degraded.chunks(SIMILARITY_PATCH_FRAMES))
+    {
+        for band in 0..SPECTRUM_BANDS {
+            let n: f32 = x.len() as f32;
+            let mean_x: f32 = x.iter().map(|f| f[band]).sum::<f32>() / n;
+            let mean_y: f32 = y.iter().map(|f| f[band]).sum::<f32>() / n;
+            let (mut variance_x, mut variance_y, mut covariance) = (0.0, 0.0, 0.0);
+            for (fx, fy) in x.iter().zip(y.iter()) {
+                variance_x += (fx[band] - mean_x).powi(2) / n;
+                variance_y += (fy[band] - mean_y).powi(2) / n;
+                covariance += (fx[band] - mean_x) * (fy[band] - mean_y) / n;
+            }
+            let intensity: f32 =
+                (2.0 * mean_x * mean_y + c1) / (mean_x.powi(2) + mean_y.powi(2) + c1);
+            let structure: f32 = (covariance + c2) / ((variance_x * variance_y).sqrt() + c2);
+            similarities.push(intensity * structure);
+        }
+    }
+
+    let similarity: f32 = similarities.iter().sum::<f32>() / similarities.len().max(1) as f32;
+    similarity.clamp(0.0, 1.0)
+}
+
+pub fn score(reference: &[f32], degraded: &[f32], sample_rate: usize) -> QualityScores {
+    let degraded: Vec<f32> = align(reference, degraded, sample_rate);
+    QualityScores {
+        snr: snr(reference, &degraded),
+        segmental_snr: segmental_snr(reference, &degraded, sample_rate),
+        similarity: spectral_similarity(reference, &degraded, sample_rate),
+    }
+}
+
+fn read_baseline(path: &Path) -> Result<HashMap<String, QualityScores>, String> {
+    let contents: String = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read baseline {}: {e}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let (name, scores) = line.split_once('\t')?;
+            Some((name.to_string(), QualityScores::parse(scores)?))
+        })
+        .collect())
+}
+
+fn write_baseline(path: &Path, scores: &[(String, QualityScores)]) -> Result<(), String> {
+    let contents: String = scores
+        .iter()
+        .map(|(name, scores)| format!("{name}\t{scores}\n"))
+        .collect();
+    fs::write(path, contents).map_err(|e| format!("Failed to write baseline: {e}"))
+}
+
+struct QualityOptions {
+    references: Vec<PathBuf>,
+    rate: ProcessingRate,
+    codec: CodecKind,
+    loss: f32,
+    noise: Option<PathBuf>,
+    suppress_transients: bool,
+    baseline: PathBuf,
+    update_baseline: bool,
+}
+
+impl QualityOptions {
+    // Baseline entries are keyed by file name so they don't depend on where the files are
+    fn name(&self, reference: &Path) -> String {
+        let file_name = |path: &Path| {
+            path.file_name()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let mut name: String = format!(
+            "{}:{:?}:{}:{}",
+            file_name(reference),
+            self.codec,
+            self.loss,
+            self.rate.sample_rate()
+        );
+        if let Some(noise_path) = self.noise.as_ref() {
+            name.push_str(&format!(":noise={}", file_name(noise_path)));
+        }
+        if self.suppress_transients {
+            name.push_str(":transients");
+        }
+        name.to_lowercase()
+    }
+}
+
+fn parse(args: &[String]) -> Result<QualityOptions, String> {
+    let mut args = args.iter();
+    let mut references: Vec<PathBuf> = Vec::new();
+    let mut rate: ProcessingRate = ProcessingRate::default();
+    let mut codec: CodecKind = CodecKind::Opus;
+    let mut loss: f32 = 0.0;
+    let mut noise: Option<PathBuf> = None;
+    let mut suppress_transients: bool = false;
+    let mut baseline: PathBuf = PathBuf::from(DEFAULT_BASELINE);
+    let mut update_baseline: bool = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+        match arg.as_str() {
+            "--rate" => rate = ProcessingRate::parse(value()?)?,
+            "--codec" => codec = CodecKind::parse(value()?)?,
+            "--loss" => {
+                loss = value()?
+                    .parse::<f32>()
+                    .map_err(|e| format!("Invalid loss: {e}"))?
+            }
+            "--noise" => noise = Some(PathBuf::from(value()?)),
+            "--suppress-transients" => suppress_transients = true,
+            "--baseline" => baseline = PathBuf::from(value()?),
+            "--update-baseline" => update_baseline = true,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ => references.push(PathBuf::from(arg)),
+        }
+    }
+
+    if references.is_empty() {
+        return Err(String::from("Missing reference WAV files"));
+    }
+
+    Ok(QualityOptions {
+        references,
+        rate,
+        codec,
+        loss,
+        noise,
+        suppress_transients,
+        baseline,
+        update_baseline,
+    })
+}
+
+// Returns why references fell short of their baseline, nothing when all of them passed.
+// A reference without a baseline entry fails too, unless the baseline is being updated
+pub fn run(args: &[String]) -> Result<Vec<String>, String> {
+    let options: QualityOptions = parse(args)?;
+    let baseline: HashMap<String, QualityScores> = match read_baseline(&options.baseline) {
+        Ok(baseline) => baseline,
+        Err(_) if options.update_baseline => HashMap::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut measured: Vec<(String, QualityScores)> = Vec::new();
+    let mut failures: Vec<String> = Vec::new();
+    for path in options.references.iter() {
+        let (_, sample_rate, _) =
+            load_wav(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        let reference: Vec<f32> = load_file(path, sample_rate)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        // The noisy input is scored against the clean reference, so suppression shows up as a better score
+        let input: Vec<f32> = match options.noise.as_ref() {
+            Some(noise_path) => {
+                let noise: Vec<f32> = load_file(noise_path, sample_rate)
+                    .map_err(|e| format!("Failed to read {}: {e}", noise_path.display()))?;
+                add_noise(&reference, &noise)
+            }
+            None => reference.clone(),
+        };
+        let degraded: Vec<f32> = process(
+            &input,
+            sample_rate,
+            options.rate,
+            options.codec,
+            options.loss,
+            options.suppress_transients,
+        )?;
+        let scores: QualityScores = score(&reference, &degraded, sample_rate);
+
+        let name: String = options.name(path);
+        info!(target: TRACING_TARGET, "Quality of {name}: {scores}");
+        match baseline.get(&name) {
+            Some(expected) => {
+                let regressions: Vec<String> = scores.regressions(expected);
+                if !regressions.is_empty() {
+                    error!(target: TRACING_TARGET, "Quality of {name} regressed: {}", regressions.join(", "));
+                    failures.push(format!("{name} regressed: {}", regressions.join(", ")));
+                }
+            }
+            None if !options.update_baseline => {
+                error!(target: TRACING_TARGET, "No baseline for {name}");
+                failures.push(format!("{name} has no baseline, measured {scores}"));
+            }
+            None => {}
+        }
+        measured.push((name, scores));
+    }
+
+    if options.update_baseline {
+        let mut updated: HashMap<String, QualityScores> = baseline;
+        updated.extend(measured);
+        let mut updated: Vec<(String, QualityScores)> = updated.into_iter().collect();
+        updated.sort_by(|a, b| a.0.cmp(&b.0));
+        write_baseline(&options.baseline, &updated)?;
+        info!(target: TRACING_TARGET, "Updated baseline {}", options.baseline.display());
+        return Ok(Vec::new());
+    }
+
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::test_signals::noise;
+
+    const SAMPLE_RATE: usize = 16000;
+
+    // Two seconds of a gliding tone that stops and starts like speech
+    fn reference() -> Vec<f32> {
+        (0..SAMPLE_RATE * 2)
+            .map(|i| {
+                let t: f32 = i as f32 / SAMPLE_RATE as f32;
+                let gate: f32 = if ((t * 4.0) as usize).is_multiple_of(2) {
+                    1.0
+                } else {
+                    0.0
+                };
+                (2.0 * PI * (200.0 + 300.0 * t) * t).sin() * 0.3 * gate
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_signals_score_best() {
+        let reference: Vec<f32> = reference();
+        let scores: QualityScores = score(&reference, &reference, SAMPLE_RATE);
+        assert!(scores.snr > 60.0, "{scores}");
+        assert_eq!(scores.segmental_snr, SEGMENT_SNR_MAX);
+        assert!(scores.similarity > 0.998, "{scores}");
+    }
+
+    #[test]
+    fn noise_lowers_every_score() {
+        let reference: Vec<f32> = reference();
+        let clean: QualityScores = score(&reference, &reference, SAMPLE_RATE);
+        // The reference is half silence, so its power is 0.0225. Uniform noise up to 0.026 has a hundredth of that
+        let noisy: Vec<f32> = add_noise(&reference, &noise(SAMPLE_RATE, 0.026, 1));
+        let scores: QualityScores = score(&reference, &noisy, SAMPLE_RATE);
+        assert!((scores.snr - 20.0).abs() < 0.5, "{scores}");
+        assert!(scores.segmental_snr < clean.segmental_snr);
+        assert!(scores.similarity < clean.similarity - 0.025, "{scores}");
+    }
+
+    #[test]
+    fn pipeline_delay_is_aligned_out() {
+        let reference: Vec<f32> = reference();
+        let mut delayed: Vec<f32> = vec![0.0; 1234];
+        delayed.extend_from_slice(&reference);
+        assert!(snr(&reference, &delayed) < 0.0);
+        assert!(score(&reference, &delayed, SAMPLE_RATE).snr > 60.0);
+    }
+
+    #[test]
+    fn scores_round_trip_and_regress_beyond_the_tolerance() {
+        let baseline: QualityScores =
+            QualityScores::parse("snr=10.00 segsnr=12.00 similarity=0.900").unwrap();
+        assert_eq!(
+            baseline.to_string(),
+            "snr=10.00 segsnr=12.00 similarity=0.900"
+        );
+        assert!(QualityScores::parse("snr=10 pesq=3").is_none());
+
+        let within: QualityScores = QualityScores {
+            snr: 9.6,
+            segmental_snr: 11.6,
+            similarity: 0.891,
+        };
+        assert!(within.regressions(&baseline).is_empty());
+        let worse: QualityScores = QualityScores {
+            snr: 9.4,
+            segmental_snr: 12.0,
+            similarity: 0.880,
+        };
+        assert_eq!(worse.regressions(&baseline).len(), 2);
+    }
+
+    #[test]
+    fn baseline_names_carry_every_option() {
+        let args = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        let options: QualityOptions = parse(&args(&[
+            "tests/Speech.wav",
+            "--codec",
+            "pcmu",
+            "--loss",
+            "5",
+            "--rate",
+            "24000",
+            "--noise",
+            "/tmp/Keyboard.wav",
+            "--suppress-transients",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options.name(&options.references[0]),
+            "speech.wav:pcmu:5:24000:noise=keyboard.wav:transients"
+        );
+        assert!(parse(&args(&["--codec", "opus"])).is_err());
+        assert!(parse(&args(&["speech.wav", "--loud"])).is_err());
+        assert!(parse(&args(&["speech.wav", "--loss"])).is_err());
+    }
+
+    #[test]
+    fn packet_loss_drops_its_share() {
+        let mut packet_loss: PacketLoss = PacketLoss::new(5.0);
+        let lost: usize = (0..10000).filter(|_| packet_loss.lost()).count();
+        assert!((400..600).contains(&lost), "{lost}");
+        let mut no_loss: PacketLoss = PacketLoss::new(0.0);
+        assert!(!(0..10000).any(|_| no_loss.lost()));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2, FRAC_PI_4, TAU},
+    fmt::Display,
+};
+
+use cpal::Sample;
+
+// Spherical head model, radius in meters and speed of sound in meters per second
+const HEAD_RADIUS: f32 = 0.0875;
+const SPEED_OF_SOUND: f32 = 343.0;
+// The far ear loses highs gradually down to this cutoff when the voice is fully to one side
+const SHADOW_MIN_CUTOFF: f32 = 1500.0;
+const SHADOW_MAX_CUTOFF: f32 = 20000.0;
+// Both ears get the level the constant power stereo pan gives each side at the center,
+// so switching modes keeps voices equally loud
+const SHADOW_GAIN: f32 = FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpatialMode {
+    #[default]
+    Stereo,
+    HeadShadow,
+}
+
+impl SpatialMode {
+    pub const ALL: [SpatialMode; 2] = [SpatialMode::Stereo, SpatialMode::HeadShadow];
+}
+
+impl Display for SpatialMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpatialMode::Stereo => f.write_str("Stereo panning"),
+            SpatialMode::HeadShadow => f.write_str("Delay and head shadow (headphones)"),
+        }
+    }
+}
+
+// Woodworth's formula, in seconds
+fn interaural_delay(azimuth: f32) -> f32 {
+    HEAD_RADIUS / SPEED_OF_SOUND * (azimuth + azimuth.sin())
+}
+
+pub struct Spatializer {
+    sample_rate: f32,
+    max_delay: usize,
+    history: Vec<f32>,
+    extended: Vec<f32>,
+    shadow: f32,
+}
+
+impl Spatializer {
+    pub fn new(sample_rate: usize) -> Self {
+        // Woodworth's ITD at 90 degrees is about 0.66 ms
+        let max_delay: usize = (interaural_delay(FRAC_PI_2) * sample_rate as f32).ceil() as usize;
+        Spatializer {
+            sample_rate: sample_rate as f32,
+            max_delay,
+            history: vec![Sample::EQUILIBRIUM; max_delay],
+            extended: Vec::new(),
+            shadow: Sample::EQUILIBRIUM,
+        }
+    }
+
+    // Adds mono `input` placed at `pan` (-1 left, 1 right) into interleaved stereo `output`
+    pub fn process(
+        &mut self,
+        mode: SpatialMode,
+        pan: f32,
+        gain: f32,
+        input: &[f32],
+        output: &mut [f32],
+    ) {
+        let pan: f32 = pan.clamp(-1.0, 1.0);
+        match mode {
+            SpatialMode::Stereo => {
+                let angle: f32 = (pan + 1.0) * FRAC_PI_4;
+                let (left, right) = (angle.cos() * gain, angle.sin() * gain);
+                for (frame, sample) in output.chunks_exact_mut(2).zip(input.iter()) {
+                    frame[0] += sample * left;
+                    frame[1] += sample * right;
+                }
+            }
+            SpatialMode::HeadShadow => self.head_shadow(pan, gain * SHADOW_GAIN, input, output),
+        }
+    }
+
+    // A spherical head model rather than measured HRTFs: the far ear gets an interaural delay and a one-pole
+    // low-pass for the head shadow
+    fn head_shadow(&mut self, pan: f32, gain: f32, input: &[f32], output: &mut [f32]) {
+        let max_delay: usize = self.max_delay;
+        let delay: usize = ((interaural_delay(pan.abs() * FRAC_PI_2) * self.sample_rate).round()
+            as usize)
+            .min(max_delay);
+        // Without the shadow the filter stays open up to the highest frequency of the processing rate
+        let max_cutoff: f32 = SHADOW_MAX_CUTOFF.min(self.sample_rate / 2.0);
+        let cutoff: f32 = max_cutoff - (max_cutoff - SHADOW_MIN_CUTOFF) * pan.abs();
+        let coefficient: f32 = 1.0 - (-TAU * cutoff / self.sample_rate).exp();
+
+        self.extended.clear();
+        self.extended.extend_from_slice(&self.history);
+        self.extended.extend_from_slice(input);
+
+        for (i, frame) in output.chunks_exact_mut(2).take(input.len()).enumerate() {
+            let near: f32 = input[i] * gain;
+            // The far ear hears the voice later and through the head shadow
+            let far_input: f32 = self.extended[i + max_delay - delay] * gain;
+            self.shadow += (far_input - self.shadow) * coefficient;
+            let (left, right) = if pan < 0.0 {
+                (near, self.shadow)
+            } else {
+                (self.shadow, near)
+            };
+            frame[0] += left;
+            frame[1] += right;
+        }
+
+        let history_start: usize = self.extended.len() - max_delay;
+        self.history
+            .copy_from_slice(&self.extended[history_start..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voice_app::test_signals::noise;
+
+    // Frames until the first non-silent sample on each side of an impulse placed at `pan`
+    fn onsets(sample_rate: usize, pan: f32) -> (usize, usize) {
+        let mut spatializer: Spatializer = Spatializer::new(sample_rate);
+        let mut input: Vec<f32> = vec![0.0; sample_rate / 100];
+        input[0] = 1.0;
+        let mut output: Vec<f32> = vec![0.0; input.len() * 2];
+        spatializer.process(SpatialMode::HeadShadow, pan, 1.0, &input, &mut output);
+        let onset = |channel: usize| {
+            output
+                .chunks_exact(2)
+                .position(|x| x[channel].abs() > 1e-6)
+                .unwrap()
+        };
+        (onset(0), onset(1))
+    }
+
+    #[test]
+    fn far_ear_delay_follows_the_processing_rate() {
+        for sample_rate in [16000, 24000, 48000] {
+            let (left, right) = onsets(sample_rate, 1.0);
+            assert!(left <= Spatializer::new(sample_rate).max_delay);
+            assert_eq!(right, 0);
+            let seconds: f32 = left as f32 / sample_rate as f32;
+            assert!((seconds - 0.00066).abs() < 1.0 / sample_rate as f32);
+        }
+        assert_eq!(onsets(48000, 0.0), (0, 0));
+        let delay: usize = (interaural_delay(FRAC_PI_2) * 48000.0).round() as usize;
+        assert_eq!(onsets(48000, -1.0), (0, delay));
+    }
+
+    #[test]
+    fn delay_carries_across_calls() {
+        let mut spatializer: Spatializer = Spatializer::new(48000);
+        let mut output: Vec<f32> = vec![0.0; 20];
+        let mut input: Vec<f32> = vec![0.0; 10];
+        input[9] = 1.0;
+        spatializer.process(SpatialMode::HeadShadow, 1.0, 1.0, &input, &mut output);
+        assert!(output.chunks_exact(2).all(|x| x[0] == 0.0));
+        let mut output: Vec<f32> = vec![0.0; 80];
+        spatializer.process(SpatialMode::HeadShadow, 1.0, 1.0, &[0.0; 40], &mut output);
+        let onset: usize = output
+            .chunks_exact(2)
+            .position(|x| x[0].abs() > 1e-6)
+            .unwrap();
+        let delay: usize = (interaural_delay(FRAC_PI_2) * 48000.0).round() as usize;
+        assert_eq!(onset + 10 - 9, delay);
+    }
+
+    #[test]
+    fn modes_are_equally_loud_at_the_center() {
+        let input: Vec<f32> = noise(48000, 0.5, 1);
+        let rms = |mode: SpatialMode| {
+            let mut output: Vec<f32> = vec![0.0; input.len() * 2];
+            Spatializer::new(48000).process(mode, 0.0, 1.0, &input, &mut output);
+            (output.iter().map(|x| x * x).sum::<f32>() / output.len() as f32).sqrt()
+        };
+        let (stereo, head_shadow) = (rms(SpatialMode::Stereo), rms(SpatialMode::HeadShadow));
+        // The open shadow filter only takes a little off the top of white noise
+        assert!(
+            (head_shadow / stereo - 1.0).abs() < 0.05,
+            "{stereo} {head_shadow}"
+        );
+    }
+}
@@ -1,7 +1,22 @@
 use iced::widget::combo_box;
 
 use crate::voice_app::{
-    audio::{P2P, SelfListen},
+    analyzer::Analyzer,
+    audio::{CueOutput, P2P, SelfListen},
+    channel_map::{InputChannelMap, OutputChannelMap},
+    codec::CodecKind,
+    cues::CuePlayer,
+    effects::EffectsHandle,
+    file_source::FilePlayer,
+    input_control::{ExtraInput, InputControl},
+    latency::ProbeSignal,
+    latency_profile::LatencyProfile,
+    opus_encoder::OpusSettingsHandle,
+    output_control::{ExtraOutput, OutputControl},
+    processing_rate::ProcessingRate,
+    spatial::SpatialMode,
+    transcription::Captions,
+    transient::TransientSuppression,
     wrapper::DeviceWrapper,
 };
 
@@ -9,9 +24,46 @@ pub struct State {
     pub input_devices: combo_box::State<DeviceWrapper>,
     pub output_devices: combo_box::State<DeviceWrapper>,
     pub input_device: Option<DeviceWrapper>,
+    pub input_channels: InputChannelMap,
+    pub input_channels_text: String,
+    pub input_control: InputControl,
+    pub extra_input_devices: combo_box::State<DeviceWrapper>,
+    pub extra_inputs: Vec<ExtraInput>,
     pub output_device: Option<DeviceWrapper>,
+    pub output_channels: OutputChannelMap,
+    pub output_channels_text: String,
+    pub output_control: OutputControl,
+    pub extra_output_devices: combo_box::State<DeviceWrapper>,
+    pub extra_outputs: Vec<ExtraOutput>,
     pub self_listen: Option<SelfListen>,
     pub p2p: Option<P2P>,
+    pub connecting: bool,
+    pub disconnecting: bool,
+    pub muted: bool,
+    pub spatial_mode: SpatialMode,
+    pub latency_profile: LatencyProfile,
+    // Set until the frame length of a newly picked profile has been applied
+    pub latency_profile_pending: bool,
+    pub processing_rate: ProcessingRate,
     pub peer_address: String,
+    pub record_calls: bool,
+    pub music_mode: bool,
+    pub preferred_codec: CodecKind,
+    pub codec_fallback: bool,
+    pub opus_settings: OpusSettingsHandle,
+    pub effects: EffectsHandle,
+    pub transient_suppression: TransientSuppression,
+    pub captions: Captions,
+    pub file_player: FilePlayer,
+    pub playback_path: String,
+    pub playback_looping: bool,
+    pub playback_gain: f32,
     pub active_tab: String,
+    pub probe_signal: ProbeSignal,
+    pub latency_result: Option<String>,
+    pub analyzer: Analyzer,
+    pub analyzer_raw: bool,
+    pub cue_player: CuePlayer,
+    pub cue_output: Option<CueOutput>,
+    pub cue_volume: f32,
 }
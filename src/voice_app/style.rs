@@ -1,5 +1,5 @@
 use iced::{
-    Background, Border, Color, Length, Shadow, Theme, Vector, border::Radius, widget::button,
+    Background, Border, Color, Length, Shadow, Theme, Vector, border::Radius, color, widget::button,
 };
 use iced_aw::tab_bar;
 
@@ -50,9 +50,11 @@ pub const COMBO_BOX_TEXT_SIZE: f32 = 14.0;
 pub const BUTTON_TEXT_SIZE: f32 = 14.0;
 pub const SELF_LISTEN_BUTTON_WIDTH: f32 = 55.0;
 pub const SELF_LISTEN_BUTTON_HEIGHT: f32 = 25.0;
+pub const MEASURE_BUTTON_WIDTH: f32 = 80.0;
 
 pub const CONNECT_BUTTON_WIDTH: f32 = 90.0;
 pub const CONNECT_BUTTON_HEIGHT: f32 = 90.0;
+pub const MUTE_BUTTON_WIDTH: f32 = 90.0;
 
 pub fn connect_button_style(theme: &Theme, status: button::Status) -> button::Style {
     let extended = theme.extended_palette();
@@ -88,5 +90,33 @@ pub const MIC_ICON_HEIGHT: f32 = 25.0;
 pub const MIC_ICON_WIDTH: f32 = 25.0;
 //
 
+// Analyzer
+pub const ANALYZER_HEIGHT: f32 = 100.0;
+//
+
+// Mixer
+pub const MIXER_LABEL_WIDTH: f32 = 90.0;
+pub const METER_WIDTH: f32 = 80.0;
+pub const METER_HEIGHT: f32 = 8.0;
+//
+
 // Text Input
 pub const TEXT_INPUT_SIZE: f32 = 14.0;
+//
+
+// Checkbox
+pub const CHECKBOX_TEXT_SIZE: f32 = 14.0;
+//
+
+// Pick List
+pub const PICK_LIST_TEXT_SIZE: f32 = 14.0;
+//
+
+// Text
+pub const RESULT_TEXT_SIZE: f32 = 12.0;
+pub const WARNING_TEXT_COLOR: Color = color!(230.0, 160.0, 0.0);
+//
+
+// Slider
+pub const SLIDER_LABEL_TEXT_SIZE: f32 = 14.0;
+pub const SLIDER_LABEL_WIDTH: f32 = 40.0;
@@ -0,0 +1,10 @@
+// Deterministic white noise between -amplitude and amplitude, another sequence for every seed
+pub fn noise(length: usize, amplitude: f32, seed: u32) -> Vec<f32> {
+    let mut state: u32 = seed;
+    (0..length)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            ((state >> 8) as f32 / (1 << 24) as f32 - 0.5) * 2.0 * amplitude
+        })
+        .collect()
+}
@@ -0,0 +1,347 @@
+use std::{collections::VecDeque, f32::consts::TAU};
+
+use cpal::Sample;
+use ringbuf::traits::{Consumer, Observer};
+
+use crate::voice_app::audio::C;
+
+// Segments of 10 ms overlap by half, a Hann window over them sums back to unity
+const HOP_SECONDS: f64 = 0.005;
+// Covers a pitch period of voices down to 100 Hz either way
+const SEARCH_SECONDS: f64 = 0.005;
+// The fill is judged by its lowest point over a second, the margin left for the next late packet.
+// The margin of the first second after prebuffering is the one kept as it depends on the packet size,
+// unless a burst has left more than the prebuffer
+const WINDOW_SECONDS: f64 = 1.0;
+// A few percent is not heard as a change of tempo
+const MAX_STRETCH: f64 = 0.05;
+// The playout speed is set to make up the margin error in about two seconds
+const CORRECTION_SECONDS: f64 = 2.0;
+// Stretching starts once the margin is 10 ms off and runs until it is within 2.5 ms
+const START_ERROR_SECONDS: f64 = 0.01;
+const STOP_ERROR_SECONDS: f64 = 0.0025;
+
+// Plays a jitter buffer slightly faster or slower to keep its margin where it started, without
+// changing pitch. Segments are overlap added at a steady output hop while the input position
+// advances at the playout rate, each segment is shifted to where it best continues the last one (WSOLA).
+// At the original speed the input is passed straight through without the lookahead of the search
+pub struct TimeStretcher {
+    channels: usize,
+    sample_rate: f64,
+    hop: usize,
+    search: usize,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    // Channel sum of `input`, used to find the best continuation
+    mono: Vec<f32>,
+    // Where the last segment used would naturally continue, in frames into `input`. Nothing before it
+    // is discarded, unlike the start of that segment, which a search can leave behind the position
+    natural: usize,
+    // Where the next segment would start at the current rate
+    position: f64,
+    // Second half of the last segment, already windowed
+    overlap: Vec<f32>,
+    // The output is exactly the input from the natural continuation on, with no overlap pending
+    aligned: bool,
+    ready: VecDeque<f32>,
+    reference: Option<usize>,
+    lowest: usize,
+    window_frames: usize,
+    stretching: bool,
+    // Playout speed, above 1 when catching up on a filling buffer
+    rate: f64,
+}
+
+impl TimeStretcher {
+    pub fn new(channels: usize, sample_rate: usize) -> Self {
+        let hop: usize = (sample_rate as f64 * HOP_SECONDS) as usize;
+        TimeStretcher {
+            channels,
+            sample_rate: sample_rate as f64,
+            hop,
+            search: (sample_rate as f64 * SEARCH_SECONDS) as usize,
+            window: (0..hop * 2)
+                .map(|x| 0.5 - 0.5 * (TAU * x as f32 / (hop * 2) as f32).cos())
+                .collect(),
+            // Starts as if a segment of silence was just played, which also gives the search some history
+            input: vec![Sample::EQUILIBRIUM; hop * channels],
+            mono: vec![Sample::EQUILIBRIUM; hop],
+            natural: hop,
+            position: hop as f64,
+            overlap: vec![Sample::EQUILIBRIUM; hop * channels],
+            aligned: true,
+            ready: VecDeque::new(),
+            reference: None,
+            lowest: usize::MAX,
+            window_frames: 0,
+            stretching: false,
+            rate: 1.0,
+        }
+    }
+
+    // Samples taken from the consumer but not played yet
+    pub fn queued(&self) -> usize {
+        self.mono.len().saturating_sub(self.natural) * self.channels + self.ready.len()
+    }
+
+    // Fills `output` with interleaved audio from `consumer`, `prebuffer` frames being what playback
+    // started from. Returns how many samples were written, fewer when the consumer ran dry
+    pub fn pull(&mut self, consumer: &mut C, output: &mut [f32], prebuffer: usize) -> usize {
+        let fill: usize = (consumer.occupied_len() + self.queued()) / self.channels;
+        self.lowest = self.lowest.min(fill);
+        self.window_frames += output.len() / self.channels;
+        if self.window_frames as f64 >= self.sample_rate * WINDOW_SECONDS {
+            let reference: usize = *self.reference.get_or_insert(self.lowest.min(prebuffer));
+            // In seconds
+            let error: f64 = (self.lowest as f64 - reference as f64) / self.sample_rate;
+            if error.abs() >= START_ERROR_SECONDS {
+                self.stretching = true;
+            } else if error.abs() < STOP_ERROR_SECONDS {
+                self.stretching = false;
+            }
+            self.rate = if self.stretching {
+                1.0 + (error / CORRECTION_SECONDS).clamp(-MAX_STRETCH, MAX_STRETCH)
+            } else {
+                1.0
+            };
+            self.lowest = usize::MAX;
+            self.window_frames = 0;
+        }
+
+        while self.ready.len() < output.len() {
+            let produced: bool = if self.aligned && self.rate == 1.0 {
+                self.copy(consumer, (output.len() - self.ready.len()) / self.channels)
+            } else {
+                self.next_segment(consumer)
+            };
+            if !produced {
+                break;
+            }
+        }
+        let count: usize = self.ready.len().min(output.len());
+        for (sample, x) in output.iter_mut().zip(self.ready.drain(..count)) {
+            *sample = x;
+        }
+        // Running dry prebuffers again, the margin is judged afresh once it plays
+        if count < output.len() {
+            self.reference = None;
+            self.lowest = usize::MAX;
+            self.window_frames = 0;
+            self.stretching = false;
+            self.rate = 1.0;
+        }
+        count
+    }
+
+    // Moves up to `frames` of input into `ready` unchanged, false when there was none
+    fn copy(&mut self, consumer: &mut C, frames: usize) -> bool {
+        let channels: usize = self.channels;
+        let natural: usize = self.natural;
+        self.read(consumer, natural + frames);
+        let frames: usize = (self.mono.len() - natural).min(frames);
+        self.ready
+            .extend(self.input[natural * channels..(natural + frames) * channels].iter());
+        self.natural += frames;
+        self.discard(self.natural.saturating_sub(self.search));
+        frames > 0
+    }
+
+    // Overlap adds one more hop into `ready`, false when there isn't enough input for it
+    fn next_segment(&mut self, consumer: &mut C) -> bool {
+        let channels: usize = self.channels;
+        let hop: usize = self.hop;
+        let segment: usize = hop * 2;
+        let natural: usize = self.natural;
+        // Coming back to the original speed takes one hop without a search to finish the overlap.
+        // The search looks ahead as far as there is input, short of that it only needs the segment
+        let (wanted, needed) = if self.rate == 1.0 {
+            self.position = natural as f64;
+            (natural + segment, natural + segment)
+        } else {
+            if self.aligned {
+                self.position = natural as f64;
+            }
+            let position: usize = self.position as usize;
+            (
+                (position + self.search).max(natural) + segment,
+                position.max(natural) + segment,
+            )
+        };
+        self.read(consumer, wanted);
+        if self.mono.len() < needed {
+            return false;
+        }
+        // Leaving a straight copy, the natural continuation is what fades out
+        if self.aligned {
+            for (i, overlap) in self.overlap.iter_mut().enumerate() {
+                *overlap = self.input[natural * channels + i] * self.window[hop + i / channels];
+            }
+        }
+
+        let chosen: usize = if self.rate == 1.0 {
+            natural
+        } else {
+            self.best_match(natural)
+        };
+        for i in 0..hop {
+            for c in 0..channels {
+                let head: f32 = self.input[(chosen + i) * channels + c];
+                let tail: f32 = self.input[(chosen + hop + i) * channels + c];
+                let overlap: &mut f32 = &mut self.overlap[i * channels + c];
+                self.ready.push_back(*overlap + head * self.window[i]);
+                *overlap = tail * self.window[hop + i];
+            }
+        }
+        self.natural = chosen + hop;
+        self.position += hop as f64 * self.rate;
+        // A hop at the original speed adds back up to the input
+        self.aligned = self.rate == 1.0;
+
+        // Only the next natural continuation and the next search range are still needed
+        self.discard(
+            self.natural
+                .min((self.position as usize).saturating_sub(self.search)),
+        );
+        true
+    }
+
+    fn discard(&mut self, frames: usize) {
+        self.input.drain(..frames * self.channels);
+        self.mono.drain(..frames);
+        self.natural -= frames;
+        self.position -= frames as f64;
+    }
+
+    // Reads whole frames from the consumer until `input` holds `end` of them or it runs dry
+    fn read(&mut self, consumer: &mut C, end: usize) {
+        let channels: usize = self.channels;
+        let frames: usize = self.mono.len();
+        if frames < end {
+            let missing: usize = (end - frames).min(consumer.occupied_len() / channels);
+            self.input
+                .resize((frames + missing) * channels, Sample::EQUILIBRIUM);
+            consumer.pop_slice(&mut self.input[frames * channels..]);
+            self.mono.extend(
+                self.input[frames * channels..]
+                    .chunks_exact(channels)
+                    .map(|frame| frame.iter().sum::<f32>()),
+            );
+        }
+    }
+
+    // Segment start around `position` most similar to the natural continuation of the last segment,
+    // searched on every other sample first and refined next to the best one
+    fn best_match(&self, natural: usize) -> usize {
+        let hop: usize = self.hop;
+        let segment: usize = hop * 2;
+        let reference: &[f32] = &self.mono[natural..natural + hop];
+        let similarity = |start: usize, step: usize| -> f32 {
+            let (correlation, energy) =
+                (0..hop)
+                    .step_by(step)
+                    .fold((0.0_f32, 0.0_f32), |(correlation, energy), i| {
+                        let x: f32 = self.mono[start + i];
+                        (correlation + reference[i] * x, energy + x * x)
+                    });
+            correlation / (energy + 1e-9).sqrt()
+        };
+        let position: usize = self.position as usize;
+        let lower: usize = position.saturating_sub(self.search);
+        let upper: usize = (position + self.search).min(self.mono.len() - segment);
+        let coarse: usize = (lower..=upper)
+            .step_by(2)
+            .map(|x| (x, similarity(x, 2)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(position, |x| x.0);
+        (coarse.saturating_sub(1).max(lower)..=(coarse + 1).min(upper))
+            .map(|x| (x, similarity(x, 1)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(coarse, |x| x.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::{
+        HeapRb,
+        traits::{Producer, Split},
+    };
+
+    use super::*;
+    use crate::voice_app::audio::P;
+
+    const SAMPLE_RATE: usize = 48000;
+    const PULL: usize = SAMPLE_RATE / 100;
+    const PREBUFFER: usize = SAMPLE_RATE / 10;
+
+    // Plays a sine arriving `speed` times as fast as it is pulled for `seconds`, returning each pull
+    // and the fill left at the end in frames
+    fn play(channels: usize, speed: f64, seconds: usize) -> (Vec<Vec<f32>>, usize) {
+        let (mut producer, mut consumer): (P, C) =
+            HeapRb::<f32>::new(SAMPLE_RATE * channels).split();
+        let mut stretcher: TimeStretcher = TimeStretcher::new(channels, SAMPLE_RATE);
+        let mut written: usize = 0;
+        let mut due: f64 = PREBUFFER as f64;
+        let mut pulls: Vec<Vec<f32>> = Vec::new();
+        for _ in 0..seconds * 100 {
+            while (written as f64) < due {
+                let sample: f32 = (TAU * 220.0 * written as f32 / SAMPLE_RATE as f32).sin();
+                producer.push_slice(&vec![sample * 0.5; channels]);
+                written += 1;
+            }
+            due += PULL as f64 * speed;
+            let mut output: Vec<f32> = vec![Sample::EQUILIBRIUM; PULL * channels];
+            let count: usize = stretcher.pull(&mut consumer, &mut output, PREBUFFER);
+            output.truncate(count);
+            pulls.push(output);
+        }
+        let fill: usize = (consumer.occupied_len() + stretcher.queued()) / channels;
+        (pulls, fill)
+    }
+
+    fn assert_continuous(pulls: &[Vec<f32>], channels: usize) {
+        for (i, pull) in pulls.iter().enumerate() {
+            assert_eq!(pull.len(), PULL * channels, "pull {i} ran dry");
+            // Overlap adding a segment out of phase would dip or double the level
+            let rms: f32 = (pull.iter().map(|x| x * x).sum::<f32>() / pull.len() as f32).sqrt();
+            assert!(
+                (rms - 0.5 / 2.0_f32.sqrt()).abs() < 0.05,
+                "pull {i} at {rms}"
+            );
+        }
+    }
+
+    #[test]
+    fn steady_input_passes_through_unchanged() {
+        let (pulls, fill) = play(2, 1.0, 3);
+        let played: Vec<f32> = pulls.concat();
+        for (i, frame) in played.chunks_exact(2).enumerate() {
+            let sample: f32 = (TAU * 220.0 * i as f32 / SAMPLE_RATE as f32).sin() * 0.5;
+            assert_eq!(frame, [sample, sample], "frame {i}");
+        }
+        // The input for the next pull is still to come
+        assert_eq!(fill, PREBUFFER - PULL);
+    }
+
+    #[test]
+    fn faster_input_is_caught_up() {
+        for channels in [1, 2] {
+            let (pulls, fill) = play(channels, 1.02, 12);
+            assert_continuous(&pulls, channels);
+            // Left alone the fill would have grown by 240 ms. The playout speed follows the error,
+            // so it settles about 40 ms over to make up the 2 %
+            assert!(fill < PREBUFFER + SAMPLE_RATE / 15, "{fill}");
+        }
+    }
+
+    #[test]
+    fn slower_input_is_stretched() {
+        for channels in [1, 2] {
+            let (pulls, fill) = play(channels, 0.98, 12);
+            assert_continuous(&pulls, channels);
+            // Left alone it would run dry after 5 s. The margin is judged from the lowest fill
+            // of the first second, which is already 20 ms down
+            assert!(fill > SAMPLE_RATE / 50, "{fill}");
+        }
+    }
+}
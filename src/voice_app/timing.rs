@@ -0,0 +1,370 @@
+use std::{
+    fmt::Display,
+    hint,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering, fence},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Rates measured over shorter spans are dominated by callback jitter
+const DRIFT_MIN_SPAN: Duration = Duration::from_secs(10);
+const TRANSPORT_SMOOTHING: f64 = 0.05;
+// Stream positions in audio packets count frames at 48 kHz whatever the codec runs at, like RTP does for Opus
+pub const POSITION_RATE: usize = 48000;
+
+pub fn unix_micros(instant: Instant) -> u64 {
+    let now: Instant = Instant::now();
+    let system_now: SystemTime = SystemTime::now();
+    let system_time: SystemTime = if instant <= now {
+        system_now - (now - instant)
+    } else {
+        system_now + (instant - now)
+    };
+    system_time
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn drift_ppm(frames: f64, span: Duration, sample_rate: f64) -> Option<f64> {
+    (span >= DRIFT_MIN_SPAN).then(|| (frames / span.as_secs_f64() / sample_rate - 1.0) * 1e6)
+}
+
+struct ClockState {
+    sample_rate: f64,
+    // Instants are kept as microseconds from here so they fit in atomics
+    base: Instant,
+    // Odd while the stream callback updates the anchors, readers retry until they see the same even value
+    // on both sides of their reads. Zero until the first callback
+    sequence: AtomicU64,
+    frames: AtomicU64,
+    first_frame: AtomicU64,
+    first_micros: AtomicI64,
+    latest_frame: AtomicU64,
+    latest_micros: AtomicI64,
+    device_latency: AtomicU64,
+}
+
+struct Anchors {
+    first: (u64, Instant),
+    latest: (u64, Instant),
+}
+
+impl ClockState {
+    fn micros(&self, instant: Instant) -> i64 {
+        if instant >= self.base {
+            (instant - self.base).as_micros() as i64
+        } else {
+            -((self.base - instant).as_micros() as i64)
+        }
+    }
+
+    fn instant(&self, micros: i64) -> Instant {
+        if micros >= 0 {
+            self.base + Duration::from_micros(micros as u64)
+        } else {
+            self.base - Duration::from_micros(micros.unsigned_abs())
+        }
+    }
+
+    fn anchors(&self) -> Option<Anchors> {
+        loop {
+            let sequence: u64 = self.sequence.load(Ordering::Acquire);
+            if sequence == 0 {
+                return None;
+            }
+            if sequence % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let first: (u64, i64) = (
+                self.first_frame.load(Ordering::Relaxed),
+                self.first_micros.load(Ordering::Relaxed),
+            );
+            let latest: (u64, i64) = (
+                self.latest_frame.load(Ordering::Relaxed),
+                self.latest_micros.load(Ordering::Relaxed),
+            );
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return Some(Anchors {
+                    first: (first.0, self.instant(first.1)),
+                    latest: (latest.0, self.instant(latest.1)),
+                });
+            }
+        }
+    }
+}
+
+// Maps a stream's running frame count onto wall clock time using the callback timestamps.
+// The stream callback is the only writer and never blocks
+#[derive(Clone)]
+pub struct StreamClock(Arc<ClockState>);
+
+impl StreamClock {
+    pub fn new(sample_rate: u32) -> Self {
+        StreamClock(Arc::new(ClockState {
+            sample_rate: sample_rate as f64,
+            base: Instant::now(),
+            sequence: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            first_frame: AtomicU64::new(0),
+            first_micros: AtomicI64::new(0),
+            latest_frame: AtomicU64::new(0),
+            latest_micros: AtomicI64::new(0),
+            device_latency: AtomicU64::new(0),
+        }))
+    }
+
+    // `instant` is when the first of `frames` was captured or will be played
+    pub fn advance(&self, frames: usize, instant: Instant) {
+        let clock: &ClockState = &self.0;
+        let now: Instant = Instant::now();
+        let device_latency: Duration = if instant <= now {
+            now - instant
+        } else {
+            instant - now
+        };
+        clock
+            .device_latency
+            .store(device_latency.as_micros() as u64, Ordering::Relaxed);
+
+        let frame: u64 = clock.frames.load(Ordering::Relaxed);
+        let micros: i64 = clock.micros(instant);
+        let sequence: u64 = clock.sequence.load(Ordering::Relaxed);
+        clock.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        if sequence == 0 {
+            clock.first_frame.store(frame, Ordering::Relaxed);
+            clock.first_micros.store(micros, Ordering::Relaxed);
+        }
+        clock.latest_frame.store(frame, Ordering::Relaxed);
+        clock.latest_micros.store(micros, Ordering::Relaxed);
+        clock.sequence.store(sequence + 2, Ordering::Release);
+        clock.frames.store(frame + frames as u64, Ordering::Relaxed);
+    }
+
+    // Time of the frame `offset` worth of audio into the stream
+    pub fn instant_at(&self, offset: Duration) -> Option<Instant> {
+        let (anchor_frame, anchor_instant) = self.0.anchors()?.latest;
+        let anchor_offset: f64 = anchor_frame as f64 / self.0.sample_rate;
+        let delta: f64 = offset.as_secs_f64() - anchor_offset;
+        if delta >= 0.0 {
+            Some(anchor_instant + Duration::from_secs_f64(delta))
+        } else {
+            anchor_instant.checked_sub(Duration::from_secs_f64(-delta))
+        }
+    }
+
+    pub fn device_latency(&self) -> Duration {
+        Duration::from_micros(self.0.device_latency.load(Ordering::Relaxed))
+    }
+
+    // Deviation of the measured sample rate from the nominal one, in parts per million
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let Anchors {
+            first: (first_frame, first_instant),
+            latest: (latest_frame, latest_instant),
+        } = self.0.anchors()?;
+        drift_ppm(
+            (latest_frame - first_frame) as f64,
+            latest_instant - first_instant,
+            self.0.sample_rate,
+        )
+    }
+}
+
+struct RemoteState {
+    sample_rate: f64,
+    first: Option<(u64, u64)>,
+    latest: Option<(u64, u64)>,
+    // Received audio at `POSITION_RATE`, for peers that send no positions
+    received: u64,
+    transport: Option<f64>,
+}
+
+// Tracks the capture timestamps of received packets against where they sit in the peer's stream
+#[derive(Clone)]
+pub struct RemoteClock(Arc<Mutex<RemoteState>>);
+
+impl RemoteClock {
+    // `sample_rate` is the rate received audio is decoded at
+    pub fn new(sample_rate: u32) -> Self {
+        RemoteClock(Arc::new(Mutex::new(RemoteState {
+            sample_rate: sample_rate as f64,
+            first: None,
+            latest: None,
+            received: 0,
+            transport: None,
+        })))
+    }
+
+    // `timestamp` is the peer's capture time in microseconds since the Unix epoch and `position` the
+    // unwrapped start of the packet in its stream. Without positions, as from version 1 peers, the received audio
+    // is counted instead and lost packets skew the drift
+    pub fn observe(&self, timestamp: u64, position: Option<u64>, frames: usize) {
+        let mut remote = self.0.lock().unwrap();
+        // Needs both clocks synchronized, e.g. by NTP, to mean anything
+        let transport: f64 = unix_micros(Instant::now()) as f64 - timestamp as f64;
+        remote.transport = Some(match remote.transport {
+            Some(smoothed) => smoothed + (transport - smoothed) * TRANSPORT_SMOOTHING,
+            None => transport,
+        });
+        let position: u64 = position.unwrap_or(remote.received);
+        remote.received += (frames as f64 * POSITION_RATE as f64 / remote.sample_rate) as u64;
+        let anchor: (u64, u64) = (position, timestamp);
+        remote.first.get_or_insert(anchor);
+        // Late packets don't move the anchor backwards
+        if remote.latest.is_none_or(|(latest, _)| position > latest) {
+            remote.latest = Some(anchor);
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.0.lock().unwrap().sample_rate
+    }
+
+    pub fn transport_latency(&self) -> Option<Duration> {
+        self.0
+            .lock()
+            .unwrap()
+            .transport
+            .map(|x| Duration::from_micros(x.max(0.0) as u64))
+    }
+
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let remote = self.0.lock().unwrap();
+        let ((first_position, first_timestamp), (latest_position, latest_timestamp)) =
+            (remote.first?, remote.latest?);
+        drift_ppm(
+            latest_position.saturating_sub(first_position) as f64,
+            Duration::from_micros(latest_timestamp.saturating_sub(first_timestamp)),
+            POSITION_RATE as f64,
+        )
+    }
+}
+
+// Extends a wrapping 32 bit stream position using the last one seen, which may be up to half the range away
+// in either direction
+pub fn unwrap_position(last: Option<(u32, u64)>, position: u32) -> u64 {
+    match last {
+        Some((last, unwrapped)) => {
+            let delta: i64 = position.wrapping_sub(last) as i32 as i64;
+            unwrapped.saturating_add_signed(delta)
+        }
+        None => position as u64,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimingReport {
+    pub input_device_latency: Duration,
+    pub output_device_latency: Duration,
+    pub transport_latency: Option<Duration>,
+    pub receive_buffering: Duration,
+    pub input_drift_ppm: Option<f64>,
+    pub output_drift_ppm: Option<f64>,
+    pub remote_drift_ppm: Option<f64>,
+}
+
+impl TimingReport {
+    // From the peer's microphone to our speaker
+    pub fn end_to_end(&self) -> Option<Duration> {
+        self.transport_latency
+            .map(|x| x + self.receive_buffering + self.output_device_latency)
+    }
+}
+
+fn format_ppm(ppm: Option<f64>) -> String {
+    ppm.map(|x| format!("{x:+.0} ppm"))
+        .unwrap_or(String::from("measuring"))
+}
+
+impl Display for TimingReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.end_to_end() {
+            Some(end_to_end) => writeln!(
+                f,
+                "End to end {:.1} ms (transport {:.1} ms, buffering {:.1} ms)",
+                end_to_end.as_secs_f64() * 1000.0,
+                self.transport_latency.unwrap_or_default().as_secs_f64() * 1000.0,
+                self.receive_buffering.as_secs_f64() * 1000.0
+            )?,
+            None => writeln!(f, "End to end: waiting for packets")?,
+        }
+        writeln!(
+            f,
+            "Device latency: input {:.1} ms, output {:.1} ms",
+            self.input_device_latency.as_secs_f64() * 1000.0,
+            self.output_device_latency.as_secs_f64() * 1000.0
+        )?;
+        write!(
+            f,
+            "Drift: input {}, output {}, peer {}",
+            format_ppm(self.input_drift_ppm),
+            format_ppm(self.output_drift_ppm),
+            format_ppm(self.remote_drift_ppm)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_clock_maps_frames_to_instants() {
+        let clock: StreamClock = StreamClock::new(48000);
+        assert!(clock.instant_at(Duration::ZERO).is_none());
+        let start: Instant = Instant::now() + Duration::from_secs(1);
+        clock.advance(480, start);
+        clock.advance(480, start + Duration::from_millis(10));
+        // Anchors are kept to the microsecond
+        let close = |offset: u64, expected: Instant| {
+            let instant: Instant = clock.instant_at(Duration::from_millis(offset)).unwrap();
+            assert!(instant.max(expected) - instant.min(expected) < Duration::from_micros(2));
+        };
+        close(25, start + Duration::from_millis(25));
+        close(0, start);
+    }
+
+    #[test]
+    fn stream_clock_measures_drift() {
+        let clock: StreamClock = StreamClock::new(48000);
+        let start: Instant = Instant::now();
+        // 100 ppm fast: 48004.8 frames per second of callbacks
+        for i in 0..=1200u64 {
+            clock.advance(
+                480,
+                start + Duration::from_secs_f64(i as f64 * 480.0 / 48004.8),
+            );
+        }
+        let drift: f64 = clock.drift_ppm().unwrap();
+        assert!((drift - 100.0).abs() < 1.0, "{drift}");
+    }
+
+    #[test]
+    fn positions_unwrap_across_the_wrap() {
+        let first: u64 = unwrap_position(None, u32::MAX - 10);
+        let next: u64 = unwrap_position(Some((u32::MAX - 10, first)), 5);
+        assert_eq!(next, first + 16);
+        let late: u64 = unwrap_position(Some((5, next)), u32::MAX - 2);
+        assert_eq!(late, first + 8);
+    }
+
+    #[test]
+    fn lost_packets_dont_skew_remote_drift() {
+        let clock: RemoteClock = RemoteClock::new(48000);
+        // 20 ms packets captured at exactly 48 kHz, every third one lost
+        for i in 0..600u64 {
+            if i % 3 == 2 {
+                continue;
+            }
+            clock.observe(1_000_000 + i * 20_000, Some(i * 960), 960);
+        }
+        let drift: f64 = clock.drift_ppm().unwrap();
+        assert!(drift.abs() < 1.0, "{drift}");
+    }
+}
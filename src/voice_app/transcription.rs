@@ -0,0 +1,433 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use tracing::error;
+
+use crate::voice_app::{app_tracing::TRACING_TARGET, file_source::resample};
+
+// whisper.cpp only accepts 16 kHz input
+const WHISPER_SAMPLE_RATE: usize = 16000;
+// Voice activity is decided per 10 ms frame
+const SEGMENT_FRAMES_PER_SECOND: usize = 100;
+// About -45 dBFS
+const SPEECH_THRESHOLD: f32 = 0.0056;
+// An utterance ends after this much silence, or is cut at the maximum length
+const SEGMENT_END_SILENCE_MS: usize = 600;
+const SEGMENT_MAX_LENGTH_MS: usize = 10000;
+// Shorter bursts are clicks and breaths more often than words
+const SEGMENT_MIN_SPEECH_MS: usize = 300;
+const MAX_CAPTION_LINES: usize = 100;
+
+pub trait Transcriber: Send {
+    // `segment` is a single utterance of mono audio at the internal processing rate
+    fn transcribe(&mut self, segment: &[f32], sample_rate: usize) -> Result<String, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TranscriberKind {
+    #[default]
+    Off,
+    WhisperCli,
+    Stub,
+}
+
+impl TranscriberKind {
+    pub const ALL: [TranscriberKind; 3] = [
+        TranscriberKind::Off,
+        TranscriberKind::WhisperCli,
+        TranscriberKind::Stub,
+    ];
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "off" => Ok(TranscriberKind::Off),
+            "whisper" | "whisper-cli" => Ok(TranscriberKind::WhisperCli),
+            "stub" => Ok(TranscriberKind::Stub),
+            _ => Err(format!("Unknown transcriber {value}")),
+        }
+    }
+}
+
+impl Display for TranscriberKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscriberKind::Off => f.write_str("No captions"),
+            TranscriberKind::WhisperCli => f.write_str("whisper-cli, started per utterance"),
+            TranscriberKind::Stub => f.write_str("Test stub"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriberSettings {
+    pub kind: TranscriberKind,
+    pub whisper_binary: String,
+    pub whisper_model: String,
+}
+
+impl Default for TranscriberSettings {
+    fn default() -> Self {
+        TranscriberSettings {
+            kind: TranscriberKind::Off,
+            whisper_binary: String::from("whisper-cli"),
+            whisper_model: String::from("models/ggml-base.en.bin"),
+        }
+    }
+}
+
+impl TranscriberSettings {
+    // `name` keeps the temporary files of transcribers running side by side apart
+    pub fn create(&self, name: &str) -> Option<Box<dyn Transcriber>> {
+        match self.kind {
+            TranscriberKind::Off => None,
+            TranscriberKind::WhisperCli => Some(Box::new(WhisperCliTranscriber {
+                binary: self.whisper_binary.clone(),
+                model: self.whisper_model.clone(),
+                path: std::env::temp_dir()
+                    .join(format!("p2p-voice-{}-{name}.wav", std::process::id())),
+            })),
+            TranscriberKind::Stub => Some(Box::new(StubTranscriber { count: 0 })),
+        }
+    }
+}
+
+// Starts the whisper.cpp command line tool for every utterance, which loads the model each time.
+// Slow, but it keeps the model out of this process and needs no bindings
+struct WhisperCliTranscriber {
+    binary: String,
+    model: String,
+    path: PathBuf,
+}
+
+impl WhisperCliTranscriber {
+    fn write_wav(&self, segment: &[f32], sample_rate: usize) -> Result<(), hound::Error> {
+        let spec: WavSpec = WavSpec {
+            channels: 1,
+            sample_rate: WHISPER_SAMPLE_RATE as u32,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer: WavWriter<BufWriter<File>> = WavWriter::create(&self.path, spec)?;
+        for sample in resample(segment, sample_rate, WHISPER_SAMPLE_RATE) {
+            writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+        writer.finalize()
+    }
+}
+
+impl Transcriber for WhisperCliTranscriber {
+    fn transcribe(&mut self, segment: &[f32], sample_rate: usize) -> Result<String, String> {
+        self.write_wav(segment, sample_rate)
+            .map_err(|e| e.to_string())?;
+        let output: Output = Command::new(&self.binary)
+            .arg("--model")
+            .arg(&self.model)
+            .arg("--file")
+            .arg(&self.path)
+            .args(["--no-timestamps", "--no-prints"])
+            .output()
+            .map_err(|e| format!("Failed to run {}: {e}", self.binary))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        // Silence and noise come back as bracketed tags like [BLANK_AUDIO]
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .filter(|x| !(x.starts_with('[') && x.ends_with(']')))
+            .collect::<Vec<&str>>()
+            .join(" "))
+    }
+}
+
+impl Drop for WhisperCliTranscriber {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Describes each utterance instead of recognizing it, so runs can be compared exactly
+struct StubTranscriber {
+    count: usize,
+}
+
+impl Transcriber for StubTranscriber {
+    fn transcribe(&mut self, segment: &[f32], sample_rate: usize) -> Result<String, String> {
+        self.count += 1;
+        let peak: f32 = segment.iter().fold(0.0_f32, |peak, x| peak.max(x.abs()));
+        Ok(format!(
+            "Utterance {} of {:.1} s peaking at {:.0} dBFS",
+            self.count,
+            segment.len() as f32 / sample_rate as f32,
+            20.0 * (peak + 1e-9).log10()
+        ))
+    }
+}
+
+// Cuts a continuous signal into utterances on pauses
+pub struct Segmenter {
+    sample_rate: usize,
+    pending: Vec<f32>,
+    // Samples taken in before `pending`
+    position: usize,
+    segment: Vec<f32>,
+    segment_start: usize,
+    speech: usize,
+    silence: usize,
+}
+
+impl Segmenter {
+    pub fn new(sample_rate: usize) -> Self {
+        Segmenter {
+            sample_rate,
+            pending: Vec::new(),
+            position: 0,
+            segment: Vec::new(),
+            segment_start: 0,
+            speech: 0,
+            silence: 0,
+        }
+    }
+
+    // Utterances come with when they started, counted from the first sample pushed
+    pub fn push(&mut self, samples: &[f32]) -> Vec<(Duration, Vec<f32>)> {
+        let frame_size: usize = self.sample_rate / SEGMENT_FRAMES_PER_SECOND;
+        let end_silence: usize = self.sample_rate * SEGMENT_END_SILENCE_MS / 1000;
+        let max_length: usize = self.sample_rate * SEGMENT_MAX_LENGTH_MS / 1000;
+        let min_speech: usize = self.sample_rate * SEGMENT_MIN_SPEECH_MS / 1000;
+        self.pending.extend_from_slice(samples);
+        let mut segments: Vec<(Duration, Vec<f32>)> = Vec::new();
+        let frames: usize = self.pending.len() / frame_size;
+        for i in 0..frames {
+            let frame: &[f32] = &self.pending[i * frame_size..(i + 1) * frame_size];
+            let rms: f32 = (frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32).sqrt();
+            if rms >= SPEECH_THRESHOLD {
+                self.speech += frame.len();
+                self.silence = 0;
+            } else if self.segment.is_empty() {
+                continue;
+            } else {
+                self.silence += frame.len();
+            }
+            if self.segment.is_empty() {
+                self.segment_start = self.position + i * frame_size;
+            }
+            self.segment.extend_from_slice(frame);
+            if self.silence >= end_silence || self.segment.len() >= max_length {
+                let segment: Vec<f32> = std::mem::take(&mut self.segment);
+                if self.speech >= min_speech {
+                    segments.push((
+                        Duration::from_secs_f64(
+                            self.segment_start as f64 / self.sample_rate as f64,
+                        ),
+                        segment,
+                    ));
+                }
+                self.speech = 0;
+                self.silence = 0;
+            }
+        }
+        self.pending.drain(..frames * frame_size);
+        self.position += frames * frame_size;
+        segments
+    }
+
+    // Moves past `samples` that never arrived. Whatever is pending before the gap goes with them,
+    // an utterance cut by it is dropped
+    pub fn skip(&mut self, samples: usize) {
+        self.position += self.pending.len() + samples;
+        self.pending.clear();
+        self.segment.clear();
+        self.speech = 0;
+        self.silence = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speaker {
+    Local,
+    Peer,
+}
+
+impl Display for Speaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Speaker::Local => f.write_str("You"),
+            Speaker::Peer => f.write_str("Peer"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptionLine {
+    pub speaker: Speaker,
+    // When the utterance started, from the start of the call
+    pub start: Duration,
+    pub text: String,
+}
+
+impl Display for CaptionLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.speaker, self.text)
+    }
+}
+
+#[derive(Default)]
+struct CaptionsState {
+    settings: TranscriberSettings,
+    lines: VecDeque<CaptionLine>,
+    transcript: Option<BufWriter<File>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Captions(Arc<Mutex<CaptionsState>>);
+
+impl Captions {
+    pub fn settings(&self) -> TranscriberSettings {
+        self.0.lock().unwrap().settings.clone()
+    }
+
+    pub fn set_settings(&self, settings: TranscriberSettings) {
+        self.0.lock().unwrap().settings = settings;
+    }
+
+    pub fn lines(&self) -> Vec<CaptionLine> {
+        self.0.lock().unwrap().lines.iter().cloned().collect()
+    }
+
+    // Clears the captions on screen and saves every following line to `path`
+    pub fn start_transcript(&self, path: &Path) -> io::Result<()> {
+        let file: File = File::create(path)?;
+        let mut captions = self.0.lock().unwrap();
+        captions.lines.clear();
+        captions.transcript = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    pub fn stop_transcript(&self) {
+        self.0.lock().unwrap().transcript = None;
+    }
+
+    pub fn push(&self, line: CaptionLine) {
+        let mut captions = self.0.lock().unwrap();
+        if let Some(writer) = captions.transcript.as_mut() {
+            // Lines are stamped with when the words were said, not when transcribing them finished
+            let start: u64 = line.start.as_secs();
+            // Flushed per line so the transcript survives the app being killed mid-call
+            if let Err(e) = writeln!(writer, "[{:02}:{:02}] {line}", start / 60, start % 60)
+                .and_then(|_| writer.flush())
+            {
+                error!(target: TRACING_TARGET, "Failed to write transcript: {e}");
+            }
+        }
+        captions.lines.push_back(line);
+        if captions.lines.len() > MAX_CAPTION_LINES {
+            captions.lines.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 16000;
+
+    fn tone(milliseconds: usize) -> Vec<f32> {
+        (0..SAMPLE_RATE * milliseconds / 1000)
+            .map(|i| 0.1 * (i as f32 * 0.1).sin())
+            .collect()
+    }
+
+    fn silence(milliseconds: usize) -> Vec<f32> {
+        vec![0.0; SAMPLE_RATE * milliseconds / 1000]
+    }
+
+    #[test]
+    fn utterances_are_cut_on_pauses_with_their_start() {
+        let mut segmenter: Segmenter = Segmenter::new(SAMPLE_RATE);
+        let mut signal: Vec<f32> = silence(1500);
+        signal.extend(tone(800));
+        signal.extend(silence(1000));
+        // Too short to be speech
+        signal.extend(tone(100));
+        signal.extend(silence(1000));
+        signal.extend(tone(500));
+        signal.extend(silence(1000));
+
+        let mut segments: Vec<(Duration, Vec<f32>)> = Vec::new();
+        // Pushed in uneven chunks like a tap delivers them
+        for chunk in signal.chunks(777) {
+            segments.extend(segmenter.push(chunk));
+        }
+        let starts: Vec<Duration> = segments.iter().map(|x| x.0).collect();
+        assert_eq!(
+            starts,
+            [Duration::from_millis(1500), Duration::from_millis(4400)]
+        );
+    }
+
+    #[test]
+    fn skipped_audio_still_counts_towards_the_start() {
+        let mut segmenter: Segmenter = Segmenter::new(SAMPLE_RATE);
+        let mut segments: Vec<(Duration, Vec<f32>)> = segmenter.push(&tone(500));
+        // An utterance cut by the gap is dropped, half a frame pending goes with the gap
+        segmenter.push(&silence(5));
+        segmenter.skip(SAMPLE_RATE * 2);
+        for chunk in [silence(1000), tone(500), silence(1000)] {
+            segments.extend(segmenter.push(&chunk));
+        }
+        let starts: Vec<Duration> = segments.iter().map(|x| x.0).collect();
+        assert_eq!(starts, [Duration::from_millis(3505)]);
+    }
+
+    #[test]
+    fn stub_transcripts_are_deterministic() {
+        let transcribe = || {
+            let mut transcriber: Box<dyn Transcriber> = TranscriberSettings {
+                kind: TranscriberKind::Stub,
+                ..TranscriberSettings::default()
+            }
+            .create("test")
+            .unwrap();
+            [tone(800), tone(500)]
+                .iter()
+                .map(|x| transcriber.transcribe(x, SAMPLE_RATE).unwrap())
+                .collect::<Vec<String>>()
+        };
+        let lines: Vec<String> = transcribe();
+        assert_eq!(lines, transcribe());
+        assert_eq!(lines[0], "Utterance 1 of 0.8 s peaking at -20 dBFS");
+        assert_eq!(lines[1], "Utterance 2 of 0.5 s peaking at -20 dBFS");
+    }
+
+    #[test]
+    fn transcript_lines_carry_when_the_speech_started() {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "p2p-voice-transcript-test-{}.txt",
+            std::process::id()
+        ));
+        let captions: Captions = Captions::default();
+        captions.start_transcript(&path).unwrap();
+        captions.push(CaptionLine {
+            speaker: Speaker::Peer,
+            start: Duration::from_millis(75500),
+            text: String::from("Hello"),
+        });
+        captions.stop_transcript();
+        let transcript: String = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(transcript, "[01:15] Peer: Hello\n");
+        assert_eq!(captions.lines().len(), 1);
+    }
+}
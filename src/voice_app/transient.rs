@@ -0,0 +1,248 @@
+use std::{
+    collections::VecDeque,
+    f32::consts::TAU,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use cpal::Sample;
+
+use crate::voice_app::denoise::DENOISE_SAMPLE_RATE;
+
+// Keystrokes rise within a 0.5 ms block, voices take several milliseconds to get loud
+const BLOCK_SIZE: usize = 24;
+// Glottal pulses are impulsive too but repeat within a pitch period, 12 ms covers voices down to about 85 Hz
+const HISTORY_BLOCKS: usize = 24;
+// A click is loudest in its first blocks and then rings down, while speech starting from a pause keeps getting louder.
+// An onset is only taken for a click once the next 2 ms didn't rise this much above it
+const CONFIRM_BLOCKS: usize = 4;
+const RISE_RATIO: f32 = 1.5;
+// The output lags 1 ms more than the confirmation takes so the gain is already down when a click arrives
+pub const LOOKAHEAD: usize = BLOCK_SIZE * (CONFIRM_BLOCKS + 2);
+// Clicks carry most of their energy above where voiced speech does
+const DETECTION_CUTOFF: f32 = 2000.0;
+// An onset rises this much above the loudest block of the last 12 ms and stands this far above the tracked level
+const ONSET_RATIO: f32 = 8.0;
+const THRESHOLD_RATIO: f32 = 20.0;
+// Consonants are impulsive too, so during speech only much louder onsets count
+const SPEECH_THRESHOLD_RATIO: f32 = 60.0;
+const SPEECH_PROBABILITY: f32 = 0.5;
+// Keystrokes ring for up to 20 ms
+const HOLD_BLOCKS: usize = 40;
+// About -60 dBFS, quieter clicks aren't worth ducking for
+const MIN_ENERGY: f32 = 1e-6;
+const ATTENUATION: f32 = 0.1;
+const SPEECH_ATTENUATION: f32 = 0.3;
+// Per sample smoothing of the gain, about 0.2 ms down and 10 ms back up
+const ATTACK: f32 = 0.1;
+const RELEASE: f32 = 0.002;
+// The level follows drops quickly and rises over about a second, so transients barely move it
+const LEVEL_FALL: f32 = 0.1;
+const LEVEL_RISE: f32 = 0.002;
+
+// Whether the denoise stage ducks clicks, shared between the UI and the denoise thread reading it every frame
+#[derive(Clone, Default)]
+pub struct TransientSuppression(Arc<AtomicBool>);
+
+impl TransientSuppression {
+    pub fn enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+// Ducks short broadband bursts like keyboard clicks that RNNoise lets through as they aren't steady noise
+// It runs inside the denoise stage, so always at RNNoise's rate, which the block sizes are counted at
+pub struct TransientSuppressor {
+    delay_line: VecDeque<f32>,
+    highpass_coefficient: f32,
+    highpass: f32,
+    last_input: f32,
+    block_energy: f32,
+    block_position: usize,
+    history: VecDeque<f32>,
+    level: f32,
+    // Energy of an unconfirmed onset and the blocks seen since
+    candidate: Option<(f32, usize)>,
+    hold: usize,
+    target: f32,
+    gain: f32,
+    detected: usize,
+}
+
+impl Default for TransientSuppressor {
+    fn default() -> Self {
+        TransientSuppressor {
+            delay_line: VecDeque::from(vec![Sample::EQUILIBRIUM; LOOKAHEAD]),
+            highpass_coefficient: (-TAU * DETECTION_CUTOFF / DENOISE_SAMPLE_RATE as f32).exp(),
+            highpass: 0.0,
+            last_input: 0.0,
+            block_energy: 0.0,
+            block_position: 0,
+            history: VecDeque::from(vec![0.0; HISTORY_BLOCKS]),
+            level: 0.0,
+            candidate: None,
+            hold: 0,
+            target: 1.0,
+            gain: 1.0,
+            detected: 0,
+        }
+    }
+}
+
+impl TransientSuppressor {
+    // `speech_probability` is the voice activity of the same audio, if known. Disabled it keeps detecting
+    // and delaying, so toggling neither changes the latency nor replays stale lookahead, and the gain just
+    // ramps back to unity
+    pub fn process(&mut self, buffer: &mut [f32], speech_probability: Option<f32>, enabled: bool) {
+        let speech: bool = speech_probability.is_some_and(|x| x >= SPEECH_PROBABILITY);
+        for sample in buffer.iter_mut() {
+            self.highpass = self.highpass_coefficient * (self.highpass + *sample - self.last_input);
+            self.last_input = *sample;
+            self.block_energy += self.highpass * self.highpass;
+            self.block_position += 1;
+            if self.block_position == BLOCK_SIZE {
+                self.detect(speech, enabled);
+            }
+
+            self.delay_line.push_back(*sample);
+            let delayed: f32 = self
+                .delay_line
+                .pop_front()
+                .expect("Failed to pop from transient delay line");
+            let target: f32 = if enabled { self.target } else { 1.0 };
+            let smoothing: f32 = if target < self.gain { ATTACK } else { RELEASE };
+            self.gain += (target - self.gain) * smoothing;
+            *sample = delayed * self.gain;
+        }
+    }
+
+    // Number of transients suppressed so far
+    pub fn detected(&self) -> usize {
+        self.detected
+    }
+
+    fn detect(&mut self, speech: bool, enabled: bool) {
+        let energy: f32 = self.block_energy / BLOCK_SIZE as f32;
+        self.block_energy = 0.0;
+        self.block_position = 0;
+
+        let threshold: f32 = if speech {
+            SPEECH_THRESHOLD_RATIO
+        } else {
+            THRESHOLD_RATIO
+        };
+        let recent: f32 = self.history.iter().fold(0.0_f32, |peak, x| peak.max(*x));
+        let loud: bool = energy > MIN_ENERGY && energy > self.level * threshold;
+        let onset: bool = energy > recent * ONSET_RATIO;
+        self.history.pop_front();
+        self.history.push_back(energy);
+
+        if loud && onset && self.hold > 0 {
+            self.hold = HOLD_BLOCKS;
+        } else if let Some((onset_energy, blocks)) = self.candidate {
+            if blocks == 0 {
+                // A click starting late in a block is only fully seen in the next one
+                self.candidate = Some((onset_energy.max(energy), 1));
+            } else if energy > onset_energy * RISE_RATIO {
+                self.candidate = None;
+            } else if blocks + 1 == CONFIRM_BLOCKS {
+                self.candidate = None;
+                if enabled {
+                    self.detected += 1;
+                }
+                self.hold = HOLD_BLOCKS;
+            } else {
+                self.candidate = Some((onset_energy, blocks + 1));
+            }
+        } else if loud && onset {
+            self.candidate = Some((energy, 0));
+        } else if self.hold > 0 {
+            self.hold -= 1;
+        }
+
+        if self.hold > 0 {
+            self.target = if speech {
+                SPEECH_ATTENUATION
+            } else {
+                ATTENUATION
+            };
+        } else if self.candidate.is_none() {
+            self.target = 1.0;
+            let rate: f32 = if energy < self.level {
+                LEVEL_FALL
+            } else {
+                LEVEL_RISE
+            };
+            self.level += (energy - self.level) * rate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::voice_app::{file_source::load_file, test_signals::noise};
+
+    // In 10 ms frames like the denoise stage
+    fn suppress(samples: &[f32]) -> (Vec<f32>, usize) {
+        let mut suppressor: TransientSuppressor = TransientSuppressor::default();
+        let mut output: Vec<f32> = samples.to_vec();
+        for frame in output.chunks_mut(480) {
+            suppressor.process(frame, None, true);
+        }
+        (output, suppressor.detected())
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|x| x * x).sum()
+    }
+
+    #[test]
+    fn keyboard_clicks_are_attenuated() {
+        let keyboard: Vec<f32> = load_file(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keyboard.wav"),
+            DENOISE_SAMPLE_RATE,
+        )
+        .expect("Failed to load fixture");
+        let (output, detected) = suppress(&keyboard);
+        // 13 key presses, each with a softer release
+        assert_eq!(detected, 26);
+        assert!(energy(&output) < energy(&keyboard) * 0.05);
+    }
+
+    #[test]
+    fn sounds_still_rising_are_not_clicks() {
+        // Noise fading in over 10 ms from silence, like a fricative starting a word
+        let mut input: Vec<f32> = vec![0.0; 4800];
+        input.extend(
+            noise(9600, 0.15, 1)
+                .iter()
+                .enumerate()
+                .map(|(i, x)| x * (i as f32 / 480.0).min(1.0)),
+        );
+        let (output, detected) = suppress(&input);
+        assert_eq!(detected, 0);
+        assert_eq!(output[LOOKAHEAD..], input[..input.len() - LOOKAHEAD]);
+    }
+
+    #[test]
+    fn toggling_keeps_the_lookahead_delay() {
+        // Steady noise, switched on and off every 100 ms once the onset at its start has passed
+        let input: Vec<f32> = noise(DENOISE_SAMPLE_RATE, 0.1, 1);
+        let mut suppressor: TransientSuppressor = TransientSuppressor::default();
+        let mut output: Vec<f32> = input.clone();
+        for (i, frame) in output.chunks_mut(480).enumerate() {
+            suppressor.process(frame, None, (i / 10) % 2 == 1);
+        }
+        assert_eq!(suppressor.detected(), 0);
+        assert_eq!(output[LOOKAHEAD..], input[..input.len() - LOOKAHEAD]);
+    }
+}
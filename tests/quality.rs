@@ -0,0 +1,97 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+}
+
+// Runs the harness from its own directory so the log file stays out of the repo
+fn quality(name: &str, args: &[&str]) -> Output {
+    let directory: PathBuf = env::temp_dir().join(format!("p2p-voice-quality-{name}"));
+    fs::create_dir_all(&directory).expect("Failed to create test directory");
+    Command::new(env!("CARGO_BIN_EXE_p2p-voice"))
+        .current_dir(&directory)
+        .arg("--quality")
+        .arg(fixture("speech.wav"))
+        .arg("--baseline")
+        .arg(fixture("quality-baseline.txt"))
+        .args(args)
+        .output()
+        .expect("Failed to run the quality harness")
+}
+
+fn assert_passes(name: &str, args: &[&str]) {
+    let output: Output = quality(name, args);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn opus_holds_the_baseline_at_every_processing_rate() {
+    for rate in ["16000", "24000", "48000"] {
+        assert_passes(&format!("opus-{rate}"), &["--rate", rate]);
+    }
+}
+
+#[test]
+fn pcm_codecs_hold_the_baseline() {
+    for codec in ["l16", "pcmu", "pcma"] {
+        for rate in ["16000", "24000", "48000"] {
+            assert_passes(
+                &format!("{codec}-{rate}"),
+                &["--codec", codec, "--rate", rate],
+            );
+        }
+    }
+}
+
+#[test]
+fn opus_holds_the_baseline_under_packet_loss() {
+    for rate in ["16000", "24000", "48000"] {
+        assert_passes(&format!("loss-{rate}"), &["--loss", "5", "--rate", rate]);
+    }
+}
+
+#[test]
+fn keyboard_noise_holds_the_baseline_with_and_without_transient_suppression() {
+    let noise: PathBuf = fixture("keyboard.wav");
+    let noise: &str = noise.to_str().expect("Failed to convert fixture path");
+    for rate in ["16000", "24000", "48000"] {
+        assert_passes(
+            &format!("keyboard-{rate}"),
+            &["--noise", noise, "--rate", rate],
+        );
+        assert_passes(
+            &format!("transients-{rate}"),
+            &["--noise", noise, "--suppress-transients", "--rate", rate],
+        );
+    }
+}
+
+#[test]
+fn missing_baseline_fails() {
+    let output: Output = Command::new(env!("CARGO_BIN_EXE_p2p-voice"))
+        .current_dir(env::temp_dir())
+        .arg("--quality")
+        .arg(fixture("speech.wav"))
+        .arg("--baseline")
+        .arg(fixture("missing-baseline.txt"))
+        .output()
+        .expect("Failed to run the quality harness");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn missing_baseline_entry_fails() {
+    let output: Output = quality("unknown", &["--loss", "50"]);
+    assert!(!output.status.success());
+}